@@ -0,0 +1,53 @@
+//! Flood a node with pings and print what [`stress::run_with`] measured.
+//!
+//! ```text
+//! cargo run --example stress --features testing -- [node_id@host[:port] | -] [messages] [payload_size]
+//! ```
+//!
+//! Without a node, or with `-` for one, an in-memory peer is used, see [`stress::run_local`].
+
+use bitcoin::secp256k1::{SecretKey, rand};
+use lnsocket::stress::{self, StressOptions, StressReport};
+use lnsocket::{Error, LNSocket};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Error> {
+    let mut args = std::env::args().skip(1);
+    let uri = args.next().filter(|uri| uri != "-");
+    let mut opts = StressOptions::default();
+    if let Some(messages) = args.next() {
+        opts.messages = messages.parse().expect("messages should be a number");
+    }
+    if let Some(size) = args.next() {
+        opts.payload_size = size.parse().expect("payload_size should be a number");
+    }
+
+    let report = match uri {
+        Some(uri) => {
+            let key = SecretKey::new(&mut rand::thread_rng());
+            let mut socket = LNSocket::connect_uri(key, &uri).await?;
+            socket.perform_init().await?;
+            stress::run_with(&opts, &mut socket).await?
+        }
+        None => stress::run_local(&opts).await?,
+    };
+    print_report(&report);
+    Ok(())
+}
+
+fn print_report(report: &StressReport) {
+    println!(
+        "sent {}, answered {}, dropped {} in {:?}",
+        report.sent, report.received, report.dropped, report.elapsed
+    );
+    println!(
+        "{:.1} msgs/s, {:.2} MB/s",
+        report.messages_per_sec(),
+        report.bytes_per_sec() / 1e6
+    );
+    for p in [50.0, 90.0, 99.0] {
+        if let Some(rtt) = report.percentile(p) {
+            println!("p{p}: {rtt:?}");
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Reaching nodes over Tor without a Tor daemon, with Arti running in-process.
+//!
+//! [`Arti::bootstrap`] starts a Tor client and waits until it can build circuits, reporting
+//! its progress as [`Event::TorBootstrap`]. The client is then shared by every connection made
+//! with [`LNSocket::connect_via_arti`], to onion addresses and clearnet hosts alike. Each
+//! connection gets circuits of its own unless told otherwise, see [`Isolation`].
+//!
+//! Only with the `tor-arti` feature.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::arti::Arti;
+//! # use bitcoin::secp256k1::{PublicKey, SecretKey};
+//! # async fn example(key: SecretKey, node: PublicKey) -> Result<(), lnsocket::Error> {
+//! let (tx, mut progress) = tokio::sync::mpsc::unbounded_channel();
+//! tokio::spawn(async move {
+//!     while let Some(event) = progress.recv().await {
+//!         println!("{event:?}");
+//!     }
+//! });
+//! let tor = Arti::bootstrap(Some(&tx)).await?;
+//! let mut socket = LNSocket::connect_via_arti(&tor, key, node, "example.onion:9735").await?;
+//! socket.perform_init().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::event::Event;
+use crate::socket_addr::split_host_port;
+use crate::tor::Isolation;
+use crate::{Error, LNSocket};
+use arti_client::config::BoolOrAuto;
+use arti_client::{
+    BootstrapBehavior, DataStream, IsolationToken, StreamPrefs, TorClient, TorClientConfig,
+};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tor_rtcompat::PreferredRuntime;
+
+/// A bootstrapped in-process Tor client. Cheap to share by reference between connections.
+pub struct Arti {
+    client: TorClient<PreferredRuntime>,
+    // circuits for each Isolation::Key
+    tokens: Mutex<HashMap<String, IsolationToken>>,
+}
+
+impl Arti {
+    /// Start a Tor client with the default configuration, keeping its state and directory cache
+    /// in the user's data directory. Waits until it's ready for traffic, sending its progress
+    /// to `events` if given.
+    pub async fn bootstrap(events: Option<&mpsc::UnboundedSender<Event>>) -> Result<Self, Error> {
+        Self::bootstrap_with(TorClientConfig::default(), events).await
+    }
+
+    /// Like [`Arti::bootstrap`], with `config`.
+    pub async fn bootstrap_with(
+        config: TorClientConfig,
+        events: Option<&mpsc::UnboundedSender<Event>>,
+    ) -> Result<Self, Error> {
+        let client = TorClient::builder()
+            .config(config)
+            .bootstrap_behavior(BootstrapBehavior::Manual)
+            .create_unbootstrapped()
+            .map_err(tor_error)?;
+
+        // the bootstrap future borrows the client, so it has to be done with before the client
+        // moves into Self
+        {
+            let mut progress = client.bootstrap_events();
+            let bootstrap = client.bootstrap();
+            tokio::pin!(bootstrap);
+            loop {
+                tokio::select! {
+                    res = &mut bootstrap => break res.map_err(tor_error)?,
+                    Some(status) = progress.next() => {
+                        let percent = (status.as_frac() * 100.0).clamp(0.0, 100.0) as u8;
+                        if let Some(events) = events {
+                            events.send(Event::TorBootstrap { percent }).ok();
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            client,
+            tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Open a Tor stream to `addr`, a `host:port` with an onion or clearnet host. Onion
+    /// services are looked up by Tor, and clearnet hosts resolved by the exit.
+    ///
+    /// This is a plain [`Transport`](crate::lnsocket::Transport) for
+    /// [`LNSocket::connect_over`] or a stack with a handshake of its own.
+    pub async fn open(&self, addr: &str, isolation: &Isolation) -> Result<DataStream, Error> {
+        let (host, port) = split_host_port(addr)?;
+        let mut prefs = StreamPrefs::new();
+        prefs.connect_to_onion_services(BoolOrAuto::Explicit(true));
+        match isolation {
+            Isolation::None => {}
+            Isolation::PerConnection => {
+                prefs.new_isolation_group();
+            }
+            Isolation::Key(key) => {
+                let mut tokens = self.tokens.lock().unwrap();
+                let token = *tokens
+                    .entry(key.clone())
+                    .or_insert_with(IsolationToken::new);
+                prefs.set_isolation(token);
+            }
+        }
+        self.client
+            .connect_with_prefs((host, port), &prefs)
+            .await
+            .map_err(tor_error)
+    }
+}
+
+fn tor_error(err: arti_client::Error) -> Error {
+    Error::Tor(err.to_string())
+}
+
+impl LNSocket {
+    /// Like [`LNSocket::connect`], but over the in-process Tor client `tor`, on circuits of its
+    /// own.
+    pub async fn connect_via_arti(
+        tor: &Arti,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+    ) -> Result<LNSocket, Error> {
+        let isolation = Isolation::PerConnection;
+        Self::connect_via_arti_isolated(tor, our_key, their_pubkey, addr, &isolation).await
+    }
+
+    /// Like [`LNSocket::connect_via_arti`], sharing circuits as `isolation` says.
+    pub async fn connect_via_arti_isolated(
+        tor: &Arti,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+        isolation: &Isolation,
+    ) -> Result<LNSocket, Error> {
+        let start = Instant::now();
+        let stream = tor.open(addr, isolation).await?;
+        let tcp_connect = start.elapsed();
+        let mut socket = Self::handshake_outbound(stream, our_key, their_pubkey).await?;
+        socket.timings.tcp_connect = Some(tcp_connect);
+        Ok(socket)
+    }
+}
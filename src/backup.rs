@@ -0,0 +1,368 @@
+//! Stashing an encrypted backup (e.g. a static channel backup) with a peer.
+//!
+//! Built on the BOLT 1 `peer_storage` messages: we send a blob with `peer_storage`, and peers
+//! that advertise `provide_storage` send the latest one back in `peer_storage_retrieval` each
+//! time we reconnect. The peer is untrusted, so blobs are encrypted and authenticated with a
+//! key only we know, and padded so their size reveals little.
+//!
+//! A peer stores at most [`MAX_STORAGE_LEN`] bytes, so larger backups are split into chunks
+//! of up to [`MAX_CHUNK_LEN`], which [`store`] spreads over several peers and [`retrieve`]
+//! puts back together.
+//!
+//! Chunk layout: format byte (1), version (u64), chunk index (u16), chunk count (u16), nonce
+//! (8 bytes), then the ChaCha20-Poly1305 encrypted payload and its tag. The payload is the
+//! length of the chunk's data (u32) followed by the data and zero padding.
+
+use crate::crypto::chacha20poly1305rfc::ChaCha20Poly1305RFC;
+use crate::features::bits;
+use crate::ln::msgs;
+use crate::{Error, LNSocket};
+use bitcoin::hashes::{Hash, HashEngine, sha256};
+use bitcoin::secp256k1::rand::{self, RngCore};
+use std::fmt;
+#[cfg(feature = "tokio")]
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+/// The most a peer stores for us (BOLT 1).
+pub const MAX_STORAGE_LEN: usize = 65531;
+
+const FORMAT: u8 = 1;
+const HEADER_LEN: usize = 1 + 8 + 2 + 2 + 8;
+const TAG_LEN: usize = 16;
+const PAD_TO: usize = 1024;
+
+/// The most backup data a single chunk, and so a single peer, holds.
+pub const MAX_CHUNK_LEN: usize = MAX_STORAGE_LEN - HEADER_LEN - TAG_LEN - 4;
+
+/// The largest backup, in as many chunks as can be numbered.
+pub const MAX_BACKUP_LEN: usize = MAX_CHUNK_LEN * u16::MAX as usize;
+
+/// Why storing or retrieving a backup failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackupError {
+    /// The peer doesn't advertise `provide_storage`.
+    Unsupported,
+    /// The backup is larger than [`MAX_BACKUP_LEN`].
+    TooLarge(usize),
+    /// The backup needs more chunks than there are peers to store them.
+    TooFewPeers { chunks: usize, peers: usize },
+    /// None of the stored chunks are ours: they were made with another key, or tampered with.
+    Corrupt,
+    /// Some chunks of the latest backup are missing.
+    Incomplete,
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::Unsupported => write!(f, "peer does not provide storage"),
+            BackupError::TooLarge(len) => {
+                write!(f, "backup is {} bytes, at most {} fit", len, MAX_BACKUP_LEN)
+            }
+            BackupError::TooFewPeers { chunks, peers } => {
+                write!(f, "backup needs {} peers, only {} given", chunks, peers)
+            }
+            BackupError::Corrupt => write!(f, "stored backup is corrupt or not ours"),
+            BackupError::Incomplete => write!(f, "chunks of the stored backup are missing"),
+        }
+    }
+}
+
+/// The key backups are encrypted with. Wiped from memory when dropped.
+#[derive(Clone)]
+pub struct BackupKey(Zeroizing<[u8; 32]>);
+
+impl BackupKey {
+    /// Derive the backup key from the node seed, so it can be recreated on a fresh device.
+    pub fn from_seed(seed: &[u8]) -> Self {
+        let mut engine = sha256::Hash::engine();
+        engine.input(b"lnsocket peer backup");
+        engine.input(seed);
+        Self(Zeroizing::new(
+            sha256::Hash::from_engine(engine).to_byte_array(),
+        ))
+    }
+}
+
+/// A decrypted backup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Backup {
+    /// The version given to [`store`], so stale backups can be told apart.
+    pub version: u64,
+    pub data: Vec<u8>,
+}
+
+/// Encrypt `data` as backup `version`, in as many chunks as it takes. Each chunk fits in a
+/// `peer_storage` message.
+pub fn seal(key: &BackupKey, version: u64, data: &[u8]) -> Result<Vec<Vec<u8>>, BackupError> {
+    if data.len() > MAX_BACKUP_LEN {
+        return Err(BackupError::TooLarge(data.len()));
+    }
+
+    // an empty backup still takes a chunk
+    let count = data.len().div_ceil(MAX_CHUNK_LEN).max(1);
+    let chunks = (0..count).map(|index| {
+        let start = index * MAX_CHUNK_LEN;
+        let part = &data[start..data.len().min(start + MAX_CHUNK_LEN)];
+        seal_chunk(key, version, index as u16, count as u16, part)
+    });
+    Ok(chunks.collect())
+}
+
+fn seal_chunk(key: &BackupKey, version: u64, index: u16, count: u16, data: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(PAD_TO);
+    payload.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    payload.extend_from_slice(data);
+    let room = MAX_STORAGE_LEN - HEADER_LEN - TAG_LEN;
+    payload.resize(payload.len().next_multiple_of(PAD_TO).min(room), 0);
+
+    let mut nonce = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let mut blob = Vec::with_capacity(HEADER_LEN + payload.len() + TAG_LEN);
+    blob.push(FORMAT);
+    blob.extend_from_slice(&version.to_be_bytes());
+    blob.extend_from_slice(&index.to_be_bytes());
+    blob.extend_from_slice(&count.to_be_bytes());
+    blob.extend_from_slice(&nonce);
+    let header_end = blob.len();
+    blob.resize(header_end + payload.len() + TAG_LEN, 0);
+
+    let (header, rest) = blob.split_at_mut(header_end);
+    let (ciphertext, tag) = rest.split_at_mut(payload.len());
+    cipher(key, &nonce, header).encrypt(&payload, ciphertext, tag);
+    blob
+}
+
+/// Decrypt the chunks made by [`seal`], in any order, and put the backup back together.
+///
+/// Chunks that don't decrypt, and chunks of older versions, e.g. from a peer that missed the
+/// last [`store`], are ignored. Every chunk of the latest version has to be there at least once.
+pub fn open<B: AsRef<[u8]>>(key: &BackupKey, blobs: &[B]) -> Result<Backup, BackupError> {
+    // a peer handing back garbage only matters if no one else has a copy of that chunk
+    let mut chunks: Vec<_> = blobs
+        .iter()
+        .filter_map(|blob| open_chunk(key, blob.as_ref()).ok())
+        .collect();
+    let Some(version) = chunks.iter().map(|chunk| chunk.version).max() else {
+        return Err(if blobs.is_empty() {
+            BackupError::Incomplete
+        } else {
+            BackupError::Corrupt
+        });
+    };
+    chunks.retain(|chunk| chunk.version == version);
+    chunks.sort_by_key(|chunk| chunk.index);
+    // the same chunk may come from several peers
+    chunks.dedup_by_key(|chunk| chunk.index);
+
+    let count = chunks[0].count as usize;
+    let complete = chunks.len() == count
+        && chunks
+            .iter()
+            .enumerate()
+            .all(|(i, chunk)| chunk.index as usize == i && chunk.count as usize == count);
+    if !complete {
+        return Err(BackupError::Incomplete);
+    }
+    Ok(Backup {
+        version,
+        data: chunks.into_iter().flat_map(|chunk| chunk.data).collect(),
+    })
+}
+
+struct Chunk {
+    version: u64,
+    index: u16,
+    count: u16,
+    data: Vec<u8>,
+}
+
+fn open_chunk(key: &BackupKey, blob: &[u8]) -> Result<Chunk, BackupError> {
+    if blob.len() < HEADER_LEN + 4 + TAG_LEN || blob[0] != FORMAT {
+        return Err(BackupError::Corrupt);
+    }
+    let (header, rest) = blob.split_at(HEADER_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let version = u64::from_be_bytes(header[1..9].try_into().expect("8 bytes"));
+    let index = u16::from_be_bytes(header[9..11].try_into().expect("2 bytes"));
+    let count = u16::from_be_bytes(header[11..13].try_into().expect("2 bytes"));
+    let nonce: [u8; 8] = header[13..].try_into().expect("8 bytes");
+
+    let mut payload = vec![0u8; ciphertext.len()];
+    cipher(key, &nonce, header)
+        .variable_time_decrypt(ciphertext, &mut payload, tag)
+        .map_err(|_| BackupError::Corrupt)?;
+
+    let len = u32::from_be_bytes(payload[..4].try_into().expect("4 bytes")) as usize;
+    let data = payload.get(4..4 + len).ok_or(BackupError::Corrupt)?;
+    if index >= count {
+        return Err(BackupError::Corrupt);
+    }
+    Ok(Chunk {
+        version,
+        index,
+        count,
+        data: data.to_vec(),
+    })
+}
+
+/// The header is authenticated too, so the peer can't pass off an old backup as a new one.
+fn cipher(key: &BackupKey, nonce: &[u8; 8], header: &[u8]) -> ChaCha20Poly1305RFC {
+    let mut full_nonce = [0u8; 12];
+    full_nonce[4..].copy_from_slice(nonce);
+    ChaCha20Poly1305RFC::new(&key.0[..], &full_nonce, header)
+}
+
+/// Encrypt `data` and ask `peers` to store it, replacing whatever they held for us.
+///
+/// Each peer gets one chunk, handed out in turn so extra peers hold spare copies. Backups of up
+/// to [`MAX_CHUNK_LEN`] need a single peer. Use an increasing `version` so [`retrieve`] can
+/// tell whether the peers returned the latest backup.
+pub async fn store(
+    peers: &mut [&mut LNSocket],
+    key: &BackupKey,
+    version: u64,
+    data: &[u8],
+) -> Result<(), Error> {
+    let provide_storage = peers.iter().all(|peer| {
+        peer.peer_info()
+            .is_some_and(|info| info.features().supports(bits::PROVIDE_STORAGE))
+    });
+    if !provide_storage {
+        return Err(BackupError::Unsupported.into());
+    }
+
+    let chunks = seal(key, version, data)?;
+    if chunks.len() > peers.len() {
+        return Err(BackupError::TooFewPeers {
+            chunks: chunks.len(),
+            peers: peers.len(),
+        }
+        .into());
+    }
+    for (i, peer) in peers.iter_mut().enumerate() {
+        let data = chunks[i % chunks.len()].clone();
+        peer.write(&msgs::PeerStorage { data }).await?;
+    }
+    Ok(())
+}
+
+/// Wait up to `wait` for each of `peers` to hand back its chunk of our backup, and decrypt it.
+///
+/// Peers send it right after `init`; a peer with nothing stored for us sends nothing at all.
+/// Anything else they send in the meantime is kept for later reads. Peers that fail, don't
+/// answer in time or hand back something that isn't ours are skipped, so this only fails when
+/// no peer returned some chunk of the latest backup.
+#[cfg(feature = "tokio")]
+pub async fn retrieve(
+    peers: &mut [&mut LNSocket],
+    key: &BackupKey,
+    wait: Duration,
+) -> Result<Backup, Error> {
+    let mut chunks = Vec::with_capacity(peers.len());
+    for peer in peers.iter_mut() {
+        if let Ok(msg) = peer
+            .wait_for_timeout::<msgs::PeerStorageRetrieval>(wait)
+            .await
+        {
+            chunks.push(msg.data);
+        }
+    }
+    Ok(open(key, &chunks)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockPeer;
+    use bitcoin::secp256k1::SecretKey;
+
+    #[test]
+    fn test_seal_open() {
+        let key = BackupKey::from_seed(&[1; 32]);
+        let chunks = seal(&key, 7, b"channel backup").unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].len(), HEADER_LEN + PAD_TO + TAG_LEN);
+        assert_eq!(
+            open(&key, &chunks),
+            Ok(Backup {
+                version: 7,
+                data: b"channel backup".to_vec(),
+            })
+        );
+
+        // wrong key
+        let other = BackupKey::from_seed(&[2; 32]);
+        assert_eq!(open(&other, &chunks), Err(BackupError::Corrupt));
+
+        // rolled back version
+        let mut tampered = chunks[0].clone();
+        tampered[8] ^= 1;
+        assert_eq!(open(&key, &[tampered]), Err(BackupError::Corrupt));
+
+        let big = vec![0u8; MAX_CHUNK_LEN];
+        let chunks = seal(&key, 1, &big).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].len(), MAX_STORAGE_LEN);
+        assert_eq!(open(&key, &chunks).unwrap().data, big);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let key = BackupKey::from_seed(&[1; 32]);
+        let data: Vec<u8> = (0..2 * MAX_CHUNK_LEN + 100).map(|i| i as u8).collect();
+        let mut chunks = seal(&key, 3, &data).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= MAX_STORAGE_LEN));
+
+        // in any order, with duplicates, garbage and an older backup's chunk mixed in
+        chunks.reverse();
+        chunks.push(chunks[0].clone());
+        chunks.push(vec![FORMAT; 100]);
+        chunks.extend(seal(&key, 2, b"old").unwrap());
+        assert_eq!(
+            open(&key, &chunks),
+            Ok(Backup {
+                version: 3,
+                data: data.clone(),
+            })
+        );
+
+        // drop the chunk at index 1, byte 10 is the low byte of the index
+        chunks.retain(|chunk| chunk[10] != 1);
+        assert_eq!(open(&key, &chunks), Err(BackupError::Incomplete));
+        assert_eq!(open::<Vec<u8>>(&key, &[]), Err(BackupError::Incomplete));
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_skips_bad_peers() -> Result<(), Error> {
+        let key = BackupKey::from_seed(&[1; 32]);
+        let chunks = seal(&key, 5, b"channel backup")?;
+        let ours = SecretKey::new(&mut rand::thread_rng());
+
+        let (mut good, good_peer) = MockPeer::connect(ours).await?;
+        let (mut garbage, garbage_peer) = MockPeer::connect(ours).await?;
+        // the offline peer never answers
+        let (mut offline, _offline_peer) = MockPeer::connect(ours).await?;
+        for socket in [&mut good, &mut garbage, &mut offline] {
+            socket.perform_init().await?;
+        }
+        good_peer.send(&msgs::PeerStorageRetrieval {
+            data: chunks[0].clone(),
+        })?;
+        garbage_peer.send(&msgs::PeerStorageRetrieval {
+            data: vec![0xab; 100],
+        })?;
+
+        let wait = Duration::from_millis(100);
+        let backup = retrieve(&mut [&mut garbage, &mut offline, &mut good], &key, wait).await?;
+        assert_eq!(backup.data, b"channel backup");
+
+        // with only the bad peers left there's nothing to put together
+        let err = retrieve(&mut [&mut garbage, &mut offline], &key, wait).await;
+        assert!(matches!(err, Err(Error::Backup(BackupError::Incomplete))));
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! A synchronous client, for scripts and command line tools that don't want async.
+//!
+//! [`LNSocket`] and [`CommandoClient`] wrap their async counterparts and drive them on a
+//! single threaded tokio runtime owned by the socket, so no runtime needs to be set up. Don't
+//! use them from inside an async runtime: blocking there panics.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::blocking::{CommandoClient, LNSocket};
+//! use serde_json::json;
+//! # use bitcoin::secp256k1::{PublicKey, SecretKey};
+//! # fn example(key: SecretKey, node: PublicKey) -> Result<(), lnsocket::Error> {
+//! let mut socket = LNSocket::connect_and_init(key, node, "ln.example.com:9735")?;
+//! let mut commando = CommandoClient::new("your-rune-token");
+//! let info = commando.call(&mut socket, "getinfo", json!({}))?;
+//! println!("{info}");
+//! # Ok(()) }
+//! ```
+
+use crate::Error;
+use crate::ln::wire::{self, Message};
+use crate::util::ser::Writeable;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::runtime::{Builder, Runtime};
+
+/// A blocking [`crate::LNSocket`].
+pub struct LNSocket {
+    runtime: Runtime,
+    socket: crate::LNSocket,
+}
+
+fn runtime() -> Result<Runtime, Error> {
+    Ok(Builder::new_current_thread().enable_all().build()?)
+}
+
+impl LNSocket {
+    /// Like [`crate::LNSocket::connect`].
+    pub fn connect(our_key: SecretKey, their_pubkey: PublicKey, addr: &str) -> Result<Self, Error> {
+        let runtime = runtime()?;
+        let socket = runtime.block_on(crate::LNSocket::connect(our_key, their_pubkey, addr))?;
+        Ok(Self { runtime, socket })
+    }
+
+    /// Like [`crate::LNSocket::connect_and_init`].
+    pub fn connect_and_init(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+    ) -> Result<Self, Error> {
+        let mut socket = Self::connect(our_key, their_pubkey, addr)?;
+        socket.perform_init()?;
+        Ok(socket)
+    }
+
+    /// Like [`crate::LNSocket::perform_init`].
+    pub fn perform_init(&mut self) -> Result<(), Error> {
+        self.runtime.block_on(self.socket.perform_init())
+    }
+
+    /// Like [`crate::LNSocket::write`].
+    pub fn write<M: wire::Type + Writeable>(&mut self, m: &M) -> Result<(), Error> {
+        self.runtime.block_on(self.socket.write(m))
+    }
+
+    /// Like [`crate::LNSocket::read`]. Blocks until a message arrives, or the read timeout set
+    /// on the inner socket runs out.
+    pub fn read(&mut self) -> Result<Message<()>, Error> {
+        self.runtime.block_on(self.socket.read())
+    }
+
+    /// The async socket, for settings such as
+    /// [`set_read_timeout`](crate::LNSocket::set_read_timeout).
+    pub fn inner_mut(&mut self) -> &mut crate::LNSocket {
+        &mut self.socket
+    }
+
+    /// The async socket. It needs a runtime of its own from here on.
+    pub fn into_inner(self) -> crate::LNSocket {
+        self.socket
+    }
+}
+
+/// A blocking [`crate::CommandoClient`].
+pub struct CommandoClient(crate::CommandoClient);
+
+impl CommandoClient {
+    pub fn new(rune: impl Into<String>) -> Self {
+        Self(crate::CommandoClient::new(rune))
+    }
+
+    /// Like [`crate::CommandoClient::call`].
+    pub fn call(
+        &mut self,
+        socket: &mut LNSocket,
+        method: impl Into<String>,
+        params: Value,
+    ) -> Result<Value, Error> {
+        let call = self.0.call(&mut socket.socket, method, params);
+        socket.runtime.block_on(call)
+    }
+
+    /// Like [`crate::CommandoClient::call_typed`].
+    pub fn call_typed<T: DeserializeOwned>(
+        &mut self,
+        socket: &mut LNSocket,
+        method: impl Into<String>,
+        params: Value,
+    ) -> Result<T, Error> {
+        let call = self.0.call_typed(&mut socket.socket, method, params);
+        socket.runtime.block_on(call)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::msgs;
+    use bitcoin::secp256k1::{Secp256k1, rand};
+
+    #[test]
+    fn test_blocking_roundtrip() -> Result<(), Error> {
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?.to_string();
+
+        let node = std::thread::spawn(move || {
+            runtime()?.block_on(async {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let (stream, _) = listener.accept().await?;
+                let mut node = crate::LNSocket::accept_over(stream, node_key).await?;
+                node.write(&crate::testing::default_init()).await?;
+                assert!(matches!(node.read().await?, Message::Init(_)));
+                node.read().await
+            })
+        });
+
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let mut socket = LNSocket::connect_and_init(key, node_id, &addr)?;
+        socket.write(&msgs::Ping {
+            ponglen: 0,
+            byteslen: 3,
+        })?;
+        let got = node.join().unwrap()?;
+        assert!(matches!(got, Message::Ping(ping) if ping.byteslen == 3));
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+//! Connecting from web pages, over the browser's WebSocket.
+//!
+//! Browsers can't open TCP connections, so with the `wasm` feature and built for
+//! `wasm32-unknown-unknown`, [`LNSocket::connect`] takes a `ws://` or `wss://` URL and opens
+//! it with the browser's `WebSocket`, like lnsocket.js does. The Noise handshake, `init` and
+//! [`CommandoClient`](crate::CommandoClient) then work as they do natively. The node needs
+//! to accept WebSockets (Core Lightning's `experimental-websocket-port`), or be reached
+//! through a [WebSocket-to-TCP proxy](crate::ws_proxy).
+//!
+//! What needs sockets or a tokio runtime isn't there in browsers: the `listener`, `tor` and
+//! `ldk` modules, host name resolution, and the background task of [`LNSocket::run`]. Don't
+//! enable `experimental`, its WebSockets are tokio's TCP ones. [`LNSocket::set_read_timeout`]
+//! needs tokio's timer, so leave it unset. TLS for `wss://` is the browser's.
+//!
+//! ### Example
+//! ```ignore
+//! use lnsocket::{CommandoClient, LNSocket};
+//! use serde_json::json;
+//!
+//! // e.g. from wasm_bindgen_futures::spawn_local
+//! let mut socket = LNSocket::connect_and_init(our_key, node_id, "wss://node.example.com").await?;
+//! let mut commando = CommandoClient::new(rune);
+//! let info = commando.call(&mut socket, "getinfo", json!({})).await?;
+//! ```
+
+use crate::{Error, LNSocket};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use futures_util::{Sink, Stream};
+use gloo_net::websocket::{Message, WebSocketError, futures::WebSocket};
+use send_wrapper::SendWrapper;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+fn ws_io_error(err: WebSocketError) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+/// A browser WebSocket carrying a byte stream in binary messages. Text messages are skipped.
+///
+/// Browsers run a page on one thread, so the socket is `Send` for [`LNSocket`]'s sake but
+/// panics if it's used from another thread, e.g. a web worker it was moved to.
+pub struct BrowserWebSocket {
+    ws: SendWrapper<WebSocket>,
+    // the rest of a binary message a read didn't have room for
+    buffered: Vec<u8>,
+    pos: usize,
+}
+
+impl std::fmt::Debug for BrowserWebSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BrowserWebSocket")
+            .field("buffered", &(self.buffered.len() - self.pos))
+            .finish_non_exhaustive()
+    }
+}
+
+impl BrowserWebSocket {
+    /// Start opening a WebSocket to `url`. Writes wait until it's open.
+    pub fn open(url: &str) -> Result<Self, Error> {
+        let ws = WebSocket::open(url).map_err(|err| Error::InvalidUri(format!("{url}: {err}")))?;
+        Ok(Self {
+            ws: SendWrapper::new(ws),
+            buffered: Vec::new(),
+            pos: 0,
+        })
+    }
+}
+
+impl AsyncRead for BrowserWebSocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.pos == this.buffered.len() {
+            match ready!(Pin::new(&mut *this.ws).poll_next(cx)) {
+                Some(Ok(Message::Bytes(data))) => {
+                    this.buffered = data;
+                    this.pos = 0;
+                }
+                Some(Ok(Message::Text(_))) => {}
+                Some(Err(WebSocketError::ConnectionClose(close))) if close.was_clean => {
+                    return Poll::Ready(Ok(()));
+                }
+                None => return Poll::Ready(Ok(())),
+                Some(Err(err)) => return Poll::Ready(Err(ws_io_error(err))),
+            }
+        }
+        let n = buf.remaining().min(this.buffered.len() - this.pos);
+        buf.put_slice(&this.buffered[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for BrowserWebSocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // pending until the socket is open, after that the browser queues what we send
+        ready!(Pin::new(&mut *self.ws).poll_ready(cx)).map_err(ws_io_error)?;
+        Pin::new(&mut *self.ws)
+            .start_send(Message::Bytes(buf.to_vec()))
+            .map_err(ws_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl LNSocket {
+    /// Like [`LNSocket::connect`], over the browser's WebSocket to `url`. [`LNSocket::connect`]
+    /// calls this for `ws://` and `wss://` URLs when built for the browser.
+    pub async fn connect_browser(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        url: &str,
+    ) -> Result<LNSocket, Error> {
+        let stream = BrowserWebSocket::open(url)?;
+        Self::handshake_outbound(stream, our_key, their_pubkey).await
+    }
+}
@@ -0,0 +1,238 @@
+//! A minimal chat protocol between lnsocket peers.
+//!
+//! Messages travel in a single odd custom message type, so nodes that don't know about chat
+//! ignore them. There are three kinds: a text message with a sender chosen id, an ack for
+//! such an id, and a typing indicator. [`Chat`] sends acks for incoming texts automatically.
+//!
+//! This is also a small example of defining a custom message, see [`wire`](crate::ln::wire): a
+//! type id, [`Type`] and [`Writeable`](crate::ln::wire::Writeable) impls for sending, and a
+//! reader function to pass to [`LNSocket::read_custom`]. There is no registry of custom
+//! message types: [`Chat::recv`] reads with [`read_chat_message`] alone. To run chat next to
+//! another custom protocol on one socket, read with a handler that tries each reader, and
+//! pass chat messages to [`Chat::handle`].
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::chat::{Chat, ChatEvent};
+//! # async fn example(mut socket: LNSocket) -> Result<(), lnsocket::Error> {
+//! let mut chat = Chat::new();
+//! chat.send_text(&mut socket, "gm").await?;
+//! loop {
+//!     match chat.recv(&mut socket).await? {
+//!         ChatEvent::Text { body, .. } => println!("> {body}"),
+//!         ChatEvent::Delivered(id) => println!("message {id} delivered"),
+//!         ChatEvent::Typing => {}
+//!     }
+//! }
+//! # }
+//! ```
+
+use crate::ln::msgs::DecodeError;
+use crate::ln::wire::{Message, Type};
+use crate::util::ser::{LengthLimitedRead, Readable, Writeable, Writer};
+use crate::{Error, LNSocket};
+use std::io;
+
+/// The custom message type carrying chat messages.
+pub const CHAT_MESSAGE: u16 = 0x8a6f;
+
+/// Longest text that fits in one message.
+pub const MAX_TEXT_LEN: usize = 65000;
+
+const KIND_TEXT: u8 = 0;
+const KIND_ACK: u8 = 1;
+const KIND_TYPING: u8 = 2;
+
+/// A chat message on the wire.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChatMessage {
+    Text { id: u64, body: String },
+    Ack { id: u64 },
+    Typing,
+}
+
+impl Writeable for ChatMessage {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        match self {
+            ChatMessage::Text { id, body } => {
+                KIND_TEXT.write(w)?;
+                id.write(w)?;
+                body.write(w)
+            }
+            ChatMessage::Ack { id } => {
+                KIND_ACK.write(w)?;
+                id.write(w)
+            }
+            ChatMessage::Typing => KIND_TYPING.write(w),
+        }
+    }
+}
+
+impl Type for ChatMessage {
+    fn type_id(&self) -> u16 {
+        CHAT_MESSAGE
+    }
+}
+
+/// Custom message reader for [`LNSocket::read_custom`] that understands chat messages.
+pub fn read_chat_message<R: LengthLimitedRead>(
+    typ: u16,
+    r: &mut R,
+) -> Result<Option<ChatMessage>, DecodeError> {
+    if typ != CHAT_MESSAGE {
+        return Ok(None);
+    }
+    let msg = match <u8 as Readable>::read(r)? {
+        KIND_TEXT => ChatMessage::Text {
+            id: Readable::read(r)?,
+            body: Readable::read(r)?,
+        },
+        KIND_ACK => ChatMessage::Ack {
+            id: Readable::read(r)?,
+        },
+        KIND_TYPING => ChatMessage::Typing,
+        _ => return Err(DecodeError::InvalidValue),
+    };
+    Ok(Some(msg))
+}
+
+/// Something that happened in the conversation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChatEvent {
+    /// The peer sent us a message. It has already been acked.
+    Text { id: u64, body: String },
+    /// The peer acked the message we sent with this id.
+    Delivered(u64),
+    /// The peer is typing.
+    Typing,
+}
+
+/// One side of a chat over an [`LNSocket`].
+#[derive(Debug, Default)]
+pub struct Chat {
+    next_id: u64,
+    /// Ids we sent that the peer hasn't acked yet.
+    pending: Vec<u64>,
+}
+
+impl Chat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send a text message, returning its id. [`ChatEvent::Delivered`] with this id follows
+    /// once the peer has it.
+    pub async fn send_text(&mut self, socket: &mut LNSocket, body: &str) -> Result<u64, Error> {
+        if body.len() > MAX_TEXT_LEN {
+            return Err(Error::Io(io::ErrorKind::InvalidInput));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        socket
+            .write(&ChatMessage::Text {
+                id,
+                body: body.to_owned(),
+            })
+            .await?;
+        self.pending.push(id);
+        Ok(id)
+    }
+
+    /// Tell the peer we're typing.
+    pub async fn send_typing(&mut self, socket: &mut LNSocket) -> Result<(), Error> {
+        socket.write(&ChatMessage::Typing).await
+    }
+
+    /// Ids of sent messages that haven't been acked yet.
+    pub fn pending(&self) -> &[u64] {
+        &self.pending
+    }
+
+    /// Wait for the next chat event, answering pings and acking texts along the way.
+    pub async fn recv(&mut self, socket: &mut LNSocket) -> Result<ChatEvent, Error> {
+        loop {
+            let msg = socket
+                .read_custom(|typ, buf| read_chat_message(typ, buf))
+                .await?;
+            match msg {
+                Message::Custom(msg) => {
+                    if let Some(event) = self.handle(socket, msg).await? {
+                        return Ok(event);
+                    }
+                }
+                Message::Ping(ping) => {
+                    if let Some(pong) = socket.pong_for(&ping)? {
+                        socket.write(&pong).await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Handle a chat message read some other way, acking it if it's a text. `None` for acks
+    /// of ids we never sent, or already saw.
+    pub async fn handle(
+        &mut self,
+        socket: &mut LNSocket,
+        msg: ChatMessage,
+    ) -> Result<Option<ChatEvent>, Error> {
+        match msg {
+            ChatMessage::Text { id, body } => {
+                socket.write(&ChatMessage::Ack { id }).await?;
+                Ok(Some(ChatEvent::Text { id, body }))
+            }
+            ChatMessage::Ack { id } => {
+                let pos = self.pending.iter().position(|p| *p == id);
+                Ok(pos.map(|pos| ChatEvent::Delivered(self.pending.remove(pos))))
+            }
+            ChatMessage::Typing => Ok(Some(ChatEvent::Typing)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lnsocket::tests::socket_pair;
+
+    #[tokio::test]
+    async fn test_chat() -> Result<(), Error> {
+        let (mut a, mut b) = socket_pair().await?;
+        let mut alice = Chat::new();
+        let mut bob = Chat::new();
+
+        alice.send_typing(&mut a).await?;
+        let id = alice.send_text(&mut a, "hello bob").await?;
+        assert_eq!(alice.pending(), &[id]);
+
+        assert_eq!(bob.recv(&mut b).await?, ChatEvent::Typing);
+        assert_eq!(
+            bob.recv(&mut b).await?,
+            ChatEvent::Text {
+                id,
+                body: "hello bob".into()
+            }
+        );
+
+        assert_eq!(alice.recv(&mut a).await?, ChatEvent::Delivered(id));
+        assert!(alice.pending().is_empty());
+
+        // reading with a handler of our own
+        let id = alice.send_text(&mut a, "still there?").await?;
+        let Message::Custom(msg) = b
+            .read_custom(|typ, buf| read_chat_message(typ, buf))
+            .await?
+        else {
+            panic!("expected a chat message");
+        };
+        assert!(matches!(
+            bob.handle(&mut b, msg).await?,
+            Some(ChatEvent::Text { id: got, .. }) if got == id
+        ));
+        assert_eq!(alice.recv(&mut a).await?, ChatEvent::Delivered(id));
+
+        Ok(())
+    }
+}
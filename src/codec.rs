@@ -0,0 +1,147 @@
+//! Encrypted Lightning frames as a `tokio-util` codec.
+//!
+//! [`LnCodec`] encrypts and decrypts BOLT 8 frames with a connection's Noise state, so a
+//! transport wrapped in `tokio_util::codec::Framed` reads and writes Lightning messages, with
+//! `Framed`'s buffering, backpressure and `StreamExt::split` halves. [`LNSocket::into_framed`]
+//! turns a connected socket into one.
+//!
+//! The codec only frames: it doesn't insist on `init` coming first, fire
+//! [`Event`](crate::Event)s or answer pings. That's left to whoever drives the stream.
+//!
+//! Only available with the `codec` feature.
+//!
+//! ### Example
+//! ```no_run
+//! use futures_util::{SinkExt, StreamExt};
+//! use lnsocket::LNSocket;
+//! use lnsocket::ln::{msgs, wire::Message};
+//! # async fn example(socket: LNSocket) -> Result<(), lnsocket::Error> {
+//! let mut framed = socket.into_framed();
+//! while let Some(msg) = framed.next().await {
+//!     if let Message::Ping(ping) = msg? {
+//!         framed.send(msgs::Pong { byteslen: ping.ponglen }).await?;
+//!     }
+//! }
+//! # Ok(()) }
+//! ```
+
+use crate::ln::peer_channel_encryptor::PeerChannelEncryptor;
+use crate::ln::wire::{self, Message};
+use crate::lnsocket::Transport;
+use crate::sans_io::{self, FrameReader};
+use crate::util::ser::Writeable;
+use crate::{Error, LNSocket};
+use bytes::BytesMut;
+use std::collections::VecDeque;
+use std::io;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// Encodes and decodes encrypted Lightning messages. See the [module docs](self).
+pub struct LnCodec {
+    channel: PeerChannelEncryptor,
+    // received bytes are moved here out of Framed's buffer as they arrive
+    frames: FrameReader,
+    // messages decrypted before the codec took over, type included
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl LnCodec {
+    /// A codec for a connection whose handshake `channel` completed.
+    pub fn new(channel: PeerChannelEncryptor) -> Self {
+        Self {
+            channel,
+            frames: FrameReader::default(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// The Noise state, to go back to reading and writing without the codec. Only valid when
+    /// everything received has been decoded.
+    pub fn into_channel(self) -> PeerChannelEncryptor {
+        self.channel
+    }
+}
+
+impl Decoder for LnCodec {
+    type Item = Message<()>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message<()>>, Error> {
+        if let Some(msg) = self.pending.pop_front() {
+            let mut cursor = io::Cursor::new(&msg[..]);
+            let msg =
+                wire::read(&mut cursor, |_type, _buf| Ok(None::<()>)).map_err(|(de, _)| de)?;
+            return Ok(Some(msg));
+        }
+        self.frames.receive(src);
+        src.clear();
+        match self.frames.next_frame(&mut self.channel)? {
+            Some(frame) => sans_io::decode(&frame, |_type, _buf| Ok(None::<()>)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Message<()>>, Error> {
+        match self.decode(src)? {
+            Some(msg) => Ok(Some(msg)),
+            None if self.frames.in_frame() => {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<M: wire::Type + Writeable> Encoder<M> for LnCodec {
+    type Error = Error;
+
+    fn encode(&mut self, msg: M, dst: &mut BytesMut) -> Result<(), Error> {
+        dst.extend_from_slice(&self.channel.encrypt_message(&msg));
+        Ok(())
+    }
+}
+
+impl LNSocket {
+    /// Hand the connection over to an [`LnCodec`], see [`codec`](self).
+    ///
+    /// Messages already received but not read yet, and a partly read frame, carry over.
+    /// Everything else set up on the socket is dropped, as with [`LNSocket::into_parts`].
+    pub fn into_framed(self) -> Framed<Box<dyn Transport>, LnCodec> {
+        let parts = self.into_parts();
+        let mut codec = LnCodec::new(parts.channel);
+        codec.pending = parts.pending.into();
+        codec.frames = FrameReader::from_parts(parts.partial, parts.body_len);
+        Framed::new(parts.stream, codec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::msgs;
+    use crate::lnsocket::tests::socket_pair;
+    use futures_util::{SinkExt, StreamExt};
+
+    #[tokio::test]
+    async fn test_framed() -> Result<(), Error> {
+        let (a, mut b) = socket_pair().await?;
+        let mut framed = a.into_framed();
+
+        // past a key rotation both ways
+        for i in 0..600u16 {
+            framed
+                .send(msgs::Ping {
+                    ponglen: i,
+                    byteslen: i % 97,
+                })
+                .await?;
+            assert!(matches!(b.read().await?, Message::Ping(p) if p.ponglen == i));
+            b.write(&msgs::Pong { byteslen: i }).await?;
+            match framed.next().await {
+                Some(Ok(Message::Pong(pong))) => assert_eq!(pong.byteslen, i),
+                other => panic!("expected pong {i}, got {other:?}"),
+            }
+        }
+        Ok(())
+    }
+}
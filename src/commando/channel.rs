@@ -0,0 +1,174 @@
+//! Opening a channel from the node we're connected to, with progress reporting.
+//!
+//! The `open_channel`/`accept_channel`/`channel_ready` exchange happens between the node and
+//! its peer, not on our connection, so progress is followed through the node's own view of
+//! the channel (`listpeerchannels`) instead.
+
+use super::CommandoClient;
+use crate::{Error, LNSocket};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::time::Duration;
+
+/// Knobs for [`CommandoClient::open_channel_to`].
+#[derive(Clone, Debug)]
+pub struct OpenChannelOptions {
+    /// Whether to announce the channel, the node's default (yes) if not set.
+    pub announce: Option<bool>,
+    /// Feerate for the funding transaction, e.g. `"normal"` or `"2500perkw"`.
+    pub feerate: Option<String>,
+    /// Amount to give the peer on open.
+    pub push_msat: Option<u64>,
+    /// Wait for the channel to be usable before returning. If `false`, returns once the
+    /// funding transaction is broadcast.
+    pub wait_for_ready: bool,
+    /// How often to check the channel state while waiting. Defaults to 30 seconds.
+    pub poll_interval: Duration,
+}
+
+impl Default for OpenChannelOptions {
+    fn default() -> Self {
+        Self {
+            announce: None,
+            feerate: None,
+            push_msat: None,
+            wait_for_ready: true,
+            poll_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Milestones reported while a channel opens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OpenChannelProgress {
+    /// The node is connected to the peer.
+    Connected,
+    /// The peer accepted the channel and the funding transaction was broadcast.
+    FundingBroadcast { txid: String, channel_id: String },
+    /// The channel moved to a new state, e.g. `CHANNELD_AWAITING_LOCKIN`.
+    State(String),
+    /// Both sides sent `channel_ready`, the channel can be used.
+    Ready { short_channel_id: Option<String> },
+}
+
+/// A channel opened by [`CommandoClient::open_channel_to`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpenedChannel {
+    pub channel_id: String,
+    pub funding_txid: String,
+    pub funding_outnum: u32,
+    /// Set once the channel is confirmed, so only if we waited for it to be ready.
+    pub short_channel_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FundChannel {
+    txid: String,
+    outnum: u32,
+    channel_id: String,
+}
+
+#[derive(Deserialize)]
+struct PeerChannels {
+    channels: Vec<PeerChannel>,
+}
+
+#[derive(Deserialize)]
+struct PeerChannel {
+    state: String,
+    #[serde(default)]
+    channel_id: Option<String>,
+    #[serde(default)]
+    short_channel_id: Option<String>,
+}
+
+/// The node id part of `id@host:port`.
+fn node_id(peer: &str) -> &str {
+    peer.split_once('@').map_or(peer, |(id, _)| id)
+}
+
+impl CommandoClient {
+    /// Have the node open a channel of `amount_sat` to `peer` (`id` or `id@host:port`),
+    /// calling `progress` at each milestone.
+    pub async fn open_channel_to(
+        &mut self,
+        socket: &mut LNSocket,
+        peer: &str,
+        amount_sat: u64,
+        opts: &OpenChannelOptions,
+        mut progress: impl FnMut(&OpenChannelProgress),
+    ) -> Result<OpenedChannel, Error> {
+        let id = node_id(peer);
+        self.call_typed::<Value>(socket, "connect", json!({"id": peer}))
+            .await?;
+        progress(&OpenChannelProgress::Connected);
+
+        let mut params = Map::new();
+        params.insert("id".into(), id.into());
+        params.insert("amount".into(), amount_sat.into());
+        if let Some(announce) = opts.announce {
+            params.insert("announce".into(), announce.into());
+        }
+        if let Some(feerate) = &opts.feerate {
+            params.insert("feerate".into(), feerate.as_str().into());
+        }
+        if let Some(push_msat) = opts.push_msat {
+            params.insert("push_msat".into(), push_msat.into());
+        }
+        let funded: FundChannel = self
+            .call_typed(socket, "fundchannel", Value::Object(params))
+            .await?;
+        progress(&OpenChannelProgress::FundingBroadcast {
+            txid: funded.txid.clone(),
+            channel_id: funded.channel_id.clone(),
+        });
+
+        let mut opened = OpenedChannel {
+            channel_id: funded.channel_id,
+            funding_txid: funded.txid,
+            funding_outnum: funded.outnum,
+            short_channel_id: None,
+        };
+        if !opts.wait_for_ready {
+            return Ok(opened);
+        }
+
+        let mut last_state = None;
+        loop {
+            let peer_channels: PeerChannels = self
+                .call_typed(socket, "listpeerchannels", json!({"id": id}))
+                .await?;
+            let channel = peer_channels
+                .channels
+                .into_iter()
+                .find(|c| c.channel_id.as_deref() == Some(&opened.channel_id));
+
+            if let Some(channel) = channel {
+                if channel.state == "CHANNELD_NORMAL" {
+                    opened.short_channel_id = channel.short_channel_id;
+                    progress(&OpenChannelProgress::Ready {
+                        short_channel_id: opened.short_channel_id.clone(),
+                    });
+                    return Ok(opened);
+                }
+                if last_state.as_ref() != Some(&channel.state) {
+                    progress(&OpenChannelProgress::State(channel.state.clone()));
+                    last_state = Some(channel.state);
+                }
+            }
+
+            tokio::time::sleep(opts.poll_interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_id() {
+        assert_eq!(node_id("02aa@1.2.3.4:9735"), "02aa");
+        assert_eq!(node_id("02aa"), "02aa");
+    }
+}
@@ -0,0 +1,124 @@
+//! Creating an invoice and waiting for it to be paid.
+
+use super::CommandoClient;
+use crate::{Error, LNSocket};
+use bitcoin::secp256k1::rand::{self, Rng};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+
+/// Knobs for [`CommandoClient::create_invoice`].
+#[derive(Clone, Debug, Default)]
+pub struct InvoiceOptions {
+    /// Unique label for the invoice on the node. A random one is generated if not set.
+    pub label: Option<String>,
+    /// Seconds until the invoice expires, the node's default (a week) if not set.
+    pub expiry: Option<u64>,
+    /// Hex encoded preimage to use instead of a random one.
+    pub preimage: Option<String>,
+}
+
+/// An invoice created by [`CommandoClient::create_invoice`].
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Invoice {
+    /// The label the node knows this invoice by.
+    #[serde(skip)]
+    pub label: String,
+    /// The invoice to hand to the payer.
+    pub bolt11: String,
+    pub payment_hash: String,
+    pub payment_secret: String,
+    /// UNIX timestamp after which the invoice can no longer be paid.
+    pub expires_at: u64,
+}
+
+/// A paid invoice, as reported by `waitinvoice`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct PaidInvoice {
+    pub label: String,
+    pub payment_hash: String,
+    /// What was actually received, which can exceed the invoice amount.
+    pub amount_received_msat: u64,
+    /// UNIX timestamp of the payment.
+    pub paid_at: u64,
+    /// Hex encoded proof of payment.
+    pub payment_preimage: String,
+}
+
+impl CommandoClient {
+    /// Create an invoice for `amount_msat` (`None` for an any-amount invoice).
+    ///
+    /// Use [`Invoice::wait_settled`] to wait for the payment.
+    pub async fn create_invoice(
+        &mut self,
+        socket: &mut LNSocket,
+        amount_msat: Option<u64>,
+        description: &str,
+        opts: &InvoiceOptions,
+    ) -> Result<Invoice, Error> {
+        let label = opts
+            .label
+            .clone()
+            .unwrap_or_else(|| format!("lnsocket-{:016x}", rand::thread_rng().r#gen::<u64>()));
+
+        let mut params = Map::new();
+        params.insert(
+            "amount_msat".into(),
+            amount_msat.map_or_else(|| "any".into(), Value::from),
+        );
+        params.insert("label".into(), label.as_str().into());
+        params.insert("description".into(), description.into());
+        if let Some(expiry) = opts.expiry {
+            params.insert("expiry".into(), expiry.into());
+        }
+        if let Some(preimage) = &opts.preimage {
+            params.insert("preimage".into(), preimage.as_str().into());
+        }
+
+        let mut invoice: Invoice = self
+            .call_typed(socket, "invoice", Value::Object(params))
+            .await?;
+        invoice.label = label;
+        Ok(invoice)
+    }
+}
+
+impl Invoice {
+    /// Wait until the invoice is paid.
+    ///
+    /// This blocks on `waitinvoice`, so the socket can't be used for anything else meanwhile.
+    /// If the invoice expires first this fails with [`Error::Rpc`] (code 903).
+    pub async fn wait_settled(
+        &self,
+        client: &mut CommandoClient,
+        socket: &mut LNSocket,
+    ) -> Result<PaidInvoice, Error> {
+        client
+            .call_typed(socket, "waitinvoice", json!({"label": self.label}))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paid_invoice() {
+        let paid: PaidInvoice = serde_json::from_value(json!({
+            "label": "coffee",
+            "bolt11": "lnbc1...",
+            "payment_hash": "ab",
+            "amount_msat": 1000,
+            "status": "paid",
+            "pay_index": 1,
+            "amount_received_msat": 1001,
+            "paid_at": 1700000000,
+            "payment_preimage": "cd",
+            "description": "one coffee",
+            "expires_at": 1700600000
+        }))
+        .unwrap();
+        assert_eq!(paid.amount_received_msat, 1001);
+        assert_eq!(paid.label, "coffee");
+    }
+}
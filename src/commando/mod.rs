@@ -1,14 +1,33 @@
 use crate::Error;
 use crate::LNSocket;
 use crate::commando;
-use crate::ln::msgs::{self, DecodeError};
+use crate::ln::msgs::DecodeError;
 use crate::ln::wire::Message;
 use crate::ln::wire::Type;
 use crate::util::ser::{LengthLimitedRead, Readable, Writeable, Writer};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
-use std::io;
+use std::fmt;
+
+#[cfg(feature = "tokio")]
+mod channel;
+mod invoice;
+#[cfg(feature = "tokio")]
+pub mod mux;
+mod notification;
+mod pay;
+mod rune;
+
+#[cfg(feature = "tokio")]
+pub use channel::{OpenChannelOptions, OpenChannelProgress, OpenedChannel};
+pub use invoice::{Invoice, InvoiceOptions, PaidInvoice};
+#[cfg(feature = "tokio")]
+pub use mux::CommandoHandle;
+pub use notification::{ChannelOpened, ClnEvent, ConnectDirection, InvoicePayment, SendpaySuccess};
+pub use pay::{PayOptions, PaymentResult};
+pub use rune::{Alternative, Condition, Rune, RuneCheck, RuneError};
 
 impl CommandoCommand {
     pub fn new(id: u64, method: String, rune: String, params: Value) -> Self {
@@ -152,10 +171,45 @@ pub struct CommandoClient {
     chunks: HashMap<u64, Vec<u8>>,
 }
 
+/// A JSON-RPC error returned by the node, e.g. a failed payment or a rune that doesn't
+/// allow the method.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    /// Method specific details, if any.
+    #[serde(default)]
+    pub data: Option<Value>,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+/// Pull `result` out of a JSON-RPC response, or its `error` as [`Error::Rpc`].
+fn parse_response<T: DeserializeOwned>(mut json: Value) -> Result<T, Error> {
+    if let Some(error) = json.get_mut("error") {
+        return Err(Error::Rpc(serde_json::from_value(error.take())?));
+    }
+    let result = json
+        .get_mut("result")
+        .map(Value::take)
+        .unwrap_or(Value::Null);
+    Ok(serde_json::from_value(result)?)
+}
+
 #[derive(Clone, Debug)]
 pub struct CompleteCommandoResponse {
     req_id: u64,
-    json: serde_json::Value,
+    raw: Vec<u8>,
+}
+
+impl CompleteCommandoResponse {
+    fn json(&self) -> Result<Value, Error> {
+        Ok(serde_json::from_slice(&self.raw)?)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -179,7 +233,7 @@ impl CommandoClient {
         socket: &mut LNSocket,
         method: impl Into<String>,
         params: Value,
-    ) -> Result<u64, io::Error> {
+    ) -> Result<u64, Error> {
         self.req_ids += 1;
         let req_id = self.req_ids;
         let command = CommandoCommand::new(req_id, method.into(), self.rune.clone(), params);
@@ -196,17 +250,11 @@ impl CommandoClient {
             .or_insert(cont.chunk)
     }
 
-    fn finalize_chunks(
-        &mut self,
-        cont: CommandoReplyChunk,
-    ) -> Result<CompleteCommandoResponse, Error> {
+    fn finalize_chunks(&mut self, cont: CommandoReplyChunk) -> CompleteCommandoResponse {
         let req_id = cont.req_id;
-        let json = {
-            let r = self.update_chunks(cont);
-            serde_json::from_slice(r)?
-        };
-        self.chunks.remove(&req_id);
-        Ok(CompleteCommandoResponse { req_id, json })
+        self.update_chunks(cont);
+        let raw = self.chunks.remove(&req_id).unwrap_or_default();
+        CompleteCommandoResponse { req_id, raw }
     }
 
     pub async fn call(
@@ -215,21 +263,31 @@ impl CommandoClient {
         method: impl Into<String>,
         params: Value,
     ) -> Result<serde_json::Value, Error> {
+        let raw = self.call_raw(socket, method, params).await?;
+        Ok(serde_json::from_slice(&raw)?)
+    }
+
+    /// Like [`CommandoClient::call`], but returns the response's JSON as the node sent it,
+    /// without parsing it. Handy for proxies passing large replies along as they are.
+    pub async fn call_raw(
+        &mut self,
+        socket: &mut LNSocket,
+        method: impl Into<String>,
+        params: Value,
+    ) -> Result<Vec<u8>, Error> {
         let req_id = self.send(socket, method, params).await?;
 
         loop {
             match self.read(socket).await? {
                 Message::Custom(CommandoResponse::Complete(msg)) if msg.req_id == req_id => {
-                    return Ok(msg.json);
+                    return Ok(msg.raw);
                 }
 
                 // rusty told me once that we will get disconnected if we don't reply to these
                 Message::Ping(ping) => {
-                    socket
-                        .write(&msgs::Pong {
-                            byteslen: ping.ponglen,
-                        })
-                        .await?;
+                    if let Some(pong) = socket.pong_for(&ping)? {
+                        socket.write(&pong).await?;
+                    }
                 }
 
                 _ => {}
@@ -240,29 +298,87 @@ impl CommandoClient {
         }
     }
 
+    /// Like [`CommandoClient::call`], but unwraps the JSON-RPC envelope: returns the
+    /// `result` deserialized as `T`, or fails with [`Error::Rpc`] if the node returned an
+    /// error.
+    pub async fn call_typed<T: DeserializeOwned>(
+        &mut self,
+        socket: &mut LNSocket,
+        method: impl Into<String>,
+        params: Value,
+    ) -> Result<T, Error> {
+        parse_response(self.call(socket, method, params).await?)
+    }
+
     async fn read(&mut self, socket: &mut LNSocket) -> Result<Message<CommandoResponse>, Error> {
         let commando_msg: Message<IncomingCommandoMessage> = socket
             .read_custom(|typ, buf| commando::read_incoming_commando_message(typ, buf))
             .await?;
 
-        Ok(match commando_msg {
-            Message::Custom(incoming) => match incoming {
-                IncomingCommandoMessage::Chunk(chunk) => {
-                    let req_id = chunk.req_id;
-                    self.update_chunks(chunk);
-                    Message::Custom(CommandoResponse::Partial(req_id))
-                }
-                IncomingCommandoMessage::Done(chunk) => {
-                    Message::Custom(CommandoResponse::Complete(self.finalize_chunks(chunk)?))
-                }
-            },
-
-            Message::Init(a) => Message::Init(a),
-            Message::Error(a) => Message::Error(a),
-            Message::Warning(a) => Message::Warning(a),
-            Message::Ping(a) => Message::Ping(a),
-            Message::Pong(a) => Message::Pong(a),
-            Message::Unknown(unk) => Message::Unknown(unk),
-        })
+        Ok(commando_msg.map_custom(|incoming| match incoming {
+            IncomingCommandoMessage::Chunk(chunk) => {
+                let req_id = chunk.req_id;
+                self.update_chunks(chunk);
+                CommandoResponse::Partial(req_id)
+            }
+            IncomingCommandoMessage::Done(chunk) => {
+                CommandoResponse::Complete(self.finalize_chunks(chunk))
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockPeer, RawMessage};
+    use bitcoin::secp256k1::{SecretKey, rand};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_call_raw() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut socket, mut peer) = MockPeer::connect(key).await?;
+        socket.perform_init().await?;
+        let mut commando = CommandoClient::new("rune");
+
+        // spacing and key order survive, since nothing is parsed
+        let reply = br#"{"result": {"z": 1, "a": 2}}"#;
+        let node = async {
+            let Some(Message::Custom(raw)) = peer.recv().await else {
+                panic!("expected a commando command");
+            };
+            let req_id = &raw.payload[..8];
+            let (first, rest) = reply.split_at(10);
+            for (type_id, chunk) in [(COMMANDO_REPLY_CONT, first), (COMMANDO_REPLY_TERM, rest)] {
+                let mut payload = req_id.to_vec();
+                payload.extend_from_slice(chunk);
+                peer.send(&RawMessage { type_id, payload }).unwrap();
+            }
+        };
+        let (_, raw) = tokio::join!(node, commando.call_raw(&mut socket, "getinfo", json!({})));
+        assert_eq!(raw?, reply);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let ok: Value =
+            parse_response(json!({"jsonrpc": "2.0", "id": 2, "result": {"a": 1}})).expect("result");
+        assert_eq!(ok, json!({"a": 1}));
+
+        let err = parse_response::<Value>(json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "error": {"code": -32602, "message": "Unknown parameter"}
+        }));
+        match err {
+            Err(Error::Rpc(err)) => {
+                assert_eq!(err.code, -32602);
+                assert_eq!(err.message, "Unknown parameter");
+                assert_eq!(err.data, None);
+            }
+            other => panic!("expected an rpc error, got {other:?}"),
+        }
     }
 }
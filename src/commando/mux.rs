@@ -0,0 +1,322 @@
+//! Sharing one connection between commando RPC and other protocol traffic.
+//!
+//! [`CommandoClient::call`] reads the socket itself and skips whatever isn't its reply, so
+//! gossip or custom messages arriving meanwhile are lost, and anyone else reading the socket
+//! can steal the reply. [`CommandoClient::run`] avoids both by moving the connection into a
+//! [background task](crate::handle) whose read loop hands commando replies and notifications
+//! to a [`CommandoHandle`] and every other message to the [`SocketHandle`].
+//!
+//! The connection stops when the [`SocketHandle`] is dropped, like with [`LNSocket::run`].
+//! Calls still waiting then fail with [`Error::NotConnected`].
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::{CommandoClient, LNSocket};
+//! use serde_json::json;
+//! # async fn example(socket: LNSocket) -> Result<(), lnsocket::Error> {
+//! let (commando, mut handle, _task) = CommandoClient::new("your-rune-token").run(socket);
+//! tokio::spawn(async move {
+//!     while let Some(msg) = handle.recv().await {
+//!         println!("{msg:?}");
+//!     }
+//! });
+//! let info = commando.call("getinfo", json!({})).await?;
+//! println!("node info: {info}");
+//! # Ok(()) }
+//! ```
+
+use super::{
+    ClnEvent, CommandoClient, CommandoCommand, IncomingCommandoMessage, parse_response,
+    read_incoming_commando_message,
+};
+use crate::handle::{RunOptions, SocketHandle, SocketSender};
+use crate::ln::msgs::DecodeError;
+use crate::ln::wire::Message;
+use crate::{Error, LNSocket};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// A custom message decoded by the connection task, before routing.
+#[derive(Debug)]
+enum Routed<T> {
+    Commando(IncomingCommandoMessage),
+    Other(T),
+}
+
+/// Replies being assembled, and who is waiting for them.
+#[derive(Default)]
+struct Replies {
+    chunks: HashMap<u64, Vec<u8>>,
+    waiting: HashMap<u64, oneshot::Sender<Vec<u8>>>,
+    events: Vec<mpsc::UnboundedSender<ClnEvent>>,
+    closed: bool,
+}
+
+impl Replies {
+    fn handle(&mut self, msg: IncomingCommandoMessage) {
+        match msg {
+            IncomingCommandoMessage::Chunk(chunk) => self
+                .chunks
+                .entry(chunk.req_id)
+                .or_default()
+                .extend_from_slice(&chunk.chunk),
+            IncomingCommandoMessage::Done(chunk) => {
+                let mut raw = self.chunks.remove(&chunk.req_id).unwrap_or_default();
+                raw.extend_from_slice(&chunk.chunk);
+                match self.waiting.remove(&chunk.req_id) {
+                    Some(waiting) => {
+                        let _ = waiting.send(raw);
+                    }
+                    // nobody asked for this, so it can only be a notification
+                    None => self.notify(&raw),
+                }
+            }
+        }
+    }
+
+    fn notify(&mut self, raw: &[u8]) {
+        if self.events.is_empty() {
+            return;
+        }
+        let Some(event) = serde_json::from_slice(raw)
+            .ok()
+            .and_then(|json| ClnEvent::from_notification(&json))
+        else {
+            return;
+        };
+        self.events
+            .retain(|events| events.send(event.clone()).is_ok());
+    }
+}
+
+/// Owned by the connection task, fails the calls still waiting when it stops.
+struct Router(Arc<Mutex<Replies>>);
+
+impl Drop for Router {
+    fn drop(&mut self) {
+        let mut replies = self.0.lock().unwrap();
+        replies.closed = true;
+        replies.waiting.clear();
+        replies.events.clear();
+    }
+}
+
+/// Makes commando calls over a connection shared with a [`SocketHandle`], see the
+/// [module docs](crate::commando::mux). Cheap to clone, calls from several tasks can be in
+/// flight at once.
+#[derive(Clone)]
+pub struct CommandoHandle {
+    sender: SocketSender,
+    rune: String,
+    req_ids: Arc<AtomicU64>,
+    replies: Arc<Mutex<Replies>>,
+}
+
+impl std::fmt::Debug for CommandoHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CommandoHandle")
+            .field("req_ids", &self.req_ids)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CommandoHandle {
+    /// Like [`CommandoClient::call`].
+    pub async fn call(&self, method: impl Into<String>, params: Value) -> Result<Value, Error> {
+        let raw = self.call_raw(method, params).await?;
+        Ok(serde_json::from_slice(&raw)?)
+    }
+
+    /// Like [`CommandoClient::call_raw`].
+    pub async fn call_raw(
+        &self,
+        method: impl Into<String>,
+        params: Value,
+    ) -> Result<Vec<u8>, Error> {
+        let req_id = self.req_ids.fetch_add(1, Ordering::Relaxed) + 1;
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut replies = self.replies.lock().unwrap();
+            if replies.closed {
+                return Err(Error::NotConnected);
+            }
+            replies.waiting.insert(req_id, tx);
+        }
+
+        let command = CommandoCommand::new(req_id, method.into(), self.rune.clone(), params);
+        if let Err(err) = self.sender.send(&command).await {
+            self.replies.lock().unwrap().waiting.remove(&req_id);
+            return Err(err);
+        }
+        rx.await.map_err(|_| Error::NotConnected)
+    }
+
+    /// Like [`CommandoClient::call_typed`].
+    pub async fn call_typed<T: DeserializeOwned>(
+        &self,
+        method: impl Into<String>,
+        params: Value,
+    ) -> Result<T, Error> {
+        parse_response(self.call(method, params).await?)
+    }
+
+    /// Receive the notifications the node sends, from now on. Every subscriber gets each
+    /// one, and the channel closes when the connection does.
+    pub fn subscribe_events(&self) -> mpsc::UnboundedReceiver<ClnEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut replies = self.replies.lock().unwrap();
+        if !replies.closed {
+            replies.events.push(tx);
+        }
+        rx
+    }
+}
+
+impl CommandoClient {
+    /// Move `socket` into a background task shared by commando calls through the returned
+    /// [`CommandoHandle`] and all other messages through the [`SocketHandle`]. See the
+    /// [module docs](crate::commando::mux).
+    ///
+    /// `init` must already have been exchanged. Must be called within a tokio runtime.
+    pub fn run(
+        self,
+        socket: LNSocket,
+    ) -> (CommandoHandle, SocketHandle, JoinHandle<Result<(), Error>>) {
+        self.run_custom_with(socket, &RunOptions::default(), |_type, _buf| Ok(None))
+    }
+
+    /// Like [`CommandoClient::run`], with control over the send queue and decoding other
+    /// custom messages with `reader`, as in [`LNSocket::run_custom_with`]. Commando replies
+    /// never reach `reader`.
+    pub fn run_custom_with<T, F>(
+        self,
+        socket: LNSocket,
+        opts: &RunOptions,
+        mut reader: F,
+    ) -> (
+        CommandoHandle,
+        SocketHandle<T>,
+        JoinHandle<Result<(), Error>>,
+    )
+    where
+        T: core::fmt::Debug + Send + 'static,
+        F: FnMut(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError> + Send + 'static,
+    {
+        let replies = Arc::new(Mutex::new(Replies::default()));
+        let router = Router(replies.clone());
+        let read =
+            move |typ, buf: &mut Cursor<&[u8]>| match read_incoming_commando_message(typ, buf)? {
+                Some(msg) => Ok(Some(Routed::Commando(msg))),
+                None => Ok(reader(typ, buf)?.map(Routed::Other)),
+            };
+        let route = move |msg: Message<Routed<T>>| match msg {
+            Message::Custom(Routed::Commando(msg)) => {
+                router.0.lock().unwrap().handle(msg);
+                None
+            }
+            msg => Some(msg.map_custom(|routed| match routed {
+                Routed::Other(custom) => custom,
+                Routed::Commando(_) => unreachable!("commando replies are routed above"),
+            })),
+        };
+
+        let (handle, task) = socket.run_routed(opts, read, route);
+        let commando = CommandoHandle {
+            sender: handle.sender(),
+            rune: self.rune,
+            req_ids: Arc::new(AtomicU64::new(self.req_ids)),
+            replies,
+        };
+        (commando, handle, task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commando::{COMMANDO_REPLY_CONT, COMMANDO_REPLY_TERM, InvoicePayment};
+    use crate::ln::msgs;
+    use crate::testing::{MockPeer, RawMessage};
+    use bitcoin::secp256k1::{SecretKey, rand};
+    use serde_json::json;
+
+    fn reply(req_id: &[u8], type_id: u16, chunk: &[u8]) -> RawMessage {
+        let mut payload = req_id.to_vec();
+        payload.extend_from_slice(chunk);
+        RawMessage { type_id, payload }
+    }
+
+    #[tokio::test]
+    async fn test_run_shared() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut socket, mut peer) = MockPeer::connect(key).await?;
+        socket.perform_init().await?;
+        let (commando, mut handle, task) = CommandoClient::new("rune").run(socket);
+        let mut events = commando.subscribe_events();
+
+        let node = async {
+            let Some(Message::Custom(raw)) = peer.recv().await else {
+                panic!("expected a commando command");
+            };
+            let req_id = raw.payload[..8].to_vec();
+            // other traffic interleaved with the reply, and a notification nobody asked for
+            let retrieval = msgs::PeerStorageRetrieval { data: vec![7] };
+            peer.send(&reply(&req_id, COMMANDO_REPLY_CONT, br#"{"result":"#))
+                .unwrap();
+            peer.send(&retrieval).unwrap();
+            let notification = json!({
+                "jsonrpc": "2.0",
+                "method": "invoice_payment",
+                "params": {"label": "a", "preimage": "00", "msat": 10}
+            });
+            peer.send(&reply(
+                &[0xff; 8],
+                COMMANDO_REPLY_TERM,
+                notification.to_string().as_bytes(),
+            ))
+            .unwrap();
+            peer.send(&RawMessage {
+                type_id: 0x8001,
+                payload: vec![],
+            })
+            .unwrap();
+            peer.send(&reply(&req_id, COMMANDO_REPLY_TERM, b"{}}"))
+                .unwrap();
+        };
+        let (_, resp) = tokio::join!(node, commando.call("getinfo", json!({})));
+        assert_eq!(resp?, json!({"result": {}}));
+
+        assert!(matches!(
+            handle.recv().await,
+            Some(Message::PeerStorageRetrieval(r)) if r.data == [7]
+        ));
+        assert!(matches!(
+            handle.recv().await,
+            Some(Message::Unknown(0x8001))
+        ));
+        assert_eq!(
+            events.recv().await,
+            Some(ClnEvent::InvoicePayment(InvoicePayment {
+                label: "a".into(),
+                preimage: "00".into(),
+                msat: json!(10),
+            }))
+        );
+
+        // stopping the connection fails calls instead of leaving them waiting
+        drop(handle);
+        task.await.unwrap()?;
+        assert!(matches!(
+            commando.call("getinfo", json!({})).await,
+            Err(Error::NotConnected)
+        ));
+        assert_eq!(events.recv().await, None);
+        Ok(())
+    }
+}
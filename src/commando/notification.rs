@@ -0,0 +1,203 @@
+//! Typed CLN notifications.
+//!
+//! Notifications are JSON-RPC requests without an `id`, e.g.
+//! `{"jsonrpc":"2.0","method":"invoice_payment","params":{"invoice_payment":{...}}}`. Depending
+//! on the CLN version the payload is either wrapped in an object named after the topic (as
+//! above) or sent flat, both are accepted.
+
+use super::{CommandoClient, CommandoResponse};
+use crate::ln::wire::Message;
+use crate::{Error, LNSocket};
+use futures_util::stream::{self, Stream};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// An invoice was paid.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct InvoicePayment {
+    pub label: String,
+    pub preimage: String,
+    /// Amount received, e.g. `"1000msat"` on older nodes.
+    pub msat: Value,
+}
+
+/// One of our payments (or payment parts) succeeded.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct SendpaySuccess {
+    pub payment_hash: String,
+    pub payment_preimage: String,
+    #[serde(default)]
+    pub destination: Option<String>,
+    #[serde(default)]
+    pub amount_msat: Option<u64>,
+    pub amount_sent_msat: u64,
+}
+
+/// A channel was opened to us by a peer.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct ChannelOpened {
+    /// The peer's node id.
+    pub id: String,
+    pub funding_msat: u64,
+    pub funding_txid: String,
+    pub channel_ready: bool,
+}
+
+/// Who initiated a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectDirection {
+    In,
+    Out,
+}
+
+/// A notification from the node.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClnEvent {
+    InvoicePayment(InvoicePayment),
+    SendpaySuccess(SendpaySuccess),
+    ChannelOpened(ChannelOpened),
+    Connect {
+        id: String,
+        direction: Option<ConnectDirection>,
+    },
+    Disconnect {
+        id: String,
+    },
+    /// A topic we don't have a type for, or one whose payload didn't match.
+    Other {
+        method: String,
+        params: Value,
+    },
+}
+
+impl ClnEvent {
+    /// Decode a JSON-RPC notification. Returns `None` if `json` isn't one (e.g. it is a
+    /// response to a request).
+    pub fn from_notification(json: &Value) -> Option<ClnEvent> {
+        if json.get("id").is_some_and(|id| !id.is_null()) {
+            return None;
+        }
+        let method = json.get("method")?.as_str()?;
+        let params = json.get("params").cloned().unwrap_or(Value::Null);
+        let payload = params
+            .get(method)
+            .cloned()
+            .unwrap_or_else(|| params.clone());
+
+        let event = match method {
+            "invoice_payment" => parse(payload).map(ClnEvent::InvoicePayment),
+            "sendpay_success" => parse(payload).map(ClnEvent::SendpaySuccess),
+            "channel_opened" => parse(payload).map(ClnEvent::ChannelOpened),
+            "connect" => parse::<Peer>(payload).map(|peer| ClnEvent::Connect {
+                id: peer.id,
+                direction: peer.direction,
+            }),
+            "disconnect" => parse::<Peer>(payload).map(|peer| ClnEvent::Disconnect { id: peer.id }),
+            _ => None,
+        };
+
+        Some(event.unwrap_or_else(|| ClnEvent::Other {
+            method: method.to_owned(),
+            params,
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+struct Peer {
+    id: String,
+    #[serde(default)]
+    direction: Option<ConnectDirection>,
+}
+
+fn parse<T: DeserializeOwned>(payload: Value) -> Option<T> {
+    serde_json::from_value(payload).ok()
+}
+
+impl CommandoClient {
+    /// Turn this client into a stream of the notifications the node sends us.
+    ///
+    /// Pings are answered along the way. Replies to requests are dropped, so use a separate
+    /// connection for RPC calls. The stream ends after the first error.
+    pub fn events(self, socket: LNSocket) -> impl Stream<Item = Result<ClnEvent, Error>> + Send {
+        stream::unfold(Some((self, socket)), |state| async move {
+            let (mut client, mut socket) = state?;
+            loop {
+                let event = match client.read(&mut socket).await {
+                    Ok(Message::Custom(CommandoResponse::Complete(msg))) => {
+                        let json = match msg.json() {
+                            Ok(json) => json,
+                            Err(err) => return Some((Err(err), None)),
+                        };
+                        match ClnEvent::from_notification(&json) {
+                            Some(event) => event,
+                            None => continue,
+                        }
+                    }
+                    Ok(Message::Ping(ping)) => {
+                        let pong = match socket.pong_for(&ping) {
+                            Ok(pong) => pong,
+                            Err(err) => return Some((Err(err), None)),
+                        };
+                        if let Some(pong) = pong
+                            && let Err(err) = socket.write(&pong).await
+                        {
+                            return Some((Err(err), None));
+                        }
+                        continue;
+                    }
+                    Ok(_) => continue,
+                    Err(err) => return Some((Err(err), None)),
+                };
+                return Some((Ok(event), Some((client, socket))));
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_notifications() {
+        let wrapped = json!({
+            "jsonrpc": "2.0",
+            "method": "invoice_payment",
+            "params": {"invoice_payment": {"label": "a", "preimage": "00", "msat": "10msat"}}
+        });
+        assert_eq!(
+            ClnEvent::from_notification(&wrapped),
+            Some(ClnEvent::InvoicePayment(InvoicePayment {
+                label: "a".into(),
+                preimage: "00".into(),
+                msat: json!("10msat"),
+            }))
+        );
+
+        let flat = json!({
+            "jsonrpc": "2.0",
+            "method": "connect",
+            "params": {"id": "02aa", "direction": "in", "address": {"type": "ipv4"}}
+        });
+        assert_eq!(
+            ClnEvent::from_notification(&flat),
+            Some(ClnEvent::Connect {
+                id: "02aa".into(),
+                direction: Some(ConnectDirection::In),
+            })
+        );
+
+        let unknown = json!({"jsonrpc": "2.0", "method": "shutdown", "params": {}});
+        assert!(matches!(
+            ClnEvent::from_notification(&unknown),
+            Some(ClnEvent::Other { method, .. }) if method == "shutdown"
+        ));
+
+        let response = json!({"jsonrpc": "2.0", "id": 1, "result": {}});
+        assert_eq!(ClnEvent::from_notification(&response), None);
+    }
+}
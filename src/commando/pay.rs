@@ -0,0 +1,363 @@
+//! Paying a bolt11 invoice and waiting for the outcome.
+//!
+//! CLN's `pay` usually blocks until the payment is done, but it can also come back early,
+//! e.g. when another `pay` for the same invoice is already running. In that case the only
+//! way to learn the outcome is to look up the payment's parts with `listsendpays` and keep
+//! calling `waitsendpay` on them until none are left in flight. [`CommandoClient::pay`] does
+//! both steps.
+
+use super::{CommandoClient, RpcError};
+use crate::{Error, LNSocket};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+
+/// `pay`: a payment for this invoice is already in flight.
+const PAY_IN_PROGRESS: i64 = 200;
+/// `waitsendpay`: the timeout passed before the payment finished. Yes, it reuses 200.
+const WAIT_TIMED_OUT: i64 = 200;
+
+/// Knobs for [`CommandoClient::pay`]. Everything is optional and left to the node's defaults.
+#[derive(Clone, Debug)]
+pub struct PayOptions {
+    /// Amount to pay, required for invoices without one.
+    pub amount_msat: Option<u64>,
+    /// Stored with the payment on the node.
+    pub label: Option<String>,
+    /// Upper bound on routing fees.
+    pub maxfee_msat: Option<u64>,
+    /// How long the node keeps retrying before giving up, in seconds.
+    pub retry_for: Option<u32>,
+    /// How long each `waitsendpay` call may block, in seconds. Defaults to 60.
+    pub wait_timeout: u32,
+    /// How many `waitsendpay` calls to make before failing with [`Error::Timeout`].
+    /// Defaults to 10.
+    pub max_waits: u32,
+}
+
+impl Default for PayOptions {
+    fn default() -> Self {
+        Self {
+            amount_msat: None,
+            label: None,
+            maxfee_msat: None,
+            retry_for: None,
+            wait_timeout: 60,
+            max_waits: 10,
+        }
+    }
+}
+
+/// A payment that completed.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct PaymentResult {
+    /// Hex encoded proof of payment.
+    #[serde(rename = "payment_preimage")]
+    pub preimage: String,
+    pub payment_hash: String,
+    /// What the recipient got, if the node knows.
+    pub amount_msat: Option<u64>,
+    /// What we sent, including fees.
+    pub amount_sent_msat: u64,
+    /// How many parts the payment was split into.
+    #[serde(default = "one")]
+    pub parts: u32,
+}
+
+fn one() -> u32 {
+    1
+}
+
+impl PaymentResult {
+    /// Routing fees paid, if the amount the recipient got is known.
+    pub fn fee_msat(&self) -> Option<u64> {
+        self.amount_msat
+            .map(|amount| self.amount_sent_msat.saturating_sub(amount))
+    }
+
+    /// The payment made of `parts`, once none are in flight and at least one went through.
+    fn from_parts(payment_hash: &str, parts: &[SendPay]) -> Option<Self> {
+        if parts.iter().any(|part| part.status == "pending") {
+            return None;
+        }
+        let done: Vec<&SendPay> = parts
+            .iter()
+            .filter(|part| part.status == "complete")
+            .collect();
+        Some(PaymentResult {
+            preimage: done.iter().find_map(|part| part.payment_preimage.clone())?,
+            payment_hash: payment_hash.to_string(),
+            amount_msat: done.iter().map(|part| part.amount_msat).sum(),
+            amount_sent_msat: done.iter().map(|part| part.amount_sent_msat).sum(),
+            parts: done.len() as u32,
+        })
+    }
+}
+
+/// The common part of `pay` and `listpays` results.
+#[derive(Deserialize)]
+struct PayStatus {
+    status: String,
+    payment_hash: String,
+}
+
+/// One part of a payment, as `listsendpays` and `waitsendpay` report it.
+#[derive(Debug, Deserialize)]
+struct SendPay {
+    #[serde(default)]
+    partid: u64,
+    #[serde(default)]
+    groupid: u64,
+    status: String,
+    amount_msat: Option<u64>,
+    amount_sent_msat: u64,
+    payment_preimage: Option<String>,
+}
+
+impl CommandoClient {
+    /// Pay a bolt11 invoice and wait until the payment succeeds or fails.
+    ///
+    /// A failed payment is reported as [`Error::Rpc`] with the node's error code (e.g. 205 for
+    /// no route, 207 for an expired invoice) and failure details in `data`.
+    pub async fn pay(
+        &mut self,
+        socket: &mut LNSocket,
+        invoice: &str,
+        opts: &PayOptions,
+    ) -> Result<PaymentResult, Error> {
+        let mut params = Map::new();
+        params.insert("bolt11".into(), invoice.into());
+        if let Some(amount) = opts.amount_msat {
+            params.insert("amount_msat".into(), amount.into());
+        }
+        if let Some(label) = &opts.label {
+            params.insert("label".into(), label.as_str().into());
+        }
+        if let Some(maxfee) = opts.maxfee_msat {
+            params.insert("maxfee".into(), maxfee.into());
+        }
+        if let Some(retry_for) = opts.retry_for {
+            params.insert("retry_for".into(), retry_for.into());
+        }
+
+        let payment_hash = match self
+            .call_typed::<Value>(socket, "pay", Value::Object(params))
+            .await
+        {
+            Ok(result) => match completed(result)? {
+                Ok(payment) => return Ok(payment),
+                Err(payment_hash) => payment_hash,
+            },
+            Err(Error::Rpc(RpcError { code, .. })) if code == PAY_IN_PROGRESS => {
+                self.in_flight_hash(socket, invoice).await?
+            }
+            Err(err) => return Err(err),
+        };
+
+        self.wait_for_payment(socket, &payment_hash, opts).await
+    }
+
+    /// Block on `waitsendpay` until no part of the payment is in flight anymore.
+    ///
+    /// `waitsendpay` follows a single part, named by `partid` and `groupid`, so every round
+    /// looks the parts up again: the node may add new ones while retrying failed ones.
+    async fn wait_for_payment(
+        &mut self,
+        socket: &mut LNSocket,
+        payment_hash: &str,
+        opts: &PayOptions,
+    ) -> Result<PaymentResult, Error> {
+        for _ in 0..opts.max_waits {
+            let parts = self.latest_parts(socket, payment_hash).await?;
+            if let Some(payment) = PaymentResult::from_parts(payment_hash, &parts) {
+                return Ok(payment);
+            }
+
+            // with nothing pending and nothing complete, waiting on a failed part gets us
+            // the node's error for it
+            let pending = parts.iter().find(|part| part.status == "pending");
+            let Some(part) = pending.or(parts.last()) else {
+                return Err(Error::Rpc(RpcError {
+                    code: PAY_IN_PROGRESS,
+                    message: "payment not found in listsendpays".into(),
+                    data: None,
+                }));
+            };
+            let params = json!({
+                "payment_hash": payment_hash,
+                "timeout": opts.wait_timeout,
+                "partid": part.partid,
+                "groupid": part.groupid,
+            });
+            match self
+                .call_typed::<Value>(socket, "waitsendpay", params)
+                .await
+            {
+                Ok(_) => {}
+                Err(Error::Rpc(RpcError { code, .. })) if code == WAIT_TIMED_OUT => {}
+                Err(err @ Error::Rpc(_)) if pending.is_none() => return Err(err),
+                // a failed part doesn't fail the payment while the node retries it
+                Err(Error::Rpc(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// The parts of the latest attempt at paying `payment_hash`. Earlier groups are attempts
+    /// that already failed.
+    async fn latest_parts(
+        &mut self,
+        socket: &mut LNSocket,
+        payment_hash: &str,
+    ) -> Result<Vec<SendPay>, Error> {
+        #[derive(Deserialize)]
+        struct ListSendPays {
+            payments: Vec<SendPay>,
+        }
+
+        let list: ListSendPays = self
+            .call_typed(
+                socket,
+                "listsendpays",
+                json!({"payment_hash": payment_hash}),
+            )
+            .await?;
+        let latest = list.payments.iter().map(|part| part.groupid).max();
+        Ok(list
+            .payments
+            .into_iter()
+            .filter(|part| Some(part.groupid) == latest)
+            .collect())
+    }
+
+    /// The payment hash of the pending payment for `invoice`.
+    async fn in_flight_hash(
+        &mut self,
+        socket: &mut LNSocket,
+        invoice: &str,
+    ) -> Result<String, Error> {
+        #[derive(Deserialize)]
+        struct ListPays {
+            pays: Vec<PayStatus>,
+        }
+
+        let list: ListPays = self
+            .call_typed(socket, "listpays", json!({"bolt11": invoice}))
+            .await?;
+        list.pays
+            .into_iter()
+            .find(|pay| pay.status == "pending")
+            .map(|pay| pay.payment_hash)
+            .ok_or_else(|| {
+                Error::Rpc(RpcError {
+                    code: PAY_IN_PROGRESS,
+                    message: "payment in progress but not found in listpays".into(),
+                    data: None,
+                })
+            })
+    }
+}
+
+/// The finished payment, or the payment hash to wait on if it is still pending.
+fn completed(result: Value) -> Result<Result<PaymentResult, String>, Error> {
+    let status: PayStatus = serde_json::from_value(result.clone())?;
+    if status.status == "complete" {
+        Ok(Ok(serde_json::from_value(result)?))
+    } else {
+        Ok(Err(status.payment_hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completed() {
+        let done = completed(json!({
+            "destination": "02aa",
+            "payment_hash": "ab",
+            "created_at": 1700000000.5,
+            "parts": 3,
+            "amount_msat": 100000,
+            "amount_sent_msat": 100012,
+            "payment_preimage": "cd",
+            "status": "complete"
+        }))
+        .unwrap()
+        .unwrap();
+        assert_eq!(done.preimage, "cd");
+        assert_eq!(done.parts, 3);
+        assert_eq!(done.fee_msat(), Some(12));
+
+        let pending = completed(json!({"payment_hash": "ab", "status": "pending"})).unwrap();
+        assert_eq!(pending, Err("ab".to_string()));
+    }
+
+    #[test]
+    fn test_waitsendpay_result() {
+        // a single part payment, as lightningd's waitsendpay returns it
+        let part: SendPay = serde_json::from_value(json!({
+            "created_index": 2,
+            "id": 2,
+            "payment_hash": "66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925",
+            "groupid": 1,
+            "updated_index": 2,
+            "destination": "035d2b1192dfba134e10e540875d366ebc8bc353d5aa766b80c090b39c3a5d885d",
+            "amount_msat": 10000,
+            "amount_sent_msat": 10001,
+            "created_at": 1738000000,
+            "completed_at": 1738000002,
+            "status": "complete",
+            "payment_preimage": "0000000000000000000000000000000000000000000000000000000000000000"
+        }))
+        .unwrap();
+        assert_eq!((part.partid, part.groupid), (0, 1));
+
+        let done = PaymentResult::from_parts(
+            "66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925",
+            &[part],
+        )
+        .unwrap();
+        assert_eq!(done.amount_msat, Some(10000));
+        assert_eq!(done.fee_msat(), Some(1));
+        assert_eq!(done.parts, 1);
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let part = |partid, status: &str, amount_msat| SendPay {
+            partid,
+            groupid: 3,
+            status: status.into(),
+            amount_msat,
+            amount_sent_msat: 501,
+            payment_preimage: (status == "complete").then(|| "cd".into()),
+        };
+
+        // still in flight
+        let parts = [
+            part(1, "complete", Some(500)),
+            part(2, "pending", Some(500)),
+        ];
+        assert_eq!(PaymentResult::from_parts("ab", &parts), None);
+
+        // a failed part that was retried doesn't count
+        let parts = [
+            part(1, "complete", Some(500)),
+            part(2, "failed", Some(500)),
+            part(3, "complete", None),
+        ];
+        let done = PaymentResult::from_parts("ab", &parts).unwrap();
+        assert_eq!(done.parts, 2);
+        assert_eq!(done.amount_sent_msat, 1002);
+        // sendonion parts don't know what the recipient got
+        assert_eq!(done.amount_msat, None);
+        assert_eq!(done.fee_msat(), None);
+
+        assert_eq!(
+            PaymentResult::from_parts("ab", &[part(1, "failed", None)]),
+            None
+        );
+    }
+}
@@ -0,0 +1,436 @@
+//! Checking whether a rune allows a call before making it.
+//!
+//! A rune is a 32 byte authentication code followed by its restrictions, all base64url
+//! encoded. The code can only be verified by the node that issued the rune, but the
+//! restrictions are plain text, so [`Rune::check`] can say ahead of time that a call is going
+//! to be refused, without a round trip. [`CommandoClient::check`] asks the node itself.
+//!
+//! Some restrictions depend on things only the node knows, like the caller's node id, so the
+//! local check can come back [`RuneCheck::Unknown`]. Rate limits (`rate`, `per`) are assumed
+//! to pass.
+
+use super::CommandoClient;
+use crate::{Error, LNSocket};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde_json::{Value, json};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const AUTHCODE_LEN: usize = 32;
+
+/// Why a rune couldn't be decoded, or was refused by [`CommandoClient::check`] without asking
+/// the node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuneError {
+    /// Not valid base64url.
+    Base64,
+    /// Shorter than the 32 byte authentication code.
+    TooShort,
+    /// The restrictions aren't UTF-8.
+    BadUtf8,
+    /// A restriction has no condition after its field name. Contains the restriction.
+    BadRestriction(String),
+    /// The restrictions don't allow the call. Contains the first unmet restriction.
+    Denied(String),
+}
+
+impl fmt::Display for RuneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuneError::Base64 => write!(f, "rune is not valid base64"),
+            RuneError::TooShort => write!(f, "rune is too short"),
+            RuneError::BadUtf8 => write!(f, "rune restrictions are not utf-8"),
+            RuneError::BadRestriction(r) => write!(f, "malformed rune restriction '{}'", r),
+            RuneError::Denied(r) => write!(f, "rune does not allow this, failed '{}'", r),
+        }
+    }
+}
+
+/// How an [`Alternative`] compares a field to its value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    /// `!`: the field is not present.
+    Missing,
+    /// `=`
+    Equal,
+    /// `/`
+    NotEqual,
+    /// `^`
+    StartsWith,
+    /// `$`
+    EndsWith,
+    /// `~`
+    Contains,
+    /// `<`: both are integers and the field is smaller.
+    IntLess,
+    /// `>`: both are integers and the field is larger.
+    IntGreater,
+    /// `{`: the field sorts before the value.
+    LexLess,
+    /// `}`: the field sorts after the value.
+    LexGreater,
+    /// `#`: a comment, always passes.
+    Comment,
+}
+
+impl Condition {
+    fn from_char(c: char) -> Option<Condition> {
+        Some(match c {
+            '!' => Condition::Missing,
+            '=' => Condition::Equal,
+            '/' => Condition::NotEqual,
+            '^' => Condition::StartsWith,
+            '$' => Condition::EndsWith,
+            '~' => Condition::Contains,
+            '<' => Condition::IntLess,
+            '>' => Condition::IntGreater,
+            '{' => Condition::LexLess,
+            '}' => Condition::LexGreater,
+            '#' => Condition::Comment,
+            _ => return None,
+        })
+    }
+
+    fn as_char(self) -> char {
+        match self {
+            Condition::Missing => '!',
+            Condition::Equal => '=',
+            Condition::NotEqual => '/',
+            Condition::StartsWith => '^',
+            Condition::EndsWith => '$',
+            Condition::Contains => '~',
+            Condition::IntLess => '<',
+            Condition::IntGreater => '>',
+            Condition::LexLess => '{',
+            Condition::LexGreater => '}',
+            Condition::Comment => '#',
+        }
+    }
+}
+
+/// One `field`-`condition`-`value` test. A restriction passes if any of its alternatives do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alternative {
+    pub field: String,
+    pub condition: Condition,
+    pub value: String,
+}
+
+impl Alternative {
+    /// `None` when the field can't be known locally.
+    fn test(&self, fields: &Fields) -> Option<bool> {
+        // rate limits are counted by the node, assume we're within them
+        if self.condition == Condition::Comment || matches!(self.field.as_str(), "rate" | "per") {
+            return Some(true);
+        }
+        let Some(field) = fields.get(&self.field)? else {
+            return Some(self.condition == Condition::Missing);
+        };
+        let value = self.value.as_str();
+        let int = |field: &str| Some((field.parse::<i64>().ok()?, value.parse::<i64>().ok()?));
+        Some(match self.condition {
+            Condition::Missing => false,
+            Condition::Equal => field == value,
+            Condition::NotEqual => field != value,
+            Condition::StartsWith => field.starts_with(value),
+            Condition::EndsWith => field.ends_with(value),
+            Condition::Contains => field.contains(value),
+            Condition::IntLess => int(&field).is_some_and(|(a, b)| a < b),
+            Condition::IntGreater => int(&field).is_some_and(|(a, b)| a > b),
+            Condition::LexLess => field.as_str() < value,
+            Condition::LexGreater => field.as_str() > value,
+            Condition::Comment => true,
+        })
+    }
+}
+
+impl fmt::Display for Alternative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            self.field,
+            self.condition.as_char(),
+            self.value
+        )
+    }
+}
+
+/// What [`Rune::check`] thinks of a call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuneCheck {
+    /// Every restriction passes.
+    Allowed,
+    /// A restriction fails. Contains it, as written in the rune.
+    Denied(String),
+    /// Nothing fails, but some restriction depends on something only the node knows.
+    Unknown,
+}
+
+/// The decoded restrictions of a rune.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rune {
+    /// The unique id the node gave the rune, if any.
+    pub unique_id: Option<String>,
+    /// Restrictions which must all pass, each a list of alternatives of which one must pass.
+    pub restrictions: Vec<Vec<Alternative>>,
+}
+
+/// Split restrictions on `&` and their alternatives on `|`, unescaping backslashes.
+fn split_restrictions(s: &str) -> Vec<Vec<String>> {
+    let mut restrictions = vec![vec![String::new()]];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let alternatives = restrictions.last_mut().unwrap();
+        match c {
+            '\\' => alternatives.last_mut().unwrap().extend(chars.next()),
+            '|' => alternatives.push(String::new()),
+            '&' => restrictions.push(vec![String::new()]),
+            c => alternatives.last_mut().unwrap().push(c),
+        }
+    }
+    restrictions
+}
+
+fn parse_alternative(alt: &str) -> Result<Alternative, RuneError> {
+    let split = alt
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .ok_or_else(|| RuneError::BadRestriction(alt.to_owned()))?;
+    let (field, rest) = alt.split_at(split);
+    let mut rest = rest.chars();
+    let condition = rest
+        .next()
+        .and_then(Condition::from_char)
+        .ok_or_else(|| RuneError::BadRestriction(alt.to_owned()))?;
+    Ok(Alternative {
+        field: field.to_owned(),
+        condition,
+        value: rest.as_str().to_owned(),
+    })
+}
+
+impl Rune {
+    /// Decode the restrictions of a rune, as issued by `createrune`.
+    pub fn parse(rune: &str) -> Result<Rune, RuneError> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(rune.trim().trim_end_matches('='))
+            .map_err(|_| RuneError::Base64)?;
+        if bytes.len() < AUTHCODE_LEN {
+            return Err(RuneError::TooShort);
+        }
+        let restrictions =
+            std::str::from_utf8(&bytes[AUTHCODE_LEN..]).map_err(|_| RuneError::BadUtf8)?;
+
+        let mut rune = Rune {
+            unique_id: None,
+            restrictions: vec![],
+        };
+        if restrictions.is_empty() {
+            return Ok(rune);
+        }
+        for restriction in split_restrictions(restrictions) {
+            let alternatives = restriction
+                .iter()
+                .map(|alt| parse_alternative(alt))
+                .collect::<Result<Vec<_>, _>>()?;
+            match alternatives.as_slice() {
+                [id] if id.field.is_empty() && id.condition == Condition::Equal => {
+                    rune.unique_id = Some(id.value.clone());
+                }
+                _ => rune.restrictions.push(alternatives),
+            }
+        }
+        Ok(rune)
+    }
+
+    /// Check a call to `method` with `params` against the restrictions.
+    pub fn check(&self, method: &str, params: &Value) -> RuneCheck {
+        let fields = Fields { method, params };
+        let mut unknown = false;
+        for restriction in &self.restrictions {
+            let results: Vec<_> = restriction.iter().map(|alt| alt.test(&fields)).collect();
+            if results.contains(&Some(true)) {
+                continue;
+            }
+            if results.contains(&None) {
+                unknown = true;
+                continue;
+            }
+            let written: Vec<_> = restriction.iter().map(|alt| alt.to_string()).collect();
+            return RuneCheck::Denied(written.join("|"));
+        }
+        if unknown {
+            RuneCheck::Unknown
+        } else {
+            RuneCheck::Allowed
+        }
+    }
+}
+
+/// The fields a rune can test, as far as we can tell them.
+struct Fields<'a> {
+    method: &'a str,
+    params: &'a Value,
+}
+
+fn param_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+impl Fields<'_> {
+    /// `Some(None)` for a field that's absent, `None` for one we can't know.
+    fn get(&self, field: &str) -> Option<Option<String>> {
+        Some(match field {
+            "method" => Some(self.method.to_owned()),
+            "pnum" => Some(match self.params {
+                Value::Object(map) => map.len().to_string(),
+                Value::Array(arr) => arr.len().to_string(),
+                _ => "0".to_owned(),
+            }),
+            "time" => Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs())
+                    .to_string(),
+            ),
+            field => {
+                if let Some(name) = field.strip_prefix("pname") {
+                    // the node drops punctuation from parameter names
+                    self.params.as_object().and_then(|map| {
+                        map.iter()
+                            .find(|(k, _)| {
+                                k.replace(|c: char| c.is_ascii_punctuation(), "") == name
+                            })
+                            .map(|(_, v)| param_string(v))
+                    })
+                } else if let Some(n) = field.strip_prefix("parr") {
+                    let n: usize = n.parse().ok()?;
+                    self.params
+                        .as_array()
+                        .and_then(|arr| arr.get(n))
+                        .map(param_string)
+                } else {
+                    return None;
+                }
+            }
+        })
+    }
+}
+
+impl CommandoClient {
+    /// Ask the node whether our rune allows calling `method` with `params`, without running
+    /// it. Useful before anything destructive.
+    ///
+    /// The rune's restrictions are checked locally first (see [`Rune::check`]), failing with
+    /// [`RuneError::Denied`] without a round trip if they clearly don't allow the call.
+    /// Otherwise this calls the node's `check` command, which fails with [`Error::Rpc`] if the
+    /// rune or the parameters are refused.
+    pub async fn check(
+        &mut self,
+        socket: &mut LNSocket,
+        method: &str,
+        params: Value,
+    ) -> Result<(), Error> {
+        if let Ok(rune) = Rune::parse(&self.rune)
+            && let RuneCheck::Denied(restriction) = rune.check(method, &params)
+        {
+            return Err(RuneError::Denied(restriction).into());
+        }
+
+        let params = match params {
+            Value::Object(mut map) => {
+                map.insert("command_to_check".into(), method.into());
+                Value::Object(map)
+            }
+            Value::Array(mut arr) => {
+                arr.insert(0, method.into());
+                Value::Array(arr)
+            }
+            _ => json!({ "command_to_check": method }),
+        };
+        let _: Value = self.call_typed(socket, "check", params).await?;
+        Ok(())
+    }
+
+    /// Check a call against our rune's restrictions locally, see [`Rune::check`].
+    pub fn precheck(&self, method: &str, params: &Value) -> Result<RuneCheck, RuneError> {
+        Ok(Rune::parse(&self.rune)?.check(method, params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(restrictions: &str) -> String {
+        let mut bytes = vec![0u8; AUTHCODE_LEN];
+        bytes.extend(restrictions.as_bytes());
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    #[test]
+    fn test_parse_rune() {
+        // a real rune, with its padding
+        let rune =
+            Rune::parse("hfYByx-RDwdBfAK-vOWeOCDJVYlvKSioVKU_y7jccZU9MjkmbWV0aG9kPWdldGluZm8=")
+                .unwrap();
+        assert_eq!(rune.unique_id.as_deref(), Some("29"));
+        assert_eq!(
+            rune.restrictions,
+            vec![vec![Alternative {
+                field: "method".into(),
+                condition: Condition::Equal,
+                value: "getinfo".into(),
+            }]]
+        );
+
+        let rune = Rune::parse(&encode(
+            r"method^list|method=getinfo&pnum<2&pnamelabel/a\&b",
+        ))
+        .unwrap();
+        assert_eq!(rune.unique_id, None);
+        assert_eq!(rune.restrictions.len(), 3);
+        assert_eq!(rune.restrictions[0].len(), 2);
+        assert_eq!(rune.restrictions[2][0].value, "a&b");
+
+        assert_eq!(Rune::parse("!!"), Err(RuneError::Base64));
+        assert_eq!(
+            Rune::parse(&encode("method")),
+            Err(RuneError::BadRestriction("method".into()))
+        );
+    }
+
+    #[test]
+    fn test_check_rune() {
+        let rune = Rune::parse(&encode("=0&method^list|method=getinfo&pnum<2")).unwrap();
+        assert_eq!(rune.check("getinfo", &json!({})), RuneCheck::Allowed);
+        assert_eq!(
+            rune.check("listpeers", &json!(["02ab"])),
+            RuneCheck::Allowed
+        );
+        assert_eq!(
+            rune.check("pay", &json!({})),
+            RuneCheck::Denied("method^list|method=getinfo".into())
+        );
+        assert_eq!(
+            rune.check("listpeers", &json!({"id": "02ab", "level": "io"})),
+            RuneCheck::Denied("pnum<2".into())
+        );
+
+        let rune = Rune::parse(&encode("pnameamountmsat<1000|pnameamountmsat!&id=02ab")).unwrap();
+        assert_eq!(
+            rune.check("invoice", &json!({"amount_msat": 1001})),
+            RuneCheck::Denied("pnameamountmsat<1000|pnameamountmsat!".into())
+        );
+        // the caller's id is only known to the node
+        assert_eq!(
+            rune.check("invoice", &json!({"amount_msat": 999})),
+            RuneCheck::Unknown
+        );
+        assert_eq!(rune.check("invoice", &json!({})), RuneCheck::Unknown);
+    }
+}
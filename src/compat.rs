@@ -0,0 +1,158 @@
+//! Using lnsocket from async-std, smol and other non-tokio runtimes.
+//!
+//! [`LNSocket`](crate::LNSocket) reads and writes through tokio's `AsyncRead` and `AsyncWrite`
+//! traits, but those are just traits: the handshake, `init`, reads, writes and commando calls
+//! run on any executor. Streams from other runtimes implement the `futures-io` traits
+//! instead; wrap them in [`Compat`] and hand them to
+//! [`LNSocket::connect_over`](crate::LNSocket::connect_over).
+//!
+//! What needs tokio's timers or tasks only exists with the `tokio` feature: dialing with
+//! [`LNSocket::connect`](crate::LNSocket::connect) (dial with your runtime instead),
+//! [`LNSocket::set_read_timeout`](crate::LNSocket::set_read_timeout),
+//! [`LNSocket::send_and_wait`](crate::LNSocket::send_and_wait),
+//! [`Recovery`](crate::recovery::Recovery) and [`LNSocket::run`](crate::LNSocket::run). Leave
+//! it out with `default-features = false, features = ["futures-io"]`, so none of them can end
+//! up needing a tokio runtime that isn't there, and use your runtime's timeouts around reads
+//! instead.
+//!
+//! Only available with the `futures-io` feature.
+//!
+//! ### Example
+//! ```ignore
+//! use lnsocket::{CommandoClient, LNSocket, compat::Compat};
+//!
+//! smol::block_on(async {
+//!     let tcp = smol::net::TcpStream::connect("ln.example.com:9735").await?;
+//!     let mut socket = LNSocket::connect_over(Compat::new(tcp), our_key, node_id).await?;
+//!     socket.perform_init().await?;
+//!     let info = CommandoClient::new(rune).call(&mut socket, "getinfo", json!({})).await?;
+//! });
+//! ```
+
+use futures_util::io::{AsyncRead as FuturesRead, AsyncWrite as FuturesWrite};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A `futures-io` stream usable as a [`Transport`](crate::lnsocket::Transport).
+#[derive(Debug)]
+pub struct Compat<S>(S);
+
+impl<S> Compat<S> {
+    pub fn new(stream: S) -> Self {
+        Self(stream)
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.0
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.0
+    }
+
+    pub fn into_inner(self) -> S {
+        self.0
+    }
+}
+
+impl<S: FuturesRead + Unpin> AsyncRead for Compat<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = ready!(Pin::new(&mut self.0).poll_read(cx, buf.initialize_unfilled()))?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: FuturesWrite + Unpin> AsyncWrite for Compat<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::msgs;
+    use crate::ln::wire::Message;
+    use crate::testing::default_init;
+    use crate::{Error, LNSocket};
+    use bitcoin::secp256k1::{Secp256k1, SecretKey, rand};
+    use tokio::io::DuplexStream;
+
+    /// A stream that only speaks `futures-io`, like async-std's and smol's.
+    struct FuturesOnly(DuplexStream);
+
+    impl FuturesRead for FuturesOnly {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut buf = ReadBuf::new(buf);
+            ready!(Pin::new(&mut self.0).poll_read(cx, &mut buf))?;
+            Poll::Ready(Ok(buf.filled().len()))
+        }
+    }
+
+    impl FuturesWrite for FuturesOnly {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_over_futures_io() -> Result<(), Error> {
+        let (a, b) = tokio::io::duplex(1024);
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let key = SecretKey::new(&mut rand::thread_rng());
+
+        let (ours, theirs) = tokio::join!(
+            LNSocket::connect_over(Compat::new(FuturesOnly(a)), key, node_id),
+            LNSocket::accept_over(Compat::new(FuturesOnly(b)), node_key)
+        );
+        let (mut ours, mut theirs) = (ours?, theirs?);
+        // perform_init waits for the peer's init, so the node speaks first
+        theirs.write(&default_init()).await?;
+        ours.perform_init().await?;
+        assert!(matches!(theirs.read().await?, Message::Init(_)));
+
+        ours.write(&msgs::Ping {
+            ponglen: 4,
+            byteslen: 2,
+        })
+        .await?;
+        assert!(matches!(theirs.read().await?, Message::Ping(_)));
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+//! Opening the TCP connection to a node.
+//!
+//! A host name often resolves to several addresses, some of which may not work, typically all
+//! the IPv6 ones on a network with broken IPv6. By default [`LNSocket::connect`] races them
+//! "Happy Eyeballs" style (RFC 8305): addresses are tried alternating between families, and if
+//! an attempt hasn't succeeded after [`ConnectOptions::happy_eyeballs`] the next one starts
+//! alongside it. The first to connect wins and the others are dropped. An attempt that takes
+//! longer than [`ConnectOptions::attempt_timeout`] counts as failed, so an address that never
+//! answers doesn't hold up the rest, and the whole of resolving and connecting fails with
+//! [`Error::Timeout`](crate::Error::Timeout) after [`ConnectOptions::timeout`].
+//!
+//! [`SocketConfig`] sets TCP options on each attempt's socket before it connects.
+//!
+//! With [`ConnectOptions::socks_proxy`] set, none of that happens locally: every connection
+//! goes out through the SOCKS5 proxy, which resolves the host itself, so neither our DNS
+//! lookups nor our address reach the network. That's for egress through a VPN host or Tor;
+//! [`LNSocket::connect_via_tor`](crate::LNSocket::connect_via_tor) does the same for a
+//! single connection.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::connect::ConnectOptions;
+//! # use bitcoin::secp256k1::{PublicKey, SecretKey};
+//! # async fn example(key: SecretKey, node: PublicKey) -> Result<(), lnsocket::Error> {
+//! // one address at a time, in the resolver's order
+//! let opts = ConnectOptions {
+//!     happy_eyeballs: None,
+//!     ..Default::default()
+//! };
+//! let socket = LNSocket::connect_with(&opts, key, node, "ln.example.com").await?;
+//! # Ok(()) }
+//! ```
+//!
+//! [`LNSocket::connect`]: crate::LNSocket::connect
+
+use crate::dns::{AddressPreference, DnsOptions};
+use crate::event::Event;
+use std::time::Duration;
+use tokio::sync::mpsc;
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    crate::Error,
+    socket2::{SockRef, TcpKeepalive},
+    std::io,
+    std::net::SocketAddr,
+    tokio::net::{TcpSocket, TcpStream},
+    tokio::task::JoinSet,
+    tokio::time::timeout,
+};
+
+/// How long to wait for an attempt before starting the next, as RFC 8305 recommends.
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long resolving and connecting may take altogether by default.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long one address gets to accept the connection by default.
+pub const DEFAULT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TCP options for the connection. Left alone, they're the OS defaults.
+#[derive(Clone, Debug, Default)]
+pub struct SocketConfig {
+    /// Send small writes right away instead of batching them (`TCP_NODELAY`), which saves a
+    /// round trip's worth of latency on request/response traffic like commando.
+    pub nodelay: bool,
+    /// Have the OS probe an idle connection (`SO_KEEPALIVE`), so a peer that vanished is
+    /// noticed and middleboxes don't drop the connection for being quiet.
+    pub keepalive: Option<Keepalive>,
+    /// The IP time to live, or hop limit for IPv6.
+    pub ttl: Option<u32>,
+}
+
+/// When TCP keepalive probes are sent, see [`SocketConfig::keepalive`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How long the connection is idle before the first probe.
+    pub idle: Duration,
+    /// Time between unanswered probes. Ignored where the OS doesn't let us set it.
+    pub interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped. Ignored where the OS doesn't let us
+    /// set it, e.g. Windows.
+    pub retries: Option<u32>,
+}
+
+impl SocketConfig {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn apply(&self, socket: &TcpSocket, addr: SocketAddr) -> io::Result<()> {
+        let sock = SockRef::from(socket);
+        if self.nodelay {
+            sock.set_nodelay(true)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            #[allow(unused_mut)]
+            let mut params = TcpKeepalive::new().with_time(keepalive.idle);
+            #[cfg(any(
+                windows,
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "netbsd"
+            ))]
+            if let Some(interval) = keepalive.interval {
+                params = params.with_interval(interval);
+            }
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "netbsd"
+            ))]
+            if let Some(retries) = keepalive.retries {
+                params = params.with_retries(retries);
+            }
+            sock.set_tcp_keepalive(&params)?;
+        }
+        match self.ttl {
+            Some(ttl) if addr.is_ipv6() => sock.set_unicast_hops_v6(ttl)?,
+            Some(ttl) => sock.set_ttl(ttl)?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// How [`LNSocket::connect_with`](crate::LNSocket::connect_with) connects.
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    /// How addresses are resolved and ordered. Alternates between IPv4 and IPv6 by default.
+    pub dns: DnsOptions,
+    /// Start the next address after this long if the ones started so far haven't connected
+    /// yet, [`DEFAULT_ATTEMPT_DELAY`] by default. `None` waits for each attempt to fail first.
+    pub happy_eyeballs: Option<Duration>,
+    /// Give up on an address after this long, [`DEFAULT_ATTEMPT_TIMEOUT`] by default. `None`
+    /// leaves it to the OS, which can take minutes.
+    pub attempt_timeout: Option<Duration>,
+    /// TCP options for the connection.
+    pub socket: SocketConfig,
+    /// Give up if resolving the host and connecting to one of its addresses take longer than
+    /// this, [`DEFAULT_CONNECT_TIMEOUT`] by default. The handshake isn't counted. `None` waits
+    /// for the resolver and [`ConnectOptions::attempt_timeout`] alone.
+    pub timeout: Option<Duration>,
+    /// Send every connection through the SOCKS5 proxy at this `host:port`, whatever the
+    /// address type. The proxy resolves host names, so `dns`, `happy_eyeballs` and `socket`
+    /// don't apply. `.b32.i2p` addresses still go to the I2P router. `None` by default.
+    pub socks_proxy: Option<String>,
+    /// Deliver the connection's [`Event`](crate::Event)s here from the start, beginning with
+    /// [`Event::HandshakeCompleted`](crate::Event::HandshakeCompleted). `None` by default;
+    /// [`LNSocket::subscribe_events`](crate::LNSocket::subscribe_events) can still be used
+    /// later.
+    pub events: Option<mpsc::UnboundedSender<Event>>,
+    /// Which connections through `socks_proxy` Tor may put on the same circuit. Each gets its
+    /// own by default.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub socks_isolation: crate::tor::Isolation,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            dns: DnsOptions {
+                preference: AddressPreference::Interleave,
+                ..Default::default()
+            },
+            happy_eyeballs: Some(DEFAULT_ATTEMPT_DELAY),
+            attempt_timeout: Some(DEFAULT_ATTEMPT_TIMEOUT),
+            socket: SocketConfig::default(),
+            timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            socks_proxy: None,
+            events: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            socks_isolation: Default::default(),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+async fn attempt(
+    addr: SocketAddr,
+    limit: Option<Duration>,
+    config: SocketConfig,
+) -> Result<(TcpStream, SocketAddr), Error> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    config.apply(&socket, addr)?;
+    let stream = match limit {
+        Some(limit) => timeout(limit, socket.connect(addr))
+            .await
+            .map_err(|_| Error::Timeout)??,
+        None => socket.connect(addr).await?,
+    };
+    Ok((stream, addr))
+}
+
+/// Connect to the first of `addrs` that accepts, starting them in order as `opts` say.
+///
+/// Fails with the error of the last attempt to fail if none connect.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn connect_tcp(
+    addrs: Vec<SocketAddr>,
+    opts: &ConnectOptions,
+) -> Result<(TcpStream, SocketAddr), Error> {
+    let mut pending = addrs.into_iter();
+    // dropping the set aborts the attempts that lost
+    let mut attempts = JoinSet::new();
+    let mut last_err = Error::DnsError;
+    loop {
+        match pending.next() {
+            Some(addr) => {
+                attempts.spawn(attempt(addr, opts.attempt_timeout, opts.socket.clone()));
+            }
+            None if attempts.is_empty() => return Err(last_err),
+            None => {}
+        }
+        let more = !pending.as_slice().is_empty();
+        let delay = opts.happy_eyeballs.unwrap_or_default();
+        tokio::select! {
+            Some(res) = attempts.join_next() => match res {
+                Ok(Ok(connected)) => return Ok(connected),
+                // the next address starts right away
+                Ok(Err(err)) => last_err = err,
+                Err(err) => std::panic::resume_unwind(err.into_panic()),
+            },
+            _ = tokio::time::sleep(delay), if more && opts.happy_eyeballs.is_some() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LNSocket;
+    use crate::dns::{Resolve, ResolveFuture};
+    use bitcoin::secp256k1::{Secp256k1, SecretKey, rand};
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    // TEST-NET-1, nothing answers there so connecting hangs until the OS gives up
+    const BLACKHOLE: &str = "192.0.2.1:9735";
+
+    #[tokio::test]
+    async fn test_races_past_unresponsive_address() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let good = listener.local_addr()?;
+        let opts = ConnectOptions {
+            happy_eyeballs: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let (_, addr) = connect_tcp(vec![BLACKHOLE.parse().unwrap(), good], &opts).await?;
+        assert_eq!(addr, good);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refused_address_falls_through() -> Result<(), Error> {
+        // the listener is dropped right away, so connecting is refused
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let good = listener.local_addr()?;
+        let opts = ConnectOptions {
+            happy_eyeballs: None,
+            ..Default::default()
+        };
+
+        let (_, addr) = connect_tcp(vec![closed, good], &opts).await?;
+        assert_eq!(addr, good);
+        assert!(matches!(
+            connect_tcp(vec![closed], &opts).await,
+            Err(Error::Io(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socket_config() -> Result<(), Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let opts = ConnectOptions {
+            socket: SocketConfig {
+                nodelay: true,
+                keepalive: Some(Keepalive {
+                    idle: Duration::from_secs(30),
+                    interval: Some(Duration::from_secs(5)),
+                    retries: Some(3),
+                }),
+                ttl: Some(17),
+            },
+            ..Default::default()
+        };
+        let (stream, _) = connect_tcp(vec![listener.local_addr()?], &opts).await?;
+        assert!(stream.nodelay()?);
+        assert_eq!(stream.ttl()?, 17);
+        assert!(SockRef::from(&stream).keepalive()?);
+        Ok(())
+    }
+
+    /// A resolver that never answers.
+    struct Stuck;
+
+    impl Resolve for Stuck {
+        fn resolve<'a>(&'a self, _host: &'a str, _port: u16) -> ResolveFuture<'a> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout_covers_dns() {
+        let mut opts = ConnectOptions {
+            timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        opts.dns.resolver = Some(Arc::new(Stuck));
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = key.public_key(&Secp256k1::signing_only());
+        let res = LNSocket::connect_with(&opts, key, node_id, "ln.example.com").await;
+        assert!(matches!(res, Err(Error::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_attempt_timeout() {
+        let opts = ConnectOptions {
+            happy_eyeballs: None,
+            attempt_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let res = connect_tcp(vec![BLACKHOLE.parse().unwrap()], &opts).await;
+        // without a route to TEST-NET-1 the OS fails it straight away instead
+        assert!(matches!(res, Err(Error::Timeout | Error::Io(_))));
+    }
+}
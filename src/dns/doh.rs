@@ -0,0 +1,329 @@
+//! DNS over HTTPS (RFC 8484).
+//!
+//! The system resolver sends the name of every node we connect to in the clear, to whoever
+//! runs the network's DNS. [`DohResolver`] asks a DoH server over TLS instead, so only that
+//! server learns them. Only the DoH server's own name goes through the system resolver, unless
+//! its URL uses an IP address.
+
+use super::{Resolve, ResolveFuture};
+use crate::websocket::TlsOptions;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Cloudflare's DoH endpoint.
+pub const CLOUDFLARE: &str = "https://cloudflare-dns.com/dns-query";
+/// Quad9's DoH endpoint.
+pub const QUAD9: &str = "https://dns.quad9.net/dns-query";
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+// more than any answer to a single A or AAAA question should need
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+fn invalid(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why)
+}
+
+/// Resolves host names with DNS over HTTPS. Needs the `tls` feature.
+///
+/// ### Example
+/// ```no_run
+/// use lnsocket::LNSocket;
+/// use lnsocket::connect::ConnectOptions;
+/// use lnsocket::dns::doh::{self, DohResolver};
+/// # use bitcoin::secp256k1::{PublicKey, SecretKey};
+/// # use std::sync::Arc;
+/// # async fn example(key: SecretKey, node: PublicKey) -> Result<(), lnsocket::Error> {
+/// let mut opts = ConnectOptions::default();
+/// opts.dns.resolver = Some(Arc::new(DohResolver::new(doh::CLOUDFLARE)));
+/// let socket = LNSocket::connect_with(&opts, key, node, "ln.example.com").await?;
+/// # Ok(()) }
+/// ```
+#[derive(Clone, Debug)]
+pub struct DohResolver {
+    /// The server's `https://` URL, e.g. [`CLOUDFLARE`].
+    pub url: String,
+    /// How to check the server's certificate.
+    pub tls: TlsOptions,
+}
+
+impl DohResolver {
+    /// Ask the DoH server at `url`, trusting the usual root certificates.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            tls: TlsOptions::default(),
+        }
+    }
+
+    /// POST one question and return the addresses in the answer.
+    async fn query(&self, host: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+        let (server, port, path) = parse_url(&self.url)?;
+        let tcp = TcpStream::connect((server, port)).await?;
+        let mut tls = crate::websocket::tls::connect(tcp, server, &self.tls)
+            .await
+            .map_err(|err| io::Error::other(err.to_string()))?;
+
+        let body = encode_query(host, qtype)?;
+        let head = format!(
+            "POST {path} HTTP/1.1\r\nHost: {server}\r\nContent-Type: application/dns-message\r\n\
+             Accept: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        tls.write_all(head.as_bytes()).await?;
+        tls.write_all(&body).await?;
+
+        let mut response = Vec::new();
+        match (&mut tls)
+            .take(MAX_RESPONSE)
+            .read_to_end(&mut response)
+            .await
+        {
+            Ok(_) => {}
+            // some servers hang up without a TLS close_notify, what came is checked below
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {}
+            Err(err) => return Err(err),
+        }
+        decode_answer(http_body(&response)?)
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                return Ok(vec![SocketAddr::new(ip, port)]);
+            }
+            let (v6, v4) = tokio::join!(self.query(host, TYPE_AAAA), self.query(host, TYPE_A));
+            // one family failing is fine as long as the other answered
+            let ips = match (v6, v4) {
+                (Err(err), Err(_)) => return Err(err),
+                (v6, v4) => v6
+                    .unwrap_or_default()
+                    .into_iter()
+                    .chain(v4.unwrap_or_default()),
+            };
+            Ok(ips.map(|ip| SocketAddr::new(ip, port)).collect())
+        })
+    }
+}
+
+/// Split `https://host[:port]/path` into its parts.
+fn parse_url(url: &str) -> io::Result<(&str, u16, &str)> {
+    let rest = url
+        .strip_prefix("https://")
+        .ok_or_else(|| invalid("DoH URLs must be https://"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let bad_port = || invalid("bad port in DoH URL");
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(v6) => match v6.split_once(']') {
+            Some((host, "")) => (host, 443),
+            Some((host, port)) => {
+                let port = port.strip_prefix(':').ok_or_else(bad_port)?;
+                (host, port.parse().map_err(|_| bad_port())?)
+            }
+            None => return Err(invalid("bad host in DoH URL")),
+        },
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| bad_port())?),
+            None => (authority, 443),
+        },
+    };
+    Ok((host, port, path))
+}
+
+/// A DNS query message for one `qtype` question about `host`.
+fn encode_query(host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    // id 0 as RFC 8484 asks, so responses cache well; recursion desired; one question
+    let mut msg = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid("bad host name"));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(msg)
+}
+
+/// The body of a successful HTTP response.
+fn http_body(response: &[u8]) -> io::Result<&[u8]> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid("truncated HTTP response"))?;
+    let head = std::str::from_utf8(&response[..end]).map_err(|_| invalid("bad HTTP response"))?;
+    let body = &response[end + 4..];
+    let mut lines = head.split("\r\n");
+    let status = lines.next().and_then(|line| line.split(' ').nth(1));
+    if status != Some("200") {
+        return Err(io::Error::other(format!(
+            "DoH server answered {}",
+            status.unwrap_or("garbage")
+        )));
+    }
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") && value != "identity" {
+            return Err(invalid("chunked DoH responses aren't supported"));
+        }
+        if name.eq_ignore_ascii_case("content-length") {
+            let len: usize = value.parse().map_err(|_| invalid("bad content length"))?;
+            return body
+                .get(..len)
+                .ok_or_else(|| invalid("truncated DoH response"));
+        }
+    }
+    Ok(body)
+}
+
+/// Reads big endian integers off a DNS message, failing on truncation.
+struct Reader<'a> {
+    msg: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .msg
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| invalid("truncated DNS message"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Skip a possibly compressed name.
+    fn skip_name(&mut self) -> io::Result<()> {
+        loop {
+            let len = self.take(1)?[0];
+            match len {
+                0 => return Ok(()),
+                // a pointer ends the name
+                len if len & 0xc0 == 0xc0 => {
+                    self.take(1)?;
+                    return Ok(());
+                }
+                len => {
+                    self.take(len as usize)?;
+                }
+            }
+        }
+    }
+}
+
+/// The A and AAAA records in the answer section of a DNS response.
+fn decode_answer(msg: &[u8]) -> io::Result<Vec<IpAddr>> {
+    let mut r = Reader { msg, pos: 0 };
+    let _id = r.u16()?;
+    let flags = r.u16()?;
+    match flags & 0xf {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(Vec::new()),
+        rcode => return Err(io::Error::other(format!("DNS error code {rcode}"))),
+    }
+    let questions = r.u16()?;
+    let answers = r.u16()?;
+    r.take(4)?;
+    for _ in 0..questions {
+        r.skip_name()?;
+        r.take(4)?;
+    }
+
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        r.skip_name()?;
+        let rtype = r.u16()?;
+        let class = r.u16()?;
+        r.take(4)?;
+        let len = r.u16()? as usize;
+        let data = r.take(len)?;
+        match (rtype, class, len) {
+            (TYPE_A, CLASS_IN, 4) => {
+                let octets: [u8; 4] = data.try_into().expect("checked length");
+                ips.push(Ipv4Addr::from(octets).into());
+            }
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let octets: [u8; 16] = data.try_into().expect("checked length");
+                ips.push(Ipv6Addr::from(octets).into());
+            }
+            // CNAMEs leading to the addresses, mostly
+            _ => {}
+        }
+    }
+    Ok(ips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url(CLOUDFLARE).unwrap(),
+            ("cloudflare-dns.com", 443, "/dns-query")
+        );
+        assert_eq!(
+            parse_url("https://[2606:4700::1111]:8443").unwrap(),
+            ("2606:4700::1111", 8443, "/")
+        );
+        assert!(parse_url("http://dns.example/dns-query").is_err());
+    }
+
+    #[test]
+    fn test_encode_query() {
+        let query = encode_query("ln.example.", TYPE_AAAA).unwrap();
+        let mut expected = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x02ln\x07example\x00\x00\x1c\x00\x01");
+        assert_eq!(query, expected);
+        assert!(encode_query("a..b", TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_decode_answer() {
+        let mut msg = vec![0, 0, 0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 0];
+        msg.extend_from_slice(b"\x03www\x07example\x00\x00\x01\x00\x01");
+        // CNAME to ln.example, pointing back at the question's "example"
+        msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 5]);
+        msg.extend_from_slice(&[2, b'l', b'n', 0xc0, 16]);
+        // the addresses, named by a pointer to the CNAME's target
+        msg.extend_from_slice(&[0xc0, 41, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        msg.extend_from_slice(&[0xc0, 41, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 2]);
+        assert_eq!(
+            decode_answer(&msg).unwrap(),
+            vec![IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])]
+        );
+
+        // NXDOMAIN is no addresses rather than an error
+        let nx = [0, 0, 0x81, 0x83, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(decode_answer(&nx).unwrap().is_empty());
+        assert!(decode_answer(&msg[..msg.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn test_http_body() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabcdef";
+        assert_eq!(http_body(response).unwrap(), b"abc");
+        assert!(http_body(b"HTTP/1.1 400 Bad Request\r\n\r\n").is_err());
+        assert!(http_body(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nabc").is_err());
+    }
+}
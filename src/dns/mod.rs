@@ -0,0 +1,260 @@
+//! Resolving node addresses.
+//!
+//! By default [`LNSocket::connect`](crate::LNSocket::connect) uses the system resolver and
+//! tries addresses alternating between IPv4 and IPv6. [`DnsOptions`] changes that, for networks
+//! where one address family is broken or missing, or to resolve through something other than
+//! the system (a fixed table in tests, say) by implementing [`Resolve`]. With the `tls`
+//! feature, `doh::DohResolver` resolves with DNS over HTTPS.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::connect::ConnectOptions;
+//! use lnsocket::dns::{AddressPreference, DnsOptions};
+//! # use bitcoin::secp256k1::{PublicKey, SecretKey};
+//! # async fn example(key: SecretKey, node: PublicKey) -> Result<(), lnsocket::Error> {
+//! let opts = ConnectOptions {
+//!     dns: DnsOptions {
+//!         preference: AddressPreference::Ipv4Only,
+//!         ..Default::default()
+//!     },
+//!     ..Default::default()
+//! };
+//! let socket = LNSocket::connect_with(&opts, key, node, "ln.example.com").await?;
+//! # Ok(()) }
+//! ```
+
+use crate::socket_addr::split_host_port;
+use crate::{Error, SocketAddress};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::net::lookup_host;
+
+#[cfg(feature = "tls")]
+pub mod doh;
+
+/// Which resolved addresses to try, in which order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressPreference {
+    /// The order the resolver returned them in.
+    #[default]
+    AsResolved,
+    /// IPv4 addresses first.
+    PreferIpv4,
+    /// IPv6 addresses first.
+    PreferIpv6,
+    /// Alternate between families, starting with the resolver's first, so a broken family
+    /// costs one failed attempt rather than all of them (RFC 8305).
+    Interleave,
+    /// Only IPv4 addresses.
+    Ipv4Only,
+    /// Only IPv6 addresses.
+    Ipv6Only,
+}
+
+impl AddressPreference {
+    /// Filter and reorder `addrs` by this preference. Within a family the order is kept.
+    pub fn apply(self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (v4, v6): (Vec<_>, Vec<_>) = addrs.iter().partition(|addr| addr.is_ipv4());
+        match self {
+            AddressPreference::AsResolved => addrs,
+            AddressPreference::PreferIpv4 => v4.into_iter().chain(v6).collect(),
+            AddressPreference::PreferIpv6 => v6.into_iter().chain(v4).collect(),
+            AddressPreference::Ipv4Only => v4,
+            AddressPreference::Ipv6Only => v6,
+            AddressPreference::Interleave => {
+                let (mut first, mut second) = match addrs.first() {
+                    Some(addr) if addr.is_ipv6() => (v6.into_iter(), v4.into_iter()),
+                    _ => (v4.into_iter(), v6.into_iter()),
+                };
+                let mut out = Vec::with_capacity(addrs.len());
+                loop {
+                    match (first.next(), second.next()) {
+                        (None, None) => break,
+                        (a, b) => out.extend(a.into_iter().chain(b)),
+                    }
+                }
+                out
+            }
+        }
+    }
+
+    /// Filter and reorder a node's announced `addresses` (from its `node_announcement`) the
+    /// same way. Only the IP addresses are affected; host names, onion addresses and the rest
+    /// are kept, after them, since which family they end up on isn't known yet.
+    pub fn apply_announced(self, addresses: &[SocketAddress]) -> Vec<SocketAddress> {
+        let ips = addresses.iter().filter_map(|addr| match addr {
+            SocketAddress::TcpIpV4 { addr, port } => Some(SocketAddr::from((*addr, *port))),
+            SocketAddress::TcpIpV6 { addr, port } => Some(SocketAddr::from((*addr, *port))),
+            _ => None,
+        });
+        let ips = self
+            .apply(ips.collect())
+            .into_iter()
+            .map(|addr| match addr {
+                SocketAddr::V4(v4) => SocketAddress::TcpIpV4 {
+                    addr: v4.ip().octets(),
+                    port: v4.port(),
+                },
+                SocketAddr::V6(v6) => SocketAddress::TcpIpV6 {
+                    addr: v6.ip().octets(),
+                    port: v6.port(),
+                },
+            });
+        let rest = addresses.iter().filter(|addr| {
+            !matches!(
+                addr,
+                SocketAddress::TcpIpV4 { .. } | SocketAddress::TcpIpV6 { .. }
+            )
+        });
+        ips.chain(rest.cloned()).collect()
+    }
+}
+
+/// The future returned by [`Resolve::resolve`].
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// A way to turn a host name into addresses.
+pub trait Resolve: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a>;
+}
+
+/// The operating system's resolver, through tokio. Browsers don't offer one, there it
+/// always fails.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move { Ok(lookup_host((host, port)).await?.collect()) })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn resolve<'a>(&'a self, _host: &'a str, _port: u16) -> ResolveFuture<'a> {
+        Box::pin(async { Err(io::ErrorKind::Unsupported.into()) })
+    }
+}
+
+/// How [`LNSocket::connect_with`](crate::LNSocket::connect_with) finds the addresses to try,
+/// see [`ConnectOptions::dns`](crate::connect::ConnectOptions::dns).
+#[derive(Clone, Default)]
+pub struct DnsOptions {
+    /// Which addresses to try first, or at all.
+    pub preference: AddressPreference,
+    /// The resolver to use instead of the system one.
+    pub resolver: Option<Arc<dyn Resolve>>,
+}
+
+impl fmt::Debug for DnsOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsOptions")
+            .field("preference", &self.preference)
+            .field("custom_resolver", &self.resolver.is_some())
+            .finish()
+    }
+}
+
+/// Resolve `host[:port]` into the addresses to try, in order. Fails with [`Error::DnsError`]
+/// if none are left.
+pub async fn resolve(addr: &str, opts: &DnsOptions) -> Result<Vec<SocketAddr>, Error> {
+    let (host, port) = split_host_port(addr)?;
+    let addrs = match &opts.resolver {
+        Some(resolver) => resolver.resolve(host, port).await?,
+        None => SystemResolver.resolve(host, port).await?,
+    };
+    let addrs = opts.preference.apply(addrs);
+    if addrs.is_empty() {
+        return Err(Error::DnsError);
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preference() {
+        let a4: SocketAddr = "192.0.2.1:9735".parse().unwrap();
+        let b4: SocketAddr = "192.0.2.2:9735".parse().unwrap();
+        let a6: SocketAddr = "[2001:db8::1]:9735".parse().unwrap();
+        let b6: SocketAddr = "[2001:db8::2]:9735".parse().unwrap();
+        let addrs = vec![a6, b6, a4, b4];
+
+        use AddressPreference::*;
+        assert_eq!(AsResolved.apply(addrs.clone()), addrs);
+        assert_eq!(PreferIpv4.apply(addrs.clone()), vec![a4, b4, a6, b6]);
+        assert_eq!(PreferIpv6.apply(addrs.clone()), vec![a6, b6, a4, b4]);
+        assert_eq!(Interleave.apply(addrs.clone()), vec![a6, a4, b6, b4]);
+        assert_eq!(Interleave.apply(vec![a4, a6, b6]), vec![a4, a6, b6]);
+        assert_eq!(Ipv4Only.apply(addrs.clone()), vec![a4, b4]);
+        assert_eq!(Ipv6Only.apply(vec![a4]), vec![]);
+    }
+
+    #[test]
+    fn test_preference_announced() {
+        let v4 = SocketAddress::TcpIpV4 {
+            addr: [192, 0, 2, 1],
+            port: 9735,
+        };
+        let v6 = SocketAddress::TcpIpV6 {
+            addr: [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            port: 9736,
+        };
+        let host = SocketAddress::Hostname {
+            hostname: "ln.example.com".to_owned().try_into().unwrap(),
+            port: 9735,
+        };
+        let announced = [host.clone(), v6.clone(), v4.clone()];
+
+        use AddressPreference::*;
+        assert_eq!(
+            AsResolved.apply_announced(&announced),
+            [v6.clone(), v4.clone(), host.clone()]
+        );
+        assert_eq!(
+            PreferIpv4.apply_announced(&announced),
+            [v4.clone(), v6.clone(), host.clone()]
+        );
+        assert_eq!(Ipv6Only.apply_announced(&announced), [v6, host]);
+    }
+
+    struct Fixed(Vec<SocketAddr>);
+
+    impl Resolve for Fixed {
+        fn resolve<'a>(&'a self, _host: &'a str, port: u16) -> ResolveFuture<'a> {
+            let addrs = self.0.iter().map(|addr| SocketAddr::new(addr.ip(), port));
+            Box::pin(std::future::ready(Ok(addrs.collect())))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_resolver() -> Result<(), Error> {
+        let opts = DnsOptions {
+            preference: AddressPreference::Ipv4Only,
+            resolver: Some(Arc::new(Fixed(vec![
+                "[::1]:0".parse().unwrap(),
+                "127.0.0.1:0".parse().unwrap(),
+            ]))),
+        };
+        let addrs = resolve("node.test:1234", &opts).await?;
+        assert_eq!(addrs, vec!["127.0.0.1:1234".parse().unwrap()]);
+
+        // nothing left after filtering
+        let opts = DnsOptions {
+            preference: AddressPreference::Ipv6Only,
+            resolver: Some(Arc::new(Fixed(vec!["127.0.0.1:0".parse().unwrap()]))),
+        };
+        assert!(matches!(
+            resolve("node.test", &opts).await,
+            Err(Error::DnsError)
+        ));
+        Ok(())
+    }
+}
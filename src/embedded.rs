@@ -0,0 +1,254 @@
+//! Lightning connections over `embedded-io-async` streams.
+//!
+//! [`EmbeddedSocket`] does the BOLT 8 handshake and message framing over any stream
+//! implementing the `embedded-io-async` `Read` and `Write` traits, such as a smoltcp or
+//! embassy-net TCP socket, without tokio. That's enough for a board to act as a minimal peer,
+//! or to send commando requests: write a [`CommandoCommand`](crate::commando::CommandoCommand)
+//! and read the replies with
+//! [`read_incoming_commando_message`](crate::commando::read_incoming_commando_message).
+//!
+//! Only available with the `embedded-io` feature. Nothing on this path needs tokio, the OS
+//! networking stack or `std`, so it builds for `no_std` targets with an allocator:
+//! `default-features = false, features = ["embedded-io"]`.
+//!
+//! Unlike [`LNSocket`](crate::LNSocket), nothing happens behind the caller's back: pings
+//! aren't answered automatically, and reads aren't cancellation safe, so don't race them
+//! against other futures.
+//!
+//! ### Example
+//! ```ignore
+//! use lnsocket::embedded::EmbeddedSocket;
+//! use lnsocket::ln::{msgs, wire::Message};
+//!
+//! let ephemeral = SecretKey::from_slice(&board_rng_bytes())?;
+//! let mut socket = EmbeddedSocket::connect(tcp, our_key, node_id, ephemeral).await?;
+//! socket.perform_init().await?;
+//! loop {
+//!     match socket.read().await? {
+//!         Message::Ping(ping) => {
+//!             socket.write(&msgs::Pong { byteslen: ping.ponglen }).await?;
+//!         }
+//!         msg => handle(msg),
+//!     }
+//! }
+//! ```
+
+use crate::Error;
+use crate::features::bits;
+use crate::init::{InitOptions, PeerInfo};
+use crate::io::{self, Cursor};
+use crate::ln::msgs::{self, DecodeError};
+use crate::ln::wire::{Message, Type};
+use crate::prelude::*;
+use crate::sans_io::Session;
+use crate::util::ser::Writeable;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use embedded_io_async::{Read, Write};
+
+fn io_error<E>(_: E) -> Error {
+    Error::Io(io::ErrorKind::ConnectionAborted)
+}
+
+/// A Lightning connection over an `embedded-io-async` stream. See the
+/// [module docs](self).
+///
+/// The protocol is a [`Session`]'s, this only moves its bytes over the stream.
+pub struct EmbeddedSocket<S> {
+    session: Session,
+    stream: S,
+}
+
+impl<S: Read + Write> EmbeddedSocket<S> {
+    /// Do the initiator side of the handshake with `their_pubkey` over `stream`.
+    ///
+    /// `ephemeral` must be a fresh random key for every connection, from whatever RNG the
+    /// platform has.
+    pub async fn connect(
+        stream: S,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        ephemeral: SecretKey,
+    ) -> Result<Self, Error> {
+        let session = Session::outbound(our_key, their_pubkey, ephemeral);
+        Self::handshake(stream, session).await
+    }
+
+    /// Do the responder side of the handshake over `stream`. `ephemeral` as in
+    /// [`EmbeddedSocket::connect`].
+    pub async fn accept(
+        stream: S,
+        our_key: SecretKey,
+        ephemeral: SecretKey,
+    ) -> Result<Self, Error> {
+        Self::handshake(stream, Session::inbound(our_key, ephemeral)).await
+    }
+
+    async fn handshake(stream: S, session: Session) -> Result<Self, Error> {
+        let mut socket = Self { session, stream };
+        socket.flush().await?;
+        while !socket.session.is_handshake_complete() {
+            socket.fill().await?;
+            socket.flush().await?;
+        }
+        Ok(socket)
+    }
+
+    /// Write what the session has queued.
+    async fn flush(&mut self) -> Result<(), Error> {
+        let len = self.session.outgoing().len();
+        if len == 0 {
+            return Ok(());
+        }
+        self.stream
+            .write_all(self.session.outgoing())
+            .await
+            .map_err(io_error)?;
+        self.stream.flush().await.map_err(io_error)?;
+        self.session.consume(len);
+        Ok(())
+    }
+
+    /// Hand whatever the stream has to the session.
+    async fn fill(&mut self) -> Result<(), Error> {
+        let mut buf = [0u8; 512];
+        let n = self.stream.read(&mut buf).await.map_err(io_error)?;
+        if n == 0 {
+            return Err(self.session.eof_error());
+        }
+        self.session.receive(&buf[..n])
+    }
+
+    /// The node id of the peer on the other end.
+    pub fn their_pubkey(&self) -> PublicKey {
+        self.session
+            .their_pubkey()
+            .expect("node id is known once the handshake completes")
+    }
+
+    /// What the peer said in its `init`, once [`EmbeddedSocket::perform_init`] is done.
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.session.peer_info()
+    }
+
+    /// Send our `init` and wait for the peer's, which must be the first message it sends.
+    pub async fn perform_init(&mut self) -> Result<(), Error> {
+        self.perform_init_with(&InitOptions::default()).await
+    }
+
+    /// Like [`EmbeddedSocket::perform_init`], with control over the `init` we send.
+    ///
+    /// [`InitOptions::max_pre_init_messages`] and [`InitOptions::echo_remote_address`] aren't
+    /// supported and are ignored.
+    pub async fn perform_init_with(&mut self, opts: &InitOptions) -> Result<(), Error> {
+        self.session.send_init(opts)?;
+        self.flush().await?;
+
+        // the session turns away anything else, or an init for other networks
+        let Message::Init(init) = self.read().await? else {
+            return Err(Error::FirstMessageNotInit);
+        };
+        let info = PeerInfo::new(init);
+        // ours is already sent, so only a refusal matters here
+        opts.check_peer(&info, &mut opts.advertised_features())?;
+
+        if opts.suppress_gossip && info.features().supports(bits::GOSSIP_QUERIES) {
+            for chain_hash in opts.networks.iter().copied() {
+                self.write(&msgs::GossipTimestampFilter {
+                    chain_hash,
+                    first_timestamp: u32::MAX,
+                    timestamp_range: 0,
+                })
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypt and send a message. Only `init` may be sent before
+    /// [`EmbeddedSocket::perform_init`] is done, see [`Session::send`].
+    pub async fn write<M: Type + Writeable>(&mut self, m: &M) -> Result<(), Error> {
+        self.session.send(m)?;
+        self.flush().await
+    }
+
+    pub async fn read(&mut self) -> Result<Message<()>, Error> {
+        self.read_custom(|_type, _buf| Ok(None)).await
+    }
+
+    pub async fn read_custom<T>(
+        &mut self,
+        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+    ) -> Result<Message<T>, Error>
+    where
+        T: core::fmt::Debug,
+    {
+        loop {
+            if let Some(frame) = self.session.next_frame()? {
+                return self.session.decode_frame(&frame, handler);
+            }
+            self.fill().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LNSocket;
+    use bitcoin::secp256k1::{Secp256k1, rand};
+    use embedded_io_async::{ErrorKind, ErrorType};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// What a board's TCP socket looks like to us.
+    struct Embedded(DuplexStream);
+
+    impl ErrorType for Embedded {
+        type Error = ErrorKind;
+    }
+
+    impl Read for Embedded {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            self.0.read(buf).await.map_err(|_| ErrorKind::Other)
+        }
+    }
+
+    impl Write for Embedded {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.0.write(buf).await.map_err(|_| ErrorKind::Other)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_embedded_socket() -> Result<(), Error> {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let ephemeral = SecretKey::new(&mut rand::thread_rng());
+
+        let (ours, node) = tokio::join!(
+            EmbeddedSocket::connect(Embedded(a), key, node_id, ephemeral),
+            LNSocket::handshake_inbound(b, node_key)
+        );
+        let (mut ours, mut node) = (ours?, node?);
+        assert_eq!(ours.their_pubkey(), node_id);
+
+        let (res, node_res) = tokio::join!(ours.perform_init(), node.perform_init());
+        res?;
+        node_res?;
+        assert!(ours.peer_info().is_some());
+
+        ours.write(&msgs::Ping {
+            ponglen: 2,
+            byteslen: 4,
+        })
+        .await?;
+        let Message::Ping(ping) = node.read().await? else {
+            panic!("expected a ping");
+        };
+        let pong = node.pong_for(&ping)?.expect("ponglen is below the limit");
+        node.write(&pong).await?;
+        assert!(matches!(ours.read().await?, Message::Pong(p) if p.byteslen == 2));
+        Ok(())
+    }
+}
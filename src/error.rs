@@ -1,30 +1,149 @@
+#[cfg(feature = "std")]
+use crate::backup::BackupError;
+#[cfg(feature = "std")]
+use crate::commando::{RpcError, RuneError};
+use crate::io;
+#[cfg(feature = "std")]
+use crate::keys::KeyError;
 use crate::ln::msgs::{DecodeError, LightningError};
-use std::fmt;
-use std::io;
-use std::net::AddrParseError;
+use crate::prelude::*;
+use bitcoin::constants::ChainHash;
+use core::fmt;
+use core::net::AddrParseError;
+
+/// Why the BOLT 8 Noise handshake failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// The peer stopped sending before a full act two (50 bytes) arrived. Contains the number
+    /// of bytes received.
+    ShortAct2(usize),
+    /// Act two used a handshake version we don't understand.
+    BadVersion(u8),
+    /// Act two contained an invalid ephemeral public key.
+    BadEphemeralKey,
+    /// Act two failed authentication. Usually this means the node's public key is not the one
+    /// we expected.
+    BadMac,
+    /// The peer answered with an HTTP response, so we probably connected to a web server
+    /// instead of the node's Lightning port.
+    HttpResponse,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::ShortAct2(len) => {
+                write!(f, "peer sent only {} of 50 act two bytes", len)
+            }
+            HandshakeError::BadVersion(v) => write!(f, "unknown handshake version {}", v),
+            HandshakeError::BadEphemeralKey => write!(f, "invalid ephemeral key in act two"),
+            HandshakeError::BadMac => write!(f, "act two failed authentication, wrong node id?"),
+            HandshakeError::HttpResponse => write!(
+                f,
+                "peer answered with HTTP, is this the node's lightning port?"
+            ),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
     NotConnected,
+    Handshake(HandshakeError),
     FirstMessageNotInit,
+    InitNotComplete,
+    /// The peer's `init` listed networks, none of which are ones we advertise.
+    NetworkMismatch {
+        ours: Vec<ChainHash>,
+        theirs: Vec<ChainHash>,
+    },
+    InvalidCustomTlv(u64),
+    /// [`InitOptions::on_peer_init`](crate::InitOptions::on_peer_init) refused the peer.
+    InitRejected(String),
+    PingFlood,
+    /// The peer didn't answer a keepalive ping in time, see
+    /// [`KeepalivePolicy`](crate::ping::KeepalivePolicy).
+    PeerUnresponsive,
+    /// A running connection's send queue is full, see
+    /// [`Backpressure::Error`](crate::handle::Backpressure::Error).
+    QueueFull,
+    Timeout,
     DnsError,
+    /// A node address wasn't of the form `node_id@host[:port]`.
+    InvalidUri(String),
+    /// A gossip message we were asked to build breaks a BOLT 7 rule.
+    InvalidGossip(String),
+    /// The SOCKS proxy refused the connection. Contains the SOCKS5 reply code.
+    Socks(u8),
+    /// The HTTP proxy refused the `CONNECT`. Contains the response's status code.
+    HttpProxy(u16),
+    /// The I2P SAM bridge refused a request. Contains its result code, and message if any.
+    I2p(String),
+    /// The in-process Tor client failed to bootstrap or to reach the peer.
+    Tor(String),
+    /// A `wss://` connection failed TLS setup, e.g. the certificate wasn't trusted.
+    Tls(String),
     Io(io::ErrorKind),
     Json(serde_json::Error),
+    /// The peer sent a BOLT 1 `error` while we were waiting for its reply.
+    RemoteError(String),
+    /// The node answered a commando request with an error.
+    #[cfg(feature = "std")]
+    Rpc(RpcError),
+    #[cfg(feature = "std")]
+    Rune(RuneError),
+    #[cfg(feature = "std")]
+    Backup(BackupError),
+    #[cfg(feature = "std")]
+    Key(KeyError),
     Lightning(LightningError),
     Decode(DecodeError),
-    AddrParse(std::net::AddrParseError),
+    AddrParse(AddrParseError),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::NotConnected => write!(f, "Not connected to server"),
+            Error::Handshake(err) => write!(f, "Handshake failed: {}", err),
             Error::FirstMessageNotInit => write!(f, "First message was not init"),
+            Error::InitNotComplete => {
+                write!(f, "Tried to send a message before init was exchanged")
+            }
+            Error::NetworkMismatch { ours, theirs } => write!(
+                f,
+                "Peer is on a different network (ours: {:?}, theirs: {:?})",
+                ours, theirs
+            ),
+            Error::InvalidCustomTlv(typ) => write!(f, "Invalid custom init TLV type {}", typ),
+            Error::InitRejected(why) => write!(f, "Refused the peer's init: {}", why),
+            Error::PingFlood => write!(f, "Peer is flooding us with pings"),
+            Error::PeerUnresponsive => write!(f, "Peer stopped answering pings"),
+            Error::QueueFull => write!(f, "Send queue is full"),
+            Error::Timeout => write!(f, "Timed out"),
             Error::DnsError => write!(f, "Failed to resolve hostname"),
-            Error::Io(kind) => write!(f, "I/O error: {}", kind),
+            Error::InvalidUri(uri) => write!(f, "Invalid node address '{}'", uri),
+            Error::InvalidGossip(why) => write!(f, "Invalid gossip message: {}", why),
+            Error::Socks(code) => write!(f, "SOCKS proxy refused the connection ({})", code),
+            Error::HttpProxy(status) => {
+                write!(f, "HTTP proxy refused the connection ({})", status)
+            }
+            Error::I2p(why) => write!(f, "I2P SAM bridge refused the connection: {}", why),
+            Error::Tor(why) => write!(f, "Tor error: {}", why),
+            Error::Tls(why) => write!(f, "TLS error: {}", why),
+            Error::Io(kind) => write!(f, "I/O error: {}", io::Error::from(*kind)),
             Error::Lightning(err) => write!(f, "Lightning error: {:?}", err),
             Error::Decode(err) => write!(f, "decoding error: {:?}", err),
             Error::Json(err) => write!(f, "json error: {:?}", err),
+            Error::RemoteError(msg) => write!(f, "Peer sent an error: {}", msg),
+            #[cfg(feature = "std")]
+            Error::Rpc(err) => write!(f, "RPC error: {}", err),
+            #[cfg(feature = "std")]
+            Error::Rune(err) => write!(f, "Rune error: {}", err),
+            #[cfg(feature = "std")]
+            Error::Backup(err) => write!(f, "Backup error: {}", err),
+            #[cfg(feature = "std")]
+            Error::Key(err) => write!(f, "Key error: {}", err),
             Error::AddrParse(err) => write!(f, "Address parse error: {}", err),
         }
     }
@@ -36,6 +155,33 @@ impl From<io::Error> for Error {
     }
 }
 
+#[cfg(feature = "std")]
+impl From<BackupError> for Error {
+    fn from(err: BackupError) -> Self {
+        Self::Backup(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<RuneError> for Error {
+    fn from(err: RuneError) -> Self {
+        Self::Rune(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<KeyError> for Error {
+    fn from(err: KeyError) -> Self {
+        Self::Key(err)
+    }
+}
+
+impl From<HandshakeError> for Error {
+    fn from(err: HandshakeError) -> Self {
+        Self::Handshake(err)
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Self {
         Self::Json(err)
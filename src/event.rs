@@ -0,0 +1,105 @@
+//! Connection events surfaced alongside the regular message stream.
+//!
+//! Events are delivered through an optional channel obtained from
+//! [`LNSocket::subscribe_events`](crate::LNSocket::subscribe_events). Nothing is buffered
+//! when no one is subscribed. To see the handshake complete as well, pass the channel in
+//! [`ConnectOptions::events`](crate::connect::ConnectOptions::events) instead.
+
+use crate::init::PeerInfo;
+use crate::io;
+use crate::ln::msgs;
+use crate::ln::types::ChannelId;
+use crate::prelude::*;
+use core::time::Duration;
+
+/// Something noteworthy that happened on a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The Noise handshake finished. Only seen through
+    /// [`ConnectOptions::events`](crate::connect::ConnectOptions::events).
+    HandshakeCompleted,
+    /// The peer's `init` arrived.
+    InitReceived(PeerInfo),
+    /// We sent a `ping`.
+    PingSent,
+    /// A `pong` arrived.
+    PongReceived,
+    /// The connection is gone. Emitted once, by the read or write that found out.
+    Disconnected { reason: DisconnectReason },
+    /// The peer sent us a BOLT 1 `warning`.
+    RemoteWarning(RemoteNotice),
+    /// The peer sent us a BOLT 1 `error`.
+    RemoteError(RemoteNotice),
+    /// The connection was replaced after its encryption broke, see [`crate::recovery`], or
+    /// after it dropped, see [`crate::reconnect`]. Anything in flight on the old one was lost.
+    Reconnected,
+    /// Connecting failed, the `attempt`th time in a row. The next attempt is made after
+    /// `delay`. See [`crate::reconnect`].
+    Reconnecting { attempt: u32, delay: Duration },
+    /// A keepalive ping went unanswered, so the connection is given up on. See
+    /// [`KeepalivePolicy`](crate::ping::KeepalivePolicy).
+    PeerUnresponsive,
+    /// The in-process Tor client is this far into bootstrapping. Only with the `tor-arti`
+    /// feature.
+    #[cfg_attr(
+        feature = "tor-arti",
+        doc = "\nSee [`Arti::bootstrap`](crate::arti::Arti::bootstrap)."
+    )]
+    TorBootstrap { percent: u8 },
+}
+
+/// Why a connection ended, see [`Event::Disconnected`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The peer closed the connection.
+    Closed,
+    /// Reading or writing failed.
+    Io(io::ErrorKind),
+    /// A message didn't decrypt, so nothing more can be read.
+    Decryption,
+    /// Keepalive pings went unanswered, see
+    /// [`KeepalivePolicy`](crate::ping::KeepalivePolicy).
+    Unresponsive,
+    /// We closed it with [`LNSocket::close`](crate::LNSocket::close).
+    Local,
+}
+
+/// The contents of a `warning` or `error` sent by the peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteNotice {
+    /// The channel the notice refers to. All-0s means the whole connection.
+    pub channel_id: ChannelId,
+    /// The notice text, if it was printable. Peers are free to send arbitrary bytes here, so
+    /// anything containing control characters or invalid UTF-8 is withheld.
+    pub message: Option<String>,
+}
+
+impl RemoteNotice {
+    fn new(channel_id: ChannelId, data: &str) -> Self {
+        Self {
+            channel_id,
+            message: printable(data).map(ToOwned::to_owned),
+        }
+    }
+}
+
+impl From<&msgs::WarningMessage> for RemoteNotice {
+    fn from(msg: &msgs::WarningMessage) -> Self {
+        Self::new(msg.channel_id, &msg.data)
+    }
+}
+
+impl From<&msgs::ErrorMessage> for RemoteNotice {
+    fn from(msg: &msgs::ErrorMessage) -> Self {
+        Self::new(msg.channel_id, &msg.data)
+    }
+}
+
+/// Returns `data` if it is safe to show to a human: no control characters other than
+/// whitespace and no replacement characters left over from lossy UTF-8 decoding.
+fn printable(data: &str) -> Option<&str> {
+    let ok = data
+        .chars()
+        .all(|c| c != char::REPLACEMENT_CHARACTER && (!c.is_control() || c == '\n' || c == '\t'));
+    if ok { Some(data) } else { None }
+}
@@ -0,0 +1,329 @@
+//! BOLT 9 feature bits.
+//!
+//! Features come in pairs: the even bit means "required", the odd bit "optional". The
+//! constants in [`bits`] name the even bit of each pair.
+
+use crate::prelude::*;
+
+/// Even (required) bit numbers of the features lnsocket knows by name.
+pub mod bits {
+    pub const DATA_LOSS_PROTECT: usize = 0;
+    pub const INITIAL_ROUTING_SYNC: usize = 2;
+    pub const UPFRONT_SHUTDOWN_SCRIPT: usize = 4;
+    pub const GOSSIP_QUERIES: usize = 6;
+    pub const VAR_ONION_OPTIN: usize = 8;
+    pub const GOSSIP_QUERIES_EX: usize = 10;
+    pub const STATIC_REMOTEKEY: usize = 12;
+    pub const PAYMENT_SECRET: usize = 14;
+    pub const BASIC_MPP: usize = 16;
+    pub const WUMBO: usize = 18;
+    pub const ANCHORS_ZERO_FEE_HTLC_TX: usize = 22;
+    pub const ROUTE_BLINDING: usize = 24;
+    pub const SHUTDOWN_ANYSEGWIT: usize = 26;
+    pub const DUAL_FUND: usize = 28;
+    pub const QUIESCE: usize = 34;
+    pub const ONION_MESSAGES: usize = 38;
+    pub const PROVIDE_STORAGE: usize = 42;
+    pub const CHANNEL_TYPE: usize = 44;
+    pub const SCID_ALIAS: usize = 46;
+    pub const PAYMENT_METADATA: usize = 48;
+    pub const ZERO_CONF: usize = 50;
+}
+
+macro_rules! named_features {
+    ($($name:ident => $bit:ident,)*) => {
+        $(
+            #[doc = concat!("Whether [`bits::", stringify!($bit), "`] is set, as optional or required.")]
+            pub fn $name(&self) -> bool {
+                self.supports(bits::$bit)
+            }
+        )*
+    };
+}
+
+/// The features a peer advertised in its `init`, see
+/// [`PeerInfo::features`](crate::PeerInfo::features).
+pub type InitFeatures = Features;
+
+/// A set of feature bits, as found in `init` and gossip messages.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct Features {
+    // little-endian, so bit n lives in le_flags[n / 8]
+    le_flags: Vec<u8>,
+}
+
+impl Features {
+    /// No features at all.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Start a [`FeaturesBuilder`] with no features.
+    pub fn builder() -> FeaturesBuilder {
+        FeaturesBuilder::default()
+    }
+
+    /// Parse the big-endian bitfield used on the wire.
+    pub fn from_be_bytes(mut bytes: Vec<u8>) -> Self {
+        bytes.reverse();
+        let mut features = Self { le_flags: bytes };
+        features.trim();
+        features
+    }
+
+    /// The big-endian bitfield used on the wire, without leading zero bytes.
+    pub fn to_be_bytes(&self) -> Vec<u8> {
+        self.le_flags.iter().rev().copied().collect()
+    }
+
+    /// Whether bit `bit` is set.
+    pub fn has_bit(&self, bit: usize) -> bool {
+        self.le_flags
+            .get(bit / 8)
+            .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+    }
+
+    /// Set bit `bit`.
+    pub fn set_bit(&mut self, bit: usize) {
+        let byte = bit / 8;
+        if self.le_flags.len() <= byte {
+            self.le_flags.resize(byte + 1, 0);
+        }
+        self.le_flags[byte] |= 1 << (bit % 8);
+    }
+
+    /// Clear bit `bit`.
+    pub fn clear_bit(&mut self, bit: usize) {
+        if let Some(byte) = self.le_flags.get_mut(bit / 8) {
+            *byte &= !(1 << (bit % 8));
+        }
+        self.trim();
+    }
+
+    /// Whether the feature whose pair contains `bit` is set, as optional or required.
+    pub fn supports(&self, bit: usize) -> bool {
+        let even = bit & !1;
+        self.has_bit(even) || self.has_bit(even + 1)
+    }
+
+    /// Whether the feature whose pair contains `bit` is required.
+    pub fn requires(&self, bit: usize) -> bool {
+        self.has_bit(bit & !1)
+    }
+
+    /// Advertise the feature whose pair contains `bit` as optional.
+    pub fn set_optional(&mut self, bit: usize) {
+        self.clear_bit(bit & !1);
+        self.set_bit(bit | 1);
+    }
+
+    /// Advertise the feature whose pair contains `bit` as required.
+    pub fn set_required(&mut self, bit: usize) {
+        self.clear_bit(bit | 1);
+        self.set_bit(bit & !1);
+    }
+
+    /// Stop advertising the feature whose pair contains `bit`.
+    pub fn clear(&mut self, bit: usize) {
+        self.clear_bit(bit & !1);
+        self.clear_bit(bit | 1);
+    }
+
+    /// OR in all the bits from `other`.
+    pub fn union(&mut self, other: &Features) {
+        if self.le_flags.len() < other.le_flags.len() {
+            self.le_flags.resize(other.le_flags.len(), 0);
+        }
+        for (ours, theirs) in self.le_flags.iter_mut().zip(&other.le_flags) {
+            *ours |= theirs;
+        }
+    }
+
+    /// Only the bits below 14. BOLT 1 says these also go in `globalfeatures` for the benefit
+    /// of old nodes.
+    pub(crate) fn up_to_13(&self) -> Features {
+        let mut le_flags: Vec<u8> = self.le_flags.iter().take(2).copied().collect();
+        if let Some(byte) = le_flags.get_mut(1) {
+            *byte &= 0b00_11_11_11;
+        }
+        let mut features = Self { le_flags };
+        features.trim();
+        features
+    }
+
+    named_features! {
+        supports_data_loss_protect => DATA_LOSS_PROTECT,
+        supports_initial_routing_sync => INITIAL_ROUTING_SYNC,
+        supports_upfront_shutdown_script => UPFRONT_SHUTDOWN_SCRIPT,
+        supports_gossip_queries => GOSSIP_QUERIES,
+        supports_var_onion_optin => VAR_ONION_OPTIN,
+        supports_gossip_queries_ex => GOSSIP_QUERIES_EX,
+        supports_static_remotekey => STATIC_REMOTEKEY,
+        supports_payment_secret => PAYMENT_SECRET,
+        supports_basic_mpp => BASIC_MPP,
+        supports_wumbo => WUMBO,
+        supports_anchors_zero_fee_htlc_tx => ANCHORS_ZERO_FEE_HTLC_TX,
+        supports_route_blinding => ROUTE_BLINDING,
+        supports_shutdown_anysegwit => SHUTDOWN_ANYSEGWIT,
+        supports_dual_fund => DUAL_FUND,
+        supports_quiesce => QUIESCE,
+        supports_onion_messages => ONION_MESSAGES,
+        supports_provide_storage => PROVIDE_STORAGE,
+        supports_channel_type => CHANNEL_TYPE,
+        supports_scid_alias => SCID_ALIAS,
+        supports_payment_metadata => PAYMENT_METADATA,
+        supports_zero_conf => ZERO_CONF,
+    }
+
+    fn trim(&mut self) {
+        while self.le_flags.last() == Some(&0) {
+            self.le_flags.pop();
+        }
+    }
+}
+
+/// Builds the [`Features`] to advertise, e.g. for
+/// [`InitOptions::features`](crate::InitOptions::features).
+///
+/// ```
+/// use lnsocket::features::{FeaturePreset, FeaturesBuilder, bits};
+///
+/// let features = FeaturesBuilder::from(FeaturePreset::MinimalClient)
+///     .optional(bits::GOSSIP_QUERIES)
+///     .optional(bits::ONION_MESSAGES)
+///     .without(bits::PAYMENT_SECRET)
+///     .build();
+/// assert!(features.supports_onion_messages());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FeaturesBuilder(Features);
+
+impl FeaturesBuilder {
+    /// Advertise the feature whose pair contains `bit` as optional.
+    pub fn optional(mut self, bit: usize) -> Self {
+        self.0.set_optional(bit);
+        self
+    }
+
+    /// Advertise the feature whose pair contains `bit` as required.
+    pub fn required(mut self, bit: usize) -> Self {
+        self.0.set_required(bit);
+        self
+    }
+
+    /// Don't advertise the feature whose pair contains `bit`.
+    pub fn without(mut self, bit: usize) -> Self {
+        self.0.clear(bit);
+        self
+    }
+
+    pub fn build(self) -> Features {
+        self.0
+    }
+}
+
+impl From<FeaturePreset> for FeaturesBuilder {
+    fn from(preset: FeaturePreset) -> Self {
+        Self(preset.into())
+    }
+}
+
+/// Ready-made feature sets for common kinds of lnsocket clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeaturePreset {
+    /// The features any modern node expects from a peer, all optional.
+    MinimalClient,
+    /// [`FeaturePreset::MinimalClient`] plus gossip queries, for collecting gossip.
+    GossipConsumer,
+    /// [`FeaturePreset::MinimalClient`] plus onion messages and route blinding.
+    OnionMessenger,
+    /// [`FeaturePreset::MinimalClient`] plus `gossip_queries`. Commando itself needs no
+    /// feature bits, but once gossip queries are negotiated the peer won't send us any gossip
+    /// until we ask for it, which saves a lot of bandwidth on RPC-only connections.
+    Commando,
+}
+
+impl From<FeaturePreset> for Features {
+    fn from(preset: FeaturePreset) -> Self {
+        let mut features = Features::empty();
+        features.set_optional(bits::DATA_LOSS_PROTECT);
+        features.set_optional(bits::VAR_ONION_OPTIN);
+        features.set_optional(bits::STATIC_REMOTEKEY);
+        features.set_optional(bits::PAYMENT_SECRET);
+
+        match preset {
+            FeaturePreset::MinimalClient => {}
+            FeaturePreset::GossipConsumer => {
+                features.set_optional(bits::GOSSIP_QUERIES);
+                features.set_optional(bits::GOSSIP_QUERIES_EX);
+            }
+            FeaturePreset::OnionMessenger => {
+                features.set_optional(bits::ONION_MESSAGES);
+                features.set_optional(bits::ROUTE_BLINDING);
+            }
+            FeaturePreset::Commando => {
+                features.set_optional(bits::GOSSIP_QUERIES);
+            }
+        }
+
+        features
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bits() {
+        let mut features = Features::empty();
+        assert_eq!(features.to_be_bytes(), Vec::<u8>::new());
+
+        features.set_optional(bits::ONION_MESSAGES);
+        assert!(features.has_bit(39));
+        assert!(!features.requires(bits::ONION_MESSAGES));
+        assert!(features.supports(bits::ONION_MESSAGES));
+        assert_eq!(features.to_be_bytes(), vec![0x80, 0, 0, 0, 0]);
+
+        features.set_required(bits::ONION_MESSAGES);
+        assert!(features.requires(bits::ONION_MESSAGES));
+        assert!(!features.has_bit(39));
+
+        features.clear(bits::ONION_MESSAGES);
+        assert_eq!(features, Features::empty());
+
+        let features = Features::from_be_bytes(vec![0, 0, 0b10, 0b1]);
+        assert!(features.has_bit(0));
+        assert!(features.has_bit(9));
+        assert_eq!(features.to_be_bytes(), vec![0b10, 0b1]);
+    }
+
+    #[test]
+    fn test_builder() {
+        let features = Features::builder()
+            .optional(bits::STATIC_REMOTEKEY)
+            .required(bits::GOSSIP_QUERIES)
+            .optional(bits::ONION_MESSAGES)
+            .without(bits::ONION_MESSAGES)
+            .build();
+        assert!(features.supports_static_remotekey());
+        assert!(features.requires(bits::GOSSIP_QUERIES));
+        assert!(!features.supports_onion_messages());
+
+        let from_preset = FeaturesBuilder::from(FeaturePreset::Commando)
+            .without(bits::GOSSIP_QUERIES)
+            .build();
+        assert_eq!(from_preset, Features::from(FeaturePreset::MinimalClient));
+    }
+
+    #[test]
+    fn test_up_to_13() {
+        let mut features = Features::from(FeaturePreset::OnionMessenger);
+        features.set_optional(bits::GOSSIP_QUERIES);
+        let global = features.up_to_13();
+        assert!(global.supports(bits::GOSSIP_QUERIES));
+        assert!(global.supports(bits::STATIC_REMOTEKEY));
+        assert!(!global.supports(bits::PAYMENT_SECRET));
+        assert!(!global.supports(bits::ONION_MESSAGES));
+    }
+}
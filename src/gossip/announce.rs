@@ -0,0 +1,354 @@
+//! Announcing ourselves with a `node_announcement` and our channels' `channel_update`s.
+//!
+//! Peers only gossip announcements for nodes with public channels, but a peer we're connected
+//! to learns our addresses from one sent directly, which is enough for lightweight endpoints
+//! such as onion-message services. [`NodeAnnouncementBuilder`] fills in the fields, checks the
+//! BOLT 7 rules and signs the result with our node key. [`ChannelUpdateBuilder`] does the same
+//! for a channel's routing policy, for LSP tooling and simulators that set policies without a
+//! full node behind them.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::{LNSocket, SocketAddress};
+//! use lnsocket::features::{Features, bits};
+//! use lnsocket::gossip::announce::NodeAnnouncementBuilder;
+//! # use bitcoin::secp256k1::SecretKey;
+//! # async fn example(mut socket: LNSocket, our_key: SecretKey) -> Result<(), lnsocket::Error> {
+//! let mut features = Features::empty();
+//! features.set_optional(bits::ONION_MESSAGES);
+//! let ann = NodeAnnouncementBuilder::new()
+//!     .alias("onion relay")
+//!     .color([0xff, 0x99, 0x00])
+//!     .address(SocketAddress::TcpIpV4 {
+//!         addr: [203, 0, 113, 7],
+//!         port: 9735,
+//!     })
+//!     .features(features)
+//!     .sign(&our_key)?;
+//! socket.write(&ann).await?;
+//! # Ok(()) }
+//! ```
+
+use crate::features::Features;
+use crate::ln::msgs::{ChannelUpdate, NodeAnnouncement};
+use crate::{Error, SocketAddress};
+use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey, ecdsa::Signature};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as u32)
+}
+
+fn placeholder_signature() -> Signature {
+    Signature::from_compact(&[0; 64]).expect("zero is a valid signature")
+}
+
+/// Builds a signed [`NodeAnnouncement`] for our own node.
+#[derive(Clone, Debug)]
+pub struct NodeAnnouncementBuilder {
+    alias: String,
+    rgb: [u8; 3],
+    addresses: Vec<SocketAddress>,
+    features: Features,
+    timestamp: Option<u32>,
+}
+
+impl Default for NodeAnnouncementBuilder {
+    fn default() -> Self {
+        Self {
+            alias: String::new(),
+            rgb: [0; 3],
+            addresses: Vec::new(),
+            features: Features::empty(),
+            timestamp: None,
+        }
+    }
+}
+
+impl NodeAnnouncementBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The node's name, at most 32 bytes of UTF-8.
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.alias = alias.to_owned();
+        self
+    }
+
+    pub fn color(mut self, rgb: [u8; 3]) -> Self {
+        self.rgb = rgb;
+        self
+    }
+
+    /// Add an address the node can be reached at. At most one may be a hostname.
+    pub fn address(mut self, address: SocketAddress) -> Self {
+        self.addresses.push(address);
+        self
+    }
+
+    pub fn features(mut self, features: Features) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// Peers ignore announcements no newer than the last one they saw from us, so this must
+    /// increase with each one. Defaults to the current time in seconds.
+    pub fn timestamp(mut self, timestamp: u32) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Check the announcement and sign it with `our_key`, which also sets its node id.
+    pub fn sign(self, our_key: &SecretKey) -> Result<NodeAnnouncement, Error> {
+        if self.alias.len() > 32 {
+            return Err(Error::InvalidGossip(format!(
+                "alias is {} bytes, at most 32 fit",
+                self.alias.len()
+            )));
+        }
+        let hostnames = self
+            .addresses
+            .iter()
+            .filter(|addr| matches!(addr, SocketAddress::Hostname { .. }))
+            .count();
+        if hostnames > 1 {
+            return Err(Error::InvalidGossip(format!(
+                "{hostnames} hostnames, at most one is allowed"
+            )));
+        }
+
+        let mut alias = [0; 32];
+        alias[..self.alias.len()].copy_from_slice(self.alias.as_bytes());
+        let timestamp = self.timestamp.unwrap_or_else(now);
+
+        let secp_ctx = Secp256k1::signing_only();
+        let mut ann = NodeAnnouncement {
+            // both replaced by sign
+            signature: placeholder_signature(),
+            node_id: PublicKey::from_secret_key(&secp_ctx, our_key),
+            features: self.features.to_be_bytes(),
+            timestamp,
+            rgb: self.rgb,
+            alias,
+            addresses: self.addresses,
+            excess_address_data: vec![],
+            excess_data: vec![],
+        };
+        ann.sign(&secp_ctx, our_key);
+        Ok(ann)
+    }
+}
+
+/// Builds a signed [`ChannelUpdate`] for our end of a channel.
+#[derive(Clone, Debug)]
+pub struct ChannelUpdateBuilder {
+    short_channel_id: u64,
+    their_node_id: PublicKey,
+    chain_hash: ChainHash,
+    timestamp: Option<u32>,
+    disabled: bool,
+    cltv_expiry_delta: u16,
+    htlc_minimum_msat: u64,
+    htlc_maximum_msat: Option<u64>,
+    fee_base_msat: u32,
+    fee_proportional_millionths: u32,
+}
+
+impl ChannelUpdateBuilder {
+    /// An update for the channel `short_channel_id` we have with `their_node_id`, which
+    /// decides the update's direction. The chain defaults to mainnet, the fees to zero and
+    /// the CLTV delta to 144 blocks.
+    pub fn new(short_channel_id: u64, their_node_id: PublicKey) -> Self {
+        Self {
+            short_channel_id,
+            their_node_id,
+            chain_hash: ChainHash::BITCOIN,
+            timestamp: None,
+            disabled: false,
+            cltv_expiry_delta: 144,
+            htlc_minimum_msat: 0,
+            htlc_maximum_msat: None,
+            fee_base_msat: 0,
+            fee_proportional_millionths: 0,
+        }
+    }
+
+    pub fn chain_hash(mut self, chain_hash: ChainHash) -> Self {
+        self.chain_hash = chain_hash;
+        self
+    }
+
+    /// Peers ignore updates no newer than the last one they saw for our direction, so this
+    /// must increase with each one. Defaults to the current time in seconds.
+    pub fn timestamp(mut self, timestamp: u32) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Tell peers not to route through the channel, e.g. while the peer is offline.
+    pub fn disabled(mut self, disabled: bool) -> Self {
+        self.disabled = disabled;
+        self
+    }
+
+    pub fn cltv_expiry_delta(mut self, delta: u16) -> Self {
+        self.cltv_expiry_delta = delta;
+        self
+    }
+
+    pub fn htlc_minimum_msat(mut self, msat: u64) -> Self {
+        self.htlc_minimum_msat = msat;
+        self
+    }
+
+    /// The largest HTLC we'll forward, at most the channel's capacity. Required.
+    pub fn htlc_maximum_msat(mut self, msat: u64) -> Self {
+        self.htlc_maximum_msat = Some(msat);
+        self
+    }
+
+    pub fn fees(mut self, base_msat: u32, proportional_millionths: u32) -> Self {
+        self.fee_base_msat = base_msat;
+        self.fee_proportional_millionths = proportional_millionths;
+        self
+    }
+
+    /// Check the update and sign it with `our_key`, the node key of our end of the channel.
+    pub fn sign(self, our_key: &SecretKey) -> Result<ChannelUpdate, Error> {
+        let Some(htlc_maximum_msat) = self.htlc_maximum_msat else {
+            return Err(Error::InvalidGossip(
+                "htlc_maximum_msat is not set".to_owned(),
+            ));
+        };
+        if self.htlc_minimum_msat > htlc_maximum_msat {
+            return Err(Error::InvalidGossip(format!(
+                "htlc_minimum_msat {} is above htlc_maximum_msat {}",
+                self.htlc_minimum_msat, htlc_maximum_msat
+            )));
+        }
+
+        let secp_ctx = Secp256k1::signing_only();
+        let our_node_id = PublicKey::from_secret_key(&secp_ctx, our_key);
+        if our_node_id == self.their_node_id {
+            return Err(Error::InvalidGossip(
+                "a channel can't be with ourselves".to_owned(),
+            ));
+        }
+        // direction 0 is the update from the lesser node id, node_id_1
+        let direction = (our_node_id.serialize() > self.their_node_id.serialize()) as u8;
+        let disabled = (self.disabled as u8) << 1;
+
+        let mut update = ChannelUpdate {
+            signature: placeholder_signature(),
+            chain_hash: self.chain_hash,
+            short_channel_id: self.short_channel_id,
+            timestamp: self.timestamp.unwrap_or_else(now),
+            // bit 0 once marked htlc_maximum_msat as present, it's always set now
+            message_flags: 1,
+            channel_flags: direction | disabled,
+            cltv_expiry_delta: self.cltv_expiry_delta,
+            htlc_minimum_msat: self.htlc_minimum_msat,
+            fee_base_msat: self.fee_base_msat,
+            fee_proportional_millionths: self.fee_proportional_millionths,
+            htlc_maximum_msat,
+            excess_data: vec![],
+        };
+        update.sign(&secp_ctx, our_key);
+        Ok(update)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::bits;
+    use crate::ln::wire::{self, Message};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_build_node_announcement() -> Result<(), Error> {
+        let sk = SecretKey::from_slice(&[7; 32]).unwrap();
+        let mut features = Features::empty();
+        features.set_optional(bits::ONION_MESSAGES);
+        let ann = NodeAnnouncementBuilder::new()
+            .alias("relay")
+            .color([1, 2, 3])
+            .address("127.0.0.1:9735".parse().unwrap())
+            .address("example.com:9735".parse().unwrap())
+            .features(features.clone())
+            .timestamp(1_700_000_000)
+            .sign(&sk)?;
+
+        assert!(ann.verify(&Secp256k1::verification_only()));
+        assert_eq!(ann.node_id, sk.public_key(&Secp256k1::signing_only()));
+        assert_eq!(&ann.alias[..6], b"relay\0");
+        assert_eq!(ann.features, features.to_be_bytes());
+        assert_eq!(ann.addresses.len(), 2);
+
+        let mut buf = Vec::new();
+        wire::write(&ann, &mut buf)?;
+        let msg = wire::read(&mut Cursor::new(&buf[..]), |_, _| Ok(None::<()>)).unwrap();
+        assert!(matches!(msg, Message::NodeAnnouncement(read) if read == ann));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_node_announcement() {
+        let sk = SecretKey::from_slice(&[7; 32]).unwrap();
+        let long = NodeAnnouncementBuilder::new()
+            .alias(&"x".repeat(33))
+            .sign(&sk);
+        assert!(matches!(long, Err(Error::InvalidGossip(_))));
+
+        let two_hosts = NodeAnnouncementBuilder::new()
+            .address("a.example.com:9735".parse().unwrap())
+            .address("b.example.com:9735".parse().unwrap())
+            .sign(&sk);
+        assert!(matches!(two_hosts, Err(Error::InvalidGossip(_))));
+    }
+
+    #[test]
+    fn test_build_channel_update() -> Result<(), Error> {
+        let secp_ctx = Secp256k1::new();
+        let a = SecretKey::from_slice(&[7; 32]).unwrap();
+        let b = SecretKey::from_slice(&[8; 32]).unwrap();
+        let (a_id, b_id) = (a.public_key(&secp_ctx), b.public_key(&secp_ctx));
+
+        let build = |ours: &SecretKey, theirs: PublicKey| {
+            ChannelUpdateBuilder::new(800_000 << 40 | 1 << 16, theirs)
+                .timestamp(1_700_000_000)
+                .disabled(true)
+                .fees(1000, 10)
+                .htlc_maximum_msat(1_000_000)
+                .sign(ours)
+        };
+        let from_a = build(&a, b_id)?;
+        let from_b = build(&b, a_id)?;
+        assert!(from_a.verify(&secp_ctx, &a_id));
+        assert!(!from_a.verify(&secp_ctx, &b_id));
+        assert!(from_b.verify(&secp_ctx, &b_id));
+
+        // the two ends have opposite directions, both disabled
+        assert_ne!(from_a.channel_flags & 1, from_b.channel_flags & 1);
+        assert_eq!(from_a.channel_flags & 2, 2);
+        assert_eq!(from_a.fee_base_msat, 1000);
+
+        let mut buf = Vec::new();
+        wire::write(&from_a, &mut buf)?;
+        let msg = wire::read(&mut Cursor::new(&buf[..]), |_, _| Ok(None::<()>)).unwrap();
+        assert!(matches!(msg, Message::ChannelUpdate(read) if read == from_a));
+
+        let no_max = ChannelUpdateBuilder::new(1, b_id).sign(&a);
+        assert!(matches!(no_max, Err(Error::InvalidGossip(_))));
+        let inverted = ChannelUpdateBuilder::new(1, b_id)
+            .htlc_minimum_msat(10)
+            .htlc_maximum_msat(5)
+            .sign(&a);
+        assert!(matches!(inverted, Err(Error::InvalidGossip(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! Dropping gossip we've already seen.
+//!
+//! Every peer relays the same announcements and updates, so an application connected to
+//! several of them gets each one several times. A [`GossipDedup`] remembers the newest of
+//! each and says whether a message adds anything. Share one between sockets with
+//! [`LNSocket::set_gossip_dedup`](crate::LNSocket::set_gossip_dedup), and reads skip the
+//! repeats; or call [`GossipDedup::is_new`] directly, e.g. on records from the
+//! [`store`](super::store).
+//!
+//! Messages are compared by what identifies them, not checked, so a forged update with a high
+//! timestamp can shadow real ones. Verify signatures before acting on gossip.
+
+use crate::ln::msgs::{ChannelAnnouncement, ChannelUpdate, NodeAnnouncement};
+use crate::ln::wire::Message;
+use bitcoin::secp256k1::PublicKey;
+use std::collections::{HashMap, HashSet};
+
+/// The newest gossip seen, by short channel id and node id.
+#[derive(Clone, Debug, Default)]
+pub struct GossipDedup {
+    channels: HashSet<u64>,
+    // (short channel id, direction) -> timestamp
+    updates: HashMap<(u64, u8), u32>,
+    nodes: HashMap<PublicKey, u32>,
+}
+
+impl GossipDedup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `msg` is gossip we haven't seen yet, remembering it if so. Announcements and
+    /// updates no newer than one already seen aren't new. Anything that isn't gossip is.
+    pub fn is_new<T>(&mut self, msg: &Message<T>) -> bool {
+        match msg {
+            Message::ChannelAnnouncement(ann) => self.channel_announcement(ann),
+            Message::ChannelUpdate(update) => self.channel_update(update),
+            Message::NodeAnnouncement(ann) => self.node_announcement(ann),
+            _ => true,
+        }
+    }
+
+    fn channel_announcement(&mut self, ann: &ChannelAnnouncement) -> bool {
+        self.channels.insert(ann.short_channel_id)
+    }
+
+    fn channel_update(&mut self, update: &ChannelUpdate) -> bool {
+        let key = (update.short_channel_id, update.channel_flags & 1);
+        newer(self.updates.entry(key).or_insert(0), update.timestamp)
+    }
+
+    fn node_announcement(&mut self, ann: &NodeAnnouncement) -> bool {
+        newer(self.nodes.entry(ann.node_id).or_insert(0), ann.timestamp)
+    }
+
+    /// How many announcements and updates are remembered.
+    pub fn len(&self) -> usize {
+        self.channels.len() + self.updates.len() + self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget everything, e.g. after a long disconnect where missed gossip should be
+    /// delivered again.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+fn newer(seen: &mut u32, timestamp: u32) -> bool {
+    // 0 is also what unseen entries start at, so a zero timestamp is never new
+    if timestamp <= *seen {
+        return false;
+    }
+    *seen = timestamp;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::msgs;
+    use crate::testing::MockPeer;
+    use crate::{Error, LNSocket};
+    use bitcoin::constants::ChainHash;
+    use bitcoin::secp256k1::{Message as SecpMessage, Secp256k1, SecretKey, rand};
+    use std::sync::{Arc, Mutex};
+
+    fn update(scid: u64, channel_flags: u8, timestamp: u32) -> ChannelUpdate {
+        let sk = SecretKey::from_slice(&[7; 32]).unwrap();
+        ChannelUpdate {
+            signature: Secp256k1::new().sign_ecdsa(&SecpMessage::from_digest([0; 32]), &sk),
+            chain_hash: ChainHash::BITCOIN,
+            short_channel_id: scid,
+            timestamp,
+            message_flags: 1,
+            channel_flags,
+            cltv_expiry_delta: 144,
+            htlc_minimum_msat: 1,
+            fee_base_msat: 1000,
+            fee_proportional_millionths: 10,
+            htlc_maximum_msat: 1_000_000_000,
+            excess_data: vec![],
+        }
+    }
+
+    fn is_new(dedup: &mut GossipDedup, update: ChannelUpdate) -> bool {
+        dedup.is_new(&Message::<()>::ChannelUpdate(update))
+    }
+
+    #[test]
+    fn test_dedup_updates() {
+        let mut dedup = GossipDedup::new();
+        assert!(is_new(&mut dedup, update(1, 0, 100)));
+        assert!(!is_new(&mut dedup, update(1, 0, 100)));
+        // older, from a peer that's behind
+        assert!(!is_new(&mut dedup, update(1, 0, 99)));
+        // each direction has its own updates, the disabled bit doesn't matter
+        assert!(is_new(&mut dedup, update(1, 1, 100)));
+        assert!(!is_new(&mut dedup, update(1, 3, 100)));
+        assert!(is_new(&mut dedup, update(1, 0, 101)));
+        assert!(is_new(&mut dedup, update(2, 0, 100)));
+        assert_eq!(dedup.len(), 3);
+
+        assert!(dedup.is_new(&Message::<()>::Unknown(999)));
+        dedup.clear();
+        assert!(is_new(&mut dedup, update(1, 0, 100)));
+    }
+
+    #[tokio::test]
+    async fn test_dedup_across_sockets() -> Result<(), Error> {
+        let dedup = Arc::new(Mutex::new(GossipDedup::new()));
+        let mut sockets: Vec<(LNSocket, MockPeer)> = Vec::new();
+        for _ in 0..2 {
+            let key = SecretKey::new(&mut rand::thread_rng());
+            let (mut socket, peer) = MockPeer::connect(key).await?;
+            socket.set_gossip_dedup(Some(dedup.clone()));
+            socket.perform_init().await?;
+            sockets.push((socket, peer));
+        }
+
+        for (_, peer) in &sockets {
+            peer.send(&update(1, 0, 100))?;
+            peer.send(&update(2, 0, 100))?;
+            peer.send(&msgs::Ping {
+                ponglen: 0,
+                byteslen: 0,
+            })?;
+        }
+
+        let (first, _) = &mut sockets[0];
+        assert!(
+            matches!(first.read().await?, Message::ChannelUpdate(u) if u.short_channel_id == 1)
+        );
+        assert!(
+            matches!(first.read().await?, Message::ChannelUpdate(u) if u.short_channel_id == 2)
+        );
+        assert!(matches!(first.read().await?, Message::Ping(_)));
+
+        // the second peer relays the same updates, which are skipped
+        let (second, _) = &mut sockets[1];
+        assert!(matches!(second.read().await?, Message::Ping(_)));
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+//! Learning peer addresses from gossip.
+//!
+//! Nodes announce where they can be reached in `node_announcement` messages. An
+//! [`AddressBook`] keeps the latest addresses of the nodes on its watch list, so a dropped
+//! connection can be re-established without asking anyone.
+//!
+//! ### Example
+//! ```no_run
+//! use std::sync::{Arc, Mutex};
+//! use lnsocket::LNSocket;
+//! use lnsocket::gossip::AddressBook;
+//! # use bitcoin::secp256k1::PublicKey;
+//! # async fn example(mut socket: LNSocket, friend: PublicKey) -> Result<(), lnsocket::Error> {
+//! let book = Arc::new(Mutex::new(AddressBook::new()));
+//! book.lock().unwrap().watch(friend);
+//! socket.set_address_book(Some(book.clone()));
+//!
+//! // any node_announcement read from the socket now updates the book
+//! socket.read().await?;
+//! if let Some(addrs) = book.lock().unwrap().addresses_for(&friend) {
+//!     println!("{friend} is at {addrs:?}");
+//! }
+//! # Ok(()) }
+//! ```
+//!
+//! [`store`] reads the gossip a Core Lightning node has saved to disk, and [`dedup`] drops
+//! gossip already seen from another peer. [`announce`] builds our own `node_announcement` and
+//! `channel_update`s, and [`query`] answers peers' gossip queries.
+
+pub mod announce;
+pub mod dedup;
+pub mod query;
+pub mod store;
+
+use crate::SocketAddress;
+use crate::ln::msgs::NodeAnnouncement;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, VerifyOnly};
+use std::collections::{HashMap, HashSet};
+
+/// Addresses of watched nodes, as last announced by them.
+#[derive(Debug)]
+pub struct AddressBook {
+    secp_ctx: Secp256k1<VerifyOnly>,
+    watching: HashSet<PublicKey>,
+    // node id -> (announcement timestamp, addresses)
+    entries: HashMap<PublicKey, (u32, Vec<SocketAddress>)>,
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        Self {
+            secp_ctx: Secp256k1::verification_only(),
+            watching: HashSet::new(),
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start caching addresses for `node_id`.
+    pub fn watch(&mut self, node_id: PublicKey) {
+        self.watching.insert(node_id);
+    }
+
+    /// Stop caching addresses for `node_id`, forgetting what we know.
+    pub fn unwatch(&mut self, node_id: &PublicKey) {
+        self.watching.remove(node_id);
+        self.entries.remove(node_id);
+    }
+
+    /// The nodes on the watch list.
+    pub fn watching(&self) -> impl Iterator<Item = &PublicKey> {
+        self.watching.iter()
+    }
+
+    /// The addresses `node_id` last announced, if we've seen an announcement from it.
+    pub fn addresses_for(&self, node_id: &PublicKey) -> Option<&[SocketAddress]> {
+        self.entries.get(node_id).map(|(_, addrs)| addrs.as_slice())
+    }
+
+    /// Record the addresses from an announcement. Returns whether anything was updated.
+    ///
+    /// Announcements for nodes we don't watch, ones older than what we have, and ones with a
+    /// bad signature are ignored.
+    pub fn handle(&mut self, ann: &NodeAnnouncement) -> bool {
+        if !self.watching.contains(&ann.node_id) {
+            return false;
+        }
+        if let Some((timestamp, _)) = self.entries.get(&ann.node_id)
+            && *timestamp >= ann.timestamp
+        {
+            return false;
+        }
+        if !ann.verify(&self.secp_ctx) {
+            return false;
+        }
+        self.entries
+            .insert(ann.node_id, (ann.timestamp, ann.addresses.clone()));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::wire::{self, Message};
+    use crate::util::ser::Writeable;
+    use bitcoin::hashes::{Hash, sha256d};
+    use bitcoin::secp256k1::{Message as SecpMessage, SecretKey};
+    use std::io::Cursor;
+
+    fn announcement(sk: &SecretKey, timestamp: u32, port: u16) -> NodeAnnouncement {
+        let secp_ctx = Secp256k1::new();
+        let mut ann = NodeAnnouncement {
+            signature: secp_ctx.sign_ecdsa(&SecpMessage::from_digest([0; 32]), sk),
+            features: vec![0x02, 0x00],
+            timestamp,
+            node_id: PublicKey::from_secret_key(&secp_ctx, sk),
+            rgb: [1, 2, 3],
+            alias: [b'a'; 32],
+            addresses: vec![SocketAddress::TcpIpV4 {
+                addr: [127, 0, 0, 1],
+                port,
+            }],
+            // an address type from the future
+            excess_address_data: vec![42, 1, 2, 3],
+            excess_data: vec![9, 9],
+        };
+        // the signed part is everything after the signature
+        let encoded = ann.encode();
+        let hash = sha256d::Hash::hash(&encoded[64..]);
+        ann.signature = secp_ctx.sign_ecdsa(&SecpMessage::from_digest(hash.to_byte_array()), sk);
+        ann
+    }
+
+    #[test]
+    fn test_node_announcement_roundtrip() {
+        let sk = SecretKey::from_slice(&[7; 32]).unwrap();
+        let ann = announcement(&sk, 1, 9735);
+        assert!(ann.verify(&Secp256k1::verification_only()));
+
+        let mut buf = Vec::new();
+        wire::write(&ann, &mut buf).unwrap();
+        let msg = wire::read(&mut Cursor::new(&buf[..]), |_, _| Ok(None::<()>)).unwrap();
+        match msg {
+            Message::NodeAnnouncement(read) => assert_eq!(read, ann),
+            other => panic!("unexpected {other:?}"),
+        }
+
+        let mut tampered = ann.clone();
+        tampered.rgb = [0; 3];
+        assert!(!tampered.verify(&Secp256k1::verification_only()));
+    }
+
+    #[test]
+    fn test_address_book() {
+        let sk = SecretKey::from_slice(&[7; 32]).unwrap();
+        let node_id = PublicKey::from_secret_key(&Secp256k1::new(), &sk);
+        let mut book = AddressBook::new();
+
+        // not watched yet
+        assert!(!book.handle(&announcement(&sk, 2, 9735)));
+        assert_eq!(book.addresses_for(&node_id), None);
+
+        book.watch(node_id);
+        assert!(book.handle(&announcement(&sk, 2, 9735)));
+        // stale and duplicate announcements don't replace newer ones
+        assert!(!book.handle(&announcement(&sk, 1, 1)));
+        assert!(!book.handle(&announcement(&sk, 2, 1)));
+        assert_eq!(
+            book.addresses_for(&node_id),
+            Some(
+                &[SocketAddress::TcpIpV4 {
+                    addr: [127, 0, 0, 1],
+                    port: 9735
+                }][..]
+            )
+        );
+
+        let mut forged = announcement(&sk, 3, 1);
+        forged.addresses.clear();
+        assert!(!book.handle(&forged));
+
+        book.unwatch(&node_id);
+        assert_eq!(book.addresses_for(&node_id), None);
+    }
+}
@@ -0,0 +1,379 @@
+//! Answering peers' gossip queries.
+//!
+//! Light clients sync the channel graph by asking a peer which channels exist in some blocks
+//! (`query_channel_range`) and then for the gossip of the ones they're missing
+//! (`query_short_channel_ids`). A [`GossipResponder`] holds the graph, typically loaded from a
+//! Core Lightning [`store`](super::store), and answers both, so an lnsocket listener can act
+//! as a gossip mirror.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::bitcoin::constants::ChainHash;
+//! use lnsocket::gossip::query::GossipResponder;
+//! use lnsocket::gossip::store::GossipStoreReader;
+//! # async fn serve(mut socket: LNSocket) -> Result<(), lnsocket::Error> {
+//! let store = GossipStoreReader::open("/home/me/.lightning/bitcoin/gossip_store")?;
+//! let responder = GossipResponder::from_store(ChainHash::BITCOIN, store)?;
+//!
+//! // for each peer accepted by the listener
+//! loop {
+//!     let msg = socket.read().await?;
+//!     if !responder.respond(&mut socket, &msg).await? {
+//!         // not a gossip query, handle it some other way
+//!     }
+//! }
+//! # }
+//! ```
+
+use crate::gossip::store::GossipStoreReader;
+use crate::ln::msgs::{
+    ChannelAnnouncement, ChannelUpdate, NodeAnnouncement, QueryChannelRange, QueryShortChannelIds,
+    ReplyChannelRange, ReplyShortChannelIdsEnd,
+};
+use crate::ln::wire::Message;
+use crate::{Error, LNSocket};
+use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::PublicKey;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+
+/// Most short channel ids put in one `reply_channel_range`, keeping it well under the
+/// 65535 byte message limit.
+pub const MAX_IDS_PER_REPLY: usize = 8000;
+
+// short channel ids keep the funding block in their top 3 bytes
+const MAX_BLOCK: u32 = 1 << 24;
+
+fn block_of(short_channel_id: u64) -> u32 {
+    (short_channel_id >> 40) as u32
+}
+
+#[derive(Debug)]
+struct Channel {
+    announcement: ChannelAnnouncement,
+    // by direction
+    updates: [Option<ChannelUpdate>; 2],
+}
+
+/// A channel graph for one chain, answering gossip queries about it.
+#[derive(Debug)]
+pub struct GossipResponder {
+    chain_hash: ChainHash,
+    channels: BTreeMap<u64, Channel>,
+    nodes: HashMap<PublicKey, NodeAnnouncement>,
+}
+
+impl GossipResponder {
+    /// An empty graph for the chain `chain_hash`.
+    pub fn new(chain_hash: ChainHash) -> Self {
+        Self {
+            chain_hash,
+            channels: BTreeMap::new(),
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// The graph in a gossip store, for the chain the store's node is on.
+    pub fn from_store<R: Read>(
+        chain_hash: ChainHash,
+        store: GossipStoreReader<R>,
+    ) -> Result<Self, Error> {
+        let mut responder = Self::new(chain_hash);
+        for record in store {
+            responder.insert(record?.message);
+        }
+        Ok(responder)
+    }
+
+    /// Add gossip to the graph, keeping only the newest update for each channel direction
+    /// and announcement for each node. Updates for channels not announced yet, gossip for
+    /// other chains and anything that isn't gossip are ignored.
+    ///
+    /// Nothing is verified, so only feed it gossip that's been checked, such as a node's
+    /// store.
+    pub fn insert<T>(&mut self, msg: Message<T>) {
+        match msg {
+            Message::ChannelAnnouncement(ann) if ann.chain_hash == self.chain_hash => {
+                self.channels
+                    .entry(ann.short_channel_id)
+                    .or_insert(Channel {
+                        announcement: ann,
+                        updates: [None, None],
+                    });
+            }
+            Message::ChannelUpdate(update) if update.chain_hash == self.chain_hash => {
+                let Some(channel) = self.channels.get_mut(&update.short_channel_id) else {
+                    return;
+                };
+                let slot = &mut channel.updates[(update.channel_flags & 1) as usize];
+                if slot
+                    .as_ref()
+                    .is_none_or(|old| old.timestamp < update.timestamp)
+                {
+                    *slot = Some(update);
+                }
+            }
+            Message::NodeAnnouncement(ann) => {
+                let newer = self
+                    .nodes
+                    .get(&ann.node_id)
+                    .is_none_or(|old| old.timestamp < ann.timestamp);
+                if newer {
+                    self.nodes.insert(ann.node_id, ann);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// How many channels the graph has.
+    pub fn channel_count(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Answer `msg` on `socket` if it's a gossip query, returning whether it was one.
+    pub async fn respond<T: core::fmt::Debug>(
+        &self,
+        socket: &mut LNSocket,
+        msg: &Message<T>,
+    ) -> Result<bool, Error> {
+        match msg {
+            Message::QueryChannelRange(query) => {
+                for reply in self.reply_channel_range(query) {
+                    socket.write(&reply).await?;
+                }
+            }
+            Message::QueryShortChannelIds(query) => {
+                for reply in self.reply_short_channel_ids(query) {
+                    socket.write(&reply).await?;
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// The `reply_channel_range`s answering `query`, in order.
+    ///
+    /// Together they cover the queried blocks without gaps, and all the channels of a block
+    /// are in the same reply.
+    pub fn reply_channel_range(&self, query: &QueryChannelRange) -> Vec<ReplyChannelRange> {
+        let reply = |first_blocknum, end_blocknum: u32, short_channel_ids| ReplyChannelRange {
+            chain_hash: query.chain_hash,
+            first_blocknum,
+            number_of_blocks: end_blocknum - first_blocknum,
+            sync_complete: query.chain_hash == self.chain_hash,
+            short_channel_ids,
+        };
+        let end = query.end_blocknum();
+        if query.chain_hash != self.chain_hash {
+            return vec![reply(query.first_blocknum, end, vec![])];
+        }
+
+        let range =
+            (query.first_blocknum.min(MAX_BLOCK) as u64) << 40..(end.min(MAX_BLOCK) as u64) << 40;
+        let mut replies = Vec::new();
+        let mut first = query.first_blocknum;
+        let mut ids: Vec<u64> = Vec::new();
+        for &scid in self.channels.range(range).map(|(scid, _)| scid) {
+            let block = block_of(scid);
+            let full = ids.len() >= MAX_IDS_PER_REPLY;
+            if full && ids.last().is_some_and(|&last| block_of(last) != block) {
+                replies.push(reply(first, block, std::mem::take(&mut ids)));
+                first = block;
+            }
+            ids.push(scid);
+        }
+        replies.push(reply(first, end, ids));
+        replies
+    }
+
+    /// The gossip answering `query`: each known channel's announcement and updates, then the
+    /// announcements of its nodes not sent yet, ending with a `reply_short_channel_ids_end`.
+    pub fn reply_short_channel_ids(&self, query: &QueryShortChannelIds) -> Vec<Message<()>> {
+        let full_information = query.chain_hash == self.chain_hash;
+        let mut replies = Vec::new();
+        let mut sent_nodes = HashSet::new();
+        for scid in &query.short_channel_ids {
+            let Some(channel) = self.channels.get(scid).filter(|_| full_information) else {
+                continue;
+            };
+            let ann = &channel.announcement;
+            replies.push(Message::ChannelAnnouncement(ann.clone()));
+            for update in channel.updates.iter().flatten() {
+                replies.push(Message::ChannelUpdate(update.clone()));
+            }
+            for node_id in [ann.node_id_1, ann.node_id_2] {
+                if let Some(node) = self.nodes.get(&node_id)
+                    && sent_nodes.insert(node_id)
+                {
+                    replies.push(Message::NodeAnnouncement(node.clone()));
+                }
+            }
+        }
+        replies.push(Message::ReplyShortChannelIdsEnd(ReplyShortChannelIdsEnd {
+            chain_hash: query.chain_hash,
+            full_information,
+        }));
+        replies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gossip::announce::{ChannelUpdateBuilder, NodeAnnouncementBuilder};
+    use crate::ln::wire::Type;
+    use crate::testing::MockPeer;
+    use bitcoin::secp256k1::{Secp256k1, SecretKey, ecdsa::Signature, rand};
+
+    fn scid(block: u64, tx: u64) -> u64 {
+        block << 40 | tx << 16
+    }
+
+    fn keys() -> (SecretKey, SecretKey) {
+        (
+            SecretKey::from_slice(&[7; 32]).unwrap(),
+            SecretKey::from_slice(&[8; 32]).unwrap(),
+        )
+    }
+
+    fn announcement(short_channel_id: u64) -> ChannelAnnouncement {
+        let secp_ctx = Secp256k1::new();
+        let (a, b) = keys();
+        let sig = Signature::from_compact(&[1; 64]).unwrap();
+        ChannelAnnouncement {
+            node_signature_1: sig,
+            node_signature_2: sig,
+            bitcoin_signature_1: sig,
+            bitcoin_signature_2: sig,
+            features: vec![],
+            chain_hash: ChainHash::BITCOIN,
+            short_channel_id,
+            node_id_1: a.public_key(&secp_ctx),
+            node_id_2: b.public_key(&secp_ctx),
+            bitcoin_key_1: a.public_key(&secp_ctx),
+            bitcoin_key_2: b.public_key(&secp_ctx),
+            excess_data: vec![],
+        }
+    }
+
+    fn responder() -> GossipResponder {
+        let (a, b) = keys();
+        let b_id = b.public_key(&Secp256k1::new());
+        let mut responder = GossipResponder::new(ChainHash::BITCOIN);
+        for (block, tx) in [(100, 1), (100, 2), (101, 1), (200, 1)] {
+            responder.insert(Message::<()>::ChannelAnnouncement(announcement(scid(
+                block, tx,
+            ))));
+        }
+        for timestamp in [2, 1] {
+            let update = ChannelUpdateBuilder::new(scid(100, 1), b_id)
+                .timestamp(timestamp)
+                .htlc_maximum_msat(1000)
+                .sign(&a)
+                .unwrap();
+            responder.insert(Message::<()>::ChannelUpdate(update));
+        }
+        let node = NodeAnnouncementBuilder::new().alias("a").sign(&a).unwrap();
+        responder.insert(Message::<()>::NodeAnnouncement(node));
+        responder
+    }
+
+    #[test]
+    fn test_reply_channel_range() {
+        let responder = responder();
+        assert_eq!(responder.channel_count(), 4);
+        let query = QueryChannelRange {
+            chain_hash: ChainHash::BITCOIN,
+            first_blocknum: 100,
+            number_of_blocks: 100,
+        };
+        let replies = responder.reply_channel_range(&query);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].first_blocknum, 100);
+        assert_eq!(replies[0].number_of_blocks, 100);
+        assert!(replies[0].sync_complete);
+        assert_eq!(
+            replies[0].short_channel_ids,
+            vec![scid(100, 1), scid(100, 2), scid(101, 1)]
+        );
+
+        let other_chain = QueryChannelRange {
+            chain_hash: ChainHash::TESTNET3,
+            ..query
+        };
+        let replies = responder.reply_channel_range(&other_chain);
+        assert!(!replies[0].sync_complete);
+        assert!(replies[0].short_channel_ids.is_empty());
+    }
+
+    #[test]
+    fn test_reply_channel_range_splits_by_block() {
+        let mut responder = GossipResponder::new(ChainHash::BITCOIN);
+        // more than a reply's worth in block 10, then one channel in block 11
+        for tx in 0..MAX_IDS_PER_REPLY as u64 + 1 {
+            responder.insert(Message::<()>::ChannelAnnouncement(announcement(scid(
+                10, tx,
+            ))));
+        }
+        responder.insert(Message::<()>::ChannelAnnouncement(announcement(scid(
+            11, 0,
+        ))));
+
+        let replies = responder.reply_channel_range(&QueryChannelRange {
+            chain_hash: ChainHash::BITCOIN,
+            first_blocknum: 0,
+            number_of_blocks: 20,
+        });
+        assert_eq!(replies.len(), 2);
+        // block 10 isn't split, even past the limit
+        assert_eq!(replies[0].short_channel_ids.len(), MAX_IDS_PER_REPLY + 1);
+        assert_eq!(
+            (replies[0].first_blocknum, replies[0].number_of_blocks),
+            (0, 11)
+        );
+        assert_eq!(
+            (replies[1].first_blocknum, replies[1].number_of_blocks),
+            (11, 9)
+        );
+        assert_eq!(replies[1].short_channel_ids, vec![scid(11, 0)]);
+    }
+
+    #[tokio::test]
+    async fn test_respond_short_channel_ids() -> Result<(), Error> {
+        let responder = responder();
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut socket, mut peer) = MockPeer::connect(key).await?;
+        socket.perform_init().await?;
+
+        let query = Message::<()>::QueryShortChannelIds(QueryShortChannelIds {
+            chain_hash: ChainHash::BITCOIN,
+            short_channel_ids: vec![scid(100, 1), scid(999, 1), scid(100, 2)],
+        });
+        assert!(responder.respond(&mut socket, &query).await?);
+        assert!(
+            !responder
+                .respond(&mut socket, &Message::<()>::Unknown(1))
+                .await?
+        );
+
+        let mut types = Vec::new();
+        loop {
+            let msg = peer.recv().await.expect("peer gone");
+            if let Message::ChannelUpdate(update) = &msg {
+                assert_eq!(update.timestamp, 2);
+            }
+            let end =
+                matches!(msg, Message::ReplyShortChannelIdsEnd(ref end) if end.full_information);
+            types.push(msg.type_id());
+            if end {
+                break;
+            }
+        }
+        // the unknown channel is skipped, the node is announced once
+        assert_eq!(types, vec![256, 258, 257, 256, 262]);
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+//! Reading Core Lightning's `gossip_store` file.
+//!
+//! CLN keeps every gossip message it has accepted in `<lightning-dir>/<network>/gossip_store`.
+//! [`GossipStoreReader`] walks that file and yields the messages as the same
+//! [`Message`] values [`LNSocket::read`](crate::LNSocket::read) produces, so a node's existing
+//! store and gossip collected live can go through the same code.
+//!
+//! Deleted records and CLN's own bookkeeping records are skipped. Reading stops at the end of
+//! the file, at a record CLN is still in the middle of writing, or where CLN marked the file
+//! as replaced after compacting it.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::gossip::store::GossipStoreReader;
+//! use lnsocket::ln::wire::Message;
+//! # fn example() -> Result<(), lnsocket::Error> {
+//! let store = GossipStoreReader::open("/home/me/.lightning/bitcoin/gossip_store")?;
+//! for record in store {
+//!     if let Message::NodeAnnouncement(ann) = record?.message {
+//!         println!("{} at {:?}", ann.node_id, ann.addresses);
+//!     }
+//! }
+//! # Ok(()) }
+//! ```
+
+use crate::Error;
+use crate::ln::msgs::DecodeError;
+use crate::ln::wire::{self, Message};
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read};
+use std::path::Path;
+
+const MAJOR_VERSION_MASK: u8 = 0xE0;
+// the oldest version with today's record header
+const MIN_MINOR_VERSION: u8 = 9;
+
+const HEADER_LEN: usize = 12;
+const DELETED_BIT: u16 = 0x8000;
+const DYING_BIT: u16 = 0x0800;
+
+// CLN's own record types: channel amounts, private channels and updates, deletions, etc.
+const STORE_TYPES: std::ops::RangeInclusive<u16> = 4101..=4106;
+const STORE_ENDED: u16 = 4105;
+
+/// A message from the gossip store.
+#[derive(Debug)]
+pub struct StoreRecord {
+    /// When CLN stored the message. For gossip this is the message's own timestamp.
+    pub timestamp: u32,
+    /// The channel was spent and CLN will forget it once the close is deep enough.
+    pub dying: bool,
+    pub message: Message<()>,
+}
+
+/// Reads messages out of a `gossip_store` file. See the [module docs](self).
+pub struct GossipStoreReader<R> {
+    reader: R,
+    version: u8,
+    ended: bool,
+}
+
+impl GossipStoreReader<BufReader<File>> {
+    /// Open the store at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> GossipStoreReader<R> {
+    /// Start reading a store from its first byte. Fails with
+    /// [`DecodeError::UnknownVersion`] for store versions we can't read.
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut version = [0u8; 1];
+        reader.read_exact(&mut version)?;
+        let version = version[0];
+        if version & MAJOR_VERSION_MASK != 0 || version < MIN_MINOR_VERSION {
+            return Err(DecodeError::UnknownVersion.into());
+        }
+        Ok(Self {
+            reader,
+            version,
+            ended: false,
+        })
+    }
+
+    /// The store's version byte.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The next record, `None` at the end of what's been fully written.
+    fn read_record(&mut self) -> Result<Option<(u16, u32, Vec<u8>)>, Error> {
+        let mut header = [0u8; HEADER_LEN];
+        if !read_full(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+        let flags = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[2], header[3]]);
+        // header[4..8] is a CRC, which only matters to CLN
+        let timestamp = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+
+        let mut msg = vec![0u8; len as usize];
+        if !read_full(&mut self.reader, &mut msg)? {
+            return Ok(None);
+        }
+        Ok(Some((flags, timestamp, msg)))
+    }
+}
+
+impl<R: Read> Iterator for GossipStoreReader<R> {
+    type Item = Result<StoreRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.ended {
+            let (flags, timestamp, msg) = match self.read_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            };
+            if flags & DELETED_BIT != 0 || msg.len() < 2 {
+                continue;
+            }
+
+            let typ = u16::from_be_bytes([msg[0], msg[1]]);
+            if typ == STORE_ENDED {
+                // compacted into a new file, anything after this is stale
+                self.ended = true;
+                return None;
+            }
+            if STORE_TYPES.contains(&typ) {
+                continue;
+            }
+
+            let message = wire::read(&mut Cursor::new(&msg[..]), |_, _| Ok(None::<()>))
+                .map_err(|(err, _)| Error::Decode(err));
+            return Some(message.map(|message| StoreRecord {
+                timestamp,
+                dying: flags & DYING_BIT != 0,
+                message,
+            }));
+        }
+        None
+    }
+}
+
+/// Fill `buf`, or return false if the reader ran out first.
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..]) {
+            Ok(0) => return Ok(false),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::msgs::ChannelUpdate;
+    use crate::ln::wire::Type;
+    use crate::util::ser::Writeable;
+    use bitcoin::constants::ChainHash;
+    use bitcoin::secp256k1::{Message as SecpMessage, Secp256k1, SecretKey};
+
+    fn update(timestamp: u32) -> ChannelUpdate {
+        let sk = SecretKey::from_slice(&[7; 32]).unwrap();
+        ChannelUpdate {
+            signature: Secp256k1::new().sign_ecdsa(&SecpMessage::from_digest([0; 32]), &sk),
+            chain_hash: ChainHash::BITCOIN,
+            short_channel_id: 800_000 << 40 | 1 << 16,
+            timestamp,
+            message_flags: 1,
+            channel_flags: 0,
+            cltv_expiry_delta: 144,
+            htlc_minimum_msat: 1,
+            fee_base_msat: 1000,
+            fee_proportional_millionths: 10,
+            htlc_maximum_msat: 1_000_000_000,
+            excess_data: vec![],
+        }
+    }
+
+    fn record(store: &mut Vec<u8>, flags: u16, timestamp: u32, msg: &[u8]) {
+        store.extend(flags.to_be_bytes());
+        store.extend((msg.len() as u16).to_be_bytes());
+        store.extend([0; 4]);
+        store.extend(timestamp.to_be_bytes());
+        store.extend(msg);
+    }
+
+    fn gossip(msg: &ChannelUpdate) -> Vec<u8> {
+        let mut buf = msg.type_id().encode();
+        buf.extend(msg.encode());
+        buf
+    }
+
+    #[test]
+    fn test_read_store() {
+        let mut store = vec![14];
+        // channel amount bookkeeping
+        record(&mut store, 0, 0, &[0x10, 0x05, 0, 0, 0, 0, 0, 0, 0, 1]);
+        record(&mut store, DELETED_BIT, 1, &gossip(&update(1)));
+        record(&mut store, 0, 2, &gossip(&update(2)));
+        record(&mut store, DYING_BIT, 3, &gossip(&update(3)));
+        // still being written
+        record(&mut store, 0, 4, &gossip(&update(4)));
+        store.truncate(store.len() - 10);
+
+        let reader = GossipStoreReader::new(&store[..]).unwrap();
+        assert_eq!(reader.version(), 14);
+        let records: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(!records[0].dying);
+        assert!(records[1].dying);
+        for (record, timestamp) in records.iter().zip([2, 3]) {
+            assert_eq!(record.timestamp, timestamp);
+            match &record.message {
+                Message::ChannelUpdate(read) => assert_eq!(*read, update(timestamp)),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_store_ended() {
+        let mut store = vec![14];
+        record(&mut store, 0, 1, &gossip(&update(1)));
+        record(&mut store, 0, 0, &[0x10, 0x09, 0, 0, 0, 0, 0, 0, 0, 0]);
+        record(&mut store, 0, 2, &gossip(&update(2)));
+        assert_eq!(GossipStoreReader::new(&store[..]).unwrap().count(), 1);
+
+        assert!(matches!(
+            GossipStoreReader::new(&[0x20][..]),
+            Err(Error::Decode(DecodeError::UnknownVersion))
+        ));
+    }
+}
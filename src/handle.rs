@@ -0,0 +1,760 @@
+//! Running a connection in its own task.
+//!
+//! [`LNSocket::run`] hands the socket to a background task that owns all reads and writes,
+//! answers pings, and keeps delivering [`Event`](crate::Event)s to whoever subscribed with
+//! [`LNSocket::subscribe_events`]. The application talks to it through the returned
+//! [`SocketHandle`]: messages are queued with [`SocketHandle::send`] and arrive through
+//! [`SocketHandle::recv`]. Dropping the handle stops the task and closes the connection, and
+//! the task's `JoinHandle` says whether the connection ended cleanly or failed.
+//! [`SocketSender::shutdown`] stops it from any of the senders too, after sending what was
+//! already queued.
+//!
+//! The send queue is bounded so a peer that stops reading can't make it grow forever.
+//! [`RunOptions`] sets its size and what happens when it's full: senders wait, fail with
+//! [`Error::QueueFull`], or the least important queued message is dropped to make room (see
+//! [`Priority`]).
+//!
+//! [`SocketHandle::subscribe`] routes the messages a filter picks to a channel of their own,
+//! so gossip, say, can be consumed by another task than the one calling
+//! [`SocketHandle::recv`].
+//!
+//! With [`RunOptions::keepalive`] set, the task also pings the peer while it's quiet, and
+//! ends with [`Error::PeerUnresponsive`] if a pong doesn't come back in time.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::ln::msgs;
+//! # async fn example(socket: LNSocket) -> Result<(), lnsocket::Error> {
+//! let (mut handle, task) = socket.run();
+//! handle.send(&msgs::Ping { ponglen: 4, byteslen: 8 }).await?;
+//! while let Some(msg) = handle.recv().await {
+//!     println!("{msg:?}");
+//! }
+//! // the connection is gone, find out why
+//! task.await.expect("connection task panicked")?;
+//! # Ok(()) }
+//! ```
+
+use crate::event::Event;
+use crate::ln::msgs::{self, DecodeError};
+use crate::ln::wire::{self, Encode, Message, Type};
+use crate::ping::KeepalivePolicy;
+use crate::util::ser::{Writeable, Writer};
+use crate::{Error, LNSocket};
+use std::collections::VecDeque;
+use std::io::{self, Cursor};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep_until};
+
+/// A message encoded by the sender, type included.
+#[derive(Debug)]
+pub(crate) struct Encoded(Vec<u8>);
+
+impl Encoded {
+    pub(crate) fn new<M: Type + Writeable>(msg: &M) -> Result<Self, Error> {
+        let mut buf = Vec::new();
+        wire::write(msg, &mut buf)?;
+        Ok(Self(buf))
+    }
+}
+
+impl Writeable for Encoded {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        w.write_all(&self.0[2..])
+    }
+}
+
+impl Type for Encoded {
+    fn type_id(&self) -> u16 {
+        u16::from_be_bytes([self.0[0], self.0[1]])
+    }
+}
+
+/// What a full send queue does to new messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Senders wait until there's room.
+    #[default]
+    Block,
+    /// Sending fails with [`Error::QueueFull`].
+    Error,
+    /// The oldest queued message with the lowest [`Priority`] below the new one's is dropped
+    /// to make room. If there is none, the new message is dropped instead. Either way sending
+    /// succeeds.
+    DropLowestPriority,
+}
+
+/// How important a queued message is, for [`Backpressure::DropLowestPriority`].
+///
+/// Priorities only decide what gets dropped. Messages are always sent in the order they were
+/// queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Can be lost without harm. Gossip gets this by default.
+    Low,
+    /// Everything else by default.
+    Normal,
+    /// Should only be dropped for other high priority messages.
+    High,
+}
+
+impl Priority {
+    /// The priority [`SocketSender::send`] uses for messages of type `type_id`.
+    pub fn for_type(type_id: u16) -> Self {
+        // gossip queries, replies and announcement_signatures aren't meant to be lost
+        match type_id {
+            msgs::ChannelAnnouncement::TYPE
+            | msgs::NodeAnnouncement::TYPE
+            | msgs::ChannelUpdate::TYPE => Priority::Low,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+/// Settings for [`LNSocket::run_with`].
+#[derive(Clone, Debug)]
+pub struct RunOptions {
+    /// How many messages may wait to be sent. 1024 by default.
+    pub queue_size: usize,
+    /// What happens when the queue is full, [`Backpressure::Block`] by default.
+    pub backpressure: Backpressure,
+    /// Ping the peer to notice when it's gone, see [`KeepalivePolicy`]. `None` by default.
+    pub keepalive: Option<KeepalivePolicy>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            queue_size: 1024,
+            backpressure: Backpressure::default(),
+            keepalive: None,
+        }
+    }
+}
+
+struct QueueState {
+    items: VecDeque<(Priority, Encoded)>,
+    closed: bool,
+}
+
+/// The send queue shared by the senders and the connection task.
+struct Queue {
+    state: Mutex<QueueState>,
+    capacity: usize,
+    backpressure: Backpressure,
+    // wakes the connection task when something is queued
+    queued: Notify,
+    // wakes blocked senders when there's room or the task stopped
+    space: Notify,
+}
+
+impl Queue {
+    fn new(opts: &RunOptions) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                items: VecDeque::new(),
+                closed: false,
+            }),
+            capacity: opts.queue_size.max(1),
+            backpressure: opts.backpressure,
+            queued: Notify::new(),
+            space: Notify::new(),
+        }
+    }
+
+    async fn push(&self, priority: Priority, msg: Encoded) -> Result<(), Error> {
+        loop {
+            // registered before looking, so room made in between isn't missed
+            let space = self.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return Err(Error::NotConnected);
+                }
+                if state.items.len() >= self.capacity {
+                    match self.backpressure {
+                        Backpressure::Block => {}
+                        Backpressure::Error => return Err(Error::QueueFull),
+                        Backpressure::DropLowestPriority => {
+                            let victim = state
+                                .items
+                                .iter()
+                                .enumerate()
+                                .filter(|(_, (queued, _))| *queued < priority)
+                                .min_by_key(|(i, (queued, _))| (*queued, *i))
+                                .map(|(i, _)| i);
+                            let Some(i) = victim else {
+                                // nothing is less important than this one
+                                return Ok(());
+                            };
+                            state.items.remove(i);
+                        }
+                    }
+                }
+                if state.items.len() < self.capacity {
+                    state.items.push_back((priority, msg));
+                    self.queued.notify_one();
+                    return Ok(());
+                }
+            }
+            space.await;
+        }
+    }
+
+    /// The next message to send, or `None` once the queue is closed and empty. Cancellation
+    /// safe.
+    async fn pop(&self) -> Option<Encoded> {
+        loop {
+            let queued = self.queued.notified();
+            tokio::pin!(queued);
+            queued.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some((_, msg)) = state.items.pop_front() {
+                    self.space.notify_one();
+                    return Some(msg);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            queued.await;
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.space.notify_waiters();
+        self.queued.notify_one();
+    }
+}
+
+/// Queues messages for a running connection. Cheap to clone, for sending from several
+/// tasks.
+#[derive(Clone)]
+pub struct SocketSender {
+    queue: Arc<Queue>,
+}
+
+impl std::fmt::Debug for SocketSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SocketSender").finish_non_exhaustive()
+    }
+}
+
+impl SocketSender {
+    /// Queue `msg` for sending, at its type's default [`Priority`].
+    ///
+    /// Fails with [`Error::NotConnected`] once the connection task has stopped. What happens
+    /// when the queue is full depends on [`RunOptions::backpressure`].
+    pub async fn send<M: Type + Writeable>(&self, msg: &M) -> Result<(), Error> {
+        self.send_with_priority(msg, Priority::for_type(msg.type_id()))
+            .await
+    }
+
+    /// Like [`SocketSender::send`], with an explicit priority.
+    pub async fn send_with_priority<M: Type + Writeable>(
+        &self,
+        msg: &M,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        let msg = Encoded::new(msg)?;
+        self.queue.push(priority, msg).await
+    }
+
+    /// Stop the connection task once it has sent what's queued. Sending fails with
+    /// [`Error::NotConnected`] from now on, and the task ends with `Ok(())` unless the
+    /// connection fails first.
+    pub fn shutdown(&self) {
+        self.queue.close();
+    }
+}
+
+type Filter<T> = Box<dyn Fn(&Message<T>) -> bool + Send>;
+type Subscriber<T> = (Filter<T>, mpsc::UnboundedSender<Message<T>>);
+
+/// Where messages picked by [`SocketHandle::subscribe`] filters go.
+struct Subscriptions<T>(Mutex<Vec<Subscriber<T>>>);
+
+impl<T> std::fmt::Debug for Subscriptions<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let count = self.0.lock().unwrap().len();
+        f.debug_tuple("Subscriptions").field(&count).finish()
+    }
+}
+
+impl<T> Subscriptions<T> {
+    /// Pass `msg` to the first subscription that wants it, or `incoming` if none does.
+    fn deliver(&self, msg: Message<T>, incoming: &mpsc::UnboundedSender<Message<T>>) {
+        let mut subs = self.0.lock().unwrap();
+        subs.retain(|(_, tx)| !tx.is_closed());
+        let tx = match subs.iter().find(|(filter, _)| filter(&msg)) {
+            Some((_, tx)) => tx,
+            None => incoming,
+        };
+        let _ = tx.send(msg);
+    }
+}
+
+/// The application's end of a connection started with [`LNSocket::run`].
+#[derive(Debug)]
+pub struct SocketHandle<T = ()> {
+    sender: SocketSender,
+    incoming: mpsc::UnboundedReceiver<Message<T>>,
+    subscriptions: Arc<Subscriptions<T>>,
+}
+
+impl<T> SocketHandle<T> {
+    /// Queue `msg` for sending, see [`SocketSender::send`].
+    pub async fn send<M: Type + Writeable>(&self, msg: &M) -> Result<(), Error> {
+        self.sender.send(msg).await
+    }
+
+    /// A sender for queueing messages from elsewhere. Senders don't keep the connection
+    /// alive, it still stops when this handle is dropped.
+    pub fn sender(&self) -> SocketSender {
+        self.sender.clone()
+    }
+
+    /// The next message from the peer, other than pings and those taken by a subscription.
+    /// `None` once the connection is gone.
+    pub async fn recv(&mut self) -> Option<Message<T>> {
+        self.incoming.recv().await
+    }
+
+    /// Receive the messages `filter` picks on a channel of their own, instead of through
+    /// [`SocketHandle::recv`].
+    ///
+    /// When several filters pick a message, the earliest subscribed gets it. Dropping the
+    /// receiver ends the subscription. Pings are answered by the connection task and never
+    /// delivered.
+    ///
+    /// ```no_run
+    /// # use lnsocket::{LNSocket, ln::wire::Message};
+    /// # fn example(socket: LNSocket) {
+    /// let (mut handle, _task) = socket.run();
+    /// let mut gossip = handle.subscribe(|msg| {
+    ///     matches!(
+    ///         msg,
+    ///         Message::ChannelAnnouncement(_)
+    ///             | Message::NodeAnnouncement(_)
+    ///             | Message::ChannelUpdate(_)
+    ///     )
+    /// });
+    /// tokio::spawn(async move {
+    ///     while let Some(msg) = gossip.recv().await {
+    ///         println!("gossip: {msg:?}");
+    ///     }
+    /// });
+    /// # }
+    /// ```
+    pub fn subscribe(
+        &self,
+        filter: impl Fn(&Message<T>) -> bool + Send + 'static,
+    ) -> mpsc::UnboundedReceiver<Message<T>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut subs = self.subscriptions.0.lock().unwrap();
+        subs.push((Box::new(filter), tx));
+        rx
+    }
+}
+
+impl LNSocket {
+    /// Move the connection into a background task, see the [module docs](crate::handle).
+    ///
+    /// `init` must already have been exchanged. Must be called within a tokio runtime.
+    pub fn run(self) -> (SocketHandle, JoinHandle<Result<(), Error>>) {
+        self.run_with(&RunOptions::default())
+    }
+
+    /// Like [`LNSocket::run`], with control over the send queue.
+    pub fn run_with(self, opts: &RunOptions) -> (SocketHandle, JoinHandle<Result<(), Error>>) {
+        self.run_custom_with(opts, |_type, _buf| Ok(None))
+    }
+
+    /// Like [`LNSocket::run`], decoding custom messages with `reader` as in
+    /// [`LNSocket::read_custom`].
+    pub fn run_custom<T, F>(self, reader: F) -> (SocketHandle<T>, JoinHandle<Result<(), Error>>)
+    where
+        T: core::fmt::Debug + Send + 'static,
+        F: FnMut(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError> + Send + 'static,
+    {
+        self.run_custom_with(&RunOptions::default(), reader)
+    }
+
+    /// [`LNSocket::run_custom`] and [`LNSocket::run_with`] combined.
+    pub fn run_custom_with<T, F>(
+        self,
+        opts: &RunOptions,
+        reader: F,
+    ) -> (SocketHandle<T>, JoinHandle<Result<(), Error>>)
+    where
+        T: core::fmt::Debug + Send + 'static,
+        F: FnMut(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError> + Send + 'static,
+    {
+        self.run_routed(opts, reader, Some)
+    }
+
+    /// Like [`LNSocket::run_custom_with`], passing every message except pings through `route`
+    /// first. Messages it returns `None` for were consumed and don't reach the handle.
+    pub(crate) fn run_routed<R, T, F, G>(
+        self,
+        opts: &RunOptions,
+        reader: F,
+        route: G,
+    ) -> (SocketHandle<T>, JoinHandle<Result<(), Error>>)
+    where
+        R: core::fmt::Debug + Send + 'static,
+        T: core::fmt::Debug + Send + 'static,
+        F: FnMut(u16, &mut Cursor<&[u8]>) -> Result<Option<R>, DecodeError> + Send + 'static,
+        G: FnMut(Message<R>) -> Option<Message<T>> + Send + 'static,
+    {
+        let queue = Arc::new(Queue::new(opts));
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let keepalive = opts.keepalive.clone().map(KeepaliveTimer::new);
+        let subscriptions = Arc::new(Subscriptions(Mutex::new(Vec::new())));
+        let task = tokio::spawn({
+            let queue = queue.clone();
+            let subscriptions = subscriptions.clone();
+            async move {
+                let incoming = Incoming {
+                    tx: incoming_tx,
+                    subscriptions,
+                };
+                let res = drive(self, &queue, incoming, keepalive, reader, route).await;
+                queue.close();
+                res
+            }
+        });
+        let handle = SocketHandle {
+            sender: SocketSender { queue },
+            incoming,
+            subscriptions,
+        };
+        (handle, task)
+    }
+}
+
+/// When the next keepalive ping is due, or the pong to the last one.
+struct KeepaliveTimer {
+    policy: KeepalivePolicy,
+    deadline: Instant,
+    awaiting_pong: bool,
+}
+
+impl KeepaliveTimer {
+    fn new(policy: KeepalivePolicy) -> Self {
+        Self {
+            deadline: Instant::now() + policy.interval,
+            policy,
+            awaiting_pong: false,
+        }
+    }
+
+    fn ping_sent(&mut self) {
+        self.deadline = Instant::now() + self.policy.timeout;
+        self.awaiting_pong = true;
+    }
+
+    fn pong_received(&mut self) {
+        self.deadline = Instant::now() + self.policy.interval;
+        self.awaiting_pong = false;
+    }
+}
+
+/// Where the connection task sends what it reads.
+struct Incoming<T> {
+    tx: mpsc::UnboundedSender<Message<T>>,
+    subscriptions: Arc<Subscriptions<T>>,
+}
+
+async fn drive<R, T, F, G>(
+    mut socket: LNSocket,
+    outgoing: &Queue,
+    incoming: Incoming<T>,
+    mut keepalive: Option<KeepaliveTimer>,
+    mut reader: F,
+    mut route: G,
+) -> Result<(), Error>
+where
+    R: core::fmt::Debug,
+    F: FnMut(u16, &mut Cursor<&[u8]>) -> Result<Option<R>, DecodeError>,
+    G: FnMut(Message<R>) -> Option<Message<T>>,
+{
+    loop {
+        let deadline = keepalive.as_ref().map(|timer| timer.deadline);
+        // reads are cancellation safe, so losing the race to a write costs nothing
+        tokio::select! {
+            // the handle is gone, nobody is listening anymore
+            _ = incoming.tx.closed() => return Ok(()),
+            msg = outgoing.pop() => match msg {
+                Some(msg) => socket.write(&msg).await?,
+                None => return Ok(()),
+            },
+            msg = socket.read_custom(|typ, buf| reader(typ, buf)) => match msg? {
+                Message::Ping(ping) => {
+                    if let Some(pong) = socket.pong_for(&ping)? {
+                        socket.write(&pong).await?;
+                    }
+                }
+                msg => {
+                    if let (Message::Pong(_), Some(timer)) = (&msg, &mut keepalive) {
+                        timer.pong_received();
+                    }
+                    if let Some(msg) = route(msg) {
+                        incoming.subscriptions.deliver(msg, &incoming.tx);
+                    }
+                }
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let timer = keepalive.as_mut().expect("deadline comes from the timer");
+                if timer.awaiting_pong {
+                    socket.emit(Event::PeerUnresponsive);
+                    return Err(socket.lost(Error::PeerUnresponsive));
+                }
+                let ping = msgs::Ping {
+                    ponglen: 0,
+                    byteslen: 0,
+                };
+                socket.write(&ping).await?;
+                timer.ping_sent();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::DisconnectReason;
+    use crate::lnsocket::tests::socket_pair;
+    use crate::testing::MockPeer;
+    use bitcoin::secp256k1::{SecretKey, rand};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_run() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut socket, mut peer) = MockPeer::connect(key).await?;
+        socket.perform_init().await?;
+        let (mut handle, task) = socket.run();
+
+        let storage = msgs::PeerStorage { data: vec![1, 2] };
+        handle.sender().send(&storage).await?;
+        assert!(matches!(peer.recv().await, Some(Message::PeerStorage(s)) if s == storage));
+
+        // pings are answered by the task and not passed on
+        peer.send(&msgs::Ping {
+            ponglen: 3,
+            byteslen: 0,
+        })?;
+        assert!(matches!(peer.recv().await, Some(Message::Pong(p)) if p.byteslen == 3));
+        let retrieval = msgs::PeerStorageRetrieval { data: vec![3] };
+        peer.send(&retrieval)?;
+        assert!(
+            matches!(handle.recv().await, Some(Message::PeerStorageRetrieval(r)) if r == retrieval)
+        );
+
+        let sender = handle.sender();
+        drop(handle);
+        task.await.unwrap()?;
+        assert!(matches!(
+            sender.send(&storage).await,
+            Err(Error::NotConnected)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut socket, mut peer) = MockPeer::connect(key).await?;
+        socket.perform_init().await?;
+        let (handle, task) = socket.run();
+
+        let sender = handle.sender();
+        let storage = msgs::PeerStorage { data: vec![1] };
+        sender.send(&storage).await?;
+        sender.shutdown();
+        task.await.unwrap()?;
+        // what was queued still went out
+        assert!(matches!(peer.recv().await, Some(Message::PeerStorage(s)) if s == storage));
+        assert!(matches!(
+            handle.send(&storage).await,
+            Err(Error::NotConnected)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keepalive() -> Result<(), Error> {
+        let opts = RunOptions {
+            keepalive: Some(KeepalivePolicy {
+                interval: Duration::from_millis(20),
+                timeout: Duration::from_millis(50),
+            }),
+            ..Default::default()
+        };
+
+        // a peer that answers keeps the connection up
+        let (socket, mut peer) = socket_pair().await?;
+        let (_handle, task) = socket.run_with(&opts);
+        for _ in 0..3 {
+            let Message::Ping(ping) = peer.read().await? else {
+                panic!("expected a keepalive ping");
+            };
+            let pong = peer.pong_for(&ping)?.expect("pong");
+            peer.write(&pong).await?;
+        }
+        assert!(!task.is_finished());
+
+        // one that stays silent is given up on
+        let (mut socket, mut peer) = socket_pair().await?;
+        let mut events = socket.subscribe_events();
+        let (_handle, task) = socket.run_with(&opts);
+        assert!(matches!(peer.read().await?, Message::Ping(_)));
+        assert!(matches!(task.await.unwrap(), Err(Error::PeerUnresponsive)));
+        let mut seen = Vec::new();
+        while let Some(event) = events.recv().await {
+            seen.push(event);
+        }
+        let disconnected = Event::Disconnected {
+            reason: DisconnectReason::Unresponsive,
+        };
+        assert_eq!(seen.first(), Some(&Event::PingSent));
+        assert!(seen.ends_with(&[Event::PeerUnresponsive, disconnected]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut socket, peer) = MockPeer::connect(key).await?;
+        socket.perform_init().await?;
+        let (mut handle, _task) = socket.run();
+
+        let mut storage = handle
+            .subscribe(|msg| matches!(msg, Message::PeerStorageRetrieval(r) if r.data[0] < 10));
+        let mut later = handle.subscribe(|msg| matches!(msg, Message::PeerStorageRetrieval(_)));
+        for byte in [1, 20] {
+            peer.send(&msgs::PeerStorageRetrieval { data: vec![byte] })?;
+        }
+        peer.send(&msgs::PeerStorage { data: vec![3] })?;
+
+        // the first filter that matches wins, and what no filter takes goes to recv
+        assert!(
+            matches!(storage.recv().await, Some(Message::PeerStorageRetrieval(r)) if r.data == [1])
+        );
+        assert!(
+            matches!(later.recv().await, Some(Message::PeerStorageRetrieval(r)) if r.data == [20])
+        );
+        assert!(matches!(handle.recv().await, Some(Message::PeerStorage(_))));
+
+        // a dropped receiver stops taking messages
+        drop(storage);
+        peer.send(&msgs::PeerStorageRetrieval { data: vec![2] })?;
+        assert!(
+            matches!(later.recv().await, Some(Message::PeerStorageRetrieval(r)) if r.data == [2])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_connection_lost() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut socket, peer) = MockPeer::connect(key).await?;
+        socket.perform_init().await?;
+        let (mut handle, task) = socket.run();
+
+        drop(peer);
+        assert!(handle.recv().await.is_none());
+        assert!(task.await.unwrap().is_err());
+        Ok(())
+    }
+
+    fn queue(queue_size: usize, backpressure: Backpressure) -> Queue {
+        Queue::new(&RunOptions {
+            queue_size,
+            backpressure,
+            ..Default::default()
+        })
+    }
+
+    fn ping(ponglen: u16) -> Encoded {
+        Encoded::new(&msgs::Ping {
+            ponglen,
+            byteslen: 0,
+        })
+        .unwrap()
+    }
+
+    async fn drain(queue: &Queue) -> Vec<u16> {
+        let mut ponglens = vec![];
+        while !queue.state.lock().unwrap().items.is_empty() {
+            let msg = queue.pop().await.unwrap();
+            ponglens.push(u16::from_be_bytes([msg.0[2], msg.0[3]]));
+        }
+        ponglens
+    }
+
+    #[test]
+    fn test_priority_for_type() {
+        for type_id in [256, 257, 258] {
+            assert_eq!(Priority::for_type(type_id), Priority::Low);
+        }
+        // announcement_signatures and query_channel_range
+        for type_id in [259, 263] {
+            assert_eq!(Priority::for_type(type_id), Priority::Normal);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() -> Result<(), Error> {
+        let full = queue(1, Backpressure::Error);
+        full.push(Priority::Normal, ping(1)).await?;
+        assert!(matches!(
+            full.push(Priority::Normal, ping(2)).await,
+            Err(Error::QueueFull)
+        ));
+
+        let dropping = queue(3, Backpressure::DropLowestPriority);
+        dropping.push(Priority::Normal, ping(1)).await?;
+        dropping.push(Priority::Low, ping(2)).await?;
+        dropping.push(Priority::Low, ping(3)).await?;
+        // the oldest low priority message makes room
+        dropping.push(Priority::Normal, ping(4)).await?;
+        // nothing is less important than another low one, so it's the one dropped
+        dropping.push(Priority::Low, ping(5)).await?;
+        assert_eq!(drain(&dropping).await, vec![1, 3, 4]);
+
+        let blocking = Arc::new(queue(1, Backpressure::Block));
+        blocking.push(Priority::Normal, ping(1)).await?;
+        let waiting = tokio::spawn({
+            let blocking = blocking.clone();
+            async move { blocking.push(Priority::Normal, ping(2)).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        assert_eq!(drain(&blocking).await, vec![1]);
+        waiting.await.unwrap()?;
+        assert_eq!(drain(&blocking).await, vec![2]);
+
+        // and gives up once the connection is gone
+        blocking.push(Priority::Normal, ping(3)).await?;
+        let waiting = tokio::spawn({
+            let blocking = blocking.clone();
+            async move { blocking.push(Priority::Normal, ping(4)).await }
+        });
+        tokio::task::yield_now().await;
+        blocking.close();
+        assert!(matches!(waiting.await.unwrap(), Err(Error::NotConnected)));
+        Ok(())
+    }
+}
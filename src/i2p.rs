@@ -0,0 +1,250 @@
+//! Connecting through I2P.
+//!
+//! I2P is reached through the SAM v3 bridge of a local router (i2pd or Java I2P), normally
+//! listening on [`DEFAULT_SAM_BRIDGE`]. Each connection gets its own transient destination, so
+//! nodes can't link our connections to each other. The bridge looks up the `.b32.i2p`
+//! destination itself; the port is meaningless to I2P and ignored.
+//!
+//! [`LNSocket::connect`] sends `.b32.i2p` addresses to the default bridge on its own, use
+//! [`LNSocket::connect_via_i2p`] for a bridge elsewhere.
+
+use crate::socket_addr::split_host_port;
+use crate::{Error, LNSocket};
+use bitcoin::secp256k1::rand::{RngCore, thread_rng};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Where I2P routers listen for SAM connections by default.
+pub const DEFAULT_SAM_BRIDGE: &str = "127.0.0.1:7656";
+
+const HELLO: &[u8] = b"HELLO VERSION MIN=3.1 MAX=3.3\n";
+// SAM lines are short, anything longer is not a SAM bridge
+const MAX_LINE: usize = 4096;
+
+/// Whether `addr` is an I2P destination, `<base32>.b32.i2p[:port]`.
+pub fn is_i2p_address(addr: &str) -> bool {
+    split_host_port(addr).is_ok_and(|(host, _)| host.to_ascii_lowercase().ends_with(".b32.i2p"))
+}
+
+/// A stream to an I2P destination.
+///
+/// The SAM session lives as long as the bridge connection that created it, so that's kept
+/// open next to the stream.
+#[derive(Debug)]
+pub struct I2pStream {
+    stream: TcpStream,
+    _session: TcpStream,
+}
+
+impl AsyncRead for I2pStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for I2pStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Read one reply line. A byte at a time, so nothing after it is taken from the stream.
+async fn read_line(stream: &mut TcpStream) -> Result<String, Error> {
+    let mut line = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            b'\n' => break,
+            _ if line.len() == MAX_LINE => {
+                return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+            }
+            b => line.push(b),
+        }
+    }
+    String::from_utf8(line).map_err(|_| io::Error::from(io::ErrorKind::InvalidData).into())
+}
+
+/// The value of `key` in a SAM reply, quotes removed.
+fn reply_value<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!(" {key}="))? + key.len() + 2;
+    let rest = &line[start..];
+    match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next(),
+        None => rest.split(' ').next(),
+    }
+}
+
+/// Send `request` and check the reply starts with `expect` and has `RESULT=OK`.
+async fn command(stream: &mut TcpStream, request: &[u8], expect: &str) -> Result<(), Error> {
+    stream.write_all(request).await?;
+    let reply = read_line(stream).await?;
+    if !reply.starts_with(expect) {
+        return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+    }
+    match reply_value(&reply, "RESULT") {
+        Some("OK") => Ok(()),
+        result => {
+            let mut why = result.unwrap_or("no result").to_owned();
+            if let Some(message) = reply_value(&reply, "MESSAGE") {
+                why = format!("{why}: {message}");
+            }
+            Err(Error::I2p(why))
+        }
+    }
+}
+
+/// Open a stream to the I2P destination `addr` through the SAM bridge at `sam`.
+pub(crate) async fn sam_connect(sam: &str, addr: &str) -> Result<I2pStream, Error> {
+    let (host, _) = split_host_port(addr)?;
+    if !is_i2p_address(addr) {
+        return Err(Error::InvalidUri(addr.to_owned()));
+    }
+    let id = format!("lnsocket-{:016x}", thread_rng().next_u64());
+
+    let mut session = TcpStream::connect(sam).await?;
+    command(&mut session, HELLO, "HELLO REPLY").await?;
+    let create = format!(
+        "SESSION CREATE STYLE=STREAM ID={id} DESTINATION=TRANSIENT SIGNATURE_TYPE=7 \
+         i2cp.leaseSetEncType=4,0\n"
+    );
+    command(&mut session, create.as_bytes(), "SESSION STATUS").await?;
+
+    let mut stream = TcpStream::connect(sam).await?;
+    command(&mut stream, HELLO, "HELLO REPLY").await?;
+    let connect = format!("STREAM CONNECT ID={id} DESTINATION={host} SILENT=false\n");
+    command(&mut stream, connect.as_bytes(), "STREAM STATUS").await?;
+
+    Ok(I2pStream {
+        stream,
+        _session: session,
+    })
+}
+
+impl LNSocket {
+    /// Like [`LNSocket::connect`], to a `.b32.i2p` address through the SAM bridge at `sam`.
+    pub async fn connect_via_i2p(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+        sam: &str,
+    ) -> Result<LNSocket, Error> {
+        let start = Instant::now();
+        let stream = sam_connect(sam, addr).await?;
+        let tcp_connect = start.elapsed();
+        let mut socket = Self::handshake_outbound(stream, our_key, their_pubkey).await?;
+        socket.timings.tcp_connect = Some(tcp_connect);
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{Secp256k1, rand};
+    use tokio::net::TcpListener;
+
+    const DEST: &str = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p";
+
+    async fn expect(stream: &mut TcpStream, prefix: &str) -> String {
+        let line = read_line(stream).await.unwrap();
+        assert!(line.starts_with(prefix), "{line}");
+        line
+    }
+
+    /// A SAM bridge that opens a session and connects one stream to `DEST`, played by a node
+    /// with `node_key`, or refuses it with `CANT_REACH_PEER` without one.
+    async fn fake_sam(listener: TcpListener, node_key: Option<SecretKey>) {
+        let (mut session, _) = listener.accept().await.unwrap();
+        expect(&mut session, "HELLO VERSION").await;
+        session
+            .write_all(b"HELLO REPLY RESULT=OK VERSION=3.3\n")
+            .await
+            .unwrap();
+        let create = expect(&mut session, "SESSION CREATE STYLE=STREAM").await;
+        let id = reply_value(&create, "ID").unwrap().to_owned();
+        session
+            .write_all(b"SESSION STATUS RESULT=OK DESTINATION=AAAA\n")
+            .await
+            .unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        expect(&mut stream, "HELLO VERSION").await;
+        stream
+            .write_all(b"HELLO REPLY RESULT=OK VERSION=3.3\n")
+            .await
+            .unwrap();
+        let connect = expect(&mut stream, "STREAM CONNECT").await;
+        assert_eq!(reply_value(&connect, "ID"), Some(id.as_str()));
+        assert_eq!(reply_value(&connect, "DESTINATION"), Some(DEST));
+        let Some(node_key) = node_key else {
+            stream
+                .write_all(b"STREAM STATUS RESULT=CANT_REACH_PEER MESSAGE=\"no lease set\"\n")
+                .await
+                .unwrap();
+            return;
+        };
+        stream
+            .write_all(b"STREAM STATUS RESULT=OK\n")
+            .await
+            .unwrap();
+        LNSocket::handshake_inbound(stream, node_key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_via_sam() -> Result<(), Error> {
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let sam = listener.local_addr()?.to_string();
+        let bridge = tokio::spawn(fake_sam(listener, Some(node_key)));
+
+        let our_key = SecretKey::new(&mut rand::thread_rng());
+        let socket = LNSocket::connect_via_i2p(our_key, node_id, DEST, &sam).await?;
+        assert_eq!(socket.their_pubkey(), node_id);
+        bridge.await.unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_destination() -> Result<(), Error> {
+        let node_id =
+            SecretKey::new(&mut rand::thread_rng()).public_key(&Secp256k1::signing_only());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let sam = listener.local_addr()?.to_string();
+        let bridge = tokio::spawn(fake_sam(listener, None));
+
+        let our_key = SecretKey::new(&mut rand::thread_rng());
+        let res = LNSocket::connect_via_i2p(our_key, node_id, &format!("{DEST}:9735"), &sam).await;
+        assert!(matches!(res, Err(Error::I2p(why)) if why == "CANT_REACH_PEER: no lease set"));
+        bridge.await.unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_i2p_address() {
+        assert!(is_i2p_address(DEST));
+        assert!(is_i2p_address(&format!("{DEST}:9735")));
+        assert!(!is_i2p_address("example.i2p"));
+        assert!(!is_i2p_address("ln.example.com:9735"));
+    }
+}
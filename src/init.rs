@@ -0,0 +1,263 @@
+//! The BOLT 1 `init` exchange: what the peer told us about itself.
+
+use crate::error::Error;
+use crate::features::{Features, InitFeatures, bits};
+use crate::ln::msgs;
+use crate::prelude::*;
+use crate::socket_addr::SocketAddress;
+use alloc::sync::Arc;
+use bitcoin::Network;
+use bitcoin::constants::ChainHash;
+use core::fmt;
+#[cfg(feature = "std")]
+use core::net::SocketAddr;
+
+/// A callback for [`InitOptions::on_peer_init`]. Gets what the peer advertised and the
+/// features we're about to advertise, and returns why the peer is refused, if it is.
+pub type PeerInitHook = Arc<dyn Fn(&PeerInfo, &mut Features) -> Result<(), String> + Send + Sync>;
+
+/// Knobs for the `init` message we send, used with
+/// [`LNSocket::perform_init_with`](crate::LNSocket::perform_init_with).
+#[derive(Clone)]
+pub struct InitOptions {
+    /// The chains we advertise in `networks`, mainnet by default. See
+    /// [`InitOptions::for_network`] for another one.
+    ///
+    /// Several can be listed, e.g. signet and regtest for a test rig. If the peer lists
+    /// networks and none of them are in here, init fails with
+    /// [`Error::NetworkMismatch`](crate::Error::NetworkMismatch).
+    pub networks: Vec<ChainHash>,
+    /// The feature bits we advertise. Empty by default, see
+    /// [`FeaturePreset`](crate::features::FeaturePreset) for common choices and
+    /// [`FeaturesBuilder`](crate::features::FeaturesBuilder) to adjust them.
+    pub features: Features,
+    /// Tell the peer which address we reached it at, via `remote_network_address`.
+    ///
+    /// Peers can use this to learn their public address (e.g. behind NAT). Only applies to
+    /// direct IP connections, proxied connections don't know the real address.
+    pub echo_remote_address: bool,
+    /// Send this as `remote_network_address`, instead of the address
+    /// [`InitOptions::echo_remote_address`] would echo. `None` by default.
+    ///
+    /// BOLT 1 means it to be the address we see the peer at, but some peers feed it into
+    /// address discovery, so it can be set for connections where we know better, e.g. over a
+    /// proxy.
+    pub remote_network_address: Option<SocketAddress>,
+    /// Extra TLV records appended to our `init`, as `(type, value)` pairs.
+    ///
+    /// Types must be odd, so peers that don't understand them can ignore them, and must not
+    /// collide with the types BOLT 1 defines (1 and 3) or each other. Order doesn't matter,
+    /// they are sorted before sending.
+    pub custom_tlvs: Vec<(u64, Vec<u8>)>,
+    /// How many non-`init` messages to tolerate from the peer before its `init`. 0 (the
+    /// default) is strict BOLT 1 and fails with
+    /// [`Error::FirstMessageNotInit`](crate::Error::FirstMessageNotInit).
+    ///
+    /// Some implementations send a stray ping or gossip around `init`. Tolerated messages are
+    /// kept and returned by [`LNSocket::read`](crate::LNSocket::read) once init completes, so
+    /// nothing is lost.
+    pub max_pre_init_messages: usize,
+    /// Ask the peer not to send us any gossip. Off by default.
+    ///
+    /// Peers that support `gossip_queries` will otherwise stream gossip at us right after
+    /// init, which clients that only make calls (like commando) just throw away. When set we
+    /// advertise `gossip_queries` too and, if the peer does, send a `gossip_timestamp_filter`
+    /// starting in the far future for each network. Peers without it can't be told.
+    pub suppress_gossip: bool,
+    /// Called once the peer's `init` arrives, before ours is sent.
+    ///
+    /// The hook can record the peer's capabilities, change the features we advertise in
+    /// reply, or refuse the peer by returning a reason, which fails init with
+    /// [`Error::InitRejected`] without sending ours. `EmbeddedSocket` sends its `init`
+    /// first, so there feature changes come too late and are ignored.
+    pub on_peer_init: Option<PeerInitHook>,
+}
+
+impl fmt::Debug for InitOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InitOptions")
+            .field("networks", &self.networks)
+            .field("features", &self.features)
+            .field("echo_remote_address", &self.echo_remote_address)
+            .field("remote_network_address", &self.remote_network_address)
+            .field("custom_tlvs", &self.custom_tlvs)
+            .field("max_pre_init_messages", &self.max_pre_init_messages)
+            .field("suppress_gossip", &self.suppress_gossip)
+            .field("on_peer_init", &self.on_peer_init.is_some())
+            .finish()
+    }
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        Self {
+            networks: vec![ChainHash::BITCOIN],
+            features: Features::empty(),
+            echo_remote_address: false,
+            remote_network_address: None,
+            custom_tlvs: vec![],
+            max_pre_init_messages: 0,
+            suppress_gossip: false,
+            on_peer_init: None,
+        }
+    }
+}
+
+impl InitOptions {
+    /// The defaults, but on `network` instead of mainnet.
+    pub fn for_network(network: Network) -> Self {
+        Self {
+            networks: vec![ChainHash::using_genesis_block(network)],
+            ..Default::default()
+        }
+    }
+
+    /// The features to advertise, including any the other options imply.
+    pub(crate) fn advertised_features(&self) -> Features {
+        let mut features = self.features.clone();
+        if self.suppress_gossip {
+            features.set_optional(bits::GOSSIP_QUERIES);
+        }
+        features
+    }
+
+    /// The `remote_network_address` to send to a peer at `peer_addr`, if any.
+    #[cfg(feature = "std")]
+    pub(crate) fn remote_address_for(
+        &self,
+        peer_addr: Option<SocketAddr>,
+    ) -> Option<SocketAddress> {
+        match (&self.remote_network_address, peer_addr) {
+            (Some(addr), _) => Some(addr.clone()),
+            (None, Some(addr)) if self.echo_remote_address => Some(SocketAddress::from(addr)),
+            _ => None,
+        }
+    }
+
+    /// Run [`InitOptions::on_peer_init`], if set, on the peer's `init` and our `features`.
+    #[cfg(any(feature = "std", feature = "embedded-io"))]
+    pub(crate) fn check_peer(&self, peer: &PeerInfo, features: &mut Features) -> Result<(), Error> {
+        match &self.on_peer_init {
+            Some(hook) => hook(peer, features).map_err(Error::InitRejected),
+            None => Ok(()),
+        }
+    }
+
+    /// [`InitOptions::custom_tlvs`] in wire order, or [`Error::InvalidCustomTlv`] for the
+    /// first offending type.
+    pub(crate) fn sorted_custom_tlvs(&self) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        let mut tlvs = self.custom_tlvs.clone();
+        tlvs.sort_by_key(|(typ, _)| *typ);
+        let mut prev = None;
+        for (typ, _) in &tlvs {
+            if typ % 2 == 0 || *typ <= 3 || prev == Some(*typ) {
+                return Err(Error::InvalidCustomTlv(*typ));
+            }
+            prev = Some(*typ);
+        }
+        Ok(tlvs)
+    }
+}
+
+/// What a peer advertised in its `init` message.
+///
+/// Available from [`LNSocket::peer_info`](crate::LNSocket::peer_info) once the peer's `init`
+/// has been received.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    init: msgs::Init,
+    features: Features,
+}
+
+impl PeerInfo {
+    pub(crate) fn new(init: msgs::Init) -> Self {
+        // BOLT 1: globalfeatures is a legacy field, treat its bits as part of features
+        let mut features = Features::from_be_bytes(init.features.clone());
+        features.union(&Features::from_be_bytes(init.global_features.clone()));
+        Self { init, features }
+    }
+
+    /// The peer's feature bits, with the legacy `globalfeatures` folded in.
+    pub fn features(&self) -> &InitFeatures {
+        &self.features
+    }
+
+    /// The chains the peer is interested in, if it said.
+    pub fn networks(&self) -> Option<&[ChainHash]> {
+        self.init.networks.as_deref()
+    }
+
+    /// The address the peer says it sees us connecting from.
+    ///
+    /// Useful for discovering our external address. The peer can put anything here, so don't
+    /// trust it blindly.
+    pub fn remote_network_address(&self) -> Option<&SocketAddress> {
+        self.init.remote_network_address.as_ref()
+    }
+
+    /// Odd-typed TLV records in the peer's `init` that BOLT 1 doesn't define.
+    pub fn custom_tlvs(&self) -> &[(u64, Vec<u8>)] {
+        &self.init.custom_tlvs
+    }
+
+    /// The `init` message exactly as the peer sent it.
+    pub fn init(&self) -> &msgs::Init {
+        &self.init
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_network() {
+        let opts = InitOptions::for_network(Network::Signet);
+        assert_eq!(opts.networks, [ChainHash::SIGNET]);
+        let opts = InitOptions::for_network(Network::Regtest);
+        assert_eq!(opts.networks, [ChainHash::REGTEST]);
+    }
+
+    #[test]
+    fn test_global_features_are_merged() {
+        let info = PeerInfo::new(msgs::Init {
+            // bit 1
+            global_features: vec![0b10],
+            // bits 9 and 39
+            features: vec![0x80, 0, 0, 0b10, 0],
+            networks: None,
+            remote_network_address: None,
+            custom_tlvs: vec![],
+        });
+
+        assert_eq!(info.features().to_be_bytes(), vec![0x80, 0, 0, 0b10, 0b10]);
+        assert!(info.features().supports(bits::DATA_LOSS_PROTECT));
+        assert!(info.features().supports(bits::VAR_ONION_OPTIN));
+        assert!(info.features().supports(bits::ONION_MESSAGES));
+        assert!(!info.features().supports(bits::GOSSIP_QUERIES));
+        assert!(info.features().supports_onion_messages());
+        assert!(!info.features().supports_gossip_queries());
+    }
+
+    #[test]
+    fn test_custom_tlv_validation() {
+        let opts = |custom_tlvs: Vec<(u64, Vec<u8>)>| InitOptions {
+            custom_tlvs,
+            ..Default::default()
+        };
+
+        let tlvs = opts(vec![(65539, vec![1]), (5, vec![])]).sorted_custom_tlvs();
+        assert_eq!(tlvs.unwrap(), vec![(5, vec![]), (65539, vec![1])]);
+
+        for bad in [4, 3, 1] {
+            assert!(matches!(
+                opts(vec![(bad, vec![])]).sorted_custom_tlvs(),
+                Err(Error::InvalidCustomTlv(typ)) if typ == bad
+            ));
+        }
+        assert!(matches!(
+            opts(vec![(7, vec![]), (7, vec![1])]).sorted_custom_tlvs(),
+            Err(Error::InvalidCustomTlv(7))
+        ));
+    }
+}
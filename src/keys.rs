@@ -0,0 +1,310 @@
+//! Loading and saving node identities.
+//!
+//! [`load_key`] and [`save_key`] keep an lnsocket identity in a file, as 32 raw bytes or hex,
+//! and [`load_key_from_env`] reads one from an environment variable. Key files must not be
+//! readable by anyone but their owner (on Unix, loading fails with
+//! [`KeyError::InsecurePermissions`] otherwise), and the buffers that held the key are wiped
+//! afterwards.
+//!
+//! Tooling that runs next to a Core Lightning node sometimes needs to speak as that node,
+//! for example to reconnect to its peers for diagnostics. [`load_cln_node_key`] derives the
+//! node's secret key from its `hsm_secret` the same way `lightningd` does, so the peer sees
+//! the node's own id.
+//!
+//! Anyone holding the node key can impersonate the node, so only do this when that is the
+//! point, and never hand the key to anything else.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::keys::{KeyFormat, load_key, save_key};
+//! # use bitcoin::secp256k1::{SecretKey, rand};
+//! # fn example() -> Result<(), lnsocket::Error> {
+//! let path = "/home/me/.config/myapp/lnsocket.key";
+//! let key = match load_key(path) {
+//!     Ok(key) => key,
+//!     Err(_) => {
+//!         let key = SecretKey::new(&mut rand::thread_rng());
+//!         save_key(path, &key, KeyFormat::Hex)?;
+//!         key
+//!     }
+//! };
+//! # Ok(()) }
+//! ```
+//!
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::keys::load_cln_node_key;
+//! # use bitcoin::secp256k1::PublicKey;
+//! # async fn example(peer: PublicKey) -> Result<(), lnsocket::Error> {
+//! let key = load_cln_node_key("/home/me/.lightning/bitcoin/hsm_secret")?;
+//! let socket = LNSocket::connect_and_init(key, peer, "peer.example.com:9735").await?;
+//! # Ok(()) }
+//! ```
+
+use crate::Error;
+use bitcoin::hashes::{Hash, HashEngine, Hmac, HmacEngine, sha256};
+use bitcoin::secp256k1::SecretKey;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+const HSM_SECRET_LEN: usize = 32;
+// salt, nonce, ciphertext and tag of a secret encrypted with `lightningd --encrypted-hsm`
+const ENCRYPTED_HSM_SECRET_LEN: usize = 73;
+
+/// Why a key couldn't be loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyError {
+    /// The `hsm_secret` is encrypted with a passphrase, decrypt it with `lightning-hsmtool`
+    /// first.
+    Encrypted,
+    /// The file isn't a key or secret we know. Contains its length.
+    BadLength(usize),
+    /// The key isn't valid hex, or isn't a valid secp256k1 secret key.
+    Invalid,
+    /// The key file can be read by others. Contains its Unix mode.
+    InsecurePermissions(u32),
+    /// The environment variable isn't set. Contains its name.
+    NotSet(String),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Encrypted => write!(f, "hsm_secret is encrypted"),
+            KeyError::BadLength(len) => write!(f, "unexpected key file length {}", len),
+            KeyError::Invalid => write!(f, "not a valid secret key"),
+            KeyError::InsecurePermissions(mode) => {
+                write!(f, "key file is accessible by others (mode {:o})", mode)
+            }
+            KeyError::NotSet(var) => write!(f, "{} is not set", var),
+        }
+    }
+}
+
+/// How [`save_key`] writes a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// The 32 key bytes as they are.
+    Raw,
+    /// 64 lowercase hex characters and a newline.
+    Hex,
+}
+
+fn parse_key(bytes: &[u8]) -> Result<SecretKey, KeyError> {
+    if bytes.len() == 32 {
+        return SecretKey::from_slice(bytes).map_err(|_| KeyError::Invalid);
+    }
+    let text = bytes.trim_ascii();
+    if text.len() != 64 {
+        return Err(KeyError::BadLength(bytes.len()));
+    }
+    let mut raw = Zeroizing::new([0u8; 32]);
+    hex::decode_to_slice(text, &mut raw[..]).map_err(|_| KeyError::Invalid)?;
+    SecretKey::from_slice(&raw[..]).map_err(|_| KeyError::Invalid)
+}
+
+#[cfg(unix)]
+fn check_permissions(file: &std::fs::File) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = file.metadata()?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(KeyError::InsecurePermissions(mode).into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_file: &std::fs::File) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Load a secret key from a file holding 32 raw bytes or 64 hex characters.
+///
+/// On Unix the file must not be accessible by group or others.
+pub fn load_key(path: impl AsRef<Path>) -> Result<SecretKey, Error> {
+    let mut file = std::fs::File::open(path)?;
+    check_permissions(&file)?;
+    let mut bytes = Zeroizing::new(Vec::new());
+    std::io::Read::read_to_end(&mut file, &mut bytes)?;
+    Ok(parse_key(&bytes)?)
+}
+
+/// Write `key` to a new file at `path`, readable only by its owner.
+///
+/// Never overwrites: fails with [`io::ErrorKind::AlreadyExists`](std::io::ErrorKind) if the
+/// file is there, so an identity can't be replaced by accident.
+pub fn save_key(path: impl AsRef<Path>, key: &SecretKey, format: KeyFormat) -> Result<(), Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+
+    let bytes = Zeroizing::new(key.secret_bytes());
+    match format {
+        KeyFormat::Raw => file.write_all(&bytes[..])?,
+        KeyFormat::Hex => {
+            let mut text = Zeroizing::new(hex::encode(&bytes[..]));
+            text.push('\n');
+            file.write_all(text.as_bytes())?
+        }
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Load a hex secret key from the environment variable `var`.
+pub fn load_key_from_env(var: &str) -> Result<SecretKey, Error> {
+    let value = Zeroizing::new(std::env::var(var).map_err(|_| KeyError::NotSet(var.to_owned()))?);
+    // hex only, a 32 character value must not be taken as raw bytes
+    if value.trim().len() != 64 {
+        return Err(KeyError::Invalid.into());
+    }
+    Ok(parse_key(value.as_bytes())?)
+}
+
+/// HKDF-SHA256 (RFC 5869), producing a single 32 byte block.
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut extract = HmacEngine::<sha256::Hash>::new(salt);
+    extract.input(ikm);
+    let prk = Hmac::<sha256::Hash>::from_engine(extract);
+
+    let mut expand = HmacEngine::<sha256::Hash>::new(prk.as_byte_array());
+    expand.input(info);
+    expand.input(&[1]);
+    Hmac::<sha256::Hash>::from_engine(expand).to_byte_array()
+}
+
+/// Derive a Core Lightning node's secret key from its raw 32 byte `hsm_secret`.
+pub fn cln_node_key(hsm_secret: &[u8; 32]) -> SecretKey {
+    // lightningd bumps a u32 salt until the output is a valid key, hashing the salt's
+    // little-endian bytes
+    let mut salt: u32 = 0;
+    loop {
+        let key = Zeroizing::new(hkdf_sha256(&salt.to_le_bytes(), hsm_secret, b"nodeid"));
+        if let Ok(key) = SecretKey::from_slice(&key[..]) {
+            return key;
+        }
+        salt += 1;
+    }
+}
+
+/// Read the `hsm_secret` file at `path` and derive the node's secret key from it.
+///
+/// Fails with [`KeyError::Encrypted`] for secrets protected with a passphrase.
+pub fn load_cln_node_key(path: impl AsRef<Path>) -> Result<SecretKey, Error> {
+    let secret = Zeroizing::new(std::fs::read(path)?);
+    match secret.len() {
+        HSM_SECRET_LEN => Ok(cln_node_key(secret[..].try_into().expect("checked length"))),
+        ENCRYPTED_HSM_SECRET_LEN => Err(KeyError::Encrypted.into()),
+        len => Err(KeyError::BadLength(len).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hex::{DisplayHex, FromHex};
+
+    #[test]
+    fn test_hkdf_rfc5869() {
+        // test case 1, the first 32 bytes of OKM
+        let ikm = [0x0b; 22];
+        let salt = Vec::<u8>::from_hex("000102030405060708090a0b0c").unwrap();
+        let info = Vec::<u8>::from_hex("f0f1f2f3f4f5f6f7f8f9").unwrap();
+        assert_eq!(
+            hkdf_sha256(&salt, &ikm, &info).to_lower_hex_string(),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf"
+        );
+    }
+
+    #[test]
+    fn test_save_and_load_key() {
+        let dir = std::env::temp_dir().join(format!("lnsocket-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = SecretKey::from_slice(&[3u8; 32]).unwrap();
+
+        for (name, format) in [("raw", KeyFormat::Raw), ("hex", KeyFormat::Hex)] {
+            let path = dir.join(name);
+            save_key(&path, &key, format).unwrap();
+            assert_eq!(load_key(&path).unwrap(), key);
+            // never clobbers an existing identity
+            assert!(save_key(&path, &key, format).is_err());
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = dir.join("hex");
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(matches!(
+                load_key(&path),
+                Err(Error::Key(KeyError::InsecurePermissions(0o644)))
+            ));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let hex = [3u8; 32].to_lower_hex_string();
+        assert_eq!(parse_key(hex.as_bytes()), Ok(key));
+        assert_eq!(parse_key(format!("  {hex}\n").as_bytes()), Ok(key));
+        assert_eq!(parse_key(&[3u8; 32]), Ok(key));
+        assert_eq!(parse_key(&[0u8; 32]), Err(KeyError::Invalid));
+        assert_eq!(parse_key(&[b'z'; 64]), Err(KeyError::Invalid));
+        assert_eq!(parse_key(&[3u8; 33]), Err(KeyError::BadLength(33)));
+    }
+
+    #[test]
+    fn test_cln_node_key() {
+        // the hsm_secrets pyln-testing gives its first nodes, and their node ids
+        let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+        for (name, node_id) in [
+            (
+                &b"lightning-1"[..],
+                "0266e4598d1d3c415f572a8488830b60f7e744ed9235eb0b1ba93283b315c03518",
+            ),
+            (
+                &b"lightning-2"[..],
+                "022d223620a359a47ff7f7ac447c85c46c923da53389221a0054c11c1e3ca31d59",
+            ),
+        ] {
+            let mut hsm_secret = [0u8; 32];
+            hsm_secret[..name.len()].copy_from_slice(name);
+            let key = cln_node_key(&hsm_secret);
+            assert_eq!(key.public_key(&secp).to_string(), node_id);
+        }
+    }
+
+    #[test]
+    fn test_load_cln_node_key() {
+        let dir = std::env::temp_dir().join(format!("lnsocket-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hsm_secret");
+
+        std::fs::write(&path, [1u8; 32]).unwrap();
+        let key = load_cln_node_key(&path).unwrap();
+        assert_eq!(key, cln_node_key(&[1u8; 32]));
+        assert_ne!(key, cln_node_key(&[2u8; 32]));
+
+        std::fs::write(&path, [1u8; 73]).unwrap();
+        assert!(matches!(
+            load_cln_node_key(&path),
+            Err(Error::Key(KeyError::Encrypted))
+        ));
+        std::fs::write(&path, [1u8; 31]).unwrap();
+        assert!(matches!(
+            load_cln_node_key(&path),
+            Err(Error::Key(KeyError::BadLength(31)))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,289 @@
+//! Using lnsocket's connections as the socket layer for rust-lightning.
+//!
+//! LDK's `PeerManager` does its own Noise handshake and message handling, and only needs a
+//! byte pipe to the peer plus a `SocketDescriptor` to write to it. [`RawConnection`] is that
+//! pump for any stream lnsocket can open, including Tor ([`open_stream`]) and other
+//! transports, so LDK nodes get the same reach.
+//!
+//! lnsocket doesn't depend on the `lightning` crate, so the trait impl lives in the
+//! application and just forwards:
+//!
+//! ```ignore
+//! #[derive(Clone, PartialEq, Eq, Hash)]
+//! struct Descriptor(lnsocket::ldk::RawDescriptor);
+//!
+//! impl lightning::ln::peer_handler::SocketDescriptor for Descriptor {
+//!     fn send_data(&mut self, data: &[u8], resume_read: bool) -> usize {
+//!         self.0.send_data(data, resume_read)
+//!     }
+//!     fn disconnect_socket(&mut self) {
+//!         self.0.disconnect_socket()
+//!     }
+//! }
+//! ```
+//!
+//! and drives the events:
+//!
+//! ```ignore
+//! let stream = lnsocket::ldk::open_stream("ln.example.com:9735", None).await?;
+//! let (desc, mut events) = RawConnection::spawn(stream);
+//! let mut desc = Descriptor(desc);
+//! let act_one = peer_manager.new_outbound_connection(their_node_id, desc.clone(), None)?;
+//! desc.send_data(&act_one, true);
+//! while let Some(event) = events.recv().await {
+//!     match event {
+//!         RawEvent::Data(data) => {
+//!             if peer_manager.read_event(&mut desc, &data).is_err() {
+//!                 desc.disconnect_socket();
+//!             }
+//!         }
+//!         RawEvent::WriteSpaceAvailable => {
+//!             let _ = peer_manager.write_buffer_space_avail(&mut desc);
+//!         }
+//!         RawEvent::Disconnected => peer_manager.socket_disconnected(&desc),
+//!     }
+//!     peer_manager.process_events();
+//! }
+//! ```
+//!
+//! ### Messages
+//!
+//! With the `ldk-compat` feature, `lightning::ln::msgs` messages convert to and from this
+//! crate's, see `ldk_compat`.
+
+use crate::Error;
+use crate::socket_addr::split_host_port;
+use crate::tor::{Isolation, socks5_connect};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// How many bytes [`RawDescriptor::send_data`] accepts before pushing back.
+pub const WRITE_BUFFER: usize = 64 * 1024;
+
+const READ_CHUNK: usize = 8 * 1024;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Open a plain TCP stream to `addr`, through the SOCKS5 proxy `proxy` if given (for Tor). Each
+/// proxied stream gets a Tor circuit of its own.
+///
+/// Unlike [`LNSocket::connect`](crate::LNSocket::connect) no handshake is done, the stream is
+/// meant for a stack that does its own.
+pub async fn open_stream(addr: &str, proxy: Option<&str>) -> Result<TcpStream, Error> {
+    match proxy {
+        Some(proxy) => {
+            let credentials = Isolation::PerConnection.credentials();
+            socks5_connect(proxy, addr, credentials).await
+        }
+        None => Ok(TcpStream::connect(split_host_port(addr)?).await?),
+    }
+}
+
+/// What happened on a [`RawConnection`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RawEvent {
+    /// Bytes from the peer, for `PeerManager::read_event`.
+    Data(Vec<u8>),
+    /// An earlier [`RawDescriptor::send_data`] didn't take everything, and now there's room.
+    WriteSpaceAvailable,
+    /// The connection is gone, whichever side closed it.
+    Disconnected,
+}
+
+enum Command {
+    Write(Vec<u8>),
+    PauseRead(bool),
+    Disconnect,
+}
+
+struct Shared {
+    queued: AtomicUsize,
+    // send_data turned something away, so WriteSpaceAvailable is owed
+    blocked: AtomicBool,
+    closed: AtomicBool,
+    read_paused: AtomicBool,
+}
+
+/// The write side of a [`RawConnection`], shaped like LDK's `SocketDescriptor`.
+///
+/// Clones refer to the same connection and compare equal.
+#[derive(Clone)]
+pub struct RawDescriptor {
+    id: u64,
+    commands: mpsc::UnboundedSender<Command>,
+    shared: Arc<Shared>,
+}
+
+impl PartialEq for RawDescriptor {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for RawDescriptor {}
+
+impl Hash for RawDescriptor {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl std::fmt::Debug for RawDescriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawDescriptor")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl RawDescriptor {
+    /// Queue as much of `data` as fits in [`WRITE_BUFFER`], returning how much was taken.
+    ///
+    /// `resume_read: false` pauses delivering [`RawEvent::Data`] until a later call passes
+    /// `true`, which is how LDK applies backpressure on reads.
+    pub fn send_data(&mut self, data: &[u8], resume_read: bool) -> usize {
+        if self.shared.closed.load(Ordering::Acquire) {
+            return 0;
+        }
+        let pause = !resume_read;
+        if self.shared.read_paused.swap(pause, Ordering::AcqRel) != pause {
+            let _ = self.commands.send(Command::PauseRead(pause));
+        }
+
+        // ask for WriteSpaceAvailable before looking at the buffer, so a pump that drains it
+        // in between still sends one. SeqCst pairs with the pump's, neither may be reordered.
+        self.shared.blocked.store(true, Ordering::SeqCst);
+        let queued = self.shared.queued.load(Ordering::SeqCst);
+        let n = data.len().min(WRITE_BUFFER.saturating_sub(queued));
+        if n == data.len() {
+            self.shared.blocked.store(false, Ordering::SeqCst);
+        }
+        if n > 0 {
+            self.shared.queued.fetch_add(n, Ordering::AcqRel);
+            if self
+                .commands
+                .send(Command::Write(data[..n].to_vec()))
+                .is_err()
+            {
+                return 0;
+            }
+        }
+        n
+    }
+
+    /// Close the connection. No [`RawEvent::Disconnected`] is sent for this, as LDK expects.
+    pub fn disconnect_socket(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        let _ = self.commands.send(Command::Disconnect);
+    }
+}
+
+/// Pumps bytes between a stream and a [`RawDescriptor`] in a background task.
+pub struct RawConnection;
+
+impl RawConnection {
+    /// Start pumping `stream`. Returns the descriptor for writing and the events to feed the
+    /// peer manager.
+    pub fn spawn<S>(stream: S) -> (RawDescriptor, mpsc::UnboundedReceiver<RawEvent>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            queued: AtomicUsize::new(0),
+            blocked: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            read_paused: AtomicBool::new(false),
+        });
+        tokio::spawn(pump(stream, command_rx, events_tx, shared.clone()));
+
+        let desc = RawDescriptor {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            commands,
+            shared,
+        };
+        (desc, events)
+    }
+}
+
+async fn pump<S>(
+    mut stream: S,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    events: mpsc::UnboundedSender<RawEvent>,
+    shared: Arc<Shared>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; READ_CHUNK];
+    let mut paused = false;
+    let remote_closed = loop {
+        tokio::select! {
+            cmd = commands.recv() => match cmd {
+                Some(Command::Write(data)) => {
+                    if stream.write_all(&data).await.is_err() {
+                        break true;
+                    }
+                    shared.queued.fetch_sub(data.len(), Ordering::SeqCst);
+                    if shared.blocked.swap(false, Ordering::SeqCst) {
+                        let _ = events.send(RawEvent::WriteSpaceAvailable);
+                    }
+                }
+                Some(Command::PauseRead(pause)) => paused = pause,
+                // every descriptor is gone, nobody can write to or close us anymore
+                Some(Command::Disconnect) | None => break false,
+            },
+            n = stream.read(&mut buf), if !paused => match n {
+                Ok(0) | Err(_) => break true,
+                Ok(n) => {
+                    if events.send(RawEvent::Data(buf[..n].to_vec())).is_err() {
+                        break false;
+                    }
+                }
+            },
+        }
+    };
+
+    shared.closed.store(true, Ordering::Release);
+    let _ = stream.shutdown().await;
+    if remote_closed {
+        let _ = events.send(RawEvent::Disconnected);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pump() -> Result<(), Error> {
+        let (ours, mut theirs) = tokio::io::duplex(WRITE_BUFFER * 4);
+        let (mut desc, mut events) = RawConnection::spawn(ours);
+
+        assert_eq!(desc.send_data(b"act one", true), 7);
+        let mut buf = [0u8; 7];
+        theirs.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"act one");
+
+        theirs.write_all(b"act two").await?;
+        assert_eq!(
+            events.recv().await,
+            Some(RawEvent::Data(b"act two".to_vec()))
+        );
+
+        // overfilling the buffer pushes back, then reports when there's room
+        let big = vec![0u8; WRITE_BUFFER + 1];
+        let taken = desc.send_data(&big, true);
+        assert!(taken <= WRITE_BUFFER);
+        assert_eq!(events.recv().await, Some(RawEvent::WriteSpaceAvailable));
+
+        drop(theirs);
+        assert_eq!(events.recv().await, Some(RawEvent::Disconnected));
+        assert_eq!(desc.send_data(b"late", true), 0);
+        Ok(())
+    }
+}
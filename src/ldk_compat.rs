@@ -0,0 +1,427 @@
+//! Conversions between this crate's messages and rust-lightning's.
+//!
+//! Hybrid applications, e.g. ones that talk to a node with lnsocket and hand some of what
+//! arrives to an LDK `PeerManager`, can move `init`, `ping`, `pong`, `error` and `warning`
+//! between the two stacks without going through bytes. Custom messages cross over wrapped:
+//! [`FromLdk`] makes an LDK custom message writable with [`LNSocket::write`], [`ToLdk`] does
+//! the reverse, and [`ldk_reader`] decodes with an LDK `CustomMessageReader` in
+//! [`LNSocket::read_custom`].
+//!
+//! Only with the `ldk-compat` feature.
+//!
+//! [`LNSocket::write`]: crate::LNSocket::write
+//! [`LNSocket::read_custom`]: crate::LNSocket::read_custom
+
+use crate::ln::msgs::{self, DecodeError};
+use crate::ln::types::ChannelId;
+use crate::ln::wire;
+use crate::socket_addr::SocketAddress;
+use crate::util::ser::{Hostname, Writeable, Writer};
+use lightning::ln::msgs as ldk;
+use lightning::ln::types::ChannelId as LdkChannelId;
+use lightning::ln::wire::CustomMessageReader;
+use lightning::util::ser::Writeable as LdkWriteable;
+use lightning_types::features::InitFeatures;
+use std::fmt;
+use std::io::{self, Cursor};
+
+/// Why one of our messages has no LDK equivalent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConversionError {
+    /// The `init` has custom TLVs, which LDK's doesn't keep.
+    CustomTlvs,
+    /// The address is a WebSocket port, which LDK doesn't know.
+    WebSocketAddress,
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::CustomTlvs => write!(f, "LDK's init has no custom TLVs"),
+            ConversionError::WebSocketAddress => write!(f, "LDK has no WebSocket addresses"),
+        }
+    }
+}
+
+impl From<ChannelId> for LdkChannelId {
+    fn from(id: ChannelId) -> Self {
+        LdkChannelId(id.0)
+    }
+}
+
+impl From<LdkChannelId> for ChannelId {
+    fn from(id: LdkChannelId) -> Self {
+        ChannelId(id.0)
+    }
+}
+
+impl TryFrom<SocketAddress> for ldk::SocketAddress {
+    type Error = ConversionError;
+
+    fn try_from(addr: SocketAddress) -> Result<Self, Self::Error> {
+        Ok(match addr {
+            SocketAddress::TcpIpV4 { addr, port } => ldk::SocketAddress::TcpIpV4 { addr, port },
+            SocketAddress::TcpIpV6 { addr, port } => ldk::SocketAddress::TcpIpV6 { addr, port },
+            SocketAddress::OnionV2(addr) => ldk::SocketAddress::OnionV2(addr),
+            SocketAddress::OnionV3 {
+                ed25519_pubkey,
+                checksum,
+                version,
+                port,
+            } => ldk::SocketAddress::OnionV3 {
+                ed25519_pubkey,
+                checksum,
+                version,
+                port,
+            },
+            SocketAddress::Hostname { hostname, port } => ldk::SocketAddress::Hostname {
+                // both follow the same rules
+                hostname: hostname.to_string().try_into().expect("valid hostname"),
+                port,
+            },
+            SocketAddress::WebSocket { .. } => return Err(ConversionError::WebSocketAddress),
+        })
+    }
+}
+
+impl From<ldk::SocketAddress> for SocketAddress {
+    fn from(addr: ldk::SocketAddress) -> Self {
+        match addr {
+            ldk::SocketAddress::TcpIpV4 { addr, port } => SocketAddress::TcpIpV4 { addr, port },
+            ldk::SocketAddress::TcpIpV6 { addr, port } => SocketAddress::TcpIpV6 { addr, port },
+            ldk::SocketAddress::OnionV2(addr) => SocketAddress::OnionV2(addr),
+            ldk::SocketAddress::OnionV3 {
+                ed25519_pubkey,
+                checksum,
+                version,
+                port,
+            } => SocketAddress::OnionV3 {
+                ed25519_pubkey,
+                checksum,
+                version,
+                port,
+            },
+            ldk::SocketAddress::Hostname { hostname, port } => SocketAddress::Hostname {
+                hostname: Hostname::try_from(hostname.to_string()).expect("valid hostname"),
+                port,
+            },
+        }
+    }
+}
+
+/// Global features are merged into the others, as LDK does when it decodes an `init`.
+impl TryFrom<msgs::Init> for ldk::Init {
+    type Error = ConversionError;
+
+    fn try_from(init: msgs::Init) -> Result<Self, Self::Error> {
+        if !init.custom_tlvs.is_empty() {
+            return Err(ConversionError::CustomTlvs);
+        }
+        let mut features = init.features;
+        let global = &init.global_features;
+        if global.len() > features.len() {
+            let mut padded = vec![0; global.len() - features.len()];
+            padded.append(&mut features);
+            features = padded;
+        }
+        let offset = features.len() - global.len();
+        for (byte, global) in features[offset..].iter_mut().zip(global) {
+            *byte |= global;
+        }
+        Ok(ldk::Init {
+            features: InitFeatures::from_be_bytes(features),
+            networks: init.networks,
+            remote_network_address: init
+                .remote_network_address
+                .map(TryInto::try_into)
+                .transpose()?,
+        })
+    }
+}
+
+impl From<ldk::Init> for msgs::Init {
+    fn from(init: ldk::Init) -> Self {
+        let mut features = init.features.le_flags().to_vec();
+        features.reverse();
+        msgs::Init {
+            global_features: vec![],
+            features,
+            networks: init.networks,
+            remote_network_address: init.remote_network_address.map(Into::into),
+            custom_tlvs: vec![],
+        }
+    }
+}
+
+impl From<msgs::Ping> for ldk::Ping {
+    fn from(ping: msgs::Ping) -> Self {
+        ldk::Ping {
+            ponglen: ping.ponglen,
+            byteslen: ping.byteslen,
+        }
+    }
+}
+
+impl From<ldk::Ping> for msgs::Ping {
+    fn from(ping: ldk::Ping) -> Self {
+        msgs::Ping {
+            ponglen: ping.ponglen,
+            byteslen: ping.byteslen,
+        }
+    }
+}
+
+impl From<msgs::Pong> for ldk::Pong {
+    fn from(pong: msgs::Pong) -> Self {
+        ldk::Pong {
+            byteslen: pong.byteslen,
+        }
+    }
+}
+
+impl From<ldk::Pong> for msgs::Pong {
+    fn from(pong: ldk::Pong) -> Self {
+        msgs::Pong {
+            byteslen: pong.byteslen,
+        }
+    }
+}
+
+impl From<msgs::ErrorMessage> for ldk::ErrorMessage {
+    fn from(msg: msgs::ErrorMessage) -> Self {
+        ldk::ErrorMessage {
+            channel_id: msg.channel_id.into(),
+            data: msg.data,
+        }
+    }
+}
+
+impl From<ldk::ErrorMessage> for msgs::ErrorMessage {
+    fn from(msg: ldk::ErrorMessage) -> Self {
+        msgs::ErrorMessage {
+            channel_id: msg.channel_id.into(),
+            data: msg.data,
+        }
+    }
+}
+
+impl From<msgs::WarningMessage> for ldk::WarningMessage {
+    fn from(msg: msgs::WarningMessage) -> Self {
+        ldk::WarningMessage {
+            channel_id: msg.channel_id.into(),
+            data: msg.data,
+        }
+    }
+}
+
+impl From<ldk::WarningMessage> for msgs::WarningMessage {
+    fn from(msg: ldk::WarningMessage) -> Self {
+        msgs::WarningMessage {
+            channel_id: msg.channel_id.into(),
+            data: msg.data,
+        }
+    }
+}
+
+impl From<ldk::DecodeError> for DecodeError {
+    fn from(err: ldk::DecodeError) -> Self {
+        match err {
+            ldk::DecodeError::UnknownVersion => DecodeError::UnknownVersion,
+            ldk::DecodeError::UnknownRequiredFeature => DecodeError::UnknownRequiredFeature,
+            ldk::DecodeError::ShortRead => DecodeError::ShortRead,
+            ldk::DecodeError::BadLengthDescriptor => DecodeError::BadLengthDescriptor,
+            ldk::DecodeError::Io(_) => DecodeError::Io(io::ErrorKind::Other),
+            _ => DecodeError::InvalidValue,
+        }
+    }
+}
+
+/// An LDK custom message, sendable with [`LNSocket::write`](crate::LNSocket::write).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FromLdk<T>(pub T);
+
+impl<T: lightning::ln::wire::Type> wire::Type for FromLdk<T> {
+    fn type_id(&self) -> u16 {
+        self.0.type_id()
+    }
+}
+
+impl<T: LdkWriteable> Writeable for FromLdk<T> {
+    fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_all(&self.0.encode())
+    }
+}
+
+/// One of our messages as an LDK custom message, e.g. for a `CustomMessageHandler` to send.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ToLdk<T>(pub T);
+
+impl<T: wire::Type + Writeable + fmt::Debug> lightning::ln::wire::Type for ToLdk<T> {
+    fn type_id(&self) -> u16 {
+        self.0.type_id()
+    }
+}
+
+impl<T: Writeable> LdkWriteable for ToLdk<T> {
+    fn write<W: lightning::util::ser::Writer>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(), lightning::io::Error> {
+        writer.write_all(&self.0.encode())
+    }
+}
+
+/// A [`LNSocket::read_custom`](crate::LNSocket::read_custom) handler decoding with `reader`.
+pub fn ldk_reader<R: CustomMessageReader>(
+    reader: &R,
+) -> impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<R::CustomMessage>, DecodeError> + '_ {
+    move |typ, buf| {
+        let start = buf.position() as usize;
+        let mut rest = &buf.get_ref()[start..];
+        Ok(reader.read(typ, &mut rest)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::Network;
+    use bitcoin::constants::ChainHash;
+
+    #[test]
+    fn test_init_round_trip() {
+        let init = msgs::Init {
+            global_features: vec![],
+            features: vec![0x02, 0xa2],
+            networks: Some(vec![ChainHash::using_genesis_block(Network::Bitcoin)]),
+            remote_network_address: Some(SocketAddress::TcpIpV4 {
+                addr: [127, 0, 0, 1],
+                port: 9735,
+            }),
+            custom_tlvs: vec![],
+        };
+        let theirs = ldk::Init::try_from(init.clone()).unwrap();
+        assert!(theirs.features.supports_variable_length_onion());
+        assert_eq!(msgs::Init::from(theirs), init);
+
+        // global features end up with the others
+        let merged = ldk::Init::try_from(msgs::Init {
+            global_features: vec![0x01, 0x00],
+            features: vec![0x02],
+            ..init.clone()
+        })
+        .unwrap();
+        assert_eq!(msgs::Init::from(merged).features, [0x01, 0x02]);
+
+        let custom = msgs::Init {
+            custom_tlvs: vec![(65537, vec![1])],
+            ..init
+        };
+        assert_eq!(
+            ldk::Init::try_from(custom),
+            Err(ConversionError::CustomTlvs)
+        );
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let ping = msgs::Ping {
+            ponglen: 4,
+            byteslen: 8,
+        };
+        assert_eq!(msgs::Ping::from(ldk::Ping::from(ping.clone())), ping);
+        let pong = msgs::Pong { byteslen: 4 };
+        assert_eq!(msgs::Pong::from(ldk::Pong::from(pong.clone())), pong);
+
+        let channel_id = ChannelId([7; 32]);
+        let error = msgs::ErrorMessage {
+            channel_id,
+            data: "bad".to_owned(),
+        };
+        let theirs = ldk::ErrorMessage::from(error.clone());
+        assert_eq!(theirs.channel_id, LdkChannelId([7; 32]));
+        assert_eq!(msgs::ErrorMessage::from(theirs), error);
+        let warning = msgs::WarningMessage {
+            channel_id,
+            data: "careful".to_owned(),
+        };
+        assert_eq!(
+            msgs::WarningMessage::from(ldk::WarningMessage::from(warning.clone())),
+            warning
+        );
+    }
+
+    #[test]
+    fn test_address_round_trip() {
+        for addr in ["127.0.0.1:9735", "[::1]:9735", "ln.example.com:9735"] {
+            let addr: SocketAddress = addr.parse().unwrap();
+            let theirs = ldk::SocketAddress::try_from(addr.clone()).unwrap();
+            assert_eq!(SocketAddress::from(theirs), addr);
+        }
+        let ws = SocketAddress::WebSocket { port: 443 };
+        assert_eq!(
+            ldk::SocketAddress::try_from(ws),
+            Err(ConversionError::WebSocketAddress)
+        );
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Hello(Vec<u8>);
+
+    impl lightning::ln::wire::Type for Hello {
+        fn type_id(&self) -> u16 {
+            32769
+        }
+    }
+
+    impl LdkWriteable for Hello {
+        fn write<W: lightning::util::ser::Writer>(
+            &self,
+            writer: &mut W,
+        ) -> Result<(), lightning::io::Error> {
+            writer.write_all(&self.0)
+        }
+    }
+
+    struct HelloReader;
+
+    impl CustomMessageReader for HelloReader {
+        type CustomMessage = Hello;
+
+        fn read<R: lightning::io::Read>(
+            &self,
+            typ: u16,
+            buffer: &mut R,
+        ) -> Result<Option<Hello>, ldk::DecodeError> {
+            if typ != 32769 {
+                return Ok(None);
+            }
+            let mut data = vec![];
+            let mut chunk = [0u8; 64];
+            loop {
+                match buffer.read(&mut chunk) {
+                    Ok(0) => return Ok(Some(Hello(data))),
+                    Ok(len) => data.extend_from_slice(&chunk[..len]),
+                    Err(err) => return Err(ldk::DecodeError::Io(err.kind())),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_round_trip() {
+        let ours = FromLdk(Hello(b"hi".to_vec()));
+        assert_eq!(wire::Type::type_id(&ours), 32769);
+        let bytes = Writeable::encode(&ours);
+        assert_eq!(bytes, b"hi");
+
+        let mut buf = Cursor::new(&bytes[..]);
+        let read = ldk_reader(&HelloReader)(32769, &mut buf).unwrap();
+        assert_eq!(read, Some(Hello(b"hi".to_vec())));
+        assert_eq!(ldk_reader(&HelloReader)(32771, &mut buf).unwrap(), None);
+
+        let back = ToLdk(msgs::Pong { byteslen: 2 });
+        assert_eq!(lightning::ln::wire::Type::type_id(&back), 19);
+        assert_eq!(LdkWriteable::encode(&back), [0, 2, 0, 0]);
+    }
+}
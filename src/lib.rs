@@ -4,15 +4,19 @@
 //! and typed Lightning wire message framing over TCP, using `tokio`.
 //!
 //! This crate is a **minimal, opinionated** wrapper around [`PeerChannelEncryptor`] that:
-//! - Resolves a `host:port` string into a socket address,
-//! - Opens a TCP connection (no retries or built-in timeouts),
+//! - Resolves a `host:port` string into socket addresses,
+//! - Opens a TCP connection, racing the resolved addresses with a timeout per attempt and one
+//!   for the whole connect ([`connect::ConnectOptions`]),
 //! - Completes the three-act Noise handshake (act1, act2, act3),
 //! - Optionally exchanges `init` messages ([`LNSocket::perform_init`]),
-//! - Provides typed `read`/`write` helpers for Lightning wire messages.
+//! - Provides typed `read`/`write` helpers for Lightning wire messages,
+//! - Reports peer warnings and errors as [`Event`]s ([`LNSocket::subscribe_events`]).
 //!
 //! ## ⚠️ Notes
 //! - Key management is the caller’s responsibility.
-//! - This crate does **not** handle reconnect logic, backpressure, or keepalives.
+//! - [`LNSocket`] does **not** reconnect by itself, apart from the opt-in [`recovery`] from
+//!   broken encryption. Wrap it in a [`reconnect::ReconnectingLNSocket`] for that. Keepalive
+//!   pings are opt-in too, see [`handle::RunOptions`].
 //! - [`LNSocket::perform_init`] uses minimal feature negotiation by design.
 //!
 //! ## Related modules
@@ -32,28 +36,131 @@
 //! ```
 //!
 //! See [`CommandoClient`] for sending RPC calls over the socket.
+//!
+//! ## Cargo features
+//! - `std` (default) — [`LNSocket`] and everything else that needs an OS. Without it the crate
+//!   is `no_std` + `alloc` and only the wire messages, the Noise encryptor and the sans-IO
+//!   [`sans_io::Session`] are left.
+//! - `tokio` (default) — dialing, timeouts, [`handle`], [`recovery`] and anything else that
+//!   needs tokio's timers or tasks. Implies `std`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
+
+#[cfg(feature = "tor-arti")]
+pub mod arti;
+#[cfg(feature = "std")]
+pub mod backup;
+#[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+pub mod blocking;
+#[cfg(feature = "wasm")]
+pub mod browser;
+#[cfg(feature = "std")]
+pub mod chat;
+#[cfg(feature = "codec")]
+pub mod codec;
+#[cfg(feature = "std")]
 pub mod commando;
+#[cfg(feature = "futures-io")]
+pub mod compat;
+#[cfg(feature = "tokio")]
+pub mod connect;
 mod crypto;
+#[cfg(feature = "tokio")]
+pub mod dns;
+#[cfg(feature = "embedded-io")]
+pub mod embedded;
 pub mod error;
+pub mod event;
+pub mod features;
+#[cfg(feature = "std")]
+pub mod gossip;
+#[cfg(feature = "tokio")]
+pub mod handle;
+#[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+pub mod i2p;
+pub mod init;
+#[cfg(feature = "std")]
+pub mod keys;
+#[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+pub mod ldk;
+#[cfg(feature = "ldk-compat")]
+pub mod ldk_compat;
+#[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+pub mod listener;
 pub mod ln;
+#[cfg(feature = "std")]
 pub mod lnsocket;
+#[cfg(feature = "experimental")]
+pub mod nostr;
+#[cfg(feature = "std")]
+pub mod ping;
+#[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+pub mod proxy;
+#[cfg(feature = "tokio")]
+pub mod ratelimit;
+#[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+pub mod reconnect;
+#[cfg(feature = "std")]
+pub mod record;
+#[cfg(feature = "tokio")]
+pub mod recovery;
+#[cfg(feature = "std")]
+pub mod rekey;
+pub mod sans_io;
+#[cfg(feature = "tokio")]
+pub mod score;
+#[cfg(feature = "std")]
 mod sign;
 mod socket_addr;
+#[cfg(any(test, feature = "testing"))]
+pub mod stress;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod timing;
+#[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+pub mod tor;
+#[cfg(feature = "tokio")]
+pub mod tunnel;
 mod util;
+#[cfg(feature = "std")]
+pub mod watchtower;
+#[cfg(feature = "webrtc")]
+pub mod webrtc;
+#[cfg(feature = "std")]
+pub mod websocket;
+#[cfg(feature = "tokio")]
+pub mod ws_proxy;
 
 pub use bitcoin;
+#[cfg(feature = "std")]
 pub use commando::CommandoClient;
+#[cfg(feature = "tokio")]
+pub use commando::CommandoHandle;
 pub use error::Error;
+pub use event::Event;
+pub use features::{FeaturePreset, Features, InitFeatures};
+pub use init::{InitOptions, PeerInfo};
+#[cfg(feature = "std")]
 pub use lnsocket::LNSocket;
+pub use socket_addr::SocketAddress;
+
+// std's io where there is one, the same API from `bitcoin` where there isn't
+#[cfg(not(feature = "std"))]
+pub(crate) use bitcoin::io;
+#[cfg(feature = "std")]
+pub(crate) use std::io;
 
 mod prelude {
     #![allow(unused_imports)]
 
-    pub use std::{boxed::Box, collections::VecDeque, string::String, vec, vec::Vec};
+    pub use alloc::{boxed::Box, collections::VecDeque, string::String, vec, vec::Vec};
 
-    pub use std::borrow::ToOwned;
-    pub use std::string::ToString;
+    pub use alloc::borrow::ToOwned;
+    pub use alloc::format;
+    pub use alloc::string::ToString;
 
     pub use core::convert::{AsMut, AsRef, TryFrom, TryInto};
     pub use core::default::Default;
@@ -67,10 +174,11 @@ mod prelude {
 ///
 /// This is not exported to bindings users as it is not intended for public consumption.
 pub mod io_extras {
-    use std::io::{self, Read, Write};
+    use crate::io::{self, Read, Write};
+    use crate::prelude::*;
 
     /// Creates an instance of a writer which will successfully consume all data.
-    pub use std::io::sink;
+    pub use crate::io::sink;
 
     pub fn copy<R: Read + ?Sized, W: Write + ?Sized>(
         reader: &mut R,
@@ -93,7 +201,7 @@ pub mod io_extras {
         Ok(count)
     }
 
-    pub fn read_to_end<D: Read>(d: &mut D) -> Result<std::vec::Vec<u8>, io::Error> {
+    pub fn read_to_end<D: Read>(d: &mut D) -> Result<Vec<u8>, io::Error> {
         let mut result = vec![];
         let mut buf = [0u8; 64];
         loop {
@@ -0,0 +1,416 @@
+//! Accepting inbound connections, with limits against connection floods.
+//!
+//! [`LNListener`] accepts TCP connections and runs the responder side of the Noise handshake
+//! on each, handing back the sockets that complete it. Handshakes run concurrently, so a slow
+//! or silent peer doesn't hold up the rest, and [`ListenerLimits`] bounds what strangers can
+//! make a public service do: how many peers are connected, how many handshakes are in flight,
+//! how often one IP may start a handshake, and how long a handshake may take. Connections
+//! over a limit are closed right away, before any cryptography.
+//!
+//! One listener can bind several addresses, e.g. `0.0.0.0:9735` and `[::]:9735` for both IPv4
+//! and IPv6, and hands out the peers from all of them under the same limits. IPv6 addresses
+//! are bound IPv6-only so the two don't collide.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::listener::{LNListener, ListenerLimits};
+//! # use bitcoin::secp256k1::SecretKey;
+//! # async fn example(our_key: SecretKey) -> Result<(), lnsocket::Error> {
+//! let limits = ListenerLimits {
+//!     max_peers: 64,
+//!     ..Default::default()
+//! };
+//! let addrs = ["0.0.0.0:9735", "[::]:9735"];
+//! let mut listener = LNListener::bind_with(limits, &addrs, our_key).await?;
+//! loop {
+//!     let mut socket = listener.accept().await?;
+//!     tokio::spawn(async move {
+//!         socket.perform_init().await?;
+//!         // talk to the peer
+//!         Ok::<_, lnsocket::Error>(())
+//!     });
+//! }
+//! # }
+//! ```
+
+use crate::{Error, LNSocket};
+use bitcoin::secp256k1::SecretKey;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, lookup_host};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+// forget IPs that haven't connected for a while once this many are tracked
+const PRUNE_AT: usize = 4096;
+
+const BACKLOG: i32 = 1024;
+
+/// Bind a listening socket to `addr`. IPv6 sockets only take IPv6, leaving IPv4 on the same
+/// port to another socket.
+fn bind_one(addr: SocketAddr) -> io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // like tokio's own bind, so restarts don't wait out TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Resolve `addr` and bind the first of its addresses that works, like
+/// [`TcpListener::bind`].
+async fn bind_addr(addr: &str) -> Result<TcpListener, Error> {
+    let mut last_err = Error::DnsError;
+    for addr in lookup_host(addr).await? {
+        match bind_one(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = err.into(),
+        }
+    }
+    Err(last_err)
+}
+
+/// What an [`LNListener`] lets peers do.
+#[derive(Clone, Debug)]
+pub struct ListenerLimits {
+    /// Most connections open at once, counting ones still handshaking. A peer's slot is
+    /// freed when its socket is dropped.
+    pub max_peers: usize,
+    /// Most handshakes in flight at once.
+    pub max_pending_handshakes: usize,
+    /// Most handshakes one IP address may start per [`ListenerLimits::rate_window`].
+    pub handshakes_per_ip: u32,
+    pub rate_window: Duration,
+    /// How long a peer has to complete the handshake before it's dropped.
+    pub handshake_timeout: Duration,
+}
+
+impl Default for ListenerLimits {
+    fn default() -> Self {
+        Self {
+            max_peers: 256,
+            max_pending_handshakes: 64,
+            handshakes_per_ip: 10,
+            rate_window: Duration::from_secs(60),
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The stream under an [`LNSocket`] accepted by an [`LNListener`].
+///
+/// It holds the peer's slot in [`ListenerLimits::max_peers`] until dropped, so
+/// [`LNSocket::into_parts`] gives this back rather than the bare [`TcpStream`].
+#[derive(Debug)]
+pub struct InboundStream {
+    stream: TcpStream,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl InboundStream {
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl AsyncRead for InboundStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for InboundStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Handshake attempts per IP, for [`ListenerLimits::handshakes_per_ip`].
+#[derive(Debug, Default)]
+struct RateLimiter {
+    // ip -> (window start, handshakes started in it)
+    seen: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    fn allow(&mut self, ip: IpAddr, limits: &ListenerLimits, now: Instant) -> bool {
+        if self.seen.len() >= PRUNE_AT {
+            self.seen
+                .retain(|_, (start, _)| now.duration_since(*start) < limits.rate_window);
+        }
+        let (start, count) = self.seen.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= limits.rate_window {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= limits.handshakes_per_ip
+    }
+}
+
+/// The next connection on any of `listeners`, polling them from `next` on so a busy one
+/// can't starve the rest.
+fn poll_accept(
+    listeners: &[TcpListener],
+    next: &mut usize,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<(TcpStream, SocketAddr)>> {
+    let count = listeners.len();
+    for i in 0..count {
+        let at = (*next + i) % count;
+        if let Poll::Ready(accepted) = listeners[at].poll_accept(cx) {
+            *next = (at + 1) % count;
+            return Poll::Ready(accepted);
+        }
+    }
+    Poll::Pending
+}
+
+/// Accepts Lightning peers on one or more TCP ports. See the [module docs](self).
+pub struct LNListener {
+    listeners: Vec<TcpListener>,
+    // which listener to poll first
+    next: usize,
+    our_key: SecretKey,
+    limits: ListenerLimits,
+    slots: Arc<Semaphore>,
+    rate: RateLimiter,
+    handshakes: JoinSet<Option<LNSocket>>,
+}
+
+impl LNListener {
+    /// Listen on each of `addrs` with the default [`ListenerLimits`], as the node `our_key`.
+    pub async fn bind(addrs: &[&str], our_key: SecretKey) -> Result<LNListener, Error> {
+        Self::bind_with(ListenerLimits::default(), addrs, our_key).await
+    }
+
+    /// Listen on each of `addrs` with `limits`, as the node `our_key`. Fails if any of them
+    /// can't be bound.
+    pub async fn bind_with(
+        limits: ListenerLimits,
+        addrs: &[&str],
+        our_key: SecretKey,
+    ) -> Result<LNListener, Error> {
+        if addrs.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput).into());
+        }
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            listeners.push(bind_addr(addr).await?);
+        }
+        Ok(LNListener {
+            listeners,
+            next: 0,
+            our_key,
+            slots: Arc::new(Semaphore::new(limits.max_peers)),
+            limits,
+            rate: RateLimiter::default(),
+            handshakes: JoinSet::new(),
+        })
+    }
+
+    /// The addresses we're listening on, in the order they were given, e.g. to find the
+    /// ports picked for port 0.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>, Error> {
+        let addrs = self.listeners.iter().map(TcpListener::local_addr);
+        Ok(addrs.collect::<io::Result<_>>()?)
+    }
+
+    /// How many peers are connected or handshaking.
+    pub fn peers(&self) -> usize {
+        self.limits.max_peers - self.slots.available_permits()
+    }
+
+    /// Wait for the next peer to complete the handshake. `init` hasn't been exchanged yet.
+    ///
+    /// Connections over a limit and failed handshakes are dropped without an error, only
+    /// failures of the listening socket itself are returned. Cancel-safe.
+    pub async fn accept(&mut self) -> Result<LNSocket, Error> {
+        loop {
+            let (listeners, next) = (&self.listeners, &mut self.next);
+            tokio::select! {
+                accepted = std::future::poll_fn(|cx| poll_accept(listeners, next, cx)) => {
+                    let (stream, addr) = accepted?;
+                    self.start_handshake(stream, addr);
+                }
+                Some(done) = self.handshakes.join_next() => {
+                    if let Ok(Some(socket)) = done {
+                        return Ok(socket);
+                    }
+                }
+            }
+        }
+    }
+
+    fn start_handshake(&mut self, stream: TcpStream, addr: SocketAddr) {
+        if self.handshakes.len() >= self.limits.max_pending_handshakes {
+            return;
+        }
+        if !self.rate.allow(addr.ip(), &self.limits, Instant::now()) {
+            return;
+        }
+        let Ok(slot) = self.slots.clone().try_acquire_owned() else {
+            return;
+        };
+
+        let stream = InboundStream {
+            stream,
+            _slot: slot,
+        };
+        let our_key = self.our_key;
+        let deadline = self.limits.handshake_timeout;
+        self.handshakes.spawn(async move {
+            let handshake = LNSocket::handshake_inbound(stream, our_key);
+            let mut socket = timeout(deadline, handshake).await.ok()?.ok()?;
+            socket.peer_addr = Some(addr);
+            Some(socket)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, rand};
+
+    async fn listener(limits: ListenerLimits) -> Result<(LNListener, PublicKey, String), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &key);
+        let listener = LNListener::bind_with(limits, &["127.0.0.1:0"], key).await?;
+        let addr = listener.local_addrs()?[0].to_string();
+        Ok((listener, node_id, addr))
+    }
+
+    #[tokio::test]
+    async fn test_accept() -> Result<(), Error> {
+        let (mut listener, node_id, addr) = listener(ListenerLimits::default()).await?;
+        let client_key = SecretKey::new(&mut rand::thread_rng());
+
+        // a silent connection doesn't hold up a real peer
+        let _silent = TcpStream::connect(&addr).await?;
+        let (client, server) = tokio::join!(
+            LNSocket::connect(client_key, node_id, &addr),
+            listener.accept()
+        );
+        let (mut client, mut server) = (client?, server?);
+        assert_eq!(
+            server.their_pubkey(),
+            client_key.public_key(&Secp256k1::signing_only())
+        );
+        assert!(server.peer_addr.is_some());
+        assert_eq!(listener.peers(), 2);
+
+        client.write(&crate::testing::default_init()).await?;
+        server.perform_init().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limits() -> Result<(), Error> {
+        let limits = ListenerLimits {
+            max_peers: 1,
+            handshake_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (mut listener, node_id, addr) = listener(limits).await?;
+        let wait = Duration::from_millis(300);
+
+        // a silent peer times out and gives its slot back
+        let _silent = TcpStream::connect(&addr).await?;
+        assert!(timeout(wait, listener.accept()).await.is_err());
+        assert_eq!(listener.peers(), 0);
+
+        let client_key = SecretKey::new(&mut rand::thread_rng());
+        let (client, server) = tokio::join!(
+            LNSocket::connect(client_key, node_id, &addr),
+            listener.accept()
+        );
+        let (_client, server) = (client?, server?);
+        assert_eq!(listener.peers(), 1);
+
+        // no room for another while the first is connected
+        let (refused, accepted) = tokio::join!(
+            LNSocket::connect(client_key, node_id, &addr),
+            timeout(wait, listener.accept())
+        );
+        assert!(refused.is_err());
+        assert!(accepted.is_err());
+
+        drop(server);
+        assert_eq!(listener.peers(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &key);
+        let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let v4 = format!("0.0.0.0:{port}");
+        let v6 = format!("[::]:{port}");
+        let Ok(mut listener) = LNListener::bind(&[&v4, &v6], key).await else {
+            eprintln!("no IPv6 here, skipping");
+            return Ok(());
+        };
+        assert_eq!(listener.local_addrs()?.len(), 2);
+
+        for addr in [format!("127.0.0.1:{port}"), format!("[::1]:{port}")] {
+            let client_key = SecretKey::new(&mut rand::thread_rng());
+            let (client, server) = tokio::join!(
+                LNSocket::connect(client_key, node_id, &addr),
+                listener.accept()
+            );
+            client?;
+            let peer_addr = server?.peer_addr.expect("peer address");
+            assert_eq!(peer_addr.is_ipv6(), addr.starts_with('['));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limits = ListenerLimits {
+            handshakes_per_ip: 2,
+            ..Default::default()
+        };
+        let mut rate = RateLimiter::default();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let other: IpAddr = "203.0.113.8".parse().unwrap();
+        let now = Instant::now();
+        assert!(rate.allow(ip, &limits, now));
+        assert!(rate.allow(ip, &limits, now));
+        assert!(!rate.allow(ip, &limits, now));
+        assert!(rate.allow(other, &limits, now));
+        assert!(rate.allow(ip, &limits, now + limits.rate_window));
+    }
+}
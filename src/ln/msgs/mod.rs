@@ -1,11 +1,20 @@
+use crate::io::{self, Read};
+use crate::io_extras::read_to_end;
+use crate::prelude::*;
 use crate::util::{
     logger,
-    ser::{LengthLimitedRead, LengthReadable, Readable, WithoutLength, Writeable, Writer},
+    ser::{
+        BigSize, FixedLengthReader, LengthLimitedRead, LengthReadable, Readable, WithoutLength,
+        Writeable, Writer,
+    },
+};
+use crate::{
+    _decode_tlv_stream_range, encode_tlv_stream, ln::types::ChannelId, socket_addr::SocketAddress,
 };
-use crate::{encode_tlv_stream, ln::types::ChannelId, socket_addr::SocketAddress};
 use bitcoin::blockdata::constants::ChainHash;
+use bitcoin::hashes::{Hash, sha256d};
+use bitcoin::secp256k1::{self, PublicKey, Secp256k1, SecretKey, ecdsa::Signature};
 use lightning_types::features::InitFeatures;
-use std::io;
 
 /// An Err type for failure to process messages.
 #[derive(Clone, Debug)]
@@ -36,12 +45,16 @@ pub enum DecodeError {
     /// A length descriptor in the packet didn't describe the later data correctly.
     BadLengthDescriptor,
     /// Error from [`crate::io`].
-    Io(std::io::ErrorKind),
+    Io(io::ErrorKind),
 }
 
-impl From<std::io::Error> for DecodeError {
-    fn from(err: std::io::Error) -> Self {
-        DecodeError::Io(err.kind())
+impl From<io::Error> for DecodeError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            DecodeError::ShortRead
+        } else {
+            DecodeError::Io(err.kind())
+        }
     }
 }
 
@@ -64,6 +77,11 @@ pub struct Init {
     /// public IPv4 address (NAT) and use that for a [`NodeAnnouncement`] update message containing
     /// the new address.
     pub remote_network_address: Option<SocketAddress>,
+    /// Odd-typed TLV records beyond the ones defined by BOLT 1, sorted by type.
+    ///
+    /// Some custom protocols signal capabilities this way. Unknown even types are rejected
+    /// when decoding, as the spec requires.
+    pub custom_tlvs: Vec<(u64, Vec<u8>)>,
 }
 
 /// An [`error`] message to be sent to or received from a peer.
@@ -125,6 +143,195 @@ pub struct Pong {
     pub byteslen: u16,
 }
 
+/// A [`peer_storage`] message asking the peer to hold a blob for us.
+///
+/// [`peer_storage`]: https://github.com/lightning/bolts/blob/master/01-messaging.md#peer-storage
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct PeerStorage {
+    /// The blob, replacing any we stored before.
+    pub data: Vec<u8>,
+}
+
+/// A [`peer_storage_retrieval`] message, sent by the peer after init to return the last blob
+/// we stored with it.
+///
+/// [`peer_storage_retrieval`]: https://github.com/lightning/bolts/blob/master/01-messaging.md#peer-storage
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct PeerStorageRetrieval {
+    pub data: Vec<u8>,
+}
+
+/// A [`node_announcement`] gossip message.
+///
+/// [`node_announcement`]: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md#the-node_announcement-message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeAnnouncement {
+    /// The node's signature over everything that follows it.
+    pub signature: Signature,
+    pub features: Vec<u8>,
+    /// Newer announcements replace older ones.
+    pub timestamp: u32,
+    pub node_id: PublicKey,
+    pub rgb: [u8; 3],
+    pub alias: [u8; 32],
+    /// The addresses the node can be reached at, in the order it listed them.
+    pub addresses: Vec<SocketAddress>,
+    /// Addresses of types we don't understand, kept so the signature still checks out.
+    pub excess_address_data: Vec<u8>,
+    /// Trailing data we don't understand, kept for the same reason.
+    pub excess_data: Vec<u8>,
+}
+
+/// A [`channel_announcement`] gossip message.
+///
+/// [`channel_announcement`]: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md#the-channel_announcement-message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelAnnouncement {
+    pub node_signature_1: Signature,
+    pub node_signature_2: Signature,
+    pub bitcoin_signature_1: Signature,
+    pub bitcoin_signature_2: Signature,
+    pub features: Vec<u8>,
+    pub chain_hash: ChainHash,
+    pub short_channel_id: u64,
+    /// The lesser of the two node ids.
+    pub node_id_1: PublicKey,
+    pub node_id_2: PublicKey,
+    pub bitcoin_key_1: PublicKey,
+    pub bitcoin_key_2: PublicKey,
+    /// Trailing data we don't understand, kept so the signatures still check out.
+    pub excess_data: Vec<u8>,
+}
+
+/// A [`channel_update`] gossip message.
+///
+/// [`channel_update`]: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md#the-channel_update-message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelUpdate {
+    pub signature: Signature,
+    pub chain_hash: ChainHash,
+    pub short_channel_id: u64,
+    /// Newer updates replace older ones.
+    pub timestamp: u32,
+    pub message_flags: u8,
+    /// Bit 0 is the direction (0 for updates from `node_id_1`), bit 1 marks the channel
+    /// disabled.
+    pub channel_flags: u8,
+    pub cltv_expiry_delta: u16,
+    pub htlc_minimum_msat: u64,
+    pub fee_base_msat: u32,
+    pub fee_proportional_millionths: u32,
+    pub htlc_maximum_msat: u64,
+    /// Trailing data we don't understand, kept so the signature still checks out.
+    pub excess_data: Vec<u8>,
+}
+
+/// A [`gossip_timestamp_filter`] message, limiting which gossip the peer relays to us.
+///
+/// [`gossip_timestamp_filter`]: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md#the-gossip_timestamp_filter-message
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct GossipTimestampFilter {
+    pub chain_hash: ChainHash,
+    /// Only gossip with a timestamp at or after this is relayed.
+    pub first_timestamp: u32,
+    /// How many seconds after `first_timestamp` to keep relaying.
+    pub timestamp_range: u32,
+}
+
+/// A [`query_short_channel_ids`] message, asking for the gossip of some channels.
+///
+/// The optional `query_flags` are ignored when reading, so every message is asked for.
+///
+/// [`query_short_channel_ids`]: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md#the-query_short_channel_idsreply_short_channel_ids_end-messages
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct QueryShortChannelIds {
+    pub chain_hash: ChainHash,
+    pub short_channel_ids: Vec<u64>,
+}
+
+/// A [`reply_short_channel_ids_end`] message, ending the answer to a
+/// [`QueryShortChannelIds`].
+///
+/// [`reply_short_channel_ids_end`]: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md#the-query_short_channel_idsreply_short_channel_ids_end-messages
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct ReplyShortChannelIdsEnd {
+    pub chain_hash: ChainHash,
+    /// False if the node doesn't keep gossip for `chain_hash`.
+    pub full_information: bool,
+}
+
+/// A [`query_channel_range`] message, asking which channels were opened in some blocks.
+///
+/// [`query_channel_range`]: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md#the-query_channel_range-and-reply_channel_range-messages
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct QueryChannelRange {
+    pub chain_hash: ChainHash,
+    pub first_blocknum: u32,
+    pub number_of_blocks: u32,
+}
+
+impl QueryChannelRange {
+    /// The block after the last one asked about.
+    pub fn end_blocknum(&self) -> u32 {
+        self.first_blocknum.saturating_add(self.number_of_blocks)
+    }
+}
+
+/// A [`reply_channel_range`] message, one of the answers to a [`QueryChannelRange`].
+///
+/// [`reply_channel_range`]: https://github.com/lightning/bolts/blob/master/07-routing-gossip.md#the-query_channel_range-and-reply_channel_range-messages
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct ReplyChannelRange {
+    pub chain_hash: ChainHash,
+    pub first_blocknum: u32,
+    pub number_of_blocks: u32,
+    /// False if the node doesn't keep gossip for `chain_hash`.
+    pub sync_complete: bool,
+    pub short_channel_ids: Vec<u64>,
+}
+
+impl NodeAnnouncement {
+    /// The signed part of the message.
+    fn contents(&self) -> Vec<u8> {
+        let mut w = Vec::new();
+        self.write_contents(&mut w)
+            .expect("writing to a Vec can't fail");
+        w
+    }
+
+    fn write_contents<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.features.write(w)?;
+        self.timestamp.write(w)?;
+        self.node_id.write(w)?;
+        self.rgb.write(w)?;
+        self.alias.write(w)?;
+        let mut addresses = Vec::new();
+        for address in &self.addresses {
+            address.write(&mut addresses)?;
+        }
+        addresses.extend_from_slice(&self.excess_address_data);
+        addresses.write(w)?;
+        w.write_all(&self.excess_data)
+    }
+
+    /// Set `node_id` to `sk`'s public key and sign the rest of the announcement with it.
+    pub fn sign<C: secp256k1::Signing>(&mut self, secp_ctx: &Secp256k1<C>, sk: &SecretKey) {
+        self.node_id = PublicKey::from_secret_key(secp_ctx, sk);
+        let hash = sha256d::Hash::hash(&self.contents());
+        let msg = secp256k1::Message::from_digest(hash.to_byte_array());
+        self.signature = secp_ctx.sign_ecdsa(&msg, sk);
+    }
+
+    /// Check that the announcement was signed by `node_id`.
+    pub fn verify<C: secp256k1::Verification>(&self, secp_ctx: &Secp256k1<C>) -> bool {
+        let hash = sha256d::Hash::hash(&self.contents());
+        let msg = secp256k1::Message::from_digest(hash.to_byte_array());
+        secp_ctx
+            .verify_ecdsa(&msg, &self.signature, &self.node_id)
+            .is_ok()
+    }
+}
+
 /// Used to put an error message in a [`LightningError`].
 #[derive(Clone, Debug, Hash, PartialEq)]
 pub enum ErrorAction {
@@ -165,7 +372,7 @@ pub enum ErrorAction {
 }
 
 impl Writeable for Init {
-    fn write<W: Writer>(&self, w: &mut W) -> Result<(), std::io::Error> {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
         // global_features gets the bottom 13 bits of our features, and local_features gets all of
         // our relevant feature bits. This keeps us compatible with old nodes.
         //write_features_up_to_13(w, self.features.le_flags())?;
@@ -175,6 +382,11 @@ impl Writeable for Init {
             (1, self.networks.as_ref().map(WithoutLength), option),
             (3, self.remote_network_address, option),
         });
+        for (typ, value) in &self.custom_tlvs {
+            BigSize(*typ).write(w)?;
+            BigSize(value.len() as u64).write(w)?;
+            w.write_all(value)?;
+        }
         Ok(())
     }
 }
@@ -244,10 +456,9 @@ impl LengthReadable for ErrorMessage {
                 let sz: usize = <u16 as Readable>::read(r)? as usize;
                 let mut data = vec![0; sz];
                 r.read_exact(&mut data)?;
-                match String::from_utf8(data) {
-                    Ok(s) => s,
-                    Err(_) => return Err(DecodeError::InvalidValue),
-                }
+                // peers are allowed to send arbitrary bytes here, don't fail the whole
+                // message just because it isn't valid UTF-8
+                String::from_utf8_lossy(&data).into_owned()
             },
         })
     }
@@ -270,10 +481,9 @@ impl LengthReadable for WarningMessage {
                 let sz: usize = <u16 as Readable>::read(r)? as usize;
                 let mut data = vec![0; sz];
                 r.read_exact(&mut data)?;
-                match String::from_utf8(data) {
-                    Ok(s) => s,
-                    Err(_) => return Err(DecodeError::InvalidValue),
-                }
+                // peers are allowed to send arbitrary bytes here, don't fail the whole
+                // message just because it isn't valid UTF-8
+                String::from_utf8_lossy(&data).into_owned()
             },
         })
     }
@@ -298,6 +508,317 @@ impl LengthReadable for Pong {
     }
 }
 
+impl Writeable for PeerStorage {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.data.write(w)
+    }
+}
+
+impl LengthReadable for PeerStorage {
+    fn read_from_fixed_length_buffer<R: LengthLimitedRead>(r: &mut R) -> Result<Self, DecodeError> {
+        Ok(PeerStorage {
+            data: Readable::read(r)?,
+        })
+    }
+}
+
+impl Writeable for PeerStorageRetrieval {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.data.write(w)
+    }
+}
+
+impl LengthReadable for PeerStorageRetrieval {
+    fn read_from_fixed_length_buffer<R: LengthLimitedRead>(r: &mut R) -> Result<Self, DecodeError> {
+        Ok(PeerStorageRetrieval {
+            data: Readable::read(r)?,
+        })
+    }
+}
+
+impl Writeable for NodeAnnouncement {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.signature.write(w)?;
+        self.write_contents(w)
+    }
+}
+
+impl LengthReadable for NodeAnnouncement {
+    fn read_from_fixed_length_buffer<R: LengthLimitedRead>(r: &mut R) -> Result<Self, DecodeError> {
+        let signature = Readable::read(r)?;
+        let features = Readable::read(r)?;
+        let timestamp = Readable::read(r)?;
+        let node_id = Readable::read(r)?;
+        let rgb = Readable::read(r)?;
+        let alias = Readable::read(r)?;
+
+        let addr_len: u16 = Readable::read(r)?;
+        let mut addr_reader = FixedLengthReader::new(&mut *r, addr_len as u64);
+        let mut addresses = Vec::new();
+        let mut excess_address_data = Vec::new();
+        while addr_reader.bytes_remain() {
+            match Readable::read(&mut addr_reader)? {
+                Ok(address) => addresses.push(address),
+                Err(unknown_type) => {
+                    // addresses have no length prefix, so anything after an unknown type is
+                    // opaque
+                    excess_address_data.push(unknown_type);
+                    excess_address_data.extend(read_to_end(&mut addr_reader)?);
+                }
+            }
+        }
+        addr_reader.eat_remaining()?;
+
+        let excess_data = read_to_end(r)?;
+
+        Ok(NodeAnnouncement {
+            signature,
+            features,
+            timestamp,
+            node_id,
+            rgb,
+            alias,
+            addresses,
+            excess_address_data,
+            excess_data,
+        })
+    }
+}
+
+impl Writeable for ChannelAnnouncement {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.node_signature_1.write(w)?;
+        self.node_signature_2.write(w)?;
+        self.bitcoin_signature_1.write(w)?;
+        self.bitcoin_signature_2.write(w)?;
+        self.features.write(w)?;
+        self.chain_hash.write(w)?;
+        self.short_channel_id.write(w)?;
+        self.node_id_1.write(w)?;
+        self.node_id_2.write(w)?;
+        self.bitcoin_key_1.write(w)?;
+        self.bitcoin_key_2.write(w)?;
+        w.write_all(&self.excess_data)
+    }
+}
+
+impl LengthReadable for ChannelAnnouncement {
+    fn read_from_fixed_length_buffer<R: LengthLimitedRead>(r: &mut R) -> Result<Self, DecodeError> {
+        Ok(ChannelAnnouncement {
+            node_signature_1: Readable::read(r)?,
+            node_signature_2: Readable::read(r)?,
+            bitcoin_signature_1: Readable::read(r)?,
+            bitcoin_signature_2: Readable::read(r)?,
+            features: Readable::read(r)?,
+            chain_hash: Readable::read(r)?,
+            short_channel_id: Readable::read(r)?,
+            node_id_1: Readable::read(r)?,
+            node_id_2: Readable::read(r)?,
+            bitcoin_key_1: Readable::read(r)?,
+            bitcoin_key_2: Readable::read(r)?,
+            excess_data: read_to_end(r)?,
+        })
+    }
+}
+
+impl Writeable for ChannelUpdate {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.signature.write(w)?;
+        self.write_contents(w)
+    }
+}
+
+impl ChannelUpdate {
+    /// The signed part of the message.
+    fn contents(&self) -> Vec<u8> {
+        let mut w = Vec::new();
+        self.write_contents(&mut w)
+            .expect("writing to a Vec can't fail");
+        w
+    }
+
+    fn write_contents<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.chain_hash.write(w)?;
+        self.short_channel_id.write(w)?;
+        self.timestamp.write(w)?;
+        self.message_flags.write(w)?;
+        self.channel_flags.write(w)?;
+        self.cltv_expiry_delta.write(w)?;
+        self.htlc_minimum_msat.write(w)?;
+        self.fee_base_msat.write(w)?;
+        self.fee_proportional_millionths.write(w)?;
+        self.htlc_maximum_msat.write(w)?;
+        w.write_all(&self.excess_data)
+    }
+
+    /// Sign the update with `sk`, the key of the node it's from.
+    pub fn sign<C: secp256k1::Signing>(&mut self, secp_ctx: &Secp256k1<C>, sk: &SecretKey) {
+        let hash = sha256d::Hash::hash(&self.contents());
+        let msg = secp256k1::Message::from_digest(hash.to_byte_array());
+        self.signature = secp_ctx.sign_ecdsa(&msg, sk);
+    }
+
+    /// Check that the update was signed by `node_id`, the channel end it's from.
+    pub fn verify<C: secp256k1::Verification>(
+        &self,
+        secp_ctx: &Secp256k1<C>,
+        node_id: &PublicKey,
+    ) -> bool {
+        let hash = sha256d::Hash::hash(&self.contents());
+        let msg = secp256k1::Message::from_digest(hash.to_byte_array());
+        secp_ctx
+            .verify_ecdsa(&msg, &self.signature, node_id)
+            .is_ok()
+    }
+}
+
+impl LengthReadable for ChannelUpdate {
+    fn read_from_fixed_length_buffer<R: LengthLimitedRead>(r: &mut R) -> Result<Self, DecodeError> {
+        Ok(ChannelUpdate {
+            signature: Readable::read(r)?,
+            chain_hash: Readable::read(r)?,
+            short_channel_id: Readable::read(r)?,
+            timestamp: Readable::read(r)?,
+            message_flags: Readable::read(r)?,
+            channel_flags: Readable::read(r)?,
+            cltv_expiry_delta: Readable::read(r)?,
+            htlc_minimum_msat: Readable::read(r)?,
+            fee_base_msat: Readable::read(r)?,
+            fee_proportional_millionths: Readable::read(r)?,
+            htlc_maximum_msat: Readable::read(r)?,
+            excess_data: read_to_end(r)?,
+        })
+    }
+}
+
+impl Writeable for GossipTimestampFilter {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.chain_hash.write(w)?;
+        self.first_timestamp.write(w)?;
+        self.timestamp_range.write(w)
+    }
+}
+
+impl LengthReadable for GossipTimestampFilter {
+    fn read_from_fixed_length_buffer<R: LengthLimitedRead>(r: &mut R) -> Result<Self, DecodeError> {
+        Ok(GossipTimestampFilter {
+            chain_hash: Readable::read(r)?,
+            first_timestamp: Readable::read(r)?,
+            timestamp_range: Readable::read(r)?,
+        })
+    }
+}
+
+// encoded_short_ids: a length, then an encoding byte and the ids. only the uncompressed
+// encoding is still allowed
+fn write_short_ids<W: Writer>(w: &mut W, ids: &[u64]) -> Result<(), io::Error> {
+    (1 + ids.len() as u16 * 8).write(w)?;
+    0u8.write(w)?;
+    for id in ids {
+        id.write(w)?;
+    }
+    Ok(())
+}
+
+fn read_short_ids<R: Read>(r: &mut R) -> Result<Vec<u64>, DecodeError> {
+    let len: u16 = Readable::read(r)?;
+    if len == 0 {
+        return Err(DecodeError::BadLengthDescriptor);
+    }
+    let encoding: u8 = Readable::read(r)?;
+    if encoding != 0 {
+        return Err(DecodeError::InvalidValue);
+    }
+    if !(len - 1).is_multiple_of(8) {
+        return Err(DecodeError::BadLengthDescriptor);
+    }
+    (0..(len - 1) / 8).map(|_| Readable::read(r)).collect()
+}
+
+// the TLVs after queries and replies only narrow or annotate them, so they're skipped
+fn skip_rest<R: LengthLimitedRead>(r: &mut R) -> Result<(), DecodeError> {
+    read_to_end(r)?;
+    Ok(())
+}
+
+impl Writeable for QueryShortChannelIds {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.chain_hash.write(w)?;
+        write_short_ids(w, &self.short_channel_ids)
+    }
+}
+
+impl LengthReadable for QueryShortChannelIds {
+    fn read_from_fixed_length_buffer<R: LengthLimitedRead>(r: &mut R) -> Result<Self, DecodeError> {
+        let msg = QueryShortChannelIds {
+            chain_hash: Readable::read(r)?,
+            short_channel_ids: read_short_ids(r)?,
+        };
+        skip_rest(r)?;
+        Ok(msg)
+    }
+}
+
+impl Writeable for ReplyShortChannelIdsEnd {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.chain_hash.write(w)?;
+        self.full_information.write(w)
+    }
+}
+
+impl LengthReadable for ReplyShortChannelIdsEnd {
+    fn read_from_fixed_length_buffer<R: LengthLimitedRead>(r: &mut R) -> Result<Self, DecodeError> {
+        Ok(ReplyShortChannelIdsEnd {
+            chain_hash: Readable::read(r)?,
+            full_information: Readable::read(r)?,
+        })
+    }
+}
+
+impl Writeable for QueryChannelRange {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.chain_hash.write(w)?;
+        self.first_blocknum.write(w)?;
+        self.number_of_blocks.write(w)
+    }
+}
+
+impl LengthReadable for QueryChannelRange {
+    fn read_from_fixed_length_buffer<R: LengthLimitedRead>(r: &mut R) -> Result<Self, DecodeError> {
+        let msg = QueryChannelRange {
+            chain_hash: Readable::read(r)?,
+            first_blocknum: Readable::read(r)?,
+            number_of_blocks: Readable::read(r)?,
+        };
+        skip_rest(r)?;
+        Ok(msg)
+    }
+}
+
+impl Writeable for ReplyChannelRange {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.chain_hash.write(w)?;
+        self.first_blocknum.write(w)?;
+        self.number_of_blocks.write(w)?;
+        self.sync_complete.write(w)?;
+        write_short_ids(w, &self.short_channel_ids)
+    }
+}
+
+impl LengthReadable for ReplyChannelRange {
+    fn read_from_fixed_length_buffer<R: LengthLimitedRead>(r: &mut R) -> Result<Self, DecodeError> {
+        let msg = ReplyChannelRange {
+            chain_hash: Readable::read(r)?,
+            first_blocknum: Readable::read(r)?,
+            number_of_blocks: Readable::read(r)?,
+            sync_complete: Readable::read(r)?,
+            short_channel_ids: read_short_ids(r)?,
+        };
+        skip_rest(r)?;
+        Ok(msg)
+    }
+}
+
 impl Writeable for Ping {
     fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
         self.ponglen.write(w)?;
@@ -321,29 +842,226 @@ impl LengthReadable for Ping {
 
 impl LengthReadable for Init {
     fn read_from_fixed_length_buffer<R: LengthLimitedRead>(r: &mut R) -> Result<Self, DecodeError> {
-        //println!("remaining 1 {}", r.remaining_bytes());
         let global_features: Vec<u8> = Readable::read(r)?;
-        //println!("reading global features {:?}", global_features);
         let features: Vec<u8> = Readable::read(r)?;
-        //println!("reading remote features {:?}", features);
-        //let mut remote_network_address: Option<SocketAddress> = None;
-        //let mut networks: Option<WithoutLength<Vec<ChainHash>>> = None;
-
-        let mut buf = Vec::with_capacity(r.remaining_bytes() as usize);
-        r.read_to_end(&mut buf)?;
-
-        // TODO: fixme
-        /*
-        decode_tlv_stream!(r, {
+        let mut remote_network_address: Option<SocketAddress> = None;
+        let mut networks: Option<WithoutLength<Vec<ChainHash>>> = None;
+        let mut custom_tlvs = Vec::new();
+        let mut read_custom_tlv = |typ: u64, s: &mut FixedLengthReader<_>| {
+            // even types are handled (rejected) by the tlv stream decoder
+            if typ.is_multiple_of(2) {
+                return Ok(false);
+            }
+            let value = read_to_end(s)?;
+            custom_tlvs.push((typ, value));
+            Ok::<bool, DecodeError>(true)
+        };
+        let rewind = |_, _| unreachable!();
+        _decode_tlv_stream_range!(r, .., rewind, {
             (1, networks, option),
             (3, remote_network_address, option)
-        });
-        */
+        }, read_custom_tlv);
         Ok(Init {
             global_features,
             features,
+            networks: networks.map(|n| n.0),
+            remote_network_address,
+            custom_tlvs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::wire::{self, Message};
+    use crate::util::ser::Hostname;
+    use proptest::prelude::*;
+    use std::io::Cursor;
+
+    fn decode(bytes: &[u8]) -> Result<Message<()>, DecodeError> {
+        let mut cursor = Cursor::new(bytes);
+        wire::read(&mut cursor, |_, _| Ok(None)).map_err(|(e, _)| e)
+    }
+
+    fn encode<M: wire::Type + Writeable>(msg: &M) -> Vec<u8> {
+        let mut buf = Vec::new();
+        wire::write(msg, &mut buf).unwrap();
+        buf
+    }
+
+    fn tlv(typ: u64, value: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        BigSize(typ).write(&mut buf).unwrap();
+        BigSize(value.len() as u64).write(&mut buf).unwrap();
+        buf.extend_from_slice(value);
+        buf
+    }
+
+    fn bare_init() -> Vec<u8> {
+        encode(&Init {
+            global_features: vec![],
+            features: vec![],
             networks: None,
             remote_network_address: None,
+            custom_tlvs: vec![],
         })
     }
+
+    fn socket_address() -> impl Strategy<Value = SocketAddress> {
+        prop_oneof![
+            (any::<[u8; 4]>(), any::<u16>())
+                .prop_map(|(addr, port)| SocketAddress::TcpIpV4 { addr, port }),
+            (any::<[u8; 16]>(), any::<u16>())
+                .prop_map(|(addr, port)| SocketAddress::TcpIpV6 { addr, port }),
+            ("[a-z0-9.-]{1,64}", any::<u16>()).prop_map(|(hostname, port)| {
+                SocketAddress::Hostname {
+                    hostname: Hostname::try_from(hostname).unwrap(),
+                    port,
+                }
+            }),
+        ]
+    }
+
+    fn init() -> impl Strategy<Value = Init> {
+        (
+            proptest::collection::vec(any::<u8>(), 0..8),
+            proptest::collection::vec(any::<u8>(), 0..16),
+            proptest::option::of(proptest::collection::vec(any::<[u8; 32]>(), 0..4)),
+            proptest::option::of(socket_address()),
+            proptest::collection::btree_map(
+                (2u64..u64::MAX / 2).prop_map(|t| t * 2 + 1),
+                proptest::collection::vec(any::<u8>(), 0..16),
+                0..4,
+            ),
+        )
+            .prop_map(
+                |(global_features, features, networks, remote_network_address, custom_tlvs)| Init {
+                    global_features,
+                    features,
+                    networks: networks.map(|n| n.into_iter().map(ChainHash::from).collect()),
+                    remote_network_address,
+                    custom_tlvs: custom_tlvs.into_iter().collect(),
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn test_init_roundtrip(msg in init()) {
+            match decode(&encode(&msg)) {
+                Ok(Message::Init(decoded)) => prop_assert_eq!(decoded, msg),
+                other => prop_assert!(false, "unexpected {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_error_roundtrip(channel_id in any::<[u8; 32]>(), data in ".{0,128}") {
+            let msg = ErrorMessage { channel_id: ChannelId(channel_id), data };
+            match decode(&encode(&msg)) {
+                Ok(Message::Error(decoded)) => prop_assert_eq!(decoded, msg),
+                other => prop_assert!(false, "unexpected {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_warning_roundtrip(channel_id in any::<[u8; 32]>(), data in ".{0,128}") {
+            let msg = WarningMessage { channel_id: ChannelId(channel_id), data };
+            match decode(&encode(&msg)) {
+                Ok(Message::Warning(decoded)) => prop_assert_eq!(decoded, msg),
+                other => prop_assert!(false, "unexpected {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_ping_roundtrip(ponglen in any::<u16>(), byteslen in 0u16..2048) {
+            let msg = Ping { ponglen, byteslen };
+            match decode(&encode(&msg)) {
+                Ok(Message::Ping(decoded)) => prop_assert_eq!(decoded, msg),
+                other => prop_assert!(false, "unexpected {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_pong_roundtrip(byteslen in 0u16..2048) {
+            let msg = Pong { byteslen };
+            match decode(&encode(&msg)) {
+                Ok(Message::Pong(decoded)) => prop_assert_eq!(decoded, msg),
+                other => prop_assert!(false, "unexpected {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_peer_storage_roundtrip(data in proptest::collection::vec(any::<u8>(), 0..4096)) {
+            let msg = PeerStorage { data: data.clone() };
+            match decode(&encode(&msg)) {
+                Ok(Message::PeerStorage(decoded)) => prop_assert_eq!(decoded, msg),
+                other => prop_assert!(false, "unexpected {:?}", other),
+            }
+            let msg = PeerStorageRetrieval { data };
+            match decode(&encode(&msg)) {
+                Ok(Message::PeerStorageRetrieval(decoded)) => prop_assert_eq!(decoded, msg),
+                other => prop_assert!(false, "unexpected {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_gossip_timestamp_filter_roundtrip(chain in any::<[u8; 32]>(), first_timestamp in any::<u32>(), timestamp_range in any::<u32>()) {
+            let msg = GossipTimestampFilter { chain_hash: ChainHash::from(chain), first_timestamp, timestamp_range };
+            match decode(&encode(&msg)) {
+                Ok(Message::GossipTimestampFilter(decoded)) => prop_assert_eq!(decoded, msg),
+                other => prop_assert!(false, "unexpected {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_init_unknown_tlvs(typ in 4u64..u64::MAX, value in proptest::collection::vec(any::<u8>(), 0..32)) {
+            let mut bytes = bare_init();
+            bytes.extend(tlv(typ, &value));
+            let res = decode(&bytes);
+            // it's ok to be odd
+            if typ % 2 == 0 {
+                prop_assert_eq!(res.unwrap_err(), DecodeError::UnknownRequiredFeature);
+            } else {
+                match res {
+                    Ok(Message::Init(init)) => prop_assert_eq!(init.custom_tlvs, vec![(typ, value)]),
+                    other => prop_assert!(false, "unexpected {:?}", other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_init_tlv_ordering() {
+        let network = ChainHash::BITCOIN.to_bytes();
+        let addr = SocketAddress::TcpIpV4 {
+            addr: [127, 0, 0, 1],
+            port: 9735,
+        }
+        .encode();
+
+        // in order is fine
+        let mut bytes = bare_init();
+        bytes.extend(tlv(1, &network));
+        bytes.extend(tlv(3, &addr));
+        assert!(matches!(decode(&bytes), Ok(Message::Init(_))));
+
+        // out of order
+        let mut bytes = bare_init();
+        bytes.extend(tlv(3, &addr));
+        bytes.extend(tlv(1, &network));
+        assert_eq!(decode(&bytes).unwrap_err(), DecodeError::InvalidValue);
+
+        // duplicates
+        let mut bytes = bare_init();
+        bytes.extend(tlv(1, &network));
+        bytes.extend(tlv(1, &network));
+        assert_eq!(decode(&bytes).unwrap_err(), DecodeError::InvalidValue);
+
+        // a length that runs past the end of the message
+        let mut bytes = bare_init();
+        bytes.extend(tlv(1, &network));
+        bytes.truncate(bytes.len() - 1);
+        assert_eq!(decode(&bytes).unwrap_err(), DecodeError::ShortRead);
+    }
 }
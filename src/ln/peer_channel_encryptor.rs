@@ -7,7 +7,7 @@
 // You may not use this file except in accordance with one or both of these
 // licenses.
 
-//use crate::prelude::*;
+use crate::prelude::*;
 
 use crate::ln::msgs;
 use crate::ln::msgs::LightningError;
@@ -48,7 +48,7 @@ const NOISE_H: [u8; 32] = [
 enum NoiseStep {
     PreActOne,
     PostActOne,
-    //PostActTwo,
+    PostActTwo,
     // When done swap noise_state for NoiseState::Finished
 }
 
@@ -57,14 +57,14 @@ struct BidirectionalNoiseState {
     ck: [u8; 32],
 }
 enum DirectionalNoiseState {
-    Outbound { ie: SecretKey },
-    /*
+    Outbound {
+        ie: SecretKey,
+    },
     Inbound {
         ie: Option<PublicKey>,     // filled in if state >= PostActOne
         re: Option<SecretKey>,     // filled in if state >= PostActTwo
         temp_k2: Option<[u8; 32]>, // filled in if state >= PostActTwo
     },
-    */
 }
 enum NoiseState {
     InProgress {
@@ -108,6 +108,36 @@ impl PeerChannelEncryptor {
         }
     }
 
+    pub fn new_inbound<C: Signing>(
+        secp_ctx: &Secp256k1<C>,
+        our_node_secret: &SecretKey,
+    ) -> PeerChannelEncryptor {
+        let mut sha = Sha256::engine();
+        sha.input(&NOISE_H);
+        let our_node_id = our_node_secret.public_key(secp_ctx);
+        sha.input(&our_node_id.serialize()[..]);
+        let h = Sha256::from_engine(sha).to_byte_array();
+
+        PeerChannelEncryptor {
+            their_node_id: None,
+            noise_state: NoiseState::InProgress {
+                state: NoiseStep::PreActOne,
+                directional_state: DirectionalNoiseState::Inbound {
+                    ie: None,
+                    re: None,
+                    temp_k2: None,
+                },
+                bidirectional_state: BidirectionalNoiseState { h, ck: NOISE_CK },
+            },
+        }
+    }
+
+    /// The peer's node id. Always known for outbound connections, and for inbound ones once
+    /// act three has been processed.
+    pub fn their_node_id(&self) -> Option<PublicKey> {
+        self.their_node_id
+    }
+
     #[inline]
     fn encrypt_with_ad(res: &mut [u8], n: u64, key: &[u8; 32], h: &[u8], plaintext: &[u8]) {
         let mut nonce = [0; 12];
@@ -296,15 +326,13 @@ impl PeerChannelEncryptor {
                     );
                     *state = NoiseStep::PostActOne;
                     res
-                } //_ => panic!("Wrong direction for act"),
+                }
+                _ => panic!("Wrong direction for act"),
             },
             _ => panic!("Cannot get act one after noise handshake completes"),
         }
     }
-    /*
-    // TODO: inbound
-
-    pub fn _process_act_one_with_keys<C: secp256k1::Signing>(
+    pub fn process_act_one_with_keys<C: secp256k1::Signing>(
         &mut self,
         act_one: &[u8],
         node_signer: &SecretKey,
@@ -352,7 +380,6 @@ impl PeerChannelEncryptor {
             _ => panic!("Cannot get act one after noise handshake completes"),
         }
     }
-        */
 
     pub fn process_act_two<C: Signing>(
         &mut self,
@@ -405,7 +432,8 @@ impl PeerChannelEncryptor {
                     final_hkdf = hkdf_extract_expand_twice(&bidirectional_state.ck, &[0; 0]);
                     ck = bidirectional_state.ck;
                     res
-                } //_ => panic!("Wrong direction for act"),
+                }
+                _ => panic!("Wrong direction for act"),
             },
             _ => panic!("Cannot get act one after noise handshake completes"),
         };
@@ -423,87 +451,81 @@ impl PeerChannelEncryptor {
         Ok(res)
     }
 
-    /*
-        pub fn process_act_three(&mut self, act_three: &[u8]) -> Result<PublicKey, LightningError> {
-            assert_eq!(act_three.len(), 66);
-
-            let final_hkdf;
-            let ck;
-            match self.noise_state {
-                NoiseState::InProgress {
-                    ref state,
-                    ref directional_state,
-                    ref mut bidirectional_state,
-                } => match directional_state {
-                    &DirectionalNoiseState::Inbound {
-                        ie: _,
-                        ref re,
-                        ref temp_k2,
-                    } => {
-                        if *state != NoiseStep::PostActTwo {
-                            panic!("Requested act at wrong step");
-                        }
-                        if act_three[0] != 0 {
+    pub fn process_act_three(&mut self, act_three: &[u8]) -> Result<PublicKey, LightningError> {
+        assert_eq!(act_three.len(), 66);
+
+        let final_hkdf;
+        let ck;
+        match self.noise_state {
+            NoiseState::InProgress {
+                ref state,
+                ref directional_state,
+                ref mut bidirectional_state,
+            } => match directional_state {
+                DirectionalNoiseState::Inbound { ie: _, re, temp_k2 } => {
+                    if *state != NoiseStep::PostActTwo {
+                        panic!("Requested act at wrong step");
+                    }
+                    if act_three[0] != 0 {
+                        return Err(LightningError {
+                            err: format!("Unknown handshake version number {}", act_three[0]),
+                            action: msgs::ErrorAction::DisconnectPeer { msg: None },
+                        });
+                    }
+
+                    let mut their_node_id = [0; 33];
+                    PeerChannelEncryptor::decrypt_with_ad(
+                        &mut their_node_id,
+                        1,
+                        &temp_k2.unwrap(),
+                        &bidirectional_state.h,
+                        &act_three[1..50],
+                    )?;
+                    self.their_node_id = Some(match PublicKey::from_slice(&their_node_id) {
+                        Ok(key) => key,
+                        Err(_) => {
                             return Err(LightningError {
-                                err: format!("Unknown handshake version number {}", act_three[0]),
+                                err: format!("Bad node_id from peer, {}", &their_node_id.as_hex()),
                                 action: msgs::ErrorAction::DisconnectPeer { msg: None },
                             });
                         }
+                    });
 
-                        let mut their_node_id = [0; 33];
-                        PeerChannelEncryptor::decrypt_with_ad(
-                            &mut their_node_id,
-                            1,
-                            &temp_k2.unwrap(),
-                            &bidirectional_state.h,
-                            &act_three[1..50],
-                        )?;
-                        self.their_node_id = Some(match PublicKey::from_slice(&their_node_id) {
-                            Ok(key) => key,
-                            Err(_) => {
-                                return Err(LightningError {
-                                    err: format!("Bad node_id from peer, {}", &their_node_id.as_hex()),
-                                    action: msgs::ErrorAction::DisconnectPeer { msg: None },
-                                });
-                            }
-                        });
-
-                        let mut sha = Sha256::engine();
-                        sha.input(&bidirectional_state.h);
-                        sha.input(&act_three[1..50]);
-                        bidirectional_state.h = Sha256::from_engine(sha).to_byte_array();
-
-                        let ss = SharedSecret::new(&self.their_node_id.unwrap(), &re.unwrap());
-                        let temp_k = PeerChannelEncryptor::hkdf(bidirectional_state, ss);
-
-                        PeerChannelEncryptor::decrypt_with_ad(
-                            &mut [0; 0],
-                            0,
-                            &temp_k,
-                            &bidirectional_state.h,
-                            &act_three[50..],
-                        )?;
-                        final_hkdf = hkdf_extract_expand_twice(&bidirectional_state.ck, &[0; 0]);
-                        ck = bidirectional_state.ck.clone();
-                    }
-                    _ => panic!("Wrong direction for act"),
-                },
-                _ => panic!("Cannot get act one after noise handshake completes"),
-            }
+                    let mut sha = Sha256::engine();
+                    sha.input(&bidirectional_state.h);
+                    sha.input(&act_three[1..50]);
+                    bidirectional_state.h = Sha256::from_engine(sha).to_byte_array();
 
-            let (rk, sk) = final_hkdf;
-            self.noise_state = NoiseState::Finished {
-                sk,
-                sn: 0,
-                sck: ck.clone(),
-                rk,
-                rn: 0,
-                rck: ck,
-            };
+                    let ss = SharedSecret::new(&self.their_node_id.unwrap(), &re.unwrap());
+                    let temp_k = PeerChannelEncryptor::hkdf(bidirectional_state, ss);
 
-            Ok(self.their_node_id.unwrap().clone())
+                    PeerChannelEncryptor::decrypt_with_ad(
+                        &mut [0; 0],
+                        0,
+                        &temp_k,
+                        &bidirectional_state.h,
+                        &act_three[50..],
+                    )?;
+                    final_hkdf = hkdf_extract_expand_twice(&bidirectional_state.ck, &[0; 0]);
+                    ck = bidirectional_state.ck;
+                }
+                _ => panic!("Wrong direction for act"),
+            },
+            _ => panic!("Cannot get act one after noise handshake completes"),
         }
-    */
+
+        let (rk, sk) = final_hkdf;
+        self.noise_state = NoiseState::Finished {
+            sk,
+            sn: 0,
+            sck: ck,
+            rk,
+            rn: 0,
+            rck: ck,
+        };
+
+        Ok(self.their_node_id.unwrap())
+    }
 
     /// Builds sendable bytes for a message.
     ///
@@ -551,6 +573,61 @@ impl PeerChannelEncryptor {
         }
     }
 
+    /// How many more messages can be sent before the sending key rotates. Zero means the next
+    /// message rotates it.
+    pub fn messages_until_rekey(&self) -> u64 {
+        match self.noise_state {
+            // each message takes two nonces, one for the length header and one for the body
+            NoiseState::Finished { sn, .. } => 1000u64.saturating_sub(sn) / 2,
+            _ => panic!("Tried to check the sending nonce prior to noise handshake completion"),
+        }
+    }
+
+    /// Splits a finished encryptor into one for sending and one for receiving, in that order.
+    ///
+    /// Each keeps only its own direction's keys and has the other's zeroed, so it must only be
+    /// used in that direction. panics if the Noise handshake has not finished.
+    pub fn split(self) -> (PeerChannelEncryptor, PeerChannelEncryptor) {
+        match self.noise_state {
+            NoiseState::Finished {
+                sk,
+                sn,
+                sck,
+                rk,
+                rn,
+                rck,
+            } => {
+                let sending = NoiseState::Finished {
+                    sk,
+                    sn,
+                    sck,
+                    rk: [0; 32],
+                    rn: 0,
+                    rck: [0; 32],
+                };
+                let receiving = NoiseState::Finished {
+                    sk: [0; 32],
+                    sn: 0,
+                    sck: [0; 32],
+                    rk,
+                    rn,
+                    rck,
+                };
+                (
+                    PeerChannelEncryptor {
+                        their_node_id: self.their_node_id,
+                        noise_state: sending,
+                    },
+                    PeerChannelEncryptor {
+                        their_node_id: self.their_node_id,
+                        noise_state: receiving,
+                    },
+                )
+            }
+            _ => panic!("Tried to split the encryptor prior to noise handshake completion"),
+        }
+    }
+
     /*
     /// Encrypts the given pre-serialized message, returning the encrypted version.
     /// panics if msg.len() > 65535 or Noise handshake has not finished.
@@ -8,13 +8,40 @@
 // licenses.
 
 //! Wire encoding/decoding for Lightning messages according to [BOLT #1], and for
-//! custom message through the [`CustomMessageReader`] trait.
+//! custom messages.
+//!
+//! A custom message is a type implementing [`Type`] and [`Writeable`], which
+//! [`LNSocket::write`](crate::LNSocket::write) sends. Incoming ones are decoded by a handler
+//! passed to [`LNSocket::read_custom`](crate::LNSocket::read_custom), which can use
+//! [`Readable`] for the fields.
+//!
+//! ### Example
+//! ```
+//! use lnsocket::ln::wire::{Type, Writeable, Writer};
+//!
+//! struct Hello(u32);
+//!
+//! impl Type for Hello {
+//!     fn type_id(&self) -> u16 {
+//!         32801
+//!     }
+//! }
+//!
+//! impl Writeable for Hello {
+//!     fn write<W: Writer>(&self, w: &mut W) -> Result<(), std::io::Error> {
+//!         self.0.write(w)
+//!     }
+//! }
+//!
+//! assert_eq!(Hello(7).encode(), [0, 0, 0, 7]);
+//! ```
 //!
 //! [BOLT #1]: https://github.com/lightning/bolts/blob/master/01-messaging.md
 
+use crate::io;
 use crate::ln::msgs;
-use crate::util::ser::{LengthLimitedRead, LengthReadable, Readable, Writeable, Writer};
-use std::io;
+use crate::util::ser::{LengthLimitedRead, LengthReadable};
+pub use crate::util::ser::{Readable, Writeable, Writer};
 
 // TestEq is a dummy trait which requires PartialEq when built in testing, and otherwise is
 // blanket-implemented for all types.
@@ -22,6 +49,7 @@ use std::io;
 /// A Lightning message returned by [`read`] when decoding bytes received over the wire. Each
 /// variant contains a message from [`msgs`] or otherwise the message type if unknown.
 #[allow(missing_docs)]
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum Message<T> {
     Init(msgs::Init),
@@ -29,10 +57,20 @@ pub enum Message<T> {
     Warning(msgs::WarningMessage),
     Ping(msgs::Ping),
     Pong(msgs::Pong),
+    PeerStorage(msgs::PeerStorage),
+    PeerStorageRetrieval(msgs::PeerStorageRetrieval),
+    ChannelAnnouncement(msgs::ChannelAnnouncement),
+    NodeAnnouncement(msgs::NodeAnnouncement),
+    ChannelUpdate(msgs::ChannelUpdate),
+    GossipTimestampFilter(msgs::GossipTimestampFilter),
+    QueryShortChannelIds(msgs::QueryShortChannelIds),
+    ReplyShortChannelIdsEnd(msgs::ReplyShortChannelIdsEnd),
+    QueryChannelRange(msgs::QueryChannelRange),
+    ReplyChannelRange(msgs::ReplyChannelRange),
     /// A message that could not be decoded because its type is unknown.
     Unknown(u16),
-    /// A message that was produced by a [`CustomMessageReader`] and is to be handled by a
-    /// [`crate::ln::peer_handler::CustomMessageHandler`].
+    /// A message decoded by the custom message handler, see
+    /// [`LNSocket::read_custom`](crate::LNSocket::read_custom).
     Custom(T),
 }
 
@@ -44,6 +82,16 @@ impl<T: core::fmt::Debug + Type + Writeable> Writeable for Message<T> {
             Message::Warning(msg) => msg.write(writer),
             Message::Ping(msg) => msg.write(writer),
             Message::Pong(msg) => msg.write(writer),
+            Message::PeerStorage(msg) => msg.write(writer),
+            Message::PeerStorageRetrieval(msg) => msg.write(writer),
+            Message::ChannelAnnouncement(msg) => msg.write(writer),
+            Message::NodeAnnouncement(msg) => msg.write(writer),
+            Message::ChannelUpdate(msg) => msg.write(writer),
+            Message::GossipTimestampFilter(msg) => msg.write(writer),
+            Message::QueryShortChannelIds(msg) => msg.write(writer),
+            Message::ReplyShortChannelIdsEnd(msg) => msg.write(writer),
+            Message::QueryChannelRange(msg) => msg.write(writer),
+            Message::ReplyChannelRange(msg) => msg.write(writer),
             Message::Unknown(_) => Ok(()),
             Message::Custom(msg) => msg.write(writer),
         }
@@ -59,12 +107,48 @@ impl<T: core::fmt::Debug + Type> Type for Message<T> {
             Message::Warning(msg) => msg.type_id(),
             Message::Ping(msg) => msg.type_id(),
             Message::Pong(msg) => msg.type_id(),
+            Message::PeerStorage(msg) => msg.type_id(),
+            Message::PeerStorageRetrieval(msg) => msg.type_id(),
+            Message::ChannelAnnouncement(msg) => msg.type_id(),
+            Message::NodeAnnouncement(msg) => msg.type_id(),
+            Message::ChannelUpdate(msg) => msg.type_id(),
+            Message::GossipTimestampFilter(msg) => msg.type_id(),
+            Message::QueryShortChannelIds(msg) => msg.type_id(),
+            Message::ReplyShortChannelIdsEnd(msg) => msg.type_id(),
+            Message::QueryChannelRange(msg) => msg.type_id(),
+            Message::ReplyChannelRange(msg) => msg.type_id(),
             Message::Unknown(type_id) => *type_id,
             Message::Custom(msg) => msg.type_id(),
         }
     }
 }
 
+impl<T> Message<T> {
+    /// Convert the custom payload with `f`, leaving every other variant as it is.
+    #[cfg(feature = "std")]
+    pub(crate) fn map_custom<U>(self, f: impl FnOnce(T) -> U) -> Message<U> {
+        match self {
+            Message::Init(a) => Message::Init(a),
+            Message::Error(a) => Message::Error(a),
+            Message::Warning(a) => Message::Warning(a),
+            Message::Ping(a) => Message::Ping(a),
+            Message::Pong(a) => Message::Pong(a),
+            Message::PeerStorage(a) => Message::PeerStorage(a),
+            Message::PeerStorageRetrieval(a) => Message::PeerStorageRetrieval(a),
+            Message::ChannelAnnouncement(a) => Message::ChannelAnnouncement(a),
+            Message::NodeAnnouncement(a) => Message::NodeAnnouncement(a),
+            Message::ChannelUpdate(a) => Message::ChannelUpdate(a),
+            Message::GossipTimestampFilter(a) => Message::GossipTimestampFilter(a),
+            Message::QueryShortChannelIds(a) => Message::QueryShortChannelIds(a),
+            Message::ReplyShortChannelIdsEnd(a) => Message::ReplyShortChannelIdsEnd(a),
+            Message::QueryChannelRange(a) => Message::QueryChannelRange(a),
+            Message::ReplyChannelRange(a) => Message::ReplyChannelRange(a),
+            Message::Unknown(unk) => Message::Unknown(unk),
+            Message::Custom(custom) => Message::Custom(f(custom)),
+        }
+    }
+}
+
 impl<T: core::fmt::Debug + Type> Message<T> {
     /// Returns whether the message's type is even, indicating both endpoints must support it.
     pub fn is_even(&self) -> bool {
@@ -72,6 +156,47 @@ impl<T: core::fmt::Debug + Type> Message<T> {
     }
 }
 
+/// A message type that can be picked out of a [`Message`], see
+/// [`LNSocket::wait_for`](crate::LNSocket::wait_for).
+pub trait FromMessage: Sized {
+    /// The message if it's one of these, or `msg` back if not.
+    #[allow(clippy::result_large_err)]
+    fn from_message<T>(msg: Message<T>) -> Result<Self, Message<T>>;
+}
+
+macro_rules! impl_from_message {
+    ($($variant:ident($ty:ty)),* $(,)?) => {
+        $(
+            impl FromMessage for $ty {
+                fn from_message<T>(msg: Message<T>) -> Result<Self, Message<T>> {
+                    match msg {
+                        Message::$variant(msg) => Ok(msg),
+                        other => Err(other),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_from_message!(
+    Init(msgs::Init),
+    Error(msgs::ErrorMessage),
+    Warning(msgs::WarningMessage),
+    Ping(msgs::Ping),
+    Pong(msgs::Pong),
+    PeerStorage(msgs::PeerStorage),
+    PeerStorageRetrieval(msgs::PeerStorageRetrieval),
+    ChannelAnnouncement(msgs::ChannelAnnouncement),
+    NodeAnnouncement(msgs::NodeAnnouncement),
+    ChannelUpdate(msgs::ChannelUpdate),
+    GossipTimestampFilter(msgs::GossipTimestampFilter),
+    QueryShortChannelIds(msgs::QueryShortChannelIds),
+    ReplyShortChannelIdsEnd(msgs::ReplyShortChannelIdsEnd),
+    QueryChannelRange(msgs::QueryChannelRange),
+    ReplyChannelRange(msgs::ReplyChannelRange),
+);
+
 /// Reads a message from the data buffer consisting of a 2-byte big-endian type and a
 /// variable-length payload conforming to the type.
 ///
@@ -116,6 +241,36 @@ where
         msgs::Pong::TYPE => Ok(Message::Pong(
             LengthReadable::read_from_fixed_length_buffer(buffer)?,
         )),
+        msgs::PeerStorage::TYPE => Ok(Message::PeerStorage(
+            LengthReadable::read_from_fixed_length_buffer(buffer)?,
+        )),
+        msgs::PeerStorageRetrieval::TYPE => Ok(Message::PeerStorageRetrieval(
+            LengthReadable::read_from_fixed_length_buffer(buffer)?,
+        )),
+        msgs::ChannelAnnouncement::TYPE => Ok(Message::ChannelAnnouncement(
+            LengthReadable::read_from_fixed_length_buffer(buffer)?,
+        )),
+        msgs::NodeAnnouncement::TYPE => Ok(Message::NodeAnnouncement(
+            LengthReadable::read_from_fixed_length_buffer(buffer)?,
+        )),
+        msgs::ChannelUpdate::TYPE => Ok(Message::ChannelUpdate(
+            LengthReadable::read_from_fixed_length_buffer(buffer)?,
+        )),
+        msgs::GossipTimestampFilter::TYPE => Ok(Message::GossipTimestampFilter(
+            LengthReadable::read_from_fixed_length_buffer(buffer)?,
+        )),
+        msgs::QueryShortChannelIds::TYPE => Ok(Message::QueryShortChannelIds(
+            LengthReadable::read_from_fixed_length_buffer(buffer)?,
+        )),
+        msgs::ReplyShortChannelIdsEnd::TYPE => Ok(Message::ReplyShortChannelIdsEnd(
+            LengthReadable::read_from_fixed_length_buffer(buffer)?,
+        )),
+        msgs::QueryChannelRange::TYPE => Ok(Message::QueryChannelRange(
+            LengthReadable::read_from_fixed_length_buffer(buffer)?,
+        )),
+        msgs::ReplyChannelRange::TYPE => Ok(Message::ReplyChannelRange(
+            LengthReadable::read_from_fixed_length_buffer(buffer)?,
+        )),
         _ => {
             if let Some(custom) = custom_reader(message_type, buffer)? {
                 Ok(Message::Custom(custom))
@@ -189,3 +344,43 @@ impl Encode for msgs::Ping {
 impl Encode for msgs::Pong {
     const TYPE: u16 = 19;
 }
+
+impl Encode for msgs::PeerStorage {
+    const TYPE: u16 = 7;
+}
+
+impl Encode for msgs::PeerStorageRetrieval {
+    const TYPE: u16 = 9;
+}
+
+impl Encode for msgs::ChannelAnnouncement {
+    const TYPE: u16 = 256;
+}
+
+impl Encode for msgs::NodeAnnouncement {
+    const TYPE: u16 = 257;
+}
+
+impl Encode for msgs::ChannelUpdate {
+    const TYPE: u16 = 258;
+}
+
+impl Encode for msgs::QueryShortChannelIds {
+    const TYPE: u16 = 261;
+}
+
+impl Encode for msgs::ReplyShortChannelIdsEnd {
+    const TYPE: u16 = 262;
+}
+
+impl Encode for msgs::QueryChannelRange {
+    const TYPE: u16 = 263;
+}
+
+impl Encode for msgs::ReplyChannelRange {
+    const TYPE: u16 = 264;
+}
+
+impl Encode for msgs::GossipTimestampFilter {
+    const TYPE: u16 = 265;
+}
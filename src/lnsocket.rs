@@ -1,23 +1,129 @@
 use crate::{
     Error,
+    event::{DisconnectReason, Event, RemoteNotice},
+    features::{InitFeatures, bits},
+    gossip::{AddressBook, dedup::GossipDedup},
+    init::{InitOptions, PeerInfo},
     ln::{
         msgs::{self, DecodeError},
         peer_channel_encryptor::PeerChannelEncryptor,
-        wire::{self, Message},
+        types::ChannelId,
+        wire::{self, Encode, FromMessage, Message},
     },
+    ping::{PingPolicy, PingResponder, PingResponse},
+    record::{Direction, Recorder},
+    rekey::{Filler, RekeyPolicy, SentCounter},
+    sans_io::{
+        self, ACT_ONE_SIZE, ACT_THREE_SIZE, ACT_TWO_SIZE, FrameReader, Session, check_act_two,
+    },
+    timing::{ConnectTimings, Instant},
     util::ser::Writeable,
 };
-use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey, rand};
+#[cfg(feature = "tokio")]
+use crate::{
+    connect::ConnectOptions,
+    ratelimit::{RateLimit, RateLimiter},
+    recovery::Recovery,
+};
+#[cfg(feature = "tokio")]
+use bitcoin::Network;
+use bitcoin::secp256k1::{PublicKey, SecretKey, rand};
+use bytes::Bytes;
+use futures_util::{Sink, Stream, sink, stream};
+use std::any::Any;
+use std::collections::VecDeque;
 use std::io::{self, Cursor};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpSocket, TcpStream, lookup_host};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+#[cfg(feature = "tokio")]
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+#[cfg(feature = "tokio")]
+use tokio::time::{timeout, timeout_at};
+
+pub use crate::sans_io::ConnectionState;
+
+/// The port Lightning nodes listen on unless they say otherwise.
+pub const DEFAULT_PORT: u16 = 9735;
+
+/// A byte stream an [`LNSocket`] can run over.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// For getting the concrete stream back out of a `Box<dyn Transport>` with
+    /// [`Box::downcast`].
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send>;
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for T {
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send> {
+        self
+    }
+}
+
+/// Write what `session` has queued.
+async fn write_outgoing(stream: &mut impl Transport, session: &mut Session) -> io::Result<()> {
+    stream.write_all(session.outgoing()).await?;
+    session.consume(session.outgoing().len());
+    Ok(())
+}
+
+/// Read act two, reporting exactly what was wrong with it if it isn't usable.
+async fn read_act_two(stream: &mut impl Transport) -> Result<[u8; ACT_TWO_SIZE], Error> {
+    let mut act_two = [0u8; ACT_TWO_SIZE];
+    let mut got = 0;
+    while got < ACT_TWO_SIZE {
+        let n = stream.read(&mut act_two[got..]).await?;
+        if n == 0 {
+            break;
+        }
+        got += n;
+    }
+    check_act_two(&act_two, got)?;
+    Ok(act_two)
+}
 
-const ACT_TWO_SIZE: usize = 50;
+/// What [`LNSocket::close`] tells the peer on the way out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Send a BOLT 1 `warning`: something's off, but nothing needs to be done about channels.
+    Warning(String),
+    /// Send a BOLT 1 `error`. It's about the whole connection, so the peer should fail all
+    /// channels it has with us.
+    Error(String),
+}
+
+/// An [`LNSocket`] taken apart by [`LNSocket::into_parts`].
+///
+/// The encryptor's nonces advance with every frame, so to hand the connection back with
+/// [`LNSocket::from_parts`] after using it directly, every frame sent or received meanwhile
+/// must have gone through [`LNSocketParts::channel`].
+pub struct LNSocketParts {
+    /// The stream. [`Transport::into_any`] gets the concrete type back, e.g. to set socket
+    /// options on a `TcpStream`.
+    pub stream: Box<dyn Transport>,
+    /// The Noise state for the connection.
+    pub channel: PeerChannelEncryptor,
+    /// The peer's `init`, if it was received.
+    pub peer_info: Option<PeerInfo>,
+    /// Whether our `init` was sent.
+    pub sent_init: bool,
+    /// Messages already decrypted but not read yet, type included.
+    pub pending: Vec<Vec<u8>>,
+    /// Encrypted bytes of a frame a dropped read had started on. They belong to its length
+    /// header if [`LNSocketParts::body_len`] is `None`, to its body otherwise. Empty when the
+    /// stream is at a frame boundary.
+    pub partial: Vec<u8>,
+    /// The body length from the length header of that frame, once decrypted.
+    pub body_len: Option<usize>,
+}
 
 /// A Lightning Network TCP socket that performs the BOLT 8 Noise handshake and message encryption.
 ///
-/// [`LNSocket`] wraps a `tokio::net::TcpStream` with Noise state (via [`PeerChannelEncryptor`])
-/// to handle encrypted Lightning messages over TCP.
+/// [`LNSocket`] wraps a byte stream (normally a `tokio::net::TcpStream`, but any [`Transport`],
+/// see [`LNSocket::connect_over`]) with Noise state (via [`PeerChannelEncryptor`]) to handle
+/// encrypted Lightning messages.
 ///
 /// # Typical usage
 /// ```no_run
@@ -33,184 +139,2525 @@ const ACT_TWO_SIZE: usize = 50;
 /// # Ok(()) }
 /// ```
 ///
-/// ⚠️ This struct does **not** retry connections or manage reconnections.
+/// ⚠️ This struct does **not** retry connections or manage reconnections, apart from the
+/// opt-in [`Recovery`] from broken encryption.
 pub struct LNSocket {
     channel: PeerChannelEncryptor,
-    stream: TcpStream,
+    stream: Box<dyn Transport>,
+    pub(crate) events: Option<mpsc::UnboundedSender<Event>>,
+    pings: PingResponder,
+    sent_init: bool,
+    peer_info: Option<PeerInfo>,
+    pub(crate) peer_addr: Option<SocketAddr>,
+    #[cfg(feature = "tokio")]
+    read_timeout: Option<Duration>,
+    // decrypted frames to hand out before reading more: ones that arrived before the peer's
+    // init (see InitOptions::max_pre_init_messages), or while send_and_wait was waiting
+    pending: VecDeque<Vec<u8>>,
+    // the frame being read, kept here so a read dropped midway can pick up where it left off
+    frames: FrameReader,
+    // when the frame being read started arriving, for the read timeout
+    #[cfg(feature = "tokio")]
+    frame_started: Option<tokio::time::Instant>,
+    // answer pings inside reads, see set_auto_pong
+    auto_pong: bool,
+    // encrypted bytes of pongs written from a read that was dropped before they all went out
+    wbuf: Vec<u8>,
+    // whether Event::Disconnected was emitted
+    disconnected: bool,
+    address_book: Option<Arc<Mutex<AddressBook>>>,
+    gossip_dedup: Option<Arc<Mutex<GossipDedup>>>,
+    recorder: Option<Recorder>,
+    #[cfg(feature = "tokio")]
+    recovery: Option<Recovery>,
+    pub(crate) timings: ConnectTimings,
+    rekey: RekeyPolicy,
+    sent: SentCounter,
+    #[cfg(feature = "tokio")]
+    limiter: Option<RateLimiter>,
 }
 
 impl LNSocket {
     /// Connect to a Lightning peer and complete the BOLT 8 Noise handshake.
     ///
     /// Resolves the given `addr`, establishes a TCP connection, and performs act1/act2/act3
-    /// handshake using `our_key` and the peer’s public key.
+    /// handshake using `our_key` and the peer’s public key. `addr` is `host[:port]`, with the
+    /// port defaulting to [`DEFAULT_PORT`]; IPv6 hosts may be bracketed.
     ///
     /// Does **not** send or expect an `init` message.  
     /// Use [`LNSocket::connect_and_init`] if you want handshake + `init` exchange.
+    #[cfg(feature = "tokio")]
     pub async fn connect(
         our_key: SecretKey,
         their_pubkey: PublicKey,
         addr: &str,
     ) -> Result<LNSocket, Error> {
-        let secp_ctx = Secp256k1::signing_only();
+        Self::connect_with(&ConnectOptions::default(), our_key, their_pubkey, addr).await
+    }
 
-        // Look up host to resolve domain name to IP address
-        let addr = lookup_host(addr).await?.next().ok_or(Error::DnsError)?;
+    /// Like [`LNSocket::connect`], connecting as `opts` say.
+    ///
+    /// The resolved addresses are tried until a TCP connection succeeds, see
+    /// [`connect`](crate::connect). The error of the last attempt is returned if none does.
+    ///
+    /// With the `experimental` feature, `addr` may also be a `ws://` or `wss://` URL, see
+    /// [`websocket`](crate::websocket). `opts` doesn't apply to those. In browsers, with the
+    /// `wasm` feature, it must be one, see the `browser` module.
+    ///
+    /// `.b32.i2p` addresses go through the local I2P router, see [`i2p`](crate::i2p).
+    #[cfg(feature = "tokio")]
+    pub async fn connect_with(
+        opts: &ConnectOptions,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+    ) -> Result<LNSocket, Error> {
+        let lnsocket = Self::dial(opts, our_key, their_pubkey, addr).await?;
+        Ok(lnsocket.connected(opts))
+    }
 
-        let socket = if addr.is_ipv4() {
-            TcpSocket::new_v4()?
-        } else {
-            TcpSocket::new_v6()?
+    #[cfg(feature = "tokio")]
+    async fn dial(
+        opts: &ConnectOptions,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+    ) -> Result<LNSocket, Error> {
+        #[cfg(all(feature = "experimental", not(target_arch = "wasm32")))]
+        if crate::websocket::is_websocket_url(addr) {
+            return Self::connect_websocket(our_key, their_pubkey, addr).await;
+        }
+        #[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+        if crate::websocket::is_websocket_url(addr) {
+            return Self::connect_browser(our_key, their_pubkey, addr).await;
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            let _ = (opts, our_key, their_pubkey);
+            return Err(Error::InvalidUri(format!(
+                "{addr}: browsers can only connect to ws:// or wss:// URLs"
+            )));
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if crate::i2p::is_i2p_address(addr) {
+            let sam = crate::i2p::DEFAULT_SAM_BRIDGE;
+            return Self::connect_via_i2p(our_key, their_pubkey, addr, sam).await;
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(proxy) = &opts.socks_proxy {
+            return Self::connect_socks(opts, proxy, our_key, their_pubkey, addr).await;
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        Self::connect_tcp(opts, our_key, their_pubkey, addr).await
+    }
+
+    #[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+    async fn connect_socks(
+        opts: &ConnectOptions,
+        proxy: &str,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+    ) -> Result<LNSocket, Error> {
+        let start = Instant::now();
+        let credentials = opts.socks_isolation.credentials();
+        let connecting = crate::tor::socks5_connect(proxy, addr, credentials);
+        let stream = match opts.timeout {
+            Some(limit) => timeout(limit, connecting)
+                .await
+                .map_err(|_| Error::Timeout)??,
+            None => connecting.await?,
+        };
+        let tcp_connect = start.elapsed();
+        let mut lnsocket = Self::handshake_outbound(stream, our_key, their_pubkey).await?;
+        lnsocket.timings.tcp_connect = Some(tcp_connect);
+        Ok(lnsocket)
+    }
+
+    /// Like [`LNSocket::connect`], to addresses that are already parsed: a [`SocketAddr`], an
+    /// `(IpAddr, u16)`, a slice of them, or anything else implementing tokio's
+    /// [`ToSocketAddrs`](tokio::net::ToSocketAddrs). IP addresses skip DNS altogether.
+    #[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+    pub async fn connect_addr(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addrs: impl tokio::net::ToSocketAddrs,
+    ) -> Result<LNSocket, Error> {
+        Self::connect_addr_with(&ConnectOptions::default(), our_key, their_pubkey, addrs).await
+    }
+
+    /// Like [`LNSocket::connect_addr`], connecting as `opts` say.
+    ///
+    /// `opts.dns.preference` still filters and orders the addresses, but host names given
+    /// here are resolved by the system resolver rather than `opts.dns.resolver`, even with
+    /// `opts.socks_proxy` set. Pass them to [`LNSocket::connect_with`] for the proxy to
+    /// resolve them instead. Through a proxy, only the first address is tried.
+    #[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+    pub async fn connect_addr_with(
+        opts: &ConnectOptions,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addrs: impl tokio::net::ToSocketAddrs,
+    ) -> Result<LNSocket, Error> {
+        let resolving = async {
+            let addrs = opts
+                .dns
+                .preference
+                .apply(tokio::net::lookup_host(addrs).await?.collect());
+            if addrs.is_empty() {
+                return Err(Error::DnsError);
+            }
+            Ok(addrs)
+        };
+        let lnsocket = match &opts.socks_proxy {
+            Some(proxy) => {
+                let addr = resolving.await?[0].to_string();
+                Self::connect_socks(opts, proxy, our_key, their_pubkey, &addr).await?
+            }
+            None => Self::connect_resolving(opts, our_key, their_pubkey, resolving).await?,
+        };
+        Ok(lnsocket.connected(opts))
+    }
+
+    /// Hook up [`ConnectOptions::events`] to a socket that just finished the handshake.
+    #[cfg(feature = "tokio")]
+    fn connected(mut self, opts: &ConnectOptions) -> Self {
+        if let Some(events) = &opts.events {
+            self.events = Some(events.clone());
+            self.emit(Event::HandshakeCompleted);
+        }
+        self
+    }
+
+    #[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+    async fn connect_tcp(
+        opts: &ConnectOptions,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+    ) -> Result<LNSocket, Error> {
+        let resolving = crate::dns::resolve(addr, &opts.dns);
+        Self::connect_resolving(opts, our_key, their_pubkey, resolving).await
+    }
+
+    /// Connect to one of the addresses `resolving` comes up with.
+    #[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+    async fn connect_resolving(
+        opts: &ConnectOptions,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        resolving: impl Future<Output = Result<Vec<SocketAddr>, Error>>,
+    ) -> Result<LNSocket, Error> {
+        let connecting = async {
+            // Look up host to resolve domain name to IP address
+            let start = Instant::now();
+            let addrs = resolving.await?;
+            let dns = start.elapsed();
+
+            let start = Instant::now();
+            let (stream, addr) = crate::connect::connect_tcp(addrs, opts).await?;
+            Ok::<_, Error>((stream, addr, dns, start.elapsed()))
+        };
+        let (stream, addr, dns, tcp_connect) = match opts.timeout {
+            Some(limit) => timeout(limit, connecting)
+                .await
+                .map_err(|_| Error::Timeout)??,
+            None => connecting.await?,
         };
+        let mut lnsocket = Self::handshake_outbound(stream, our_key, their_pubkey).await?;
+        lnsocket.peer_addr = Some(addr);
+        lnsocket.timings.dns = Some(dns);
+        lnsocket.timings.tcp_connect = Some(tcp_connect);
+        Ok(lnsocket)
+    }
+
+    /// Like [`LNSocket::connect`], to a node given as `node_id@host[:port]`.
+    #[cfg(feature = "tokio")]
+    pub async fn connect_uri(our_key: SecretKey, uri: &str) -> Result<LNSocket, Error> {
+        let (node_id, addr) = uri
+            .split_once('@')
+            .ok_or_else(|| Error::InvalidUri(uri.to_owned()))?;
+        let their_pubkey = node_id
+            .parse()
+            .map_err(|_| Error::InvalidUri(uri.to_owned()))?;
+        Self::connect(our_key, their_pubkey, addr).await
+    }
+
+    /// Like [`LNSocket::connect`], over a TCP connection the caller dialed themselves, or got
+    /// from socket activation. Only the handshake is done.
+    ///
+    /// Unlike [`LNSocket::connect_over`], this knows the peer's address, so `init` can echo it
+    /// (see [`InitOptions::echo_remote_address`]).
+    #[cfg(all(feature = "tokio", not(target_arch = "wasm32")))]
+    pub async fn from_stream(
+        stream: tokio::net::TcpStream,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+    ) -> Result<LNSocket, Error> {
+        let peer_addr = stream.peer_addr()?;
+        let mut lnsocket = Self::handshake_outbound(stream, our_key, their_pubkey).await?;
+        lnsocket.peer_addr = Some(peer_addr);
+        Ok(lnsocket)
+    }
+
+    /// Like [`LNSocket::connect`], over a stream the caller already opened: a TLS tunnel, an
+    /// in-memory pipe, a proxy this crate doesn't know about. Any [`Transport`] will do.
+    pub async fn connect_over(
+        stream: impl Transport,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+    ) -> Result<LNSocket, Error> {
+        Self::handshake_outbound(stream, our_key, their_pubkey).await
+    }
+
+    /// The other side of [`LNSocket::connect_over`]: the responder's handshake, for a peer that
+    /// connected to us over `stream`.
+    pub async fn accept_over(
+        stream: impl Transport,
+        our_key: SecretKey,
+    ) -> Result<LNSocket, Error> {
+        Self::handshake_inbound(stream, our_key).await
+    }
 
-        let mut stream = socket.connect(addr).await?;
+    /// Perform the initiator side of the Noise handshake over an already connected stream.
+    ///
+    /// The handshake itself is a [`Session`]'s, this only moves its acts over `stream`.
+    pub(crate) async fn handshake_outbound(
+        mut stream: impl Transport + 'static,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+    ) -> Result<LNSocket, Error> {
         let ephemeral = SecretKey::new(&mut rand::thread_rng());
+        let mut session = Session::outbound(our_key, their_pubkey, ephemeral);
 
-        let mut channel = PeerChannelEncryptor::new_outbound(their_pubkey, ephemeral);
-        let act_one = channel.get_act_one(&secp_ctx);
-        stream.write_all(&act_one).await?;
+        let mut timings = ConnectTimings::default();
+        let start = Instant::now();
+        write_outgoing(&mut stream, &mut session).await?;
+        timings.act_one = Some(start.elapsed());
 
-        let mut act_two = [0u8; ACT_TWO_SIZE];
-        stream.read_exact(&mut act_two).await?;
-        let act_three = channel.process_act_two(&secp_ctx, &act_two, &our_key)?;
+        let start = Instant::now();
+        let act_two = read_act_two(&mut stream).await?;
+        session.receive(&act_two)?;
+        timings.act_two = Some(start.elapsed());
 
         // Finalize the handshake by sending act3
-        stream.write_all(&act_three).await?;
+        let start = Instant::now();
+        write_outgoing(&mut stream, &mut session).await?;
+        timings.act_three = Some(start.elapsed());
+
+        let mut lnsocket = Self::new(session.into_channel(), Box::new(stream));
+        lnsocket.timings = timings;
+        Ok(lnsocket)
+    }
+
+    /// Perform the responder side of the Noise handshake over an already connected stream.
+    pub(crate) async fn handshake_inbound(
+        mut stream: impl Transport + 'static,
+        our_key: SecretKey,
+    ) -> Result<LNSocket, Error> {
+        let ephemeral = SecretKey::new(&mut rand::thread_rng());
+        let mut session = Session::inbound(our_key, ephemeral);
+
+        let mut timings = ConnectTimings::default();
+        let start = Instant::now();
+        let mut act_one = [0u8; ACT_ONE_SIZE];
+        stream.read_exact(&mut act_one).await?;
+        timings.act_one = Some(start.elapsed());
+
+        let start = Instant::now();
+        session.receive(&act_one)?;
+        write_outgoing(&mut stream, &mut session).await?;
+        timings.act_two = Some(start.elapsed());
+
+        let start = Instant::now();
+        let mut act_three = [0u8; ACT_THREE_SIZE];
+        stream.read_exact(&mut act_three).await?;
+        session.receive(&act_three)?;
+        timings.act_three = Some(start.elapsed());
+
+        let mut lnsocket = Self::new(session.into_channel(), Box::new(stream));
+        lnsocket.timings = timings;
+        Ok(lnsocket)
+    }
+
+    fn new(channel: PeerChannelEncryptor, stream: Box<dyn Transport>) -> Self {
+        Self {
+            channel,
+            stream,
+            events: None,
+            pings: PingResponder::new(PingPolicy::default()),
+            sent_init: false,
+            peer_info: None,
+            peer_addr: None,
+            #[cfg(feature = "tokio")]
+            read_timeout: None,
+            pending: VecDeque::new(),
+            frames: FrameReader::default(),
+            #[cfg(feature = "tokio")]
+            frame_started: None,
+            auto_pong: false,
+            wbuf: Vec::new(),
+            disconnected: false,
+            address_book: None,
+            gossip_dedup: None,
+            recorder: None,
+            #[cfg(feature = "tokio")]
+            recovery: None,
+            timings: ConnectTimings::default(),
+            rekey: RekeyPolicy::default(),
+            sent: SentCounter::default(),
+            #[cfg(feature = "tokio")]
+            limiter: None,
+        }
+    }
+
+    /// Take the socket apart, to use the stream directly or close it some particular way.
+    ///
+    /// Everything set up on the socket, like event subscriptions, the recorder and timeouts,
+    /// is dropped.
+    pub fn into_parts(self) -> LNSocketParts {
+        let (partial, body_len) = self.frames.into_parts();
+        LNSocketParts {
+            stream: self.stream,
+            channel: self.channel,
+            peer_info: self.peer_info,
+            sent_init: self.sent_init,
+            pending: self
+                .pending
+                .into_iter()
+                .map(|mut buf| {
+                    buf.truncate(buf.len() - 16);
+                    buf
+                })
+                .collect(),
+            partial,
+            body_len,
+        }
+    }
+
+    /// Put a socket taken apart by [`LNSocket::into_parts`] back together, with default
+    /// settings.
+    pub fn from_parts(parts: LNSocketParts) -> LNSocket {
+        let mut socket = Self::new(parts.channel, parts.stream);
+        socket.peer_info = parts.peer_info;
+        socket.sent_init = parts.sent_init;
+        socket.pending = parts
+            .pending
+            .into_iter()
+            .map(|mut msg| {
+                // frames are kept with room for their MAC
+                msg.extend_from_slice(&[0; 16]);
+                msg
+            })
+            .collect();
+        socket.frames = FrameReader::from_parts(parts.partial, parts.body_len);
+        socket
+    }
+
+    /// Split the socket into halves that read and write independently, so one task can keep
+    /// reading while another writes. Each half owns its direction's keys.
+    ///
+    /// Split after [`LNSocket::perform_init`]: the writer only knows about `init`s exchanged
+    /// before the split, and refuses to send anything else until both were. The reader keeps
+    /// the read settings, such as the read timeout, events, the address book, gossip dedup and
+    /// the recorder (which then only sees incoming messages); the writer keeps the ping and
+    /// rekey policies. The reader can't answer pings itself, so automatic pongs stop.
+    /// [`Recovery`] can't replace one half of a connection, so it's dropped.
+    ///
+    /// ### Example
+    /// ```no_run
+    /// # use lnsocket::{Error, LNSocket, ln::wire::Message};
+    /// # async fn example(socket: LNSocket) -> Result<(), Error> {
+    /// let (mut reader, mut writer) = socket.split();
+    /// let (pings_tx, mut pings) = tokio::sync::mpsc::unbounded_channel();
+    /// tokio::spawn(async move {
+    ///     while let Ok(msg) = reader.read().await {
+    ///         if let Message::Ping(ping) = msg {
+    ///             let _ = pings_tx.send(ping);
+    ///         }
+    ///     }
+    /// });
+    /// while let Some(ping) = pings.recv().await {
+    ///     if let Some(pong) = writer.pong_for(&ping)? {
+    ///         writer.write(&pong).await?;
+    ///     }
+    /// }
+    /// # Ok(()) }
+    /// ```
+    pub fn split(self) -> (LNReader, LNWriter) {
+        let (sending, receiving) = self.channel.split();
+        let (read, write) = tokio::io::split(self.stream);
 
-        Ok(Self { channel, stream })
+        let mut writer = LNSocket::new(sending, Box::new(WriteOnly(write)));
+        writer.sent_init = self.sent_init;
+        writer.peer_info = self.peer_info.clone();
+        writer.peer_addr = self.peer_addr;
+        writer.timings = self.timings.clone();
+        writer.pings = self.pings;
+        writer.rekey = self.rekey;
+        writer.sent = self.sent;
+        writer.wbuf = self.wbuf;
+        #[cfg(feature = "tokio")]
+        {
+            writer.limiter = self.limiter;
+        }
+
+        let reader = LNSocket {
+            channel: receiving,
+            stream: Box::new(ReadOnly(read)),
+            pings: PingResponder::new(PingPolicy::default()),
+            auto_pong: false,
+            wbuf: Vec::new(),
+            #[cfg(feature = "tokio")]
+            recovery: None,
+            rekey: RekeyPolicy::default(),
+            sent: SentCounter::default(),
+            #[cfg(feature = "tokio")]
+            limiter: None,
+            ..self
+        };
+        (LNReader(reader), LNWriter(writer))
+    }
+
+    /// Turn the socket into a [`Stream`] of incoming messages, for `StreamExt` combinators
+    /// and `select!`. The stream ends after the first error.
+    ///
+    /// Pings come out like anything else. To answer them, [split](LNSocket::split) first and
+    /// use [`LNReader::into_stream`] with [`LNWriter::into_sink`].
+    pub fn into_stream(self) -> impl Stream<Item = Result<Message<()>, Error>> + Send {
+        stream::unfold(Some(self), |socket| async move {
+            let mut socket = socket?;
+            match socket.read().await {
+                Ok(msg) => Some((Ok(msg), Some(socket))),
+                Err(err) => Some((Err(err), None)),
+            }
+        })
+    }
+
+    /// The node id of the peer on the other end of this connection.
+    pub fn their_pubkey(&self) -> PublicKey {
+        self.channel
+            .their_node_id()
+            .expect("node id is known once the handshake completes")
     }
 
+    #[cfg(feature = "tokio")]
     pub async fn connect_and_init(
         our_key: SecretKey,
         their_pubkey: PublicKey,
         addr: &str,
+    ) -> Result<LNSocket, Error> {
+        Self::connect_and_init_with(&InitOptions::default(), our_key, their_pubkey, addr).await
+    }
+
+    /// Like [`LNSocket::connect_and_init`], for a node on `network`, such as testnet, signet or
+    /// regtest.
+    #[cfg(feature = "tokio")]
+    pub async fn connect_and_init_on(
+        network: Network,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+    ) -> Result<LNSocket, Error> {
+        let opts = InitOptions::for_network(network);
+        Self::connect_and_init_with(&opts, our_key, their_pubkey, addr).await
+    }
+
+    /// Like [`LNSocket::connect_and_init`], but with control over the `init` we send.
+    #[cfg(feature = "tokio")]
+    pub async fn connect_and_init_with(
+        opts: &InitOptions,
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
     ) -> Result<LNSocket, Error> {
         let mut lnsocket = LNSocket::connect(our_key, their_pubkey, addr).await?;
-        lnsocket.perform_init().await?;
+        lnsocket.perform_init_with(opts).await?;
         Ok(lnsocket)
     }
 
     /// Completes the initial `init` message exchange.
     ///
     /// This must be called before issuing any other Lightning messages.
-    /// Fails if the first incoming message isn’t `Init`.
+    /// Fails if the first incoming message isn’t `Init`, or with [`Error::NetworkMismatch`] if
+    /// the peer only cares about chains we don't.
     pub async fn perform_init(&mut self) -> Result<(), Error> {
-        // first message should be init, if not, we fail
-        if let Message::Init(_) = self.read().await? {
-            // ok
-        } else {
-            return Err(Error::FirstMessageNotInit);
+        self.perform_init_with(&InitOptions::default()).await
+    }
+
+    /// Like [`LNSocket::perform_init`], but with control over the `init` we send.
+    pub async fn perform_init_with(&mut self, opts: &InitOptions) -> Result<(), Error> {
+        let start = Instant::now();
+        let ours = opts.networks.clone();
+        let custom_tlvs = opts.sorted_custom_tlvs()?;
+
+        // first message should be init, if not, we fail (unless asked to be lenient)
+        let their_init = loop {
+            let buf = self.read_frame_timed().await?;
+            let is_init = buf.get(..2) == Some(&msgs::Init::TYPE.to_be_bytes()[..]);
+            if !is_init && self.pending.len() < opts.max_pre_init_messages {
+                self.pending.push_back(buf);
+                continue;
+            }
+            match self.decode_frame(&buf, |_type, _buf| Ok(None::<()>))? {
+                Message::Init(init) => break init,
+                _ => return Err(Error::FirstMessageNotInit),
+            }
+        };
+
+        if let Some(theirs) = their_init.networks
+            && !theirs.iter().any(|network| ours.contains(network))
+        {
+            return Err(Error::NetworkMismatch { ours, theirs });
+        }
+
+        let mut features = opts.advertised_features();
+        if let Some(info) = &self.peer_info {
+            opts.check_peer(info, &mut features)?;
         }
 
         // send some bs
-        Ok(self
-            .write(&msgs::Init {
-                features: vec![0; 5],
-                global_features: vec![0; 2],
-                remote_network_address: None,
-                networks: Some(vec![bitcoin::constants::ChainHash::BITCOIN]),
-            })
-            .await?)
-    }
+        self.write(&msgs::Init {
+            features: features.to_be_bytes(),
+            global_features: features.up_to_13().to_be_bytes(),
+            remote_network_address: opts.remote_address_for(self.peer_addr),
+            networks: Some(ours.clone()),
+            custom_tlvs,
+        })
+        .await?;
+
+        let gossip_queries = self
+            .peer_info
+            .as_ref()
+            .is_some_and(|info| info.features().supports(bits::GOSSIP_QUERIES));
+        if opts.suppress_gossip && gossip_queries {
+            for chain_hash in ours {
+                // nothing is timestamped this late, so nothing gets relayed
+                self.write(&msgs::GossipTimestampFilter {
+                    chain_hash,
+                    first_timestamp: u32::MAX,
+                    timestamp_range: 0,
+                })
+                .await?;
+            }
+        }
 
-    pub async fn write<M: wire::Type + Writeable>(&mut self, m: &M) -> Result<(), io::Error> {
-        let msg = self.channel.encrypt_message(m);
-        self.stream.write_all(&msg).await?;
+        self.timings.init = Some(start.elapsed());
         Ok(())
     }
 
-    pub async fn read(&mut self) -> Result<Message<()>, Error> {
-        self.read_custom(|_type, _buf| Ok(None)).await
+    /// The address of the peer's end of the TCP connection, when we know it. Not for streams
+    /// of other kinds, such as a Tor circuit or a WebSocket.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
     }
 
-    pub async fn read_custom<T>(
-        &mut self,
-        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
-    ) -> Result<Message<T>, Error>
-    where
-        T: core::fmt::Debug,
-    {
-        let mut hdr = [0u8; 18];
+    /// How long each step of setting up this connection took.
+    pub fn timings(&self) -> &ConnectTimings {
+        &self.timings
+    }
 
-        self.stream.read_exact(&mut hdr).await?;
-        let size = self.channel.decrypt_length_header(&hdr)? as usize;
-        //println!("len header {size}");
-        let mut buf = vec![0; size + 16];
-        self.stream.read_exact(&mut buf).await?;
-        //println!("got cipher bytes {}", hex::encode(&buf));
-        self.channel.decrypt_message(&mut buf)?;
-        let u8_buf: &[u8] = &buf[..buf.len() - 16];
-        let mut cursor = io::Cursor::new(u8_buf);
+    /// What the peer told us about itself in its `init`, once it has been received.
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.peer_info.as_ref()
+    }
 
-        Ok(wire::read(&mut cursor, handler).map_err(|(de, _)| de)?)
+    /// The features from the peer's `init`, once it has been received.
+    pub fn peer_features(&self) -> Option<&InitFeatures> {
+        self.peer_info.as_ref().map(PeerInfo::features)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::ln::msgs;
-    use std::str::FromStr;
+    /// Subscribe to connection [`Event`]s, such as warnings and errors sent by the peer.
+    ///
+    /// Only one subscriber is supported; calling this again replaces the previous channel.
+    pub fn subscribe_events(&mut self) -> mpsc::UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.events = Some(tx);
+        rx
+    }
 
-    #[tokio::test]
-    async fn test_ping_pong() -> Result<(), Error> {
-        let key = SecretKey::new(&mut rand::thread_rng());
-        let their_key = PublicKey::from_str(
-            "03f3c108ccd536b8526841f0a5c58212bb9e6584a1eb493080e7c1cc34f82dad71",
-        )
-        .unwrap();
+    /// Emit [`Event::Disconnected`] if `err` means the connection is gone, the first time it
+    /// does. Returns `err`.
+    pub(crate) fn lost(&mut self, err: Error) -> Error {
+        let reason = match &err {
+            Error::Io(io::ErrorKind::UnexpectedEof) => DisconnectReason::Closed,
+            Error::Io(kind) => DisconnectReason::Io(*kind),
+            // only decryption fails with a lightning error while reading frames
+            Error::Lightning(_) => DisconnectReason::Decryption,
+            Error::PeerUnresponsive => DisconnectReason::Unresponsive,
+            _ => return err,
+        };
+        if !self.disconnected {
+            self.disconnected = true;
+            self.emit(Event::Disconnected { reason });
+        }
+        err
+    }
 
-        let mut lnsocket = LNSocket::connect_and_init(key, their_key, "ln.damus.io:9735").await?;
+    pub(crate) fn emit(&mut self, event: Event) {
+        if let Some(events) = &self.events
+            && events.send(event).is_err()
+        {
+            // receiver is gone, stop bothering
+            self.events = None;
+        }
+    }
 
-        //println!("got here");
-        lnsocket
-            .write(&msgs::Ping {
-                ponglen: 4,
-                byteslen: 8,
-            })
-            .await?;
+    /// Limit how long reading a single message may take, from the first byte of its length
+    /// header to the last byte of its body. `None` (the default) waits forever.
+    ///
+    /// This keeps a peer that trickles a partial frame and then stalls from holding the
+    /// connection hostage, while a peer that simply has nothing to say can stay quiet for as
+    /// long as it likes. When the deadline passes the read fails with [`Error::Timeout`].
+    #[cfg(feature = "tokio")]
+    pub fn set_read_timeout(&mut self, deadline: Option<Duration>) {
+        self.read_timeout = deadline;
+    }
 
-        loop {
-            if let Message::Pong(_) = lnsocket.read().await? {
-                break;
-            } else {
-                // didn't get pong?
-            }
+    /// The current [`ConnectionState`].
+    pub fn state(&self) -> ConnectionState {
+        if self.sent_init && self.peer_info.is_some() {
+            ConnectionState::Ready
+        } else {
+            ConnectionState::AwaitingInit
         }
+    }
 
-        Ok(())
+    /// Answer pings inside [`LNSocket::read`] and [`LNSocket::read_custom`] with a pong as
+    /// [`LNSocket::pong_for`] says, instead of returning them, or stop with `false` (the
+    /// default).
+    ///
+    /// Reads only answer pings while they are running, so a connection nobody reads from still
+    /// goes unanswered; [`LNSocket::run`] covers that. Raw reads return pings as usual.
+    /// Dropping a read midway through a pong is fine, the rest goes out before the next
+    /// message written.
+    pub fn set_auto_pong(&mut self, on: bool) {
+        self.auto_pong = on;
     }
 
-    #[tokio::test]
-    async fn test_commando() -> Result<(), Error> {
-        use crate::commando::CommandoClient;
+    /// Set the rules used by [`LNSocket::pong_for`] when answering pings.
+    pub fn set_ping_policy(&mut self, policy: PingPolicy) {
+        self.pings = PingResponder::new(policy);
+    }
 
-        let key = SecretKey::new(&mut rand::thread_rng());
-        let their_key = PublicKey::from_str(
-            "03f3c108ccd536b8526841f0a5c58212bb9e6584a1eb493080e7c1cc34f82dad71",
-        )
-        .unwrap();
+    /// Feed `node_announcement`s read from this socket into `book`, or stop with `None`.
+    ///
+    /// The same book can be shared by several sockets.
+    pub fn set_address_book(&mut self, book: Option<Arc<Mutex<AddressBook>>>) {
+        self.address_book = book;
+    }
 
-        let mut lnsocket = LNSocket::connect_and_init(key, their_key, "ln.damus.io:9735").await?;
-        let mut commando = CommandoClient::new(
-            "hfYByx-RDwdBfAK-vOWeOCDJVYlvKSioVKU_y7jccZU9MjkmbWV0aG9kPWdldGluZm8=",
-        );
+    /// Skip gossip `dedup` has already seen when reading from this socket, or stop with
+    /// `None`.
+    ///
+    /// Share one between sockets to each peer, so an update relayed by all of them is read
+    /// once. Only [`LNSocket::read`] and [`LNSocket::read_custom`] skip, raw reads don't.
+    pub fn set_gossip_dedup(&mut self, dedup: Option<Arc<Mutex<GossipDedup>>>) {
+        self.gossip_dedup = dedup;
+    }
 
-        let resp = commando
-            .call(&mut lnsocket, "getinfo", serde_json::json!({}))
-            .await?;
+    /// Log every message sent or received from now on to `recorder`, or stop with `None`.
+    ///
+    /// If writing to the recording fails, recording stops but the connection carries on.
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder;
+    }
 
-        let bad_resp = commando
-            .call(
-                &mut lnsocket,
-                "invoice",
-                serde_json::json!({"msatoshi": "any"}),
-            )
-            .await?;
+    /// Reconnect as described by `recovery` when a frame fails to decrypt, or stop with
+    /// `None`. See [`crate::recovery`].
+    ///
+    /// Reads that end up reconnecting aren't cancellation safe: dropping one midway leaves the
+    /// broken connection in place, so the next read starts over.
+    #[cfg(feature = "tokio")]
+    pub fn set_recovery(&mut self, recovery: Option<Recovery>) {
+        self.recovery = recovery;
+    }
+
+    /// Rotate the sending key early as `policy` says, see [`crate::rekey`].
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.rekey = policy;
+    }
 
-        println!("{}", serde_json::to_string(&resp).unwrap());
-        println!("{}", serde_json::to_string(&bad_resp).unwrap());
+    /// Hold back writes that would send faster than `limit`, or stop with `None`. See
+    /// [`crate::ratelimit`].
+    #[cfg(feature = "tokio")]
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.limiter = limit.as_ref().map(RateLimiter::new);
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        if let Some(recorder) = &mut self.recorder
+            && recorder.record(direction, data).is_err()
+        {
+            self.recorder = None;
+        }
+    }
+
+    /// Decide how to answer an incoming ping according to the current [`PingPolicy`].
+    ///
+    /// Returns the pong to send, or `None` if the ping should be ignored (oversized or
+    /// rate-limited). Fails with [`Error::PingFlood`] if the peer exceeded the rate limit and
+    /// the policy says to disconnect.
+    pub fn pong_for(&mut self, ping: &msgs::Ping) -> Result<Option<msgs::Pong>, Error> {
+        match self.pings.respond(ping) {
+            PingResponse::Pong(pong) => Ok(Some(pong)),
+            PingResponse::Ignore => Ok(None),
+            PingResponse::Flood => Err(Error::PingFlood),
+        }
+    }
+
+    /// Encrypt and send a message.
+    ///
+    /// Fails with [`Error::InitNotComplete`] if `m` is not an `init` and the `init` exchange
+    /// has not finished yet.
+    pub async fn write<M: wire::Type + Writeable>(&mut self, m: &M) -> Result<(), Error> {
+        self.write_all_msgs(std::slice::from_ref(m)).await
+    }
+
+    /// Encrypt all of `messages` and send them with a single write, instead of one per message,
+    /// e.g. for a burst of gossip queries or pipelined commando requests.
+    ///
+    /// Fails with [`Error::InitNotComplete`] before anything is sent if one of them isn't an
+    /// `init` and the `init` exchange won't have finished by then. For messages of several
+    /// types, wrap them in an enum implementing [`wire::Type`] and [`wire::Writeable`] by
+    /// delegating to each.
+    pub async fn write_all_msgs<M: wire::Type + Writeable>(
+        &mut self,
+        messages: &[M],
+    ) -> Result<(), Error> {
+        let mut sent_init = self.sent_init;
+        for m in messages {
+            if m.type_id() == msgs::Init::TYPE {
+                sent_init = true;
+            } else if !sent_init || self.peer_info.is_none() {
+                return Err(Error::InitNotComplete);
+            }
+        }
+
+        self.flush_pongs().await?;
+        #[cfg(feature = "tokio")]
+        if let Some(limiter) = &mut self.limiter {
+            for m in messages {
+                // the type and the body, with the encrypted length and MACs around them
+                let len = 18 + 2 + m.serialized_length() + 16;
+                limiter.acquire(len).await;
+            }
+        }
+
+        let mut buf = Vec::new();
+        let mut pings = 0;
+        for m in messages {
+            if self.recorder.is_some() {
+                let mut plain = Vec::new();
+                wire::write(m, &mut plain)?;
+                self.record(Direction::Outbound, &plain);
+            }
+            if self.channel.messages_until_rekey() == 0 {
+                self.sent = SentCounter::default();
+            }
+            let msg = self.channel.encrypt_message(m);
+            self.sent.messages += 1;
+            self.sent.bytes += msg.len() as u64;
+            buf.extend_from_slice(&msg);
+            if self.rekey.exceeded(self.sent.messages, self.sent.bytes) {
+                self.rekey_fillers(&mut buf);
+            }
+            if m.type_id() == msgs::Ping::TYPE {
+                pings += 1;
+            }
+        }
+        self.stream
+            .write_all(&buf)
+            .await
+            .map_err(|err| self.lost(err.into()))?;
+
+        self.sent_init = sent_init;
+        for _ in 0..pings {
+            self.emit(Event::PingSent);
+        }
+        Ok(())
+    }
+
+    /// Answer `buf` if it's a ping and pongs are answered automatically. Returns whether it
+    /// was consumed.
+    async fn answer_ping(&mut self, buf: &[u8]) -> Result<bool, Error> {
+        if !self.auto_pong {
+            return Ok(false);
+        }
+        self.answer_if_ping(buf).await
+    }
+
+    /// Answer `buf` if it's a ping. Returns whether it was one.
+    async fn answer_if_ping(&mut self, buf: &[u8]) -> Result<bool, Error> {
+        // the peer's init has to be read first
+        if self.peer_info.is_none() || buf.len() < 2 + 16 {
+            return Ok(false);
+        }
+        if u16::from_be_bytes([buf[0], buf[1]]) != msgs::Ping::TYPE {
+            return Ok(false);
+        }
+        let Message::Ping(ping) = self.decode_frame(buf, |_type, _buf| Ok(None::<()>))? else {
+            return Ok(false);
+        };
+        if let Some(pong) = self.pong_for(&ping)? {
+            if self.recorder.is_some() {
+                let mut plain = Vec::new();
+                wire::write(&pong, &mut plain)?;
+                self.record(Direction::Outbound, &plain);
+            }
+            if self.channel.messages_until_rekey() == 0 {
+                self.sent = SentCounter::default();
+            }
+            let msg = self.channel.encrypt_message(&pong);
+            self.sent.messages += 1;
+            self.sent.bytes += msg.len() as u64;
+            self.wbuf.extend_from_slice(&msg);
+            self.flush_pongs().await?;
+        }
+        Ok(true)
+    }
+
+    /// Write out what's left of automatic pongs. Cancellation safe: progress is kept in
+    /// `wbuf` after every write.
+    async fn flush_pongs(&mut self) -> Result<(), Error> {
+        while !self.wbuf.is_empty() {
+            let n = self
+                .stream
+                .write(&self.wbuf)
+                .await
+                .map_err(|err| self.lost(err.into()))?;
+            if n == 0 {
+                let err = io::Error::from(io::ErrorKind::WriteZero);
+                return Err(self.lost(err.into()));
+            }
+            self.wbuf.drain(..n);
+        }
+        Ok(())
+    }
+
+    /// Tell the peer why we're leaving, then close the connection cleanly, instead of
+    /// dropping it and leaving the peer to time out.
+    ///
+    /// `reason` is sent as a `warning` or `error` with an all-0s channel id, unless `init`
+    /// hasn't been exchanged yet. Then the stream is flushed and shut down, and
+    /// [`Event::Disconnected`] is emitted with [`DisconnectReason::Local`].
+    pub async fn close(mut self, reason: CloseReason) -> Result<(), Error> {
+        if self.state() == ConnectionState::Ready {
+            let channel_id = ChannelId::new_zero();
+            match reason {
+                CloseReason::Warning(data) => {
+                    self.write(&msgs::WarningMessage { channel_id, data })
+                        .await?
+                }
+                CloseReason::Error(data) => {
+                    self.write(&msgs::ErrorMessage { channel_id, data }).await?
+                }
+            }
+        }
+        self.flush_pongs().await?;
+        self.stream.flush().await?;
+        self.stream.shutdown().await?;
+        if !self.disconnected {
+            self.disconnected = true;
+            self.emit(Event::Disconnected {
+                reason: DisconnectReason::Local,
+            });
+        }
+        Ok(())
+    }
+
+    /// Use up the current sending key with fillers appended to `buf`, so the next message
+    /// rotates it.
+    fn rekey_fillers(&mut self, buf: &mut Vec<u8>) {
+        for _ in 0..self.channel.messages_until_rekey() {
+            buf.extend(self.channel.encrypt_message(&Filler));
+        }
+    }
+
+    /// Read and decrypt a single frame, returning the plaintext followed by 16 bytes of MAC.
+    ///
+    /// Partial progress is kept in `self`, so dropping the future between reads loses nothing.
+    async fn read_frame(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(buf) = self.frames.next_frame(&mut self.channel)? {
+                #[cfg(feature = "tokio")]
+                {
+                    self.frame_started = None;
+                }
+                self.record(Direction::Inbound, &buf[..buf.len() - 16]);
+                return Ok(buf);
+            }
+
+            // the read timeout runs from the frame's first byte
+            #[cfg(feature = "tokio")]
+            let deadline = match self.read_timeout {
+                Some(limit) if self.frames.in_frame() => Some(
+                    *self
+                        .frame_started
+                        .get_or_insert_with(tokio::time::Instant::now)
+                        + limit,
+                ),
+                _ => None,
+            };
+
+            // never read past the current frame, and only hand over bytes once the read is done
+            let mut chunk = [0u8; 4096];
+            let missing = self.frames.missing().min(chunk.len());
+            let read = self.stream.read(&mut chunk[..missing]);
+            #[cfg(feature = "tokio")]
+            let n = match deadline {
+                Some(deadline) => timeout_at(deadline, read)
+                    .await
+                    .map_err(|_| Error::Timeout)??,
+                None => read.await?,
+            };
+            #[cfg(not(feature = "tokio"))]
+            let n = read.await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.frames.receive(&chunk[..n]);
+        }
+    }
+
+    /// [`LNSocket::read_frame`], reporting a lost connection.
+    async fn read_frame_timed(&mut self) -> Result<Vec<u8>, Error> {
+        match self.read_frame().await {
+            Err(Error::Timeout) => Err(Error::Timeout),
+            res => res.map_err(|err| self.lost(err)),
+        }
+    }
+
+    /// [`LNSocket::read_frame_timed`], reconnecting if the frame doesn't decrypt and a
+    /// [`Recovery`] is set.
+    #[cfg(feature = "tokio")]
+    async fn read_frame_recovering(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            match self.read_frame_timed().await {
+                // only decryption fails with a lightning error here
+                Err(Error::Lightning(err)) => match self.recovery.take() {
+                    Some(recovery) => {
+                        let res = self.recover(&recovery).await;
+                        self.recovery = Some(recovery);
+                        res?;
+                    }
+                    None => return Err(Error::Lightning(err)),
+                },
+                res => return res,
+            }
+        }
+    }
+
+    #[cfg(not(feature = "tokio"))]
+    async fn read_frame_recovering(&mut self) -> Result<Vec<u8>, Error> {
+        self.read_frame_timed().await
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn recover(&mut self, recovery: &Recovery) -> Result<(), Error> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.reconnect(recovery).await {
+                Ok(()) => break,
+                Err(err) if attempt >= recovery.max_attempts => return Err(err),
+                Err(_) => tokio::time::sleep(recovery.retry_delay).await,
+            }
+        }
+        for msg in &recovery.restore {
+            self.write(msg).await?;
+        }
+        self.emit(Event::Reconnected);
+        Ok(())
+    }
+
+    /// Replace the connection with a new one, keeping everything the user set up on this one.
+    #[cfg(feature = "tokio")]
+    async fn reconnect(&mut self, recovery: &Recovery) -> Result<(), Error> {
+        let mut fresh =
+            LNSocket::connect(recovery.our_key, self.their_pubkey(), &recovery.addr).await?;
+        fresh.read_timeout = self.read_timeout;
+        fresh.perform_init_with(&recovery.init).await?;
+
+        self.channel = fresh.channel;
+        self.stream = fresh.stream;
+        self.sent_init = fresh.sent_init;
+        self.peer_info = fresh.peer_info;
+        self.peer_addr = fresh.peer_addr;
+        self.timings = fresh.timings;
+        self.sent = SentCounter::default();
+        self.pending.extend(fresh.pending);
+        self.frames = FrameReader::default();
+        self.frame_started = None;
+        self.wbuf.clear();
+        self.disconnected = false;
+        Ok(())
+    }
+
+    /// Send `msg` and wait up to `deadline` for the first incoming message `predicate`
+    /// accepts, such as the pong to a ping.
+    ///
+    /// Other messages that arrive meanwhile are kept, and returned by later reads in the order
+    /// they arrived. Fails with [`Error::Timeout`] if nothing matched in time; nothing read is
+    /// lost then either.
+    #[cfg(feature = "tokio")]
+    pub async fn send_and_wait<M: wire::Type + Writeable>(
+        &mut self,
+        msg: &M,
+        predicate: impl FnMut(&Message<()>) -> bool,
+        deadline: Duration,
+    ) -> Result<Message<()>, Error> {
+        self.send_and_wait_custom(msg, |_type, _buf| Ok(None), predicate, deadline)
+            .await
+    }
+
+    /// Like [`LNSocket::send_and_wait`], decoding custom messages with `reader` as in
+    /// [`LNSocket::read_custom`].
+    #[cfg(feature = "tokio")]
+    pub async fn send_and_wait_custom<M, T>(
+        &mut self,
+        msg: &M,
+        reader: impl FnMut(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+        predicate: impl FnMut(&Message<T>) -> bool,
+        deadline: Duration,
+    ) -> Result<Message<T>, Error>
+    where
+        M: wire::Type + Writeable,
+        T: core::fmt::Debug,
+    {
+        self.write(msg).await?;
+        timeout(deadline, self.wait_matching(reader, predicate))
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    /// Read until a message of type `M` arrives, and return it.
+    ///
+    /// Pings that arrive meanwhile are answered as [`LNSocket::pong_for`] says. Other messages
+    /// are kept and returned by later reads in the order they arrived, and one of type `M`
+    /// that was already kept is returned first.
+    ///
+    /// ```no_run
+    /// # use lnsocket::{Error, LNSocket, ln::msgs};
+    /// # async fn example(mut socket: LNSocket) -> Result<(), Error> {
+    /// socket.write(&msgs::Ping { ponglen: 4, byteslen: 0 }).await?;
+    /// let pong = socket.wait_for::<msgs::Pong>().await?;
+    /// # Ok(()) }
+    /// ```
+    pub async fn wait_for<M: FromMessage>(&mut self) -> Result<M, Error> {
+        let kept = self.pending.iter().position(|buf| {
+            let mut cursor = Cursor::new(&buf[..buf.len() - 16]);
+            wire::read(&mut cursor, |_type, _buf| Ok(None::<()>))
+                .is_ok_and(|msg| M::from_message(msg).is_ok())
+        });
+        if let Some(i) = kept {
+            let buf = self.pending.remove(i).expect("found in pending");
+            let msg = self.decode_frame(&buf, |_type, _buf| Ok(None::<()>))?;
+            return Ok(M::from_message(msg).expect("checked above"));
+        }
+        loop {
+            let buf = self.read_frame_recovering().await?;
+            if self.seen_gossip(&buf) || self.answer_if_ping(&buf).await? {
+                continue;
+            }
+            let mut cursor = Cursor::new(&buf[..buf.len() - 16]);
+            let wanted = wire::read(&mut cursor, |_type, _buf| Ok(None::<()>))
+                .is_ok_and(|msg| M::from_message(msg).is_ok());
+            if wanted {
+                let msg = self.decode_frame(&buf, |_type, _buf| Ok(None::<()>))?;
+                return Ok(M::from_message(msg).expect("checked above"));
+            }
+            self.pending.push_back(buf);
+        }
+    }
+
+    /// Like [`LNSocket::wait_for`], failing with [`Error::Timeout`] if nothing of type `M`
+    /// arrived within `limit`. Nothing read is lost then.
+    #[cfg(feature = "tokio")]
+    pub async fn wait_for_timeout<M: FromMessage>(&mut self, limit: Duration) -> Result<M, Error> {
+        timeout(limit, self.wait_for())
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn wait_matching<T>(
+        &mut self,
+        mut reader: impl FnMut(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+        mut predicate: impl FnMut(&Message<T>) -> bool,
+    ) -> Result<Message<T>, Error>
+    where
+        T: core::fmt::Debug,
+    {
+        loop {
+            let buf = self.read_frame_recovering().await?;
+            // a trial decode without side effects, events etc. fire once the frame is read
+            let mut cursor = Cursor::new(&buf[..buf.len() - 16]);
+            let matched = wire::read(&mut cursor, |typ, buf| reader(typ, buf))
+                .is_ok_and(|msg| predicate(&msg));
+            if matched {
+                return self.decode_frame(&buf, |typ, buf| reader(typ, buf));
+            }
+            self.pending.push_back(buf);
+        }
+    }
+
+    /// Read the next message from the peer.
+    ///
+    /// Cancellation safe, so it can race other work in `tokio::select!`: a read dropped
+    /// midway keeps what it got of the frame it was reading, and the next read carries on
+    /// from there. Reads that end up reconnecting are the exception, see
+    /// [`LNSocket::set_recovery`].
+    ///
+    /// ```no_run
+    /// # use lnsocket::{Error, LNSocket, ln::msgs};
+    /// # async fn example(mut socket: LNSocket) -> Result<(), Error> {
+    /// let mut tick = tokio::time::interval(std::time::Duration::from_secs(30));
+    /// loop {
+    ///     tokio::select! {
+    ///         msg = socket.read() => println!("{:?}", msg?),
+    ///         _ = tick.tick() => socket.write(&msgs::Ping { ponglen: 0, byteslen: 0 }).await?,
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub async fn read(&mut self) -> Result<Message<()>, Error> {
+        self.read_custom(|_type, _buf| Ok(None)).await
+    }
+
+    /// Like [`LNSocket::read`], decoding messages of types this crate doesn't know with
+    /// `handler`. It returns `None` for types it doesn't know either.
+    pub async fn read_custom<T>(
+        &mut self,
+        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+    ) -> Result<Message<T>, Error>
+    where
+        T: core::fmt::Debug,
+    {
+        loop {
+            let buf = match self.pending.pop_front() {
+                Some(buf) => buf,
+                None => self.read_frame_recovering().await?,
+            };
+            if self.seen_gossip(&buf) || self.answer_ping(&buf).await? {
+                continue;
+            }
+            return self.decode_frame(&buf, handler);
+        }
+    }
+
+    /// Like [`LNSocket::read`], failing with [`Error::Timeout`] if no message arrives within
+    /// `limit`.
+    ///
+    /// A timeout loses nothing: a partly read frame stays in the socket and the next read
+    /// carries on with it. Unlike [`LNSocket::set_read_timeout`], which starts counting at a
+    /// frame's first byte, `limit` also covers the wait for that byte.
+    #[cfg(feature = "tokio")]
+    pub async fn read_with_timeout(&mut self, limit: Duration) -> Result<Message<()>, Error> {
+        self.read_until(tokio::time::Instant::now() + limit).await
+    }
+
+    /// Like [`LNSocket::read_with_timeout`], giving up at `deadline`, e.g. one shared by
+    /// several reads.
+    #[cfg(feature = "tokio")]
+    pub async fn read_until(
+        &mut self,
+        deadline: tokio::time::Instant,
+    ) -> Result<Message<()>, Error> {
+        self.read_custom_until(deadline, |_type, _buf| Ok(None))
+            .await
+    }
+
+    /// Like [`LNSocket::read_custom`], giving up at `deadline` as in
+    /// [`LNSocket::read_until`].
+    #[cfg(feature = "tokio")]
+    pub async fn read_custom_until<T>(
+        &mut self,
+        deadline: tokio::time::Instant,
+        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+    ) -> Result<Message<T>, Error>
+    where
+        T: core::fmt::Debug,
+    {
+        tokio::time::timeout_at(deadline, self.read_custom(handler))
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    /// Whether `buf` is gossip the dedup set has seen before. Nothing is skipped before the
+    /// peer's init, which has to be read first.
+    fn seen_gossip(&self, buf: &[u8]) -> bool {
+        let Some(dedup) = &self.gossip_dedup else {
+            return false;
+        };
+        if self.peer_info.is_none() || buf.len() < 2 + 16 {
+            return false;
+        }
+        let type_id = u16::from_be_bytes([buf[0], buf[1]]);
+        if !matches!(
+            type_id,
+            msgs::ChannelAnnouncement::TYPE
+                | msgs::NodeAnnouncement::TYPE
+                | msgs::ChannelUpdate::TYPE
+        ) {
+            return false;
+        }
+        // malformed gossip isn't skipped, so decoding it reports the error
+        let mut cursor = Cursor::new(&buf[..buf.len() - 16]);
+        match wire::read(&mut cursor, |_type, _buf| Ok(None::<()>)) {
+            Ok(msg) => !dedup.lock().unwrap().is_new(&msg),
+            Err(_) => false,
+        }
+    }
+
+    /// Read the next message's type and payload without decoding it, for proxies and
+    /// bridges passing frames along, including types this crate doesn't know.
+    ///
+    /// Since nothing is decoded, no [`Event`]s fire and the address book isn't fed, except
+    /// for the peer's `init`, which is still checked and kept. Pings are returned like
+    /// anything else, so answering them is up to the caller.
+    pub async fn read_raw(&mut self) -> Result<(u16, Bytes), Error> {
+        let buf = match self.pending.pop_front() {
+            Some(buf) => buf,
+            None => self.read_frame_recovering().await?,
+        };
+        if buf.len() < 2 + 16 {
+            return Err(DecodeError::ShortRead.into());
+        }
+        if self.peer_info.is_none() {
+            self.decode_frame(&buf, |_type, _buf| Ok(None::<()>))?;
+        }
+
+        let type_id = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = buf.len() - 16;
+        Ok((type_id, Bytes::from(buf).slice(2..len)))
+    }
+
+    fn decode_frame<T>(
+        &mut self,
+        buf: &[u8],
+        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+    ) -> Result<Message<T>, Error>
+    where
+        T: core::fmt::Debug,
+    {
+        let msg = sans_io::decode(buf, handler)?;
+
+        // BOLT 1: the first message from the peer must be init
+        if self.peer_info.is_none() {
+            if let Message::Init(init) = &msg {
+                let info = PeerInfo::new(init.clone());
+                self.emit(Event::InitReceived(info.clone()));
+                self.peer_info = Some(info);
+            } else {
+                return Err(Error::FirstMessageNotInit);
+            }
+        }
+
+        match &msg {
+            Message::Warning(warning) => {
+                self.emit(Event::RemoteWarning(RemoteNotice::from(warning)))
+            }
+            Message::Error(error) => self.emit(Event::RemoteError(RemoteNotice::from(error))),
+            Message::Pong(_) => self.emit(Event::PongReceived),
+            Message::NodeAnnouncement(ann) => {
+                if let Some(book) = &self.address_book {
+                    book.lock().unwrap().handle(ann);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(msg)
+    }
+}
+
+/// The reading half of a [split](LNSocket::split) [`LNSocket`].
+pub struct LNReader(LNSocket);
+
+impl LNReader {
+    /// Like [`LNSocket::read`].
+    pub async fn read(&mut self) -> Result<Message<()>, Error> {
+        self.0.read().await
+    }
+
+    /// Like [`LNSocket::read_custom`].
+    pub async fn read_custom<T>(
+        &mut self,
+        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+    ) -> Result<Message<T>, Error>
+    where
+        T: core::fmt::Debug,
+    {
+        self.0.read_custom(handler).await
+    }
+
+    /// Like [`LNSocket::read_raw`].
+    pub async fn read_raw(&mut self) -> Result<(u16, Bytes), Error> {
+        self.0.read_raw().await
+    }
+
+    /// Like [`LNSocket::set_read_timeout`].
+    #[cfg(feature = "tokio")]
+    pub fn set_read_timeout(&mut self, deadline: Option<Duration>) {
+        self.0.set_read_timeout(deadline);
+    }
+
+    /// Like [`LNSocket::into_stream`].
+    pub fn into_stream(self) -> impl Stream<Item = Result<Message<()>, Error>> + Send {
+        self.0.into_stream()
+    }
+
+    /// The peer's `init`, see [`LNSocket::peer_info`]. Unlike the writer's, this one is
+    /// filled in if the `init` arrives after the split.
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.0.peer_info()
+    }
+
+    pub fn their_pubkey(&self) -> PublicKey {
+        self.0.their_pubkey()
+    }
+}
+
+/// The writing half of a [split](LNSocket::split) [`LNSocket`].
+pub struct LNWriter(LNSocket);
+
+impl LNWriter {
+    /// Like [`LNSocket::write`].
+    pub async fn write<M: wire::Type + Writeable>(&mut self, m: &M) -> Result<(), Error> {
+        self.0.write(m).await
+    }
+
+    /// Like [`LNSocket::write_all_msgs`].
+    pub async fn write_all_msgs<M: wire::Type + Writeable>(
+        &mut self,
+        messages: &[M],
+    ) -> Result<(), Error> {
+        self.0.write_all_msgs(messages).await
+    }
+
+    /// Like [`LNSocket::pong_for`], for pings the reader got.
+    pub fn pong_for(&mut self, ping: &msgs::Ping) -> Result<Option<msgs::Pong>, Error> {
+        self.0.pong_for(ping)
+    }
+
+    /// Turn the writer into a [`Sink`] of messages of type `M`, to forward a stream into with
+    /// `StreamExt::forward` or feed from `SinkExt::send`. Each message is written as it's
+    /// sent, so flushing does nothing more.
+    ///
+    /// For several message types, wrap them in an enum implementing [`wire::Type`] and
+    /// [`wire::Writeable`] by delegating to each.
+    pub fn into_sink<M>(self) -> impl Sink<M, Error = Error> + Send
+    where
+        M: wire::Type + Writeable + Send + Sync + 'static,
+    {
+        sink::unfold(self, |mut writer, msg: M| async move {
+            writer.write(&msg).await?;
+            Ok::<_, Error>(writer)
+        })
+    }
+
+    /// Shut down the writing direction of the stream.
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        Ok(self.0.stream.shutdown().await?)
+    }
+
+    pub fn their_pubkey(&self) -> PublicKey {
+        self.0.their_pubkey()
+    }
+}
+
+/// The read direction of a split stream. Writing fails, that belongs to the other half.
+struct ReadOnly(ReadHalf<Box<dyn Transport>>);
+
+impl AsyncRead for ReadOnly {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ReadOnly {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(io::ErrorKind::Unsupported.into()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The write direction of a split stream. Reading fails, that belongs to the other half.
+struct WriteOnly(WriteHalf<Box<dyn Transport>>);
+
+impl AsyncRead for WriteOnly {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Err(io::ErrorKind::Unsupported.into()))
+    }
+}
+
+impl AsyncWrite for WriteOnly {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::error::HandshakeError;
+    use crate::features::{FeaturePreset, Features};
+    use crate::ln::msgs;
+    use crate::socket_addr::SocketAddress;
+    use crate::testing::{MockPeer, RawMessage, default_init};
+    use bitcoin::constants::ChainHash;
+    use bitcoin::secp256k1::Secp256k1;
+
+    /// Two sockets that completed the handshake with each other, but not init.
+    async fn handshaked_pair() -> Result<(LNSocket, LNSocket), Error> {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let a_key = SecretKey::new(&mut rand::thread_rng());
+        let b_key = SecretKey::new(&mut rand::thread_rng());
+        let b_pubkey = b_key.public_key(&Secp256k1::signing_only());
+
+        let (a, b) = tokio::join!(
+            LNSocket::connect_over(a, a_key, b_pubkey),
+            LNSocket::accept_over(b, b_key)
+        );
+        let (a, b) = (a?, b?);
+        assert_eq!(
+            b.their_pubkey(),
+            a_key.public_key(&Secp256k1::signing_only())
+        );
+        Ok((a, b))
+    }
+
+    pub(crate) async fn socket_pair() -> Result<(LNSocket, LNSocket), Error> {
+        let (mut a, mut b) = handshaked_pair().await?;
+        let init = msgs::Init {
+            features: vec![],
+            global_features: vec![],
+            remote_network_address: None,
+            networks: None,
+            custom_tlvs: vec![],
+        };
+        a.write(&init).await?;
+        b.perform_init().await?;
+        assert!(matches!(a.read().await?, Message::Init(_)));
+        assert_eq!(b.peer_info().map(|info| info.init()), Some(&init));
+        assert_eq!(a.state(), ConnectionState::Ready);
+
+        Ok((a, b))
+    }
+
+    /// Sends `count` pings of varying sizes from `from` and checks they all arrive intact.
+    async fn pump(from: &mut LNSocket, to: &mut LNSocket, count: u16) -> Result<(), Error> {
+        let send = async {
+            for i in 0..count {
+                from.write(&msgs::Ping {
+                    ponglen: i,
+                    byteslen: i % 97,
+                })
+                .await?;
+            }
+            Ok::<(), Error>(())
+        };
+        let recv = async {
+            for i in 0..count {
+                match to.read().await? {
+                    Message::Ping(ping) => {
+                        assert_eq!(ping.ponglen, i);
+                        assert_eq!(ping.byteslen, i % 97);
+                    }
+                    other => panic!("expected ping {i}, got {other:?}"),
+                }
+            }
+            Ok::<(), Error>(())
+        };
+        let (sent, received) = tokio::join!(send, recv);
+        sent?;
+        received
+    }
+
+    /// Runs an outbound handshake against a "peer" that answers act one with `reply`.
+    async fn handshake_against(reply: &[u8]) -> Result<LNSocket, Error> {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let their_key = SecretKey::new(&mut rand::thread_rng());
+        let their_pubkey = their_key.public_key(&Secp256k1::signing_only());
+
+        let reply = reply.to_vec();
+        let peer = async move {
+            let mut act_one = [0u8; 50];
+            b.read_exact(&mut act_one).await.unwrap();
+            b.write_all(&reply).await.unwrap();
+        };
+        let (res, _) = tokio::join!(LNSocket::handshake_outbound(a, key, their_pubkey), peer);
+        res
+    }
+
+    #[tokio::test]
+    async fn test_handshake_errors() {
+        let err = handshake_against(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        assert!(matches!(
+            err,
+            Err(Error::Handshake(HandshakeError::HttpResponse))
+        ));
+
+        let err = handshake_against(&[0u8; 10]).await;
+        assert!(matches!(
+            err,
+            Err(Error::Handshake(HandshakeError::ShortAct2(10)))
+        ));
+
+        let err = handshake_against(&[1u8; 50]).await;
+        assert!(matches!(
+            err,
+            Err(Error::Handshake(HandshakeError::BadVersion(1)))
+        ));
+
+        let err = handshake_against(&[0u8; 50]).await;
+        assert!(matches!(
+            err,
+            Err(Error::Handshake(HandshakeError::BadEphemeralKey))
+        ));
+
+        // a valid key but garbage MAC
+        let mut act_two = [0u8; 50];
+        let pk = SecretKey::new(&mut rand::thread_rng()).public_key(&Secp256k1::signing_only());
+        act_two[1..34].copy_from_slice(&pk.serialize());
+        let err = handshake_against(&act_two).await;
+        assert!(matches!(err, Err(Error::Handshake(HandshakeError::BadMac))));
+    }
+
+    #[tokio::test]
+    async fn test_echo_remote_address() -> Result<(), Error> {
+        let (mut a, mut b) = handshaked_pair().await?;
+        let addr: SocketAddr = "203.0.113.7:9735".parse().unwrap();
+        b.peer_addr = Some(addr);
+
+        a.write(&msgs::Init {
+            features: vec![],
+            global_features: vec![],
+            remote_network_address: None,
+            networks: None,
+            custom_tlvs: vec![],
+        })
+        .await?;
+        let opts = InitOptions {
+            echo_remote_address: true,
+            ..Default::default()
+        };
+        b.perform_init_with(&opts).await?;
+        a.read().await?;
+
+        let reported = a.peer_info().and_then(|info| info.remote_network_address());
+        assert_eq!(reported, Some(&SocketAddress::from(addr)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_remote_address() -> Result<(), Error> {
+        let (mut a, mut b) = handshaked_pair().await?;
+        b.peer_addr = Some("203.0.113.7:9735".parse().unwrap());
+        let ours: SocketAddr = "198.51.100.1:9735".parse().unwrap();
+
+        a.write(&default_init()).await?;
+        let opts = InitOptions {
+            echo_remote_address: true,
+            remote_network_address: Some(SocketAddress::from(ours)),
+            ..Default::default()
+        };
+        b.perform_init_with(&opts).await?;
+        a.read().await?;
+
+        let reported = a.peer_info().and_then(|info| info.remote_network_address());
+        assert_eq!(reported, Some(&SocketAddress::from(ours)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multiple_networks() -> Result<(), Error> {
+        let (mut a, mut b) = handshaked_pair().await?;
+        a.write(&msgs::Init {
+            features: vec![],
+            global_features: vec![],
+            remote_network_address: None,
+            networks: Some(vec![ChainHash::REGTEST]),
+            custom_tlvs: vec![],
+        })
+        .await?;
+
+        let opts = InitOptions {
+            networks: vec![ChainHash::SIGNET, ChainHash::REGTEST],
+            ..Default::default()
+        };
+        b.perform_init_with(&opts).await?;
+        a.read().await?;
+
+        let advertised = a.peer_info().and_then(|info| info.networks());
+        assert_eq!(advertised, Some(&opts.networks[..]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_network_mismatch() -> Result<(), Error> {
+        let (mut a, mut b) = handshaked_pair().await?;
+        a.write(&msgs::Init {
+            features: vec![],
+            global_features: vec![],
+            remote_network_address: None,
+            networks: Some(vec![ChainHash::REGTEST]),
+            custom_tlvs: vec![],
+        })
+        .await?;
+
+        match b.perform_init().await {
+            Err(Error::NetworkMismatch { ours, theirs }) => {
+                assert_eq!(ours, vec![ChainHash::BITCOIN]);
+                assert_eq!(theirs, vec![ChainHash::REGTEST]);
+            }
+            other => panic!("expected a network mismatch, got {other:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_on_peer_init() -> Result<(), Error> {
+        let mut their_init = default_init();
+        their_init.features = Features::from(FeaturePreset::OnionMessenger).to_be_bytes();
+        let opts = InitOptions {
+            on_peer_init: Some(Arc::new(|peer, ours| {
+                // only offer onion messages to peers that know route blinding
+                if peer.features().supports(bits::ROUTE_BLINDING) {
+                    ours.set_optional(bits::ONION_MESSAGES);
+                }
+                if peer.features().supports(bits::WUMBO) {
+                    return Err("no wumbo".to_owned());
+                }
+                Ok(())
+            })),
+            ..Default::default()
+        };
+
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut socket, mut peer) = MockPeer::connect_with(key, their_init.clone()).await?;
+        socket.perform_init_with(&opts).await?;
+        let ours = peer.their_init().await.expect("init sent");
+        assert!(Features::from_be_bytes(ours.features).supports(bits::ONION_MESSAGES));
+
+        their_init.features = {
+            let mut features = Features::empty();
+            features.set_optional(bits::WUMBO);
+            features.to_be_bytes()
+        };
+        let (mut socket, _peer) = MockPeer::connect_with(key, their_init).await?;
+        let err = socket.perform_init_with(&opts).await;
+        assert!(matches!(err, Err(Error::InitRejected(why)) if why == "no wumbo"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_messages_before_init() -> Result<(), Error> {
+        let init = msgs::Init {
+            features: vec![],
+            global_features: vec![],
+            remote_network_address: None,
+            networks: None,
+            custom_tlvs: vec![],
+        };
+        let ping = msgs::Ping {
+            ponglen: 4,
+            byteslen: 2,
+        };
+        // write() refuses to send anything but init this early, so go around it
+        async fn send_raw(
+            sock: &mut LNSocket,
+            m: &(impl wire::Type + Writeable),
+        ) -> Result<(), Error> {
+            let msg = sock.channel.encrypt_message(m);
+            sock.stream.write_all(&msg).await?;
+            Ok(())
+        }
+
+        // strict by default
+        let (mut a, mut b) = handshaked_pair().await?;
+        send_raw(&mut a, &ping).await?;
+        send_raw(&mut a, &init).await?;
+        assert!(matches!(
+            b.perform_init().await,
+            Err(Error::FirstMessageNotInit)
+        ));
+
+        // lenient mode queues them and hands them back afterwards
+        let (mut a, mut b) = handshaked_pair().await?;
+        send_raw(&mut a, &ping).await?;
+        send_raw(&mut a, &ping).await?;
+        send_raw(&mut a, &init).await?;
+        let opts = InitOptions {
+            max_pre_init_messages: 2,
+            ..Default::default()
+        };
+        b.perform_init_with(&opts).await?;
+        assert_eq!(b.state(), ConnectionState::Ready);
+        assert!(matches!(b.read().await?, Message::Ping(p) if p == ping));
+        assert!(matches!(b.read().await?, Message::Ping(p) if p == ping));
+
+        // but only up to the limit
+        let (mut a, mut b) = handshaked_pair().await?;
+        for _ in 0..3 {
+            send_raw(&mut a, &ping).await?;
+        }
+        assert!(matches!(
+            b.perform_init_with(&opts).await,
+            Err(Error::FirstMessageNotInit)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout_on_partial_header() -> Result<(), Error> {
+        let (mut a, mut b) = socket_pair().await?;
+        b.set_read_timeout(Some(Duration::from_millis(50)));
+
+        // 17 of the 18 header bytes, then nothing
+        a.stream.write_all(&[0u8; 17]).await?;
+        assert!(matches!(b.read().await, Err(Error::Timeout)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout_quiet_peer() -> Result<(), Error> {
+        let (mut a, mut b) = socket_pair().await?;
+        b.set_read_timeout(Some(Duration::from_millis(20)));
+
+        // a peer with nothing to say isn't stalling a frame
+        let ping = msgs::Ping {
+            ponglen: 4,
+            byteslen: 0,
+        };
+        let (msg, _) = tokio::join!(b.read(), async {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            a.write(&ping).await
+        });
+        assert!(matches!(msg?, Message::Ping(p) if p == ping));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_cancelled() -> Result<(), Error> {
+        let (mut a, mut b) = socket_pair().await?;
+        let frames: Vec<u8> = [3, 4]
+            .into_iter()
+            .flat_map(|ponglen| {
+                b.channel.encrypt_message(&msgs::Ping {
+                    ponglen,
+                    byteslen: 0,
+                })
+            })
+            .collect();
+
+        // reads that lose a select! midway through the header, just past it, and in the
+        // middle of the body
+        for (from, to) in [(0, 10), (10, 20), (20, 30)] {
+            b.stream.write_all(&frames[from..to]).await?;
+            tokio::select! {
+                _ = a.read() => panic!("the frame isn't all there"),
+                _ = tokio::time::sleep(Duration::from_millis(20)) => {}
+            }
+        }
+        b.stream.write_all(&frames[30..]).await?;
+        assert!(matches!(a.read().await?, Message::Ping(p) if p.ponglen == 3));
+        assert!(matches!(a.read().await?, Message::Ping(p) if p.ponglen == 4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_with_timeout() -> Result<(), Error> {
+        let (mut a, mut b) = socket_pair().await?;
+        let limit = Duration::from_millis(20);
+        assert!(matches!(
+            a.read_with_timeout(limit).await,
+            Err(Error::Timeout)
+        ));
+
+        // a frame that's half there when the deadline passes is picked up by the next read
+        let frame = b.channel.encrypt_message(&msgs::Ping {
+            ponglen: 7,
+            byteslen: 0,
+        });
+        b.stream.write_all(&frame[..10]).await?;
+        assert!(matches!(
+            a.read_with_timeout(limit).await,
+            Err(Error::Timeout)
+        ));
+        b.stream.write_all(&frame[10..]).await?;
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        assert!(matches!(a.read_until(deadline).await?, Message::Ping(p) if p.ponglen == 7));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation_both_directions() -> Result<(), Error> {
+        let (mut a, mut b) = socket_pair().await?;
+
+        // every message uses two nonces (length header + body), so keys rotate every 500
+        // messages. push well past a couple of rotations each way.
+        pump(&mut a, &mut b, 1201).await?;
+        pump(&mut b, &mut a, 1201).await?;
+
+        // and interleaved, so both directions cross boundaries at different offsets
+        for round in 0..7 {
+            pump(&mut a, &mut b, 499 + round).await?;
+            pump(&mut b, &mut a, 1 + round).await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_split() -> Result<(), Error> {
+        let (a, b) = socket_pair().await?;
+        let (mut a_reader, mut a_writer) = a.split();
+        let (mut b_reader, mut b_writer) = b.split();
+
+        async fn send(writer: &mut LNWriter, count: u16) -> Result<(), Error> {
+            for i in 0..count {
+                writer
+                    .write(&msgs::Ping {
+                        ponglen: i,
+                        byteslen: i % 97,
+                    })
+                    .await?;
+            }
+            Ok(())
+        }
+        async fn recv(mut reader: LNReader, count: u16) -> Result<LNReader, Error> {
+            for i in 0..count {
+                match reader.read().await? {
+                    Message::Ping(ping) => assert_eq!(ping.ponglen, i),
+                    other => panic!("expected ping {i}, got {other:?}"),
+                }
+            }
+            Ok(reader)
+        }
+
+        // both directions at once, past key rotations, with the readers on their own tasks
+        let a_recv = tokio::spawn(recv(a_reader, 1201));
+        let b_recv = tokio::spawn(recv(b_reader, 1201));
+        let (a_sent, b_sent) = tokio::join!(send(&mut a_writer, 1201), send(&mut b_writer, 1201));
+        a_sent?;
+        b_sent?;
+        a_reader = a_recv.await.unwrap()?;
+        b_reader = b_recv.await.unwrap()?;
+
+        let ping = msgs::Ping {
+            ponglen: 4,
+            byteslen: 0,
+        };
+        a_writer.write(&ping).await?;
+        let Message::Ping(ping) = b_reader.read().await? else {
+            panic!("expected a ping");
+        };
+        let pong = b_writer.pong_for(&ping)?.expect("pong");
+        b_writer.write(&pong).await?;
+        assert!(matches!(a_reader.read().await?, Message::Pong(p) if p.byteslen == 4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_and_sink() -> Result<(), Error> {
+        use futures_util::{SinkExt, StreamExt};
+
+        let (a, b) = socket_pair().await?;
+        let (_reader, writer) = a.split();
+        let mut sink = Box::pin(writer.into_sink::<msgs::Ping>());
+        let pings = Box::pin(b.into_stream());
+
+        for ponglen in 0..3 {
+            sink.send(msgs::Ping {
+                ponglen,
+                byteslen: 0,
+            })
+            .await?;
+        }
+        let got: Vec<u16> = pings
+            .take(3)
+            .map(|msg| match msg {
+                Ok(Message::Ping(ping)) => ping.ponglen,
+                other => panic!("expected a ping, got {other:?}"),
+            })
+            .collect()
+            .await;
+        assert_eq!(got, [0, 1, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation_boundary() -> Result<(), Error> {
+        let (mut a, mut b) = socket_pair().await?;
+
+        // init used one message each way. land exactly on, just before, and just after the
+        // rotation boundary one message at a time.
+        for _ in 0..1003 {
+            pump(&mut a, &mut b, 1).await?;
+            pump(&mut b, &mut a, 1).await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_pong() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut lnsocket, _peer) = MockPeer::connect(key).await?;
+        lnsocket.perform_init().await?;
+
+        lnsocket
+            .write(&msgs::Ping {
+                ponglen: 4,
+                byteslen: 8,
+            })
+            .await?;
+
+        let pong = lnsocket.wait_for::<msgs::Pong>().await?;
+        assert_eq!(pong.byteslen, 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auto_pong() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut lnsocket, mut peer) = MockPeer::connect(key).await?;
+        lnsocket.perform_init().await?;
+        lnsocket.set_auto_pong(true);
+
+        peer.send(&msgs::Ping {
+            ponglen: 5,
+            byteslen: 0,
+        })?;
+        let storage = msgs::PeerStorage { data: vec![1] };
+        peer.send(&storage)?;
+        // the ping is answered on the way and never returned
+        assert!(matches!(lnsocket.read().await?, Message::PeerStorage(s) if s == storage));
+        assert!(matches!(peer.recv().await, Some(Message::Pong(p)) if p.byteslen == 5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wait_for() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut lnsocket, mut peer) = MockPeer::connect(key).await?;
+        lnsocket.perform_init().await?;
+
+        let storage = msgs::PeerStorage { data: vec![1] };
+        peer.send(&storage)?;
+        peer.send(&msgs::Ping {
+            ponglen: 2,
+            byteslen: 0,
+        })?;
+        lnsocket
+            .write(&msgs::Ping {
+                ponglen: 4,
+                byteslen: 0,
+            })
+            .await?;
+        let pong = lnsocket.wait_for::<msgs::Pong>().await?;
+        assert_eq!(pong.byteslen, 4);
+        // the peer's ping was answered on the way, and the rest kept
+        assert!(matches!(peer.recv().await, Some(Message::Pong(p)) if p.byteslen == 2));
+        assert!(matches!(lnsocket.read().await?, Message::PeerStorage(s) if s == storage));
+
+        let limit = Duration::from_millis(20);
+        let res = lnsocket.wait_for_timeout::<msgs::Pong>(limit).await;
+        assert!(matches!(res, Err(Error::Timeout)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commando() -> Result<(), Error> {
+        use crate::commando::{COMMANDO_COMMAND, COMMANDO_REPLY_TERM, CommandoClient};
+
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut lnsocket, mut peer) = MockPeer::connect(key).await?;
+        lnsocket.perform_init().await?;
+        let mut commando = CommandoClient::new(
+            "hfYByx-RDwdBfAK-vOWeOCDJVYlvKSioVKU_y7jccZU9MjkmbWV0aG9kPWdldGluZm8=",
+        );
+
+        // a node that only allows getinfo, like the rune above says
+        let node = async {
+            for _ in 0..2 {
+                let Some(Message::Custom(raw)) = peer.recv().await else {
+                    panic!("expected a commando command");
+                };
+                assert_eq!(raw.type_id, COMMANDO_COMMAND);
+                let (req_id, json) = raw.payload.split_at(8);
+                let cmd: serde_json::Value = serde_json::from_slice(json).unwrap();
+                let reply = if cmd["method"] == "getinfo" {
+                    serde_json::json!({"result": {"alias": "mock"}})
+                } else {
+                    serde_json::json!({"error": {"code": 19537, "message": "Not authorized"}})
+                };
+                let mut payload = req_id.to_vec();
+                payload.extend(reply.to_string().into_bytes());
+                peer.send(&RawMessage {
+                    type_id: COMMANDO_REPLY_TERM,
+                    payload,
+                })
+                .unwrap();
+            }
+        };
+        let client = async {
+            let resp = commando
+                .call(&mut lnsocket, "getinfo", serde_json::json!({}))
+                .await?;
+            let bad_resp = commando
+                .call(
+                    &mut lnsocket,
+                    "invoice",
+                    serde_json::json!({"msatoshi": "any"}),
+                )
+                .await?;
+            Ok::<_, Error>((resp, bad_resp))
+        };
+        let (_, res) = tokio::join!(node, client);
+        let (resp, bad_resp) = res?;
+
+        assert_eq!(resp["result"]["alias"], "mock");
+        assert_eq!(bad_resp["error"]["code"], 19537);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_suppress_gossip() -> Result<(), Error> {
+        let opts = InitOptions {
+            suppress_gossip: true,
+            ..Default::default()
+        };
+
+        let mut features = Features::empty();
+        features.set_optional(bits::GOSSIP_QUERIES);
+        let init = msgs::Init {
+            features: features.to_be_bytes(),
+            ..crate::testing::default_init()
+        };
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut lnsocket, mut peer) = MockPeer::connect_with(key, init).await?;
+        lnsocket.perform_init_with(&opts).await?;
+
+        let their_init = peer.their_init().await.unwrap();
+        assert!(Features::from_be_bytes(their_init.features).supports(bits::GOSSIP_QUERIES));
+        match peer.recv().await {
+            Some(Message::GossipTimestampFilter(filter)) => {
+                assert_eq!(filter.chain_hash, ChainHash::BITCOIN);
+                assert_eq!(filter.first_timestamp, u32::MAX);
+            }
+            other => panic!("expected a gossip filter, got {other:?}"),
+        }
+
+        // peers without gossip_queries don't get one
+        let (mut lnsocket, mut peer) = MockPeer::connect(key).await?;
+        lnsocket.perform_init_with(&opts).await?;
+        let storage = msgs::PeerStorage { data: vec![1] };
+        lnsocket.write(&storage).await?;
+        assert!(matches!(peer.recv().await, Some(Message::PeerStorage(s)) if s == storage));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_and_wait() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut lnsocket, peer) = MockPeer::connect(key).await?;
+        lnsocket.perform_init().await?;
+
+        // something unrelated arrives before the answer
+        let storage = msgs::PeerStorageRetrieval { data: vec![7] };
+        peer.send(&storage)?;
+        let ping = msgs::Ping {
+            ponglen: 5,
+            byteslen: 0,
+        };
+        let deadline = Duration::from_secs(5);
+        let pong = lnsocket
+            .send_and_wait(&ping, |msg| matches!(msg, Message::Pong(_)), deadline)
+            .await?;
+        assert!(matches!(pong, Message::Pong(p) if p.byteslen == 5));
+        assert!(matches!(lnsocket.read().await?, Message::PeerStorageRetrieval(s) if s == storage));
+
+        // nothing matches
+        let res = lnsocket
+            .send_and_wait(&ping, |_| false, Duration::from_millis(50))
+            .await;
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert!(matches!(lnsocket.read().await?, Message::Pong(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_from_stream() -> Result<(), Error> {
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &node_key);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let node = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            LNSocket::accept_over(stream, node_key).await
+        });
+
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let socket = LNSocket::from_stream(stream, key, node_id).await?;
+        assert_eq!(socket.peer_addr(), Some(addr));
+        assert_eq!(socket.their_pubkey(), node_id);
+        node.await.unwrap()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_addr() -> Result<(), Error> {
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &node_key);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let node = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await?;
+                LNSocket::accept_over(stream, node_key).await?;
+            }
+            Ok::<_, Error>(())
+        });
+
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let socket = LNSocket::connect_addr(key, node_id, addr).await?;
+        assert_eq!(socket.peer_addr(), Some(addr));
+        let socket = LNSocket::connect_addr(key, node_id, (addr.ip(), addr.port())).await?;
+        assert_eq!(socket.peer_addr(), Some(addr));
+        node.await.unwrap()?;
+
+        let opts = ConnectOptions {
+            dns: crate::dns::DnsOptions {
+                preference: crate::dns::AddressPreference::Ipv6Only,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let res = LNSocket::connect_addr_with(&opts, key, node_id, addr).await;
+        assert!(matches!(res, Err(Error::DnsError)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_events() -> Result<(), Error> {
+        use crate::event::DisconnectReason;
+
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &node_key);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let node = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut peer = LNSocket::accept_over(stream, node_key).await?;
+            peer.write(&default_init()).await?;
+            assert!(matches!(peer.read().await?, Message::Init(_)));
+            let Message::Ping(ping) = peer.read().await? else {
+                panic!("expected a ping");
+            };
+            let pong = peer.pong_for(&ping)?.expect("a pong");
+            peer.write(&pong).await
+        });
+
+        let (tx, mut events) = mpsc::unbounded_channel();
+        let opts = ConnectOptions {
+            events: Some(tx),
+            ..Default::default()
+        };
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let mut socket = LNSocket::connect_with(&opts, key, node_id, &addr).await?;
+        socket.perform_init().await?;
+        socket
+            .write(&msgs::Ping {
+                ponglen: 1,
+                byteslen: 0,
+            })
+            .await?;
+        assert!(matches!(socket.read().await?, Message::Pong(_)));
+        node.await.unwrap()?;
+        assert!(socket.read().await.is_err());
+        assert!(socket.read().await.is_err());
+
+        assert_eq!(events.try_recv().ok(), Some(Event::HandshakeCompleted));
+        assert!(matches!(events.try_recv(), Ok(Event::InitReceived(_))));
+        assert_eq!(events.try_recv().ok(), Some(Event::PingSent));
+        assert_eq!(events.try_recv().ok(), Some(Event::PongReceived));
+        let disconnected = Event::Disconnected {
+            reason: DisconnectReason::Closed,
+        };
+        assert_eq!(events.try_recv().ok(), Some(disconnected));
+        // only the first failed read says so
+        assert!(events.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recovery() -> Result<(), Error> {
+        use crate::recovery::Recovery;
+
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &node_key);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+
+        let node = tokio::spawn(async move {
+            let accept = || async {
+                let (stream, _) = listener.accept().await?;
+                let mut peer = LNSocket::handshake_inbound(stream, node_key).await?;
+                peer.write(&default_init()).await?;
+                assert!(matches!(peer.read().await?, Message::Init(_)));
+                Ok::<_, Error>(peer)
+            };
+
+            // skip a nonce, so the client can't decrypt anything we say anymore
+            let mut broken = accept().await?;
+            let ping = msgs::Ping {
+                ponglen: 0,
+                byteslen: 0,
+            };
+            broken.channel.encrypt_message(&ping);
+            broken.write(&ping).await?;
+
+            let mut peer = accept().await?;
+            let restored = peer.read_custom(|typ, _buf| Ok(Some(typ))).await?;
+            assert!(matches!(restored, Message::Custom(0x8001)));
+            peer.write(&msgs::Ping {
+                ponglen: 1,
+                byteslen: 0,
+            })
+            .await?;
+            // keep both connections open until the client is done
+            peer.read().await.ok();
+            drop(broken);
+            Ok::<_, Error>(())
+        });
+
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let mut lnsocket = LNSocket::connect_and_init(key, node_id, &addr).await?;
+        let mut events = lnsocket.subscribe_events();
+        let mut recovery = Recovery::new(key, &addr);
+        recovery.restore(&RawMessage {
+            type_id: 0x8001,
+            payload: vec![],
+        })?;
+        lnsocket.set_recovery(Some(recovery));
+
+        assert!(matches!(lnsocket.read().await?, Message::Ping(p) if p.ponglen == 1));
+        let disconnected = Event::Disconnected {
+            reason: crate::event::DisconnectReason::Decryption,
+        };
+        assert_eq!(events.try_recv().ok(), Some(disconnected));
+        assert_eq!(events.try_recv().ok(), Some(Event::Reconnected));
+        drop(lnsocket);
+        node.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_write_all_msgs() -> Result<(), Error> {
+        let pings: Vec<_> = (1..=3)
+            .map(|ponglen| msgs::Ping {
+                ponglen,
+                byteslen: 0,
+            })
+            .collect();
+
+        // nothing goes out before init, not even the messages that come first
+        let (mut a, mut b) = handshaked_pair().await?;
+        let res = a.write_all_msgs(&pings).await;
+        assert!(matches!(res, Err(Error::InitNotComplete)));
+        // init alone may go before the peer's
+        let batch = [default_init()];
+        a.write_all_msgs(&batch).await?;
+        b.write(&default_init()).await?;
+        assert!(matches!(b.read().await?, Message::Init(_)));
+        assert!(matches!(a.read().await?, Message::Init(_)));
+
+        a.write_all_msgs(&pings).await?;
+        for i in 1..=3 {
+            assert!(matches!(b.read().await?, Message::Ping(p) if p.ponglen == i));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() -> Result<(), Error> {
+        let (mut a, mut b) = socket_pair().await?;
+        a.set_rate_limit(Some(crate::ratelimit::RateLimit {
+            messages_per_sec: Some(20),
+            burst: Duration::from_millis(100),
+            ..Default::default()
+        }));
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 0,
+        };
+
+        // a burst of 2 goes out at once, the next 3 at 50ms intervals
+        let start = tokio::time::Instant::now();
+        for _ in 0..5 {
+            a.write(&ping).await?;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        for _ in 0..5 {
+            assert!(matches!(b.read().await?, Message::Ping(_)));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close() -> Result<(), Error> {
+        let (mut a, mut b) = socket_pair().await?;
+        let mut events = a.subscribe_events();
+        a.close(CloseReason::Warning("going away".to_owned()))
+            .await?;
+
+        let Message::Warning(warning) = b.read().await? else {
+            panic!("expected a warning");
+        };
+        assert_eq!(warning.channel_id, ChannelId::new_zero());
+        assert_eq!(warning.data, "going away");
+        assert!(matches!(
+            b.read().await,
+            Err(Error::Io(io::ErrorKind::UnexpectedEof))
+        ));
+        let closed = Event::Disconnected {
+            reason: DisconnectReason::Local,
+        };
+        assert_eq!(events.recv().await, Some(closed));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_into_parts() -> Result<(), Error> {
+        let (a, mut b) = socket_pair().await?;
+        let ping = msgs::Ping {
+            ponglen: 2,
+            byteslen: 0,
+        };
+
+        // take over the stream and speak the protocol by hand
+        let mut parts = a.into_parts();
+        assert!(parts.sent_init && parts.peer_info.is_some());
+        assert!(parts.partial.is_empty());
+        let frame = parts.channel.encrypt_message(&ping);
+        let mut stream = parts
+            .stream
+            .into_any()
+            .downcast::<tokio::io::DuplexStream>()
+            .expect("a duplex stream");
+        stream.write_all(&frame).await?;
+        assert!(matches!(b.read().await?, Message::Ping(p) if p.ponglen == 2));
+
+        parts.stream = stream;
+        let mut a = LNSocket::from_parts(parts);
+        b.write(&ping).await?;
+        assert!(matches!(a.read().await?, Message::Ping(_)));
+        a.write(&ping).await?;
+        assert!(matches!(b.read().await?, Message::Ping(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_raw() -> Result<(), Error> {
+        let (mut a, mut b) = handshaked_pair().await?;
+        a.write(&default_init()).await?;
+        b.write(&default_init()).await?;
+        assert!(matches!(a.read().await?, Message::Init(_)));
+        a.write(&RawMessage {
+            type_id: 0x8123,
+            payload: vec![1, 2, 3],
+        })
+        .await?;
+
+        // the peer's init still counts as init
+        let (type_id, _) = b.read_raw().await?;
+        assert_eq!(type_id, msgs::Init::TYPE);
+        assert!(b.peer_info().is_some());
+        assert_eq!(
+            b.read_raw().await?,
+            (0x8123, Bytes::from_static(&[1, 2, 3]))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rekey_policy() -> Result<(), Error> {
+        use crate::rekey::{FILLER_TYPE, RekeyPolicy};
+
+        let (mut a, mut b) = socket_pair().await?;
+        a.set_rekey_policy(RekeyPolicy {
+            max_messages: Some(10),
+            max_bytes: None,
+        });
+        let ping = msgs::Ping {
+            ponglen: 0,
+            byteslen: 0,
+        };
+
+        // init and nine pings reach the limit, then fillers up to the 500 message rotation point
+        for _ in 0..9 {
+            a.write(&ping).await?;
+        }
+        let mut fillers = 0;
+        let mut pings = 0;
+        while pings < 9 || fillers < 490 {
+            match b.read().await? {
+                Message::Ping(_) => pings += 1,
+                Message::Unknown(FILLER_TYPE) => fillers += 1,
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        assert_eq!((pings, fillers), (9, 490));
 
+        // the next message goes out under the new key
+        a.write(&ping).await?;
+        assert!(matches!(b.read().await?, Message::Ping(_)));
+        assert_eq!(a.channel.messages_until_rekey(), 499);
         Ok(())
     }
 }
@@ -0,0 +1,321 @@
+//! Experimental: carrying a connection over a Nostr relay.
+//!
+//! Two endpoints that can't reach each other directly (both behind NAT, say) can still both
+//! reach a Nostr relay. Each chunk of the byte stream is sent as an ephemeral event addressed
+//! to the counterpart with a `p` tag, and the Noise layer on top keeps it private. The relay
+//! only sees who is talking to whom, and how much.
+//!
+//! The "address" is the relay URL plus the counterpart's Nostr public key, see
+//! [`NostrAddress`]. Both sides must be using this transport.
+//!
+//! Only available with the `experimental` feature. The event kind and content format aren't
+//! standardized and may change.
+
+use crate::{Error, LNSocket};
+use bitcoin::hashes::{Hash, sha256};
+use bitcoin::secp256k1::{
+    Keypair, Message, PublicKey, Secp256k1, SecretKey, XOnlyPublicKey, schnorr,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+/// The ephemeral event kind carrying stream chunks. Relays forward ephemeral events without
+/// storing them.
+pub const STREAM_EVENT_KIND: u64 = 25735;
+
+/// Largest chunk of the stream put in one event.
+const MAX_CHUNK: usize = 16 * 1024;
+
+/// How far past the next chunk due one may arrive. Further than that, the relay lost some.
+const MAX_REORDER: u64 = 64;
+
+/// Where to find the other end of a Nostr-carried connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NostrAddress {
+    /// The relay both sides are connected to, e.g. `wss://relay.example.com`.
+    pub relay: String,
+    /// The counterpart's Nostr public key.
+    pub counterpart: XOnlyPublicKey,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn event_id(pubkey: &XOnlyPublicKey, created_at: u64, tags: &Value, content: &str) -> [u8; 32] {
+    // NIP-01: the id is the hash of this exact array, serialized without whitespace
+    let commitment = json!([
+        0,
+        pubkey.to_string(),
+        created_at,
+        STREAM_EVENT_KIND,
+        tags,
+        content
+    ]);
+    sha256::Hash::hash(commitment.to_string().as_bytes()).to_byte_array()
+}
+
+/// A signed event carrying chunk number `seq` of the stream.
+fn build_event(keys: &Keypair, to: &XOnlyPublicKey, seq: u64, chunk: &[u8]) -> Value {
+    let secp_ctx = Secp256k1::signing_only();
+    let (pubkey, _) = keys.x_only_public_key();
+    let created_at = now();
+    let tags = json!([["p", to.to_string()]]);
+    let content = format!("{}:{}", seq, hex::encode(chunk));
+    let id = event_id(&pubkey, created_at, &tags, &content);
+    let sig = secp_ctx.sign_schnorr(&Message::from_digest(id), keys);
+    json!({
+        "id": hex::encode(id),
+        "pubkey": pubkey.to_string(),
+        "created_at": created_at,
+        "kind": STREAM_EVENT_KIND,
+        "tags": tags,
+        "content": content,
+        "sig": sig.to_string(),
+    })
+}
+
+/// The sequence number and chunk of a stream event from `from`, if it's a valid one.
+fn parse_event(event: &Value, from: &XOnlyPublicKey) -> Option<(u64, Vec<u8>)> {
+    let pubkey: XOnlyPublicKey = event["pubkey"].as_str()?.parse().ok()?;
+    if pubkey != *from || event["kind"].as_u64()? != STREAM_EVENT_KIND {
+        return None;
+    }
+    let created_at = event["created_at"].as_u64()?;
+    let content = event["content"].as_str()?;
+    let id = event_id(&pubkey, created_at, &event["tags"], content);
+    if hex::encode(id) != event["id"].as_str()? {
+        return None;
+    }
+    let sig: schnorr::Signature = event["sig"].as_str()?.parse().ok()?;
+    Secp256k1::verification_only()
+        .verify_schnorr(&sig, &Message::from_digest(id), &pubkey)
+        .ok()?;
+
+    let (seq, data) = content.split_once(':')?;
+    Some((seq.parse().ok()?, hex::decode(data).ok()?))
+}
+
+fn ws_error<E>(_: E) -> Error {
+    Error::Io(io::ErrorKind::ConnectionAborted)
+}
+
+/// Puts chunks the relay reordered back in order.
+#[derive(Default)]
+struct Reorder {
+    next: u64,
+    early: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Reorder {
+    /// Take chunk `seq`, returning the chunks that can now be delivered, in order. Fails once
+    /// `seq` is [`MAX_REORDER`] or more ahead of the next one due.
+    fn push(&mut self, seq: u64, data: Vec<u8>) -> io::Result<Vec<Vec<u8>>> {
+        // a repeat of one already delivered
+        if seq < self.next {
+            return Ok(Vec::new());
+        }
+        if seq - self.next >= MAX_REORDER {
+            let msg = "nostr relay lost stream chunks";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        self.early.insert(seq, data);
+        let mut ready = Vec::new();
+        while let Some(data) = self.early.remove(&self.next) {
+            ready.push(data);
+            self.next += 1;
+        }
+        Ok(ready)
+    }
+}
+
+/// A byte stream to the counterpart, from [`connect_stream`].
+pub struct NostrStream {
+    inner: DuplexStream,
+    // why the relay task gave up, reported in place of the end of the stream
+    failed: Arc<Mutex<Option<io::Error>>>,
+}
+
+impl AsyncRead for NostrStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == filled && buf.remaining() > 0 => {
+                match self.failed.lock().unwrap().take() {
+                    Some(err) => Poll::Ready(Err(err)),
+                    None => Poll::Ready(Ok(())),
+                }
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl AsyncWrite for NostrStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Open a byte stream to `addr.counterpart` through `addr.relay`.
+///
+/// The relay connection is driven by a background task, which ends when the returned stream
+/// is dropped or the relay goes away. Chunks are numbered, so they are delivered in order even
+/// if the relay reorders events. A relay that loses chunks fails the stream: reads error out
+/// once a chunk arrives 64 chunks ahead of the one missing.
+pub async fn connect_stream(addr: &NostrAddress, keys: &Keypair) -> Result<NostrStream, Error> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(addr.relay.as_str())
+        .await
+        .map_err(ws_error)?;
+    let (ours, _) = keys.x_only_public_key();
+    let counterpart = addr.counterpart;
+
+    let sub_id = hex::encode(&ours.serialize()[..8]);
+    let filter = json!({
+        "kinds": [STREAM_EVENT_KIND],
+        "authors": [counterpart.to_string()],
+        "#p": [ours.to_string()],
+        "since": now(),
+    });
+    ws.send(WsMessage::text(json!(["REQ", sub_id, filter]).to_string()))
+        .await
+        .map_err(ws_error)?;
+
+    let (stream, mut pipe) = tokio::io::duplex(2 * MAX_CHUNK);
+    let failed = Arc::new(Mutex::new(None));
+    let stream = NostrStream {
+        inner: stream,
+        failed: failed.clone(),
+    };
+    let keys = *keys;
+    tokio::spawn(async move {
+        let mut next_out = 0u64;
+        let mut incoming = Reorder::default();
+        let mut chunk = vec![0u8; MAX_CHUNK];
+        loop {
+            tokio::select! {
+                n = pipe.read(&mut chunk) => {
+                    let n = match n {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => n,
+                    };
+                    let event = build_event(&keys, &counterpart, next_out, &chunk[..n]);
+                    next_out += 1;
+                    let msg = WsMessage::text(json!(["EVENT", event]).to_string());
+                    if ws.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                msg = ws.next() => {
+                    let text = match msg {
+                        Some(Ok(WsMessage::Text(text))) => text,
+                        Some(Ok(_)) => continue,
+                        _ => break,
+                    };
+                    let Ok(Value::Array(parts)) = serde_json::from_str::<Value>(&text) else {
+                        continue;
+                    };
+                    if parts.first().and_then(Value::as_str) != Some("EVENT") {
+                        continue;
+                    }
+                    let Some((seq, data)) = parts.get(2).and_then(|e| parse_event(e, &counterpart)) else {
+                        continue;
+                    };
+                    let ready = match incoming.push(seq, data) {
+                        Ok(ready) => ready,
+                        Err(err) => {
+                            *failed.lock().unwrap() = Some(err);
+                            break;
+                        }
+                    };
+                    for data in ready {
+                        if pipe.write_all(&data).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(stream)
+}
+
+impl LNSocket {
+    /// Like [`LNSocket::connect`], but over a Nostr relay. `nostr_keys` identify us on the
+    /// relay and are unrelated to our node key.
+    pub async fn connect_nostr(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &NostrAddress,
+        nostr_keys: &Keypair,
+    ) -> Result<LNSocket, Error> {
+        let stream = connect_stream(addr, nostr_keys).await?;
+        Self::handshake_outbound(stream, our_key, their_pubkey).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::rand;
+
+    #[test]
+    fn test_event_roundtrip() {
+        let secp_ctx = Secp256k1::new();
+        let alice = Keypair::new(&secp_ctx, &mut rand::thread_rng());
+        let bob = Keypair::new(&secp_ctx, &mut rand::thread_rng());
+        let (alice_pk, _) = alice.x_only_public_key();
+        let (bob_pk, _) = bob.x_only_public_key();
+
+        let event = build_event(&alice, &bob_pk, 7, b"noise");
+        assert_eq!(parse_event(&event, &alice_pk), Some((7, b"noise".to_vec())));
+        // only events from the counterpart count
+        assert_eq!(parse_event(&event, &bob_pk), None);
+
+        let mut tampered = event.clone();
+        tampered["content"] = json!("7:00");
+        assert_eq!(parse_event(&tampered, &alice_pk), None);
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut reorder = Reorder::default();
+        assert!(reorder.push(1, vec![1]).unwrap().is_empty());
+        assert_eq!(reorder.push(0, vec![0]).unwrap(), vec![vec![0], vec![1]]);
+        // repeats are dropped
+        assert!(reorder.push(1, vec![1]).unwrap().is_empty());
+
+        // chunk 2 never comes
+        assert!(reorder.push(MAX_REORDER + 1, vec![]).unwrap().is_empty());
+        let err = reorder.push(MAX_REORDER + 2, vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,190 @@
+//! BOLT 1 `ping` handling rules.
+//!
+//! A peer decides how large our `pong` is and how often we send one, so answering every ping
+//! blindly lets it burn our upload bandwidth. [`PingPolicy`] applies the spec's rules plus a
+//! configurable size cap and flood limit.
+
+use crate::ln::msgs;
+use crate::timing::Instant;
+use std::time::Duration;
+
+/// Pings asking for `num_pong_bytes` of this value or more must not be answered (BOLT 1).
+pub const PONG_IGNORE_THRESHOLD: u16 = 65532;
+
+/// What to do when a peer sends pings faster than [`PingPolicy`] allows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PingFloodAction {
+    /// Silently stop answering until the window resets.
+    Ignore,
+    /// Treat the flood as a protocol violation and fail with [`Error::PingFlood`].
+    ///
+    /// [`Error::PingFlood`]: crate::Error::PingFlood
+    Disconnect,
+}
+
+/// Rules for answering incoming pings.
+#[derive(Clone, Debug)]
+pub struct PingPolicy {
+    /// Largest pong body we are willing to send. Pings asking for more are ignored.
+    ///
+    /// Defaults to the spec maximum of 65531. Lowering it deviates from BOLT 1 (which requires
+    /// answering anything below 65532), but some peers abuse large pongs.
+    pub max_pong_len: u16,
+    /// How many pings we answer per [`PingPolicy::window`].
+    pub max_pings: u32,
+    /// The rate limiting window.
+    pub window: Duration,
+    /// What happens when the rate limit is exceeded.
+    pub on_flood: PingFloodAction,
+}
+
+impl Default for PingPolicy {
+    fn default() -> Self {
+        Self {
+            max_pong_len: PONG_IGNORE_THRESHOLD - 1,
+            // BOLT 1 only expects about one ping per 30 seconds, leave plenty of slack
+            max_pings: 10,
+            window: Duration::from_secs(30),
+            on_flood: PingFloodAction::Ignore,
+        }
+    }
+}
+
+/// Pinging an idle peer to find out whether it's still there.
+///
+/// A TCP connection whose other end vanished (a phone changing networks, a Tor circuit
+/// collapsing) can look healthy for a long time, since nothing notices until a write goes
+/// unacknowledged. Sending a ping every [`KeepalivePolicy::interval`] and expecting the pong
+/// within [`KeepalivePolicy::timeout`] notices. Used by
+/// [`RunOptions::keepalive`](crate::handle::RunOptions::keepalive).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeepalivePolicy {
+    /// How long after the last pong (or the start) the next ping is sent.
+    pub interval: Duration,
+    /// How long the pong may take before the peer counts as gone.
+    pub timeout: Duration,
+}
+
+impl Default for KeepalivePolicy {
+    fn default() -> Self {
+        Self {
+            // CLN and LND ping about once a minute, and drop peers that don't answer in 30s
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Tracks ping rate and decides whether (and how) to answer each ping.
+#[derive(Debug)]
+pub(crate) struct PingResponder {
+    pub(crate) policy: PingPolicy,
+    window_start: Option<Instant>,
+    count: u32,
+}
+
+/// The outcome of [`PingResponder::respond`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PingResponse {
+    Pong(msgs::Pong),
+    Ignore,
+    Flood,
+}
+
+impl PingResponder {
+    pub(crate) fn new(policy: PingPolicy) -> Self {
+        Self {
+            policy,
+            window_start: None,
+            count: 0,
+        }
+    }
+
+    pub(crate) fn respond(&mut self, ping: &msgs::Ping) -> PingResponse {
+        self.respond_at(ping, Instant::now())
+    }
+
+    fn respond_at(&mut self, ping: &msgs::Ping, now: Instant) -> PingResponse {
+        match self.window_start {
+            Some(start) if now.duration_since(start) < self.policy.window => {}
+            _ => {
+                self.window_start = Some(now);
+                self.count = 0;
+            }
+        }
+
+        self.count = self.count.saturating_add(1);
+        if self.count > self.policy.max_pings {
+            return match self.policy.on_flood {
+                PingFloodAction::Ignore => PingResponse::Ignore,
+                PingFloodAction::Disconnect => PingResponse::Flood,
+            };
+        }
+
+        if ping.ponglen >= PONG_IGNORE_THRESHOLD || ping.ponglen > self.policy.max_pong_len {
+            return PingResponse::Ignore;
+        }
+
+        PingResponse::Pong(msgs::Pong {
+            byteslen: ping.ponglen,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping(ponglen: u16) -> msgs::Ping {
+        msgs::Ping {
+            ponglen,
+            byteslen: 0,
+        }
+    }
+
+    #[test]
+    fn test_oversized_pings_are_ignored() {
+        let mut responder = PingResponder::new(PingPolicy::default());
+        assert_eq!(responder.respond(&ping(65532)), PingResponse::Ignore);
+        assert_eq!(responder.respond(&ping(u16::MAX)), PingResponse::Ignore);
+        assert_eq!(
+            responder.respond(&ping(65531)),
+            PingResponse::Pong(msgs::Pong { byteslen: 65531 })
+        );
+
+        responder.policy.max_pong_len = 16;
+        assert_eq!(responder.respond(&ping(17)), PingResponse::Ignore);
+        assert_eq!(
+            responder.respond(&ping(16)),
+            PingResponse::Pong(msgs::Pong { byteslen: 16 })
+        );
+    }
+
+    #[test]
+    fn test_ping_flood() {
+        let policy = PingPolicy {
+            max_pings: 2,
+            on_flood: PingFloodAction::Disconnect,
+            ..Default::default()
+        };
+        let window = policy.window;
+        let mut responder = PingResponder::new(policy);
+        let start = Instant::now();
+
+        assert!(matches!(
+            responder.respond_at(&ping(4), start),
+            PingResponse::Pong(_)
+        ));
+        assert!(matches!(
+            responder.respond_at(&ping(4), start),
+            PingResponse::Pong(_)
+        ));
+        assert_eq!(responder.respond_at(&ping(4), start), PingResponse::Flood);
+
+        // a new window forgives the peer
+        assert!(matches!(
+            responder.respond_at(&ping(4), start + window),
+            PingResponse::Pong(_)
+        ));
+    }
+}
@@ -0,0 +1,199 @@
+//! Connecting through an HTTP proxy.
+//!
+//! Networks that only let traffic out through a web proxy usually still allow tunnels opened
+//! with `CONNECT`. The node's address is passed to the proxy as is, so host names are
+//! resolved by the proxy rather than locally.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::proxy::HttpProxy;
+//! # use bitcoin::secp256k1::{PublicKey, SecretKey};
+//! # async fn example(key: SecretKey, node: PublicKey) -> Result<(), lnsocket::Error> {
+//! let proxy = HttpProxy::new("proxy.corp.example:3128").with_basic_auth("alice", "hunter2");
+//! let socket = LNSocket::connect_via_http_proxy(key, node, "ln.example.com:9735", &proxy).await?;
+//! # Ok(()) }
+//! ```
+
+use crate::socket_addr::split_host_port;
+use crate::{Error, LNSocket};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use std::io;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// a proxy sending more than this before the end of its headers isn't one we want to talk to
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+/// An HTTP proxy to tunnel connections through with `CONNECT`.
+#[derive(Clone, Debug)]
+pub struct HttpProxy {
+    /// Where the proxy listens, `host:port`.
+    pub addr: String,
+    /// Username and password for `Basic` proxy authentication, if the proxy wants them.
+    pub auth: Option<(String, String)>,
+}
+
+impl HttpProxy {
+    /// The proxy at `addr`, without authentication.
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            auth: None,
+        }
+    }
+
+    /// Authenticate to the proxy with `Basic` credentials.
+    pub fn with_basic_auth(mut self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.auth = Some((user.into(), password.into()));
+        self
+    }
+}
+
+/// Open a tunnel to `addr` through the HTTP proxy `proxy`.
+pub(crate) async fn http_connect(proxy: &HttpProxy, addr: &str) -> Result<TcpStream, Error> {
+    let (host, port) = split_host_port(addr)?;
+    let authority = if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    };
+    let mut stream = TcpStream::connect(proxy.addr.as_str()).await?;
+
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some((user, password)) = &proxy.auth {
+        let credentials = STANDARD.encode(format!("{user}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // a byte at a time, so nothing past the headers is taken from the tunnel
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == MAX_RESPONSE_HEAD {
+            return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+        }
+        head.push(stream.read_u8().await?);
+    }
+    match parse_status(&head) {
+        Some(200..=299) => Ok(stream),
+        Some(status) => Err(Error::HttpProxy(status)),
+        None => Err(io::Error::from(io::ErrorKind::InvalidData).into()),
+    }
+}
+
+/// The status code of an HTTP response, from its first line.
+fn parse_status(head: &[u8]) -> Option<u16> {
+    let line = head.split(|&b| b == b'\r').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let mut parts = line.split(' ');
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+impl LNSocket {
+    /// Like [`LNSocket::connect`], but tunneled through the HTTP proxy `proxy`.
+    pub async fn connect_via_http_proxy(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+        proxy: &HttpProxy,
+    ) -> Result<LNSocket, Error> {
+        let start = Instant::now();
+        let stream = http_connect(proxy, addr).await?;
+        let tcp_connect = start.elapsed();
+        let mut socket = Self::handshake_outbound(stream, our_key, their_pubkey).await?;
+        socket.timings.tcp_connect = Some(tcp_connect);
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{Secp256k1, rand};
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// A one-shot proxy that answers the `CONNECT` with `status`, then plays the node if that
+    /// was a success. Returns the request it got.
+    async fn fake_proxy(listener: TcpListener, status: &str, node_key: SecretKey) -> Vec<String> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut request = Vec::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            request.push(line.trim_end().to_owned());
+        }
+        let response = format!("HTTP/1.1 {status}\r\nVia: fake\r\n\r\n");
+        stream.write_all(response.as_bytes()).await.unwrap();
+        if status.starts_with('2') {
+            LNSocket::handshake_inbound(stream.into_inner(), node_key)
+                .await
+                .unwrap();
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_proxy() -> Result<(), Error> {
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy = HttpProxy::new(listener.local_addr()?.to_string()).with_basic_auth("u", "p");
+        let fake = tokio::spawn(fake_proxy(listener, "200 Connection established", node_key));
+
+        let our_key = SecretKey::new(&mut rand::thread_rng());
+        let socket = LNSocket::connect_via_http_proxy(our_key, node_id, "::1", &proxy).await?;
+        assert_eq!(socket.their_pubkey(), node_id);
+        assert_eq!(
+            fake.await.unwrap(),
+            [
+                "CONNECT [::1]:9735 HTTP/1.1",
+                "Host: [::1]:9735",
+                "Proxy-Authorization: Basic dTpw",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_refusal() -> Result<(), Error> {
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy = HttpProxy::new(listener.local_addr()?.to_string());
+        let fake = tokio::spawn(fake_proxy(
+            listener,
+            "407 Proxy Authentication Required",
+            node_key,
+        ));
+
+        let our_key = SecretKey::new(&mut rand::thread_rng());
+        let res =
+            LNSocket::connect_via_http_proxy(our_key, node_id, "ln.example.com", &proxy).await;
+        assert!(matches!(res, Err(Error::HttpProxy(407))));
+        assert_eq!(
+            fake.await.unwrap()[0],
+            "CONNECT ln.example.com:9735 HTTP/1.1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(parse_status(b"HTTP/1.0 200 OK\r\n\r\n"), Some(200));
+        assert_eq!(parse_status(b"HTTP/1.1 502 Bad Gateway\r\n\r\n"), Some(502));
+        assert_eq!(parse_status(b"SSH-2.0-OpenSSH\r\n\r\n"), None);
+    }
+}
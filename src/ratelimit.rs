@@ -0,0 +1,161 @@
+//! Pacing what we send.
+//!
+//! Peers protect themselves from floods, and a burst of gossip queries or batched commando
+//! calls can look like one and get the connection dropped. A [`RateLimit`] set with
+//! [`LNSocket::set_rate_limit`](crate::LNSocket::set_rate_limit) makes
+//! [`LNSocket::write`](crate::LNSocket::write) wait whenever sending right away would go over
+//! the limit. Each limit is a token bucket: quiet periods build up an allowance of up to
+//! [`RateLimit::burst`] worth of traffic that can then be sent at once.
+//!
+//! Pongs sent from reads and the fillers of [`rekey`](crate::rekey) aren't held back, and
+//! don't count.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::ratelimit::RateLimit;
+//! # fn example(socket: &mut LNSocket) {
+//! socket.set_rate_limit(Some(RateLimit {
+//!     messages_per_sec: Some(50),
+//!     bytes_per_sec: Some(64 * 1024),
+//!     ..Default::default()
+//! }));
+//! # }
+//! ```
+
+use std::time::Duration;
+use tokio::time::{Instant, sleep_until};
+
+/// How fast messages may be sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Messages per second. `None`, the default, doesn't count messages.
+    pub messages_per_sec: Option<u32>,
+    /// Bytes per second, framing and MACs included. `None`, the default, doesn't count bytes.
+    pub bytes_per_sec: Option<u64>,
+    /// How much unused allowance is kept, in time at the full rate. 1 second by default.
+    pub burst: Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            messages_per_sec: None,
+            bytes_per_sec: None,
+            burst: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A [`RateLimit`] in force on a socket.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: &RateLimit) -> Self {
+        let now = Instant::now();
+        let bucket = |rate: f64| Bucket::new(rate, limit.burst, now);
+        Self {
+            messages: limit.messages_per_sec.map(|rate| bucket(rate as f64)),
+            bytes: limit.bytes_per_sec.map(|rate| bucket(rate as f64)),
+        }
+    }
+
+    /// Wait until a message of `len` bytes may be sent, and count it.
+    pub(crate) async fn acquire(&mut self, len: usize) {
+        let now = Instant::now();
+        let ready = [(&mut self.messages, 1.0), (&mut self.bytes, len as f64)]
+            .into_iter()
+            .filter_map(|(bucket, cost)| bucket.as_mut().map(|b| b.ready_at(cost, now)))
+            .max();
+        if let Some(ready) = ready
+            && ready > now
+        {
+            sleep_until(ready).await;
+        }
+
+        let now = Instant::now();
+        if let Some(bucket) = &mut self.messages {
+            bucket.take(1.0, now);
+        }
+        if let Some(bucket) = &mut self.bytes {
+            bucket.take(len as f64, now);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // tokens added per second
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// A full bucket.
+    fn new(rate: f64, burst: Duration, now: Instant) -> Self {
+        // at least one token, so anything can be sent eventually
+        let capacity = (rate * burst.as_secs_f64()).max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// When `cost` tokens will be there. Costs above the capacity only wait for a full
+    /// bucket, and leave it in debt.
+    fn ready_at(&mut self, cost: f64, now: Instant) -> Instant {
+        self.refill(now);
+        let missing = cost.min(self.capacity) - self.tokens;
+        if missing <= 0.0 || self.rate <= 0.0 {
+            return now;
+        }
+        now + Duration::from_secs_f64(missing / self.rate)
+    }
+
+    fn take(&mut self, cost: f64, now: Instant) {
+        self.refill(now);
+        self.tokens -= cost;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(10.0, Duration::from_millis(500), start);
+
+        // a full bucket lets a burst of 5 through at once
+        for _ in 0..5 {
+            assert_eq!(bucket.ready_at(1.0, start), start);
+            bucket.take(1.0, start);
+        }
+        // then one every 100ms
+        let ready = bucket.ready_at(1.0, start);
+        assert_eq!(ready - start, Duration::from_millis(100));
+        bucket.take(1.0, ready);
+
+        // an oversized cost waits for a full bucket and leaves it in debt
+        let ready = bucket.ready_at(20.0, ready);
+        assert_eq!(ready - start, Duration::from_millis(600));
+        bucket.take(20.0, ready);
+        let next = bucket.ready_at(1.0, ready);
+        assert_eq!(next - ready, Duration::from_millis(1600));
+    }
+}
@@ -0,0 +1,289 @@
+//! A socket that comes back by itself when the connection drops.
+//!
+//! [`ReconnectingLNSocket`] dials the peer, does the handshake and `init`, and whenever a read
+//! or write finds the connection gone it does it all again, waiting longer after each failed
+//! attempt as [`Backoff`] says. Every failed attempt emits an
+//! [`Event::Reconnecting`](crate::Event::Reconnecting), and getting the connection back an
+//! [`Event::Reconnected`](crate::Event::Reconnected).
+//!
+//! Like with [`recovery`](crate::recovery), whatever was in flight on the old connection is
+//! lost, and the peer has forgotten any subscriptions made on it.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::reconnect::ReconnectingLNSocket;
+//! # use bitcoin::secp256k1::{PublicKey, SecretKey};
+//! # async fn example(key: SecretKey, node: PublicKey) -> Result<(), lnsocket::Error> {
+//! let mut socket = ReconnectingLNSocket::new(key, node, "ln.example.com:9735");
+//! socket.backoff.max_attempts = Some(10);
+//! loop {
+//!     let msg = socket.read().await?;
+//!     println!("{msg:?}");
+//! }
+//! # }
+//! ```
+
+use crate::connect::ConnectOptions;
+use crate::event::Event;
+use crate::init::InitOptions;
+use crate::ln::wire::{self, Message};
+use crate::util::ser::Writeable;
+use crate::{Error, LNSocket};
+use bitcoin::secp256k1::rand::{self, Rng};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How long to wait between connection attempts.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// The wait after the first failed attempt, 500 milliseconds by default.
+    pub initial: Duration,
+    /// The longest wait, 1 minute by default.
+    pub max: Duration,
+    /// Each wait is this many times the one before, 2 by default.
+    pub multiplier: u32,
+    /// Make each wait up to this fraction longer or shorter at random, so clients that lost
+    /// the same node don't all come back at the same moment. 0.2 by default, at most 1.
+    pub jitter: f64,
+    /// Give up after this many attempts in a row have failed. `None`, the default, never does.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(60),
+            multiplier: 2,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// How long to wait after `attempt` attempts in a row failed.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.saturating_pow(attempt.saturating_sub(1));
+        let base = self.initial.saturating_mul(factor).min(self.max);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return base;
+        }
+        base.mul_f64(1.0 + rand::thread_rng().gen_range(-jitter..=jitter))
+    }
+}
+
+/// An [`LNSocket`] that reconnects when its connection drops. See the
+/// [module docs](crate::reconnect).
+pub struct ReconnectingLNSocket {
+    our_key: SecretKey,
+    their_pubkey: PublicKey,
+    addr: String,
+    /// How the connections are made. The defaults unless set.
+    pub connect: ConnectOptions,
+    /// The `init` sent on every connection. The defaults unless set.
+    pub init: InitOptions,
+    /// How long to wait between failed attempts.
+    pub backoff: Backoff,
+    socket: Option<LNSocket>,
+    events: Option<mpsc::UnboundedSender<Event>>,
+    // whether a connection was made before, so the next one is a reconnect
+    connected: bool,
+}
+
+impl ReconnectingLNSocket {
+    /// A socket for the node `their_pubkey` at `addr`. Nothing is dialed until the first read
+    /// or write, or [`ReconnectingLNSocket::connect`].
+    pub fn new(our_key: SecretKey, their_pubkey: PublicKey, addr: impl Into<String>) -> Self {
+        Self {
+            our_key,
+            their_pubkey,
+            addr: addr.into(),
+            connect: ConnectOptions::default(),
+            init: InitOptions::default(),
+            backoff: Backoff::default(),
+            socket: None,
+            events: None,
+            connected: false,
+        }
+    }
+
+    /// Events from this socket and every connection it makes, as
+    /// [`LNSocket::subscribe_events`].
+    pub fn subscribe_events(&mut self) -> mpsc::UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        if let Some(socket) = &mut self.socket {
+            socket.events = Some(tx.clone());
+        }
+        self.events = Some(tx);
+        rx
+    }
+
+    /// The current connection, making one first if there is none.
+    ///
+    /// Fails with the last attempt's error once [`Backoff::max_attempts`] attempts failed.
+    pub async fn connect(&mut self) -> Result<&mut LNSocket, Error> {
+        if self.socket.is_none() {
+            let socket = self.dial().await?;
+            self.socket = Some(socket);
+        }
+        Ok(self.socket.as_mut().expect("just connected"))
+    }
+
+    /// The current connection, if there is one.
+    pub fn socket_mut(&mut self) -> Option<&mut LNSocket> {
+        self.socket.as_mut()
+    }
+
+    /// Drop the current connection. The next read or write makes a new one.
+    pub fn disconnect(&mut self) {
+        self.socket = None;
+    }
+
+    /// Like [`LNSocket::read`], reconnecting until a message arrives.
+    pub async fn read(&mut self) -> Result<Message<()>, Error> {
+        loop {
+            let res = self.connect().await?.read().await;
+            match res {
+                Err(err) if is_disconnect(&err) => self.disconnect(),
+                res => return res,
+            }
+        }
+    }
+
+    /// Like [`LNSocket::write`], reconnecting until `msg` is written.
+    pub async fn write<M: wire::Type + Writeable>(&mut self, msg: &M) -> Result<(), Error> {
+        loop {
+            let res = self.connect().await?.write(msg).await;
+            match res {
+                Err(err) if is_disconnect(&err) => self.disconnect(),
+                res => return res,
+            }
+        }
+    }
+
+    async fn dial(&mut self) -> Result<LNSocket, Error> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match self.attempt().await {
+                Ok(socket) => {
+                    if self.connected {
+                        self.emit(Event::Reconnected);
+                    }
+                    self.connected = true;
+                    return Ok(socket);
+                }
+                Err(err) => err,
+            };
+            if self.backoff.max_attempts.is_some_and(|max| attempt >= max) {
+                return Err(err);
+            }
+            let delay = self.backoff.delay(attempt);
+            self.emit(Event::Reconnecting { attempt, delay });
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn attempt(&self) -> Result<LNSocket, Error> {
+        let opts = ConnectOptions {
+            events: self.events.clone(),
+            ..self.connect.clone()
+        };
+        let mut socket =
+            LNSocket::connect_with(&opts, self.our_key, self.their_pubkey, &self.addr).await?;
+        socket.perform_init_with(&self.init).await?;
+        Ok(socket)
+    }
+
+    fn emit(&mut self, event: Event) {
+        if let Some(events) = &self.events
+            && events.send(event).is_err()
+        {
+            self.events = None;
+        }
+    }
+}
+
+/// Whether `err` means the connection is gone, rather than something about one message.
+fn is_disconnect(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Io(_) | Error::Lightning(_) | Error::PeerUnresponsive | Error::NotConnected
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::DisconnectReason;
+    use crate::ln::msgs;
+    use crate::testing::default_init;
+    use bitcoin::secp256k1::Secp256k1;
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let delays: Vec<u64> = (1..=5).map(|n| backoff.delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+
+        let jittered = Backoff {
+            jitter: 0.5,
+            ..backoff
+        };
+        let delay = jittered.delay(2);
+        assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() -> Result<(), Error> {
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &node_key);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+
+        let node = tokio::spawn(async move {
+            for ponglen in [1, 2] {
+                let (stream, _) = listener.accept().await?;
+                let mut peer = LNSocket::accept_over(stream, node_key).await?;
+                peer.write(&default_init()).await?;
+                assert!(matches!(peer.read().await?, Message::Init(_)));
+                peer.write(&msgs::Ping {
+                    ponglen,
+                    byteslen: 0,
+                })
+                .await?;
+                // hang up on the first connection, keep the second until the client is done
+                if ponglen == 2 {
+                    peer.read().await.ok();
+                }
+            }
+            Ok::<_, Error>(())
+        });
+
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let mut socket = ReconnectingLNSocket::new(key, node_id, addr);
+        socket.backoff.initial = Duration::from_millis(10);
+        let mut events = socket.subscribe_events();
+
+        assert!(matches!(socket.read().await?, Message::Ping(p) if p.ponglen == 1));
+        assert!(matches!(socket.read().await?, Message::Ping(p) if p.ponglen == 2));
+        let seen: Vec<Event> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        let disconnected = Event::Disconnected {
+            reason: DisconnectReason::Closed,
+        };
+        let handshakes = seen.iter().filter(|e| **e == Event::HandshakeCompleted);
+        assert_eq!(handshakes.count(), 2);
+        let after = seen.iter().skip_while(|e| **e != disconnected);
+        assert!(after.skip(1).any(|e| *e == Event::Reconnected));
+        drop(socket);
+        node.await.unwrap()
+    }
+}
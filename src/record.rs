@@ -0,0 +1,226 @@
+//! Recording a session's decrypted messages, and playing them back.
+//!
+//! A [`Recorder`] attached with [`LNSocket::set_recorder`](crate::LNSocket::set_recorder) logs
+//! every message the socket sends or receives, after decryption, with the time it happened. [`Replay`] reads such a log back,
+//! so an interop failure seen against someone's node can be decoded again offline, or fed to
+//! a client through `MockPeer::replay` with the `testing` feature.
+//!
+//! Recordings hold everything in the clear, including commando runes and responses, so treat
+//! them like the secrets they may contain.
+//!
+//! File layout: the magic `LNSREC01`, then one entry per message: direction (0 for inbound, 1
+//! for outbound), milliseconds since the Unix epoch (u64), message length (u32) and the
+//! message, type included. All integers are big-endian.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::record::{Direction, Recorder, Replay};
+//! # async fn example(mut socket: LNSocket) -> Result<(), lnsocket::Error> {
+//! socket.set_recorder(Some(Recorder::create("session.lnrec")?));
+//! socket.perform_init().await?;
+//! socket.read().await?;
+//!
+//! for frame in Replay::open("session.lnrec")? {
+//!     let frame = frame?;
+//!     if frame.direction == Direction::Inbound {
+//!         println!("{:?}", frame.decode()?);
+//!     }
+//! }
+//! # Ok(()) }
+//! ```
+
+use crate::Error;
+use crate::ln::wire::{self, Message};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"LNSREC01";
+
+/// Which way a recorded message went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From the peer to us.
+    Inbound,
+    /// From us to the peer.
+    Outbound,
+}
+
+/// One message from a recording.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedFrame {
+    pub direction: Direction,
+    /// When the message was sent or received.
+    pub time: SystemTime,
+    /// The decrypted message, starting with its type.
+    pub data: Vec<u8>,
+}
+
+impl RecordedFrame {
+    /// The message type, if the frame is long enough to have one.
+    pub fn type_id(&self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.data.get(..2)?.try_into().ok()?))
+    }
+
+    /// Decode the message the way [`LNSocket::read`](crate::LNSocket::read) would.
+    pub fn decode(&self) -> Result<Message<()>, Error> {
+        wire::read(&mut Cursor::new(&self.data[..]), |_, _| Ok(None::<()>))
+            .map_err(|(err, _)| Error::Decode(err))
+    }
+}
+
+/// Writes a recording, see the [module docs](self).
+pub struct Recorder {
+    out: Box<dyn Write + Send>,
+}
+
+impl Recorder {
+    /// Record to `out`, starting with the file magic.
+    pub fn new(out: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut out: Box<dyn Write + Send> = Box::new(out);
+        out.write_all(MAGIC)?;
+        Ok(Self { out })
+    }
+
+    /// Record to a new file at `path`, replacing any that's there.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    pub(crate) fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.out.write_all(&[direction as u8])?;
+        self.out.write_all(&millis.to_be_bytes())?;
+        self.out.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.out.write_all(data)?;
+        // a recording is most wanted right after something went wrong, keep it complete
+        self.out.flush()
+    }
+}
+
+/// Reads a recording back, one [`RecordedFrame`] at a time.
+pub struct Replay<R> {
+    reader: R,
+}
+
+impl Replay<BufReader<File>> {
+    /// Open the recording at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Replay<R> {
+    /// Start reading a recording from its first byte.
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Io(io::ErrorKind::InvalidData));
+        }
+        Ok(Self { reader })
+    }
+
+    fn read_frame(&mut self) -> Result<Option<RecordedFrame>, Error> {
+        let mut header = [0u8; 13];
+        match self.reader.read_exact(&mut header[..1]) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            res => res?,
+        }
+        self.reader.read_exact(&mut header[1..])?;
+
+        let direction = match header[0] {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            _ => return Err(Error::Io(io::ErrorKind::InvalidData)),
+        };
+        let millis = u64::from_be_bytes(header[1..9].try_into().expect("8 bytes"));
+        let len = u32::from_be_bytes(header[9..13].try_into().expect("4 bytes"));
+        let mut data = vec![0u8; len as usize];
+        self.reader.read_exact(&mut data)?;
+
+        Ok(Some(RecordedFrame {
+            direction,
+            time: UNIX_EPOCH + Duration::from_millis(millis),
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Replay<R> {
+    type Item = Result<RecordedFrame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::msgs;
+    use crate::testing::MockPeer;
+    use bitcoin::secp256k1::{SecretKey, rand};
+    use std::sync::{Arc, Mutex};
+
+    /// A `Write` the test can look into after handing it to the recorder.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() -> Result<(), Error> {
+        let out = Shared::default();
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut socket, _peer) = MockPeer::connect(key).await?;
+        socket.set_recorder(Some(Recorder::new(out.clone())?));
+        socket.perform_init().await?;
+        socket
+            .write(&msgs::Ping {
+                ponglen: 4,
+                byteslen: 0,
+            })
+            .await?;
+        assert!(matches!(socket.read().await?, Message::Pong(_)));
+
+        let recording = out.0.lock().unwrap().clone();
+        let frames: Vec<_> = Replay::new(&recording[..])?.collect::<Result<_, _>>()?;
+        let seen: Vec<_> = frames
+            .iter()
+            .map(|frame| (frame.direction, frame.type_id()))
+            .collect();
+        assert_eq!(
+            seen,
+            vec![
+                (Direction::Inbound, Some(16)),
+                (Direction::Outbound, Some(16)),
+                (Direction::Outbound, Some(18)),
+                (Direction::Inbound, Some(19)),
+            ]
+        );
+        assert!(matches!(frames[3].decode()?, Message::Pong(p) if p.byteslen == 4));
+
+        // a replayed peer says the same things
+        let (mut socket, peer) = MockPeer::connect(key).await?;
+        socket.perform_init().await?;
+        peer.replay(Replay::new(&recording[..])?)?;
+        assert!(matches!(socket.read().await?, Message::Pong(p) if p.byteslen == 4));
+
+        assert!(Replay::new(&b"not a recording"[..]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+//! Reconnecting after the encrypted stream breaks.
+//!
+//! If a frame fails to decrypt mid-session, the Noise nonces are out of step (a peer that
+//! restarted with stale state, a middlebox that mangled a byte) and nothing more can be read
+//! from the connection. With a [`Recovery`] set through
+//! [`LNSocket::set_recovery`](crate::LNSocket::set_recovery), the socket instead dials the peer
+//! again, redoes the handshake and `init`, resends the messages registered with
+//! [`Recovery::restore`], and carries on reading as if nothing happened.
+//!
+//! Anything in flight on the old connection is lost, such as a commando request waiting for
+//! its reply. An [`Event::Reconnected`](crate::Event::Reconnected) is emitted every time, so
+//! layers above can redo what they need to.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::recovery::Recovery;
+//! # use bitcoin::secp256k1::{PublicKey, SecretKey};
+//! # async fn example(key: SecretKey, node: PublicKey) -> Result<(), lnsocket::Error> {
+//! let addr = "ln.example.com:9735";
+//! let mut socket = LNSocket::connect_and_init(key, node, addr).await?;
+//! socket.set_recovery(Some(Recovery::new(key, addr)));
+//! loop {
+//!     let msg = socket.read().await?;
+//!     println!("{msg:?}");
+//! }
+//! # }
+//! ```
+
+use crate::Error;
+use crate::handle::Encoded;
+use crate::init::InitOptions;
+use crate::ln::wire::Type;
+use crate::util::ser::Writeable;
+use bitcoin::secp256k1::SecretKey;
+use std::time::Duration;
+
+/// How to re-establish a connection whose encryption broke.
+pub struct Recovery {
+    pub(crate) our_key: SecretKey,
+    pub(crate) addr: String,
+    /// The `init` sent on the new connection. The defaults unless set.
+    pub init: InitOptions,
+    /// Connection attempts before giving up and failing the read, 3 by default.
+    pub max_attempts: u32,
+    /// How long to wait between attempts, 1 second by default.
+    pub retry_delay: Duration,
+    pub(crate) restore: Vec<Encoded>,
+}
+
+impl Recovery {
+    /// Reconnect to `addr` as `our_key`.
+    pub fn new(our_key: SecretKey, addr: impl Into<String>) -> Self {
+        Self {
+            our_key,
+            addr: addr.into(),
+            init: InitOptions::default(),
+            max_attempts: 3,
+            retry_delay: Duration::from_secs(1),
+            restore: Vec::new(),
+        }
+    }
+
+    /// Send `msg` on every new connection right after `init`, e.g. a
+    /// `gossip_timestamp_filter` or a custom subscription the peer has forgotten.
+    pub fn restore<M: Type + Writeable>(&mut self, msg: &M) -> Result<(), Error> {
+        self.restore.push(Encoded::new(msg)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,593 @@
+//! The BOLT 8 protocol as a state machine that does no I/O.
+//!
+//! A [`Session`] holds the handshake and framing logic without reading or writing anything.
+//! [`LNSocket`](crate::LNSocket) does its handshake with one over a tokio stream and reads
+//! frames the same way, and the embedded socket is a session over an `embedded-io-async`
+//! stream. Integrators with their own executor, an io_uring runtime, or a C event loop drive
+//! it themselves: bytes from the network go in with [`Session::receive`], decoded messages
+//! come out of [`Session::next_message`], and messages queued with [`Session::send`] wait in
+//! [`Session::outgoing`] until the caller has written them and calls [`Session::consume`].
+//!
+//! Nothing happens behind the caller's back. Pings aren't answered and there are no timeouts,
+//! those are up to the loop driving the session.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::sans_io::Session;
+//! use lnsocket::InitOptions;
+//! use lnsocket::ln::{msgs, wire::Message};
+//! use bitcoin::secp256k1::{PublicKey, SecretKey, rand};
+//! use std::io::{Read, Write};
+//! use std::net::TcpStream;
+//! # fn example(node_id: PublicKey) -> Result<(), lnsocket::Error> {
+//! let key = SecretKey::new(&mut rand::thread_rng());
+//! let ephemeral = SecretKey::new(&mut rand::thread_rng());
+//! let mut session = Session::outbound(key, node_id, ephemeral);
+//! let mut tcp = TcpStream::connect("127.0.0.1:9735")?;
+//! let mut buf = [0u8; 4096];
+//! loop {
+//!     // the first time around this sends act one
+//!     tcp.write_all(session.outgoing())?;
+//!     session.consume(session.outgoing().len());
+//!
+//!     let n = tcp.read(&mut buf)?;
+//!     session.receive(&buf[..n])?;
+//!     if session.is_handshake_complete() && !session.sent_init() {
+//!         session.send_init(&InitOptions::default())?;
+//!     }
+//!     while let Some(msg) = session.next_message()? {
+//!         if let Message::Ping(ping) = msg {
+//!             session.send(&msgs::Pong { byteslen: ping.ponglen })?;
+//!         }
+//!     }
+//! }
+//! # }
+//! ```
+
+use crate::init::{InitOptions, PeerInfo};
+use crate::io::Cursor;
+use crate::ln::msgs::{self, DecodeError};
+use crate::ln::peer_channel_encryptor::PeerChannelEncryptor;
+use crate::ln::wire::{self, Encode, Message, Type};
+use crate::prelude::*;
+use crate::util::ser::Writeable;
+use crate::{Error, error::HandshakeError};
+use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+pub(crate) const ACT_ONE_SIZE: usize = 50;
+pub(crate) const ACT_TWO_SIZE: usize = 50;
+pub(crate) const ACT_THREE_SIZE: usize = 66;
+
+// encrypted length header: 2 bytes of length and a MAC
+const HEADER_SIZE: usize = 18;
+const MAC_SIZE: usize = 16;
+
+/// Check the first `got` bytes read of act two.
+///
+/// Anything the encryptor itself could still reject after this is an authentication failure.
+pub(crate) fn check_act_two(act_two: &[u8; ACT_TWO_SIZE], got: usize) -> Result<(), Error> {
+    // connecting to a web port is a common mistake, give it a friendlier error
+    if act_two[..got].starts_with(b"HTTP/") {
+        return Err(HandshakeError::HttpResponse.into());
+    }
+    if got < ACT_TWO_SIZE {
+        return Err(HandshakeError::ShortAct2(got).into());
+    }
+    if act_two[0] != 0 {
+        return Err(HandshakeError::BadVersion(act_two[0]).into());
+    }
+    if PublicKey::from_slice(&act_two[1..34]).is_err() {
+        return Err(HandshakeError::BadEphemeralKey.into());
+    }
+    Ok(())
+}
+
+/// Incoming frames put back together from the bytes however they arrive, and decrypted.
+///
+/// This is the one place frames are read: [`Session`], [`LNSocket`](crate::LNSocket), the
+/// codec and the embedded socket all go through it.
+#[derive(Debug, Default)]
+pub(crate) struct FrameReader {
+    // encrypted bytes not decrypted yet: the current frame's length header, or its body once
+    // the header has been decrypted
+    buf: Vec<u8>,
+    body_len: Option<usize>,
+}
+
+impl FrameReader {
+    /// Carry on with a frame someone else started reading, see
+    /// [`LNSocketParts`](crate::lnsocket::LNSocketParts).
+    #[cfg(feature = "std")]
+    pub(crate) fn from_parts(partial: Vec<u8>, body_len: Option<usize>) -> Self {
+        Self {
+            buf: partial,
+            body_len,
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn into_parts(self) -> (Vec<u8>, Option<usize>) {
+        (self.buf, self.body_len)
+    }
+
+    /// Bytes received but not decrypted yet.
+    pub(crate) fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Whether part of a frame has arrived.
+    #[cfg(feature = "std")]
+    pub(crate) fn in_frame(&self) -> bool {
+        !self.buf.is_empty() || self.body_len.is_some()
+    }
+
+    /// How many more bytes the current length header or body needs. Reading no more than this
+    /// never takes bytes of the next frame.
+    #[cfg(feature = "std")]
+    pub(crate) fn missing(&self) -> usize {
+        let want = match self.body_len {
+            None => HEADER_SIZE,
+            Some(size) => size + MAC_SIZE,
+        };
+        want.saturating_sub(self.buf.len())
+    }
+
+    /// Hand over bytes read from the network.
+    pub(crate) fn receive(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next complete frame, decrypted: the message followed by the 16 bytes of its MAC.
+    /// `None` if more bytes are needed.
+    pub(crate) fn next_frame(
+        &mut self,
+        channel: &mut PeerChannelEncryptor,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let size = match self.body_len {
+            Some(size) => size,
+            None => {
+                if self.buf.len() < HEADER_SIZE {
+                    return Ok(None);
+                }
+                let hdr: [u8; HEADER_SIZE] = self.buf[..HEADER_SIZE].try_into().expect("header");
+                let size = channel.decrypt_length_header(&hdr)? as usize;
+                self.buf.drain(..HEADER_SIZE);
+                self.body_len = Some(size);
+                size
+            }
+        };
+        if self.buf.len() < size + MAC_SIZE {
+            return Ok(None);
+        }
+        self.body_len = None;
+        let mut frame = if self.buf.len() == size + MAC_SIZE {
+            core::mem::take(&mut self.buf)
+        } else {
+            self.buf.drain(..size + MAC_SIZE).collect()
+        };
+        channel.decrypt_message(&mut frame)?;
+        Ok(Some(frame))
+    }
+}
+
+/// Decode a frame from [`FrameReader::next_frame`], with `handler` for custom messages.
+pub(crate) fn decode<T>(
+    frame: &[u8],
+    handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+) -> Result<Message<T>, Error>
+where
+    T: core::fmt::Debug,
+{
+    let mut cursor = Cursor::new(&frame[..frame.len() - MAC_SIZE]);
+    Ok(wire::read(&mut cursor, handler).map_err(|(de, _)| de)?)
+}
+
+/// Where a connection is in its setup.
+///
+/// BOLT 1 requires `init` to be the first message in both directions, so nothing else may be
+/// sent until we have sent our `init` and received the peer's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The Noise handshake is still in progress.
+    Handshaking,
+    /// The handshake completed but `init` has not been exchanged in both directions yet.
+    AwaitingInit,
+    /// `init` was exchanged, any message may be sent.
+    Ready,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Handshake {
+    /// Initiator, act one sent.
+    AwaitingActTwo,
+    /// Responder, nothing received yet.
+    AwaitingActOne,
+    /// Responder, act two sent.
+    AwaitingActThree,
+    Done,
+}
+
+/// One end of a Lightning connection, driven by the caller. See the [module docs](self).
+pub struct Session {
+    channel: PeerChannelEncryptor,
+    handshake: Handshake,
+    our_key: SecretKey,
+    ephemeral: SecretKey,
+    // received handshake bytes not used yet, and everything received after it
+    incoming: Vec<u8>,
+    frames: FrameReader,
+    outgoing: Vec<u8>,
+    // the networks from our init, to check the peer's against
+    networks: Option<Vec<ChainHash>>,
+    sent_init: bool,
+    peer_info: Option<PeerInfo>,
+}
+
+impl core::fmt::Debug for Session {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Session")
+            .field("state", &self.state())
+            .field("incoming", &(self.incoming.len() + self.frames.len()))
+            .field("outgoing", &self.outgoing.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Session {
+    /// Start the initiator side of the handshake with `their_pubkey`. Act one is queued in
+    /// [`Session::outgoing`] right away.
+    ///
+    /// `ephemeral` must be a fresh random key for every connection.
+    pub fn outbound(our_key: SecretKey, their_pubkey: PublicKey, ephemeral: SecretKey) -> Self {
+        let mut channel = PeerChannelEncryptor::new_outbound(their_pubkey, ephemeral);
+        let act_one = channel.get_act_one(&Secp256k1::signing_only());
+        let mut session = Self::new(channel, Handshake::AwaitingActTwo, our_key, ephemeral);
+        session.outgoing.extend_from_slice(&act_one);
+        session
+    }
+
+    /// Start the responder side of the handshake. `ephemeral` as in [`Session::outbound`].
+    pub fn inbound(our_key: SecretKey, ephemeral: SecretKey) -> Self {
+        let channel = PeerChannelEncryptor::new_inbound(&Secp256k1::signing_only(), &our_key);
+        Self::new(channel, Handshake::AwaitingActOne, our_key, ephemeral)
+    }
+
+    fn new(
+        channel: PeerChannelEncryptor,
+        handshake: Handshake,
+        our_key: SecretKey,
+        ephemeral: SecretKey,
+    ) -> Self {
+        Self {
+            channel,
+            handshake,
+            our_key,
+            ephemeral,
+            incoming: Vec::new(),
+            frames: FrameReader::default(),
+            outgoing: Vec::new(),
+            networks: None,
+            sent_init: false,
+            peer_info: None,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        if self.handshake != Handshake::Done {
+            ConnectionState::Handshaking
+        } else if self.sent_init && self.peer_info.is_some() {
+            ConnectionState::Ready
+        } else {
+            ConnectionState::AwaitingInit
+        }
+    }
+
+    pub fn is_handshake_complete(&self) -> bool {
+        self.handshake == Handshake::Done
+    }
+
+    /// The peer's node id, known once the handshake is complete.
+    pub fn their_pubkey(&self) -> Option<PublicKey> {
+        self.channel.their_node_id()
+    }
+
+    /// Whether our `init` was queued.
+    pub fn sent_init(&self) -> bool {
+        self.sent_init
+    }
+
+    /// What the peer said in its `init`, once [`Session::next_message`] returned it.
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.peer_info.as_ref()
+    }
+
+    /// Hand over bytes read from the network. Any handshake act they complete is processed,
+    /// which may queue our next act in [`Session::outgoing`].
+    pub fn receive(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.is_handshake_complete() {
+            self.frames.receive(data);
+            return Ok(());
+        }
+        self.incoming.extend_from_slice(data);
+        let secp_ctx = Secp256k1::signing_only();
+        loop {
+            match self.handshake {
+                Handshake::AwaitingActTwo if self.incoming.len() >= ACT_TWO_SIZE => {
+                    let act_two: [u8; ACT_TWO_SIZE] =
+                        self.incoming[..ACT_TWO_SIZE].try_into().expect("act two");
+                    check_act_two(&act_two, ACT_TWO_SIZE)?;
+                    let act_three = self
+                        .channel
+                        .process_act_two(&secp_ctx, &act_two, &self.our_key)
+                        .map_err(|_| HandshakeError::BadMac)?;
+                    self.incoming.drain(..ACT_TWO_SIZE);
+                    self.outgoing.extend_from_slice(&act_three);
+                    self.handshake = Handshake::Done;
+                }
+                Handshake::AwaitingActOne if self.incoming.len() >= ACT_ONE_SIZE => {
+                    let act_two = self.channel.process_act_one_with_keys(
+                        &self.incoming[..ACT_ONE_SIZE],
+                        &self.our_key,
+                        self.ephemeral,
+                        &secp_ctx,
+                    )?;
+                    self.incoming.drain(..ACT_ONE_SIZE);
+                    self.outgoing.extend_from_slice(&act_two);
+                    self.handshake = Handshake::AwaitingActThree;
+                }
+                Handshake::AwaitingActThree if self.incoming.len() >= ACT_THREE_SIZE => {
+                    self.channel
+                        .process_act_three(&self.incoming[..ACT_THREE_SIZE])?;
+                    self.incoming.drain(..ACT_THREE_SIZE);
+                    self.handshake = Handshake::Done;
+                }
+                Handshake::Done => {
+                    // the peer may have sent its first messages right behind its last act
+                    self.frames.receive(&self.incoming);
+                    self.incoming.clear();
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// What to report when the peer closes the connection before the handshake completed. A
+    /// cut off act two usually says more than the end of the stream, e.g. that this was a web
+    /// server.
+    #[cfg(feature = "embedded-io")]
+    pub(crate) fn eof_error(&self) -> Error {
+        if self.handshake == Handshake::AwaitingActTwo {
+            let mut act_two = [0u8; ACT_TWO_SIZE];
+            act_two[..self.incoming.len()].copy_from_slice(&self.incoming);
+            if let Err(err) = check_act_two(&act_two, self.incoming.len()) {
+                return err;
+            }
+        }
+        Error::Io(crate::io::ErrorKind::UnexpectedEof)
+    }
+
+    /// The encryptor, once the handshake is complete, for [`LNSocket`](crate::LNSocket) to take
+    /// over the connection. Nothing may be left to send or decode.
+    #[cfg(feature = "std")]
+    pub(crate) fn into_channel(self) -> PeerChannelEncryptor {
+        debug_assert!(self.is_handshake_complete());
+        debug_assert!(self.frames.len() == 0 && self.outgoing.is_empty());
+        self.channel
+    }
+
+    /// Bytes waiting to be written to the network.
+    pub fn outgoing(&self) -> &[u8] {
+        &self.outgoing
+    }
+
+    /// Drop the first `n` bytes of [`Session::outgoing`], once they were written.
+    pub fn consume(&mut self, n: usize) {
+        self.outgoing.drain(..n.min(self.outgoing.len()));
+    }
+
+    /// Queue our `init`, built from `opts` as in [`LNSocket::perform_init_with`]. The peer's
+    /// comes out of [`Session::next_message`] like any other message.
+    ///
+    /// [`InitOptions::on_peer_init`], [`InitOptions::max_pre_init_messages`],
+    /// [`InitOptions::echo_remote_address`] and [`InitOptions::suppress_gossip`] aren't
+    /// supported and are ignored.
+    ///
+    /// [`LNSocket::perform_init_with`]: crate::LNSocket::perform_init_with
+    pub fn send_init(&mut self, opts: &InitOptions) -> Result<(), Error> {
+        let features = opts.advertised_features();
+        self.send(&msgs::Init {
+            features: features.to_be_bytes(),
+            global_features: features.up_to_13().to_be_bytes(),
+            remote_network_address: opts.remote_network_address.clone(),
+            networks: Some(opts.networks.clone()),
+            custom_tlvs: opts.sorted_custom_tlvs()?,
+        })?;
+        self.networks = Some(opts.networks.clone());
+        Ok(())
+    }
+
+    /// Encrypt `m` and queue it in [`Session::outgoing`].
+    ///
+    /// Fails with [`Error::NotConnected`] during the handshake, and with
+    /// [`Error::InitNotComplete`] if `m` is not an `init` and the `init` exchange has not
+    /// finished yet.
+    pub fn send<M: Type + Writeable>(&mut self, m: &M) -> Result<(), Error> {
+        if !self.is_handshake_complete() {
+            return Err(Error::NotConnected);
+        }
+        let is_init = m.type_id() == msgs::Init::TYPE;
+        if !is_init && (!self.sent_init || self.peer_info.is_none()) {
+            return Err(Error::InitNotComplete);
+        }
+        self.outgoing.extend(self.channel.encrypt_message(m));
+        if is_init {
+            self.sent_init = true;
+        }
+        Ok(())
+    }
+
+    /// The next complete message received, or `None` if more bytes are needed.
+    pub fn next_message(&mut self) -> Result<Option<Message<()>>, Error> {
+        self.next_message_custom(|_type, _buf| Ok(None))
+    }
+
+    /// Like [`Session::next_message`], decoding custom messages with `handler` as in
+    /// [`LNSocket::read_custom`](crate::LNSocket::read_custom).
+    pub fn next_message_custom<T>(
+        &mut self,
+        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+    ) -> Result<Option<Message<T>>, Error>
+    where
+        T: core::fmt::Debug,
+    {
+        match self.next_frame()? {
+            Some(frame) => self.decode_frame(&frame, handler).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The next complete frame, decrypted, for front-ends that wait for it with a handler
+    /// they can only use once.
+    pub(crate) fn next_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if !self.is_handshake_complete() {
+            return Ok(None);
+        }
+        self.frames.next_frame(&mut self.channel)
+    }
+
+    /// Decode a frame from [`Session::next_frame`], checking that the peer starts with `init`.
+    pub(crate) fn decode_frame<T>(
+        &mut self,
+        frame: &[u8],
+        handler: impl FnOnce(u16, &mut Cursor<&[u8]>) -> Result<Option<T>, DecodeError>,
+    ) -> Result<Message<T>, Error>
+    where
+        T: core::fmt::Debug,
+    {
+        let msg = decode(frame, handler)?;
+        // BOLT 1: the first message from the peer must be init
+        if self.peer_info.is_none() {
+            let Message::Init(init) = &msg else {
+                return Err(Error::FirstMessageNotInit);
+            };
+            if let (Some(ours), Some(theirs)) = (&self.networks, &init.networks)
+                && !theirs.iter().any(|network| ours.contains(network))
+            {
+                return Err(Error::NetworkMismatch {
+                    ours: ours.clone(),
+                    theirs: theirs.clone(),
+                });
+            }
+            self.peer_info = Some(PeerInfo::new(init.clone()));
+        }
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LNSocket;
+    use bitcoin::secp256k1::rand;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_session_against_lnsocket() -> Result<(), Error> {
+        let (mut ours, theirs) = tokio::io::duplex(64 * 1024);
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let ephemeral = SecretKey::new(&mut rand::thread_rng());
+        let node = tokio::spawn(async move {
+            let mut node = LNSocket::handshake_inbound(theirs, node_key).await?;
+            node.perform_init().await?;
+            let Message::Ping(ping) = node.read().await? else {
+                panic!("expected a ping");
+            };
+            let pong = node.pong_for(&ping)?.expect("ponglen is below the limit");
+            node.write(&pong).await?;
+            Ok::<_, Error>(node)
+        });
+
+        let mut session = Session::outbound(key, node_id, ephemeral);
+        assert_eq!(session.state(), ConnectionState::Handshaking);
+        assert!(matches!(
+            session.send(&msgs::Ping {
+                ponglen: 0,
+                byteslen: 0
+            }),
+            Err(Error::NotConnected)
+        ));
+
+        // feed the bytes in one at a time, the worst a caller's loop can do
+        let mut pong = None;
+        let mut byte = [0u8; 1];
+        while pong.is_none() {
+            ours.write_all(session.outgoing()).await?;
+            session.consume(session.outgoing().len());
+            ours.read_exact(&mut byte).await?;
+            session.receive(&byte)?;
+            if session.is_handshake_complete() && !session.sent_init() {
+                session.send_init(&InitOptions::default())?;
+            }
+            while let Some(msg) = session.next_message()? {
+                match msg {
+                    Message::Init(_) => session.send(&msgs::Ping {
+                        ponglen: 3,
+                        byteslen: 1,
+                    })?,
+                    Message::Pong(p) => pong = Some(p),
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(pong.map(|p| p.byteslen), Some(3));
+        assert_eq!(session.state(), ConnectionState::Ready);
+        assert_eq!(session.their_pubkey(), Some(node_id));
+        node.await.unwrap()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sessions_in_memory() -> Result<(), Error> {
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let mut a = Session::outbound(
+            SecretKey::new(&mut rand::thread_rng()),
+            node_id,
+            SecretKey::new(&mut rand::thread_rng()),
+        );
+        let mut b = Session::inbound(node_key, SecretKey::new(&mut rand::thread_rng()));
+
+        let pump = |from: &mut Session, to: &mut Session| {
+            let bytes = from.outgoing().to_vec();
+            from.consume(bytes.len());
+            to.receive(&bytes)
+        };
+        pump(&mut a, &mut b)?;
+        pump(&mut b, &mut a)?;
+        // act three and a's init arrive together
+        a.send_init(&InitOptions::default())?;
+        pump(&mut a, &mut b)?;
+        assert!(a.is_handshake_complete() && b.is_handshake_complete());
+
+        let ping = msgs::Ping {
+            ponglen: 1,
+            byteslen: 0,
+        };
+        // b's init hasn't arrived yet
+        assert!(matches!(a.send(&ping), Err(Error::InitNotComplete)));
+        // nothing but init may come first
+        assert!(matches!(b.next_message()?, Some(Message::Init(_))));
+        assert!(b.next_message()?.is_none());
+        assert_eq!(b.state(), ConnectionState::AwaitingInit);
+        b.send_init(&InitOptions::default())?;
+        assert_eq!(b.state(), ConnectionState::Ready);
+        pump(&mut b, &mut a)?;
+        assert!(matches!(a.next_message()?, Some(Message::Init(_))));
+        a.send(&ping)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+//! Picking the best of several equivalent peers.
+//!
+//! Applications that can work through any of a few gateway peers (several LSPs, say) want the
+//! fastest one that is currently reachable. [`PeerScores`] remembers how long connecting to
+//! each peer took and how quickly it answers pings, and ranks candidates by that.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::score::PeerScores;
+//! # use bitcoin::secp256k1::{PublicKey, SecretKey};
+//! # async fn example(key: SecretKey, lsp1: PublicKey, lsp2: PublicKey) -> Result<(), lnsocket::Error> {
+//! let mut scores = PeerScores::new();
+//! let candidates = [(lsp1, "lsp1.example.com:9735"), (lsp2, "lsp2.example.com:9735")];
+//! let mut socket = scores.connect_best(key, &candidates).await?;
+//! scores.measure_rtt(&mut socket).await?;
+//! # Ok(()) }
+//! ```
+
+use crate::ln::msgs;
+use crate::ln::wire::Message;
+use crate::{Error, LNSocket};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Peers that failed this many times in a row are considered unhealthy.
+pub const MAX_FAILURES: u32 = 3;
+
+/// What we've measured about one peer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Smoothed time to connect, handshake and exchange `init`.
+    pub connect_time: Option<Duration>,
+    /// Smoothed ping round trip time.
+    pub rtt: Option<Duration>,
+    /// Failed connection attempts since the last success.
+    pub failures: u32,
+}
+
+impl PeerStats {
+    /// Whether the peer has been reachable recently.
+    pub fn is_healthy(&self) -> bool {
+        self.failures < MAX_FAILURES
+    }
+
+    /// Lower is better. Round trips count for more than connect time, since they are paid on
+    /// every request while connecting happens once.
+    pub fn score(&self) -> Option<Duration> {
+        match (self.connect_time, self.rtt) {
+            (None, None) => None,
+            (connect, rtt) => Some(connect.unwrap_or_default() + rtt.unwrap_or_default() * 4),
+        }
+    }
+}
+
+// exponentially weighted moving average, so one slow sample doesn't ruin a peer
+fn smooth(old: Option<Duration>, sample: Duration) -> Duration {
+    match old {
+        Some(old) => (old * 3 + sample) / 4,
+        None => sample,
+    }
+}
+
+/// Latency measurements for a set of peers.
+#[derive(Clone, Debug, Default)]
+pub struct PeerScores {
+    peers: HashMap<PublicKey, PeerStats>,
+}
+
+impl PeerScores {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What we know about `node_id`, if anything.
+    pub fn stats(&self, node_id: &PublicKey) -> Option<&PeerStats> {
+        self.peers.get(node_id)
+    }
+
+    /// Record a successful connection that took `elapsed`.
+    pub fn record_connect(&mut self, node_id: PublicKey, elapsed: Duration) {
+        let stats = self.peers.entry(node_id).or_default();
+        stats.connect_time = Some(smooth(stats.connect_time, elapsed));
+        stats.failures = 0;
+    }
+
+    /// Record a ping round trip of `rtt`.
+    pub fn record_rtt(&mut self, node_id: PublicKey, rtt: Duration) {
+        let stats = self.peers.entry(node_id).or_default();
+        stats.rtt = Some(smooth(stats.rtt, rtt));
+    }
+
+    /// Record a failed connection attempt.
+    pub fn record_failure(&mut self, node_id: PublicKey) {
+        let stats = self.peers.entry(node_id).or_default();
+        stats.failures = stats.failures.saturating_add(1);
+    }
+
+    /// `candidates` from most to least preferred.
+    ///
+    /// Healthy peers come first, fastest first. Peers we haven't measured yet come after the
+    /// measured healthy ones (so they get tried eventually), and unhealthy peers come last.
+    pub fn rank(&self, candidates: &[PublicKey]) -> Vec<PublicKey> {
+        let mut ranked = candidates.to_vec();
+        ranked.sort_by_key(|node_id| match self.peers.get(node_id) {
+            Some(stats) if !stats.is_healthy() => (2, stats.failures as u128),
+            Some(stats) => match stats.score() {
+                Some(score) => (0, score.as_nanos()),
+                None => (1, 0),
+            },
+            None => (1, 0),
+        });
+        ranked
+    }
+
+    /// Connect to the best of `candidates`, falling back to the next one on failure.
+    ///
+    /// Every attempt is recorded. Returns the error of the last attempt if all of them fail.
+    pub async fn connect_best(
+        &mut self,
+        our_key: SecretKey,
+        candidates: &[(PublicKey, &str)],
+    ) -> Result<LNSocket, Error> {
+        let node_ids: Vec<PublicKey> = candidates.iter().map(|(id, _)| *id).collect();
+        let mut last_err = Error::NotConnected;
+        for node_id in self.rank(&node_ids) {
+            let (_, addr) = candidates
+                .iter()
+                .find(|(id, _)| *id == node_id)
+                .expect("ranked from candidates");
+            match LNSocket::connect_and_init(our_key, node_id, addr).await {
+                Ok(socket) => {
+                    self.record_connect(node_id, socket.timings().total());
+                    return Ok(socket);
+                }
+                Err(err) => {
+                    self.record_failure(node_id);
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Ping the peer on `socket` and record how long the pong took.
+    ///
+    /// Meant for idle connections: messages other than the pong that arrive in the meantime
+    /// are dropped (pings are still answered).
+    pub async fn measure_rtt(&mut self, socket: &mut LNSocket) -> Result<Duration, Error> {
+        let start = Instant::now();
+        socket
+            .write(&msgs::Ping {
+                ponglen: 0,
+                byteslen: 0,
+            })
+            .await?;
+        loop {
+            match socket.read().await? {
+                Message::Pong(_) => break,
+                Message::Ping(ping) => {
+                    if let Some(pong) = socket.pong_for(&ping)? {
+                        socket.write(&pong).await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let rtt = start.elapsed();
+        self.record_rtt(socket.their_pubkey(), rtt);
+        Ok(rtt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lnsocket::tests::socket_pair;
+    use bitcoin::secp256k1::Secp256k1;
+
+    fn node(n: u8) -> PublicKey {
+        let sk = SecretKey::from_slice(&[n; 32]).unwrap();
+        PublicKey::from_secret_key(&Secp256k1::signing_only(), &sk)
+    }
+
+    #[test]
+    fn test_rank() {
+        let (fast, slow, unknown, down) = (node(1), node(2), node(3), node(4));
+        let mut scores = PeerScores::new();
+        scores.record_connect(fast, Duration::from_millis(100));
+        scores.record_rtt(fast, Duration::from_millis(10));
+        scores.record_connect(slow, Duration::from_millis(50));
+        scores.record_rtt(slow, Duration::from_millis(100));
+        scores.record_connect(down, Duration::from_millis(1));
+        for _ in 0..MAX_FAILURES {
+            scores.record_failure(down);
+        }
+
+        assert_eq!(
+            scores.rank(&[down, unknown, slow, fast]),
+            vec![fast, slow, unknown, down]
+        );
+
+        // one success makes it healthy again
+        scores.record_connect(down, Duration::from_millis(1));
+        assert_eq!(scores.rank(&[fast, down])[0], down);
+    }
+
+    #[tokio::test]
+    async fn test_measure_rtt() -> Result<(), Error> {
+        let (mut a, mut b) = socket_pair().await?;
+        let mut scores = PeerScores::new();
+
+        let pong = async {
+            match b.read().await? {
+                Message::Ping(ping) => {
+                    let pong = b.pong_for(&ping)?.expect("small ping");
+                    b.write(&pong).await
+                }
+                other => panic!("expected ping, got {other:?}"),
+            }
+        };
+        let (rtt, pong) = tokio::join!(scores.measure_rtt(&mut a), pong);
+        pong?;
+        let rtt = rtt?;
+
+        assert_eq!(scores.stats(&a.their_pubkey()).unwrap().rtt, Some(rtt));
+        Ok(())
+    }
+}
@@ -1 +1,45 @@
+//! Lightning message signing, as used by `signmessage` in CLN and LND and by watchtowers to
+//! authenticate users.
 
+use crate::util::base32::Alphabet;
+use bitcoin::hashes::{Hash, sha256d};
+use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+
+const SIGNED_MSG_PREFIX: &[u8] = b"Lightning Signed Message:";
+
+fn message_hash(msg: &[u8]) -> Message {
+    let hash = sha256d::Hash::hash(&[SIGNED_MSG_PREFIX, msg].concat());
+    Message::from_digest(hash.to_byte_array())
+}
+
+/// Sign `msg` with `sk`, returning the zbase32 encoded recoverable signature.
+pub(crate) fn sign(msg: &[u8], sk: &SecretKey) -> String {
+    let sig = Secp256k1::signing_only().sign_ecdsa_recoverable(&message_hash(msg), sk);
+    let (recid, compact) = sig.serialize_compact();
+    let mut bytes = Vec::with_capacity(65);
+    bytes.push(recid.to_i32() as u8 + 31);
+    bytes.extend_from_slice(&compact);
+    Alphabet::ZBase32.encode(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+
+    #[test]
+    fn test_sign_recovers_to_signer() {
+        let secp_ctx = Secp256k1::new();
+        let sk = SecretKey::from_slice(&[0x42; 32]).unwrap();
+        let zsig = sign(b"test message", &sk);
+
+        let bytes = Alphabet::ZBase32.decode(&zsig).unwrap();
+        assert_eq!(bytes.len(), 65);
+        let recid = RecoveryId::from_i32(bytes[0] as i32 - 31).unwrap();
+        let sig = RecoverableSignature::from_compact(&bytes[1..], recid).unwrap();
+        let pk = secp_ctx
+            .recover_ecdsa(&message_hash(b"test message"), &sig)
+            .unwrap();
+        assert_eq!(pk, sk.public_key(&secp_ctx));
+    }
+}
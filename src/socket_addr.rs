@@ -1,11 +1,37 @@
+#[cfg(feature = "tokio")]
+use crate::Error;
+use crate::io::{self, Read};
 use crate::ln::msgs::DecodeError;
+use crate::prelude::*;
 use crate::util::{
     base32,
     ser::{Hostname, Readable, Writeable, Writer},
 };
-use std::fmt::Display;
-use std::io::{self, Read};
-use std::str::FromStr;
+use core::fmt::Display;
+use core::str::FromStr;
+
+/// Split `host[:port]`, accepting IPv6 hosts with or without brackets. The port defaults to
+/// [`DEFAULT_PORT`](crate::lnsocket::DEFAULT_PORT).
+#[cfg(feature = "tokio")]
+pub(crate) fn split_host_port(addr: &str) -> Result<(&str, u16), Error> {
+    let default = crate::lnsocket::DEFAULT_PORT;
+    if let Some(rest) = addr.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or(Error::DnsError)?;
+        return match rest {
+            "" => Ok((host, default)),
+            _ => {
+                let port = rest.strip_prefix(':').ok_or(Error::DnsError)?;
+                Ok((host, port.parse().map_err(|_| Error::DnsError)?))
+            }
+        };
+    }
+    match addr.split_once(':') {
+        None => Ok((addr, default)),
+        // more than one colon is a bare IPv6 literal, which can't have a port
+        Some((_, rest)) if rest.contains(':') => Ok((addr, default)),
+        Some((host, port)) => Ok((host, port.parse().map_err(|_| Error::DnsError)?)),
+    }
+}
 
 /// An address which can be used to connect to a remote peer.
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
@@ -50,6 +76,12 @@ pub enum SocketAddress {
         /// The port on which the node is listening.
         port: u16,
     },
+    /// A port on which the peer accepts WebSocket connections, on the hosts of its other
+    /// addresses. Announced by Core Lightning's `experimental-websocket-port`.
+    WebSocket {
+        /// The WebSocket port
+        port: u16,
+    },
 }
 impl SocketAddress {
     /// The maximum length of any address descriptor, not including the 1-byte type.
@@ -64,6 +96,7 @@ impl SocketAddress {
             SocketAddress::OnionV2(_) => true,
             SocketAddress::OnionV3 { .. } => true,
             SocketAddress::Hostname { .. } => false,
+            SocketAddress::WebSocket { .. } => false,
         }
     }
 }
@@ -102,6 +135,10 @@ impl Writeable for SocketAddress {
                 hostname.write(writer)?;
                 port.write(writer)?;
             }
+            SocketAddress::WebSocket { port } => {
+                6u8.write(writer)?;
+                port.write(writer)?;
+            }
         }
         Ok(())
     }
@@ -132,6 +169,9 @@ impl Readable for Result<SocketAddress, u8> {
                 hostname: Readable::read(reader)?,
                 port: Readable::read(reader)?,
             })),
+            6 => Ok(Ok(SocketAddress::WebSocket {
+                port: Readable::read(reader)?,
+            })),
             _ => Ok(Err(byte)),
         }
     }
@@ -160,8 +200,8 @@ pub enum SocketAddressParseError {
     InvalidOnionV3,
 }
 
-impl std::fmt::Display for SocketAddressParseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for SocketAddressParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
             SocketAddressParseError::SocketAddrParse => {
                 write!(f, "Socket address (IPv4/IPv6) parsing error")
@@ -177,8 +217,8 @@ impl std::fmt::Display for SocketAddressParseError {
     }
 }
 
-impl From<std::net::SocketAddrV4> for SocketAddress {
-    fn from(addr: std::net::SocketAddrV4) -> Self {
+impl From<core::net::SocketAddrV4> for SocketAddress {
+    fn from(addr: core::net::SocketAddrV4) -> Self {
         SocketAddress::TcpIpV4 {
             addr: addr.ip().octets(),
             port: addr.port(),
@@ -186,8 +226,8 @@ impl From<std::net::SocketAddrV4> for SocketAddress {
     }
 }
 
-impl From<std::net::SocketAddrV6> for SocketAddress {
-    fn from(addr: std::net::SocketAddrV6) -> Self {
+impl From<core::net::SocketAddrV6> for SocketAddress {
+    fn from(addr: core::net::SocketAddrV6) -> Self {
         SocketAddress::TcpIpV6 {
             addr: addr.ip().octets(),
             port: addr.port(),
@@ -195,15 +235,16 @@ impl From<std::net::SocketAddrV6> for SocketAddress {
     }
 }
 
-impl From<std::net::SocketAddr> for SocketAddress {
-    fn from(addr: std::net::SocketAddr) -> Self {
+impl From<core::net::SocketAddr> for SocketAddress {
+    fn from(addr: core::net::SocketAddr) -> Self {
         match addr {
-            std::net::SocketAddr::V4(addr) => addr.into(),
-            std::net::SocketAddr::V6(addr) => addr.into(),
+            core::net::SocketAddr::V4(addr) => addr.into(),
+            core::net::SocketAddr::V6(addr) => addr.into(),
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl std::net::ToSocketAddrs for SocketAddress {
     type Iter = std::vec::IntoIter<std::net::SocketAddr>;
 
@@ -229,6 +270,9 @@ impl std::net::ToSocketAddrs for SocketAddress {
             SocketAddress::OnionV3 { .. } => Err(std::io::Error::other(
                 "Resolution of OnionV3 addresses is currently unsupported.",
             )),
+            SocketAddress::WebSocket { .. } => Err(std::io::Error::other(
+                "WebSocket addresses have no host of their own.",
+            )),
         }
     }
 }
@@ -313,6 +357,7 @@ impl Display for SocketAddress {
                 write!(f, "{}.onion:{}", onion, port)?
             }
             SocketAddress::Hostname { hostname, port } => write!(f, "{}:{}", hostname, port)?,
+            SocketAddress::WebSocket { port } => write!(f, "websocket:{}", port)?,
         }
         Ok(())
     }
@@ -322,7 +367,7 @@ impl FromStr for SocketAddress {
     type Err = SocketAddressParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match std::net::SocketAddr::from_str(s) {
+        match core::net::SocketAddr::from_str(s) {
             Ok(addr) => Ok(addr.into()),
             Err(_) => {
                 let trimmed_input = match s.rfind(":") {
@@ -383,3 +428,34 @@ pub fn parse_onion_address(
         Err(SocketAddressParseError::InvalidInput)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("a.onion:9736").unwrap(), ("a.onion", 9736));
+        assert_eq!(split_host_port("a.onion").unwrap(), ("a.onion", 9735));
+        assert_eq!(split_host_port("[::1]:1").unwrap(), ("::1", 1));
+        assert_eq!(split_host_port("[::1]").unwrap(), ("::1", 9735));
+        assert_eq!(
+            split_host_port("2001:db8::1").unwrap(),
+            ("2001:db8::1", 9735)
+        );
+        assert!(split_host_port("host:nope").is_err());
+        assert!(split_host_port("[::1").is_err());
+        assert!(split_host_port("[::1]9735").is_err());
+    }
+
+    #[test]
+    fn test_websocket_address() {
+        let addr = SocketAddress::WebSocket { port: 9736 };
+        let mut buf = Vec::new();
+        addr.write(&mut buf).unwrap();
+        assert_eq!(buf, [6, 0x26, 0x08]);
+        let read: SocketAddress = Readable::read(&mut &buf[..]).unwrap();
+        assert_eq!(read, addr);
+        assert_eq!(addr.to_string(), "websocket:9736");
+    }
+}
@@ -0,0 +1,262 @@
+//! Stress-testing a connection with a flood of pings.
+//!
+//! [`run`] sends pings as fast as allowed, or at a fixed rate, and times each `pong`, giving
+//! throughput, latency percentiles and how many pings went unanswered. Pointing it at sockets
+//! opened over different transports (TCP, Tor, WebSocket) compares them; [`run_local`] runs
+//! against an in-memory peer to measure the crate's own framing and encryption.
+//!
+//! Needs the `testing` feature. `examples/stress.rs` runs it from the command line.
+//!
+//! Peers may rate-limit pings, lnsocket's own [`PingPolicy`] answers 10 per 30 seconds by
+//! default, so against a real node much of a flood can show up as dropped. That's the drop
+//! behavior being measured, not a failure of the run.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::stress::{self, StressOptions};
+//! # async fn example() -> Result<(), lnsocket::Error> {
+//! let opts = StressOptions {
+//!     messages: 10_000,
+//!     payload_size: 4096,
+//!     ..Default::default()
+//! };
+//! let report = stress::run_local(&opts).await?;
+//! println!(
+//!     "{:.1} MB/s, p99 {:?}",
+//!     report.bytes_per_sec() / 1e6,
+//!     report.percentile(99.0)
+//! );
+//! # Ok(()) }
+//! ```
+
+use crate::ln::msgs;
+use crate::ln::wire::Message;
+use crate::ping::{PONG_IGNORE_THRESHOLD, PingPolicy};
+use crate::testing::default_init;
+use crate::{Error, LNSocket};
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey, rand};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+/// How to flood the connection.
+#[derive(Clone, Debug)]
+pub struct StressOptions {
+    /// How many pings to send.
+    pub messages: usize,
+    /// Bytes of padding in each ping, and asked for in each pong. At most 65531, larger sizes
+    /// are capped.
+    pub payload_size: u16,
+    /// Pings to send per second, or `None` for as fast as the window allows.
+    pub rate: Option<u32>,
+    /// How many pings may be waiting for their pong at once.
+    pub window: usize,
+    /// How long to wait for a pong before counting the pings still waiting as dropped.
+    pub timeout: Duration,
+}
+
+impl Default for StressOptions {
+    fn default() -> Self {
+        Self {
+            messages: 1000,
+            payload_size: 1024,
+            rate: None,
+            window: 32,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What a stress run measured.
+#[derive(Clone, Debug, Default)]
+pub struct StressReport {
+    pub sent: usize,
+    /// Pings answered with a pong.
+    pub received: usize,
+    /// Pings never answered, because the peer ignored them or the run timed out.
+    pub dropped: usize,
+    /// Plaintext message bytes both ways, not counting the encryption overhead.
+    pub bytes: u64,
+    pub elapsed: Duration,
+    // round trip times, sorted
+    latencies: Vec<Duration>,
+}
+
+impl StressReport {
+    /// The round trip time at or below which `p` percent of pongs arrived, e.g. 99.0 for p99.
+    /// `None` if nothing was answered.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let rank = (p.clamp(0.0, 100.0) / 100.0 * (self.latencies.len() - 1) as f64).round();
+        Some(self.latencies[rank as usize])
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Answered pings per second.
+    pub fn messages_per_sec(&self) -> f64 {
+        self.received as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Flood `socket`, which must have completed `init`, with the default options.
+pub async fn run(socket: &mut LNSocket) -> Result<StressReport, Error> {
+    run_with(&StressOptions::default(), socket).await
+}
+
+/// Flood `socket`, which must have completed `init`, with pings as described by `opts`.
+///
+/// Messages other than pongs are skipped, and the peer's pings answered, while the run lasts.
+pub async fn run_with(opts: &StressOptions, socket: &mut LNSocket) -> Result<StressReport, Error> {
+    // larger pongs must not be sent, so nothing would come back
+    let payload_size = opts.payload_size.min(PONG_IGNORE_THRESHOLD - 1);
+    let ping = msgs::Ping {
+        ponglen: payload_size,
+        byteslen: payload_size,
+    };
+    // type, the two lengths and the padding; pongs are type, length and padding
+    let ping_len = 2 + 4 + payload_size as u64;
+    let pong_len = 2 + 2 + payload_size as u64;
+    let interval = opts.rate.map(|rate| Duration::from_secs(1) / rate.max(1));
+
+    let mut report = StressReport::default();
+    // send times of the pings still waiting, pongs come back in order
+    let mut waiting: VecDeque<Instant> = VecDeque::new();
+    let start = Instant::now();
+    let mut next_send = start;
+
+    while report.sent < opts.messages || !waiting.is_empty() {
+        let now = Instant::now();
+        let may_send = report.sent < opts.messages && waiting.len() < opts.window.max(1);
+        if may_send && now >= next_send {
+            socket.write(&ping).await?;
+            waiting.push_back(Instant::now());
+            report.sent += 1;
+            report.bytes += ping_len;
+            if let Some(interval) = interval {
+                next_send += interval;
+            }
+            continue;
+        }
+
+        // wait for a pong, but not past when the next ping is due. reads are cancel-safe, so
+        // giving up on one midway loses nothing
+        let wait = match (may_send, waiting.front()) {
+            (true, _) => next_send.saturating_duration_since(now),
+            (false, Some(oldest)) => (*oldest + opts.timeout).saturating_duration_since(now),
+            (false, None) => next_send.saturating_duration_since(now),
+        };
+        let msg = match timeout(wait, socket.read()).await {
+            Ok(msg) => msg?,
+            Err(_) => {
+                let timed_out = waiting
+                    .front()
+                    .is_some_and(|oldest| oldest.elapsed() >= opts.timeout);
+                if timed_out {
+                    // no pong in time, assume every ping still waiting was ignored
+                    report.dropped += waiting.len();
+                    waiting.clear();
+                }
+                continue;
+            }
+        };
+        match msg {
+            Message::Pong(_) => {
+                if let Some(sent_at) = waiting.pop_front() {
+                    report.latencies.push(sent_at.elapsed());
+                    report.received += 1;
+                    report.bytes += pong_len;
+                }
+            }
+            Message::Ping(ping) => {
+                if let Some(pong) = socket.pong_for(&ping)? {
+                    socket.write(&pong).await?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    report.elapsed = start.elapsed();
+    report.latencies.sort();
+    Ok(report)
+}
+
+/// Flood an in-memory peer that answers every ping, measuring the crate itself without a
+/// network in the way.
+pub async fn run_local(opts: &StressOptions) -> Result<StressReport, Error> {
+    let our_key = SecretKey::new(&mut rand::thread_rng());
+    let peer_key = SecretKey::new(&mut rand::thread_rng());
+    let peer_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &peer_key);
+    let (client, server) = tokio::io::duplex(256 * 1024);
+
+    let (socket, peer) = tokio::join!(
+        LNSocket::handshake_outbound(client, our_key, peer_id),
+        LNSocket::handshake_inbound(server, peer_key)
+    );
+    let (mut socket, mut peer) = (socket?, peer?);
+    peer.set_ping_policy(PingPolicy {
+        max_pings: u32::MAX,
+        ..Default::default()
+    });
+    peer.write(&default_init()).await?;
+    tokio::spawn(async move {
+        while let Ok(msg) = peer.read().await {
+            if let Message::Ping(ping) = msg
+                && let Ok(Some(pong)) = peer.pong_for(&ping)
+                && peer.write(&pong).await.is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    socket.perform_init().await?;
+    run_with(opts, &mut socket).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockPeer;
+
+    #[tokio::test]
+    async fn test_run_local() -> Result<(), Error> {
+        let opts = StressOptions {
+            messages: 200,
+            payload_size: 512,
+            ..Default::default()
+        };
+        let report = run_local(&opts).await?;
+        assert_eq!(report.sent, 200);
+        assert_eq!(report.received, 200);
+        assert_eq!(report.dropped, 0);
+        assert_eq!(report.bytes, 200 * (518 + 516));
+        assert!(report.percentile(50.0) <= report.percentile(99.0));
+        assert!(report.messages_per_sec() > 0.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_counts_drops() -> Result<(), Error> {
+        // the mock peer answers 10 pings per 30 seconds, like a real node would
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut socket, _peer) = MockPeer::connect(key).await?;
+        socket.perform_init().await?;
+
+        let opts = StressOptions {
+            messages: 15,
+            timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let report = run_with(&opts, &mut socket).await?;
+        assert_eq!(report.sent, 15);
+        assert_eq!(report.received, 10);
+        assert_eq!(report.dropped, 5);
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+//! Test helpers: an in-memory peer to point an [`LNSocket`] at.
+//!
+//! [`MockPeer`] runs the responder side of the handshake over an in-memory pipe, answers the
+//! client's `init` and pings on its own, and hands everything else to the test. Tests script
+//! what the peer says with [`MockPeer::send`] and check what the client said with
+//! [`MockPeer::recv`], without touching the network.
+//!
+//! ### Example
+//! ```
+//! use lnsocket::ln::{msgs, wire::Message};
+//! use lnsocket::testing::MockPeer;
+//! # use bitcoin::secp256k1::{SecretKey, rand};
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> Result<(), lnsocket::Error> {
+//! let key = SecretKey::new(&mut rand::thread_rng());
+//! let (mut socket, mut peer) = MockPeer::connect(key).await?;
+//! socket.perform_init().await?;
+//!
+//! socket.write(&msgs::Ping { ponglen: 4, byteslen: 8 }).await?;
+//! assert!(matches!(socket.read().await?, Message::Pong(_)));
+//! # Ok(()) }
+//! ```
+//!
+//! [`regtest`] starts a real Core Lightning node for end-to-end tests.
+
+pub mod regtest;
+
+use crate::ln::msgs::{self, DecodeError};
+use crate::ln::wire::{self, Encode, Message, Type};
+use crate::record::{Direction, RecordedFrame};
+use crate::util::ser::{LengthLimitedRead, Writeable, Writer};
+use crate::{Error, LNSocket};
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey, rand};
+use std::io;
+use tokio::sync::{mpsc, watch};
+
+/// A message with its payload left undecoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawMessage {
+    pub type_id: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Writeable for RawMessage {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        w.write_all(&self.payload)
+    }
+}
+
+impl Type for RawMessage {
+    fn type_id(&self) -> u16 {
+        self.type_id
+    }
+}
+
+fn read_raw<R: LengthLimitedRead>(typ: u16, r: &mut R) -> Result<Option<RawMessage>, DecodeError> {
+    let mut payload = Vec::new();
+    r.read_to_end(&mut payload)?;
+    Ok(Some(RawMessage {
+        type_id: typ,
+        payload,
+    }))
+}
+
+/// The default `init` a [`MockPeer`] sends: no features, no networks.
+pub fn default_init() -> msgs::Init {
+    msgs::Init {
+        features: vec![],
+        global_features: vec![],
+        networks: None,
+        remote_network_address: None,
+        custom_tlvs: vec![],
+    }
+}
+
+/// An in-memory Lightning peer driven by a test.
+pub struct MockPeer {
+    node_id: PublicKey,
+    outgoing: mpsc::UnboundedSender<RawMessage>,
+    incoming: mpsc::UnboundedReceiver<Message<RawMessage>>,
+    their_init: watch::Receiver<Option<msgs::Init>>,
+}
+
+impl MockPeer {
+    /// Connect a client using `our_key` to a new peer with a random node id, which sends
+    /// [`default_init`]. The returned socket has completed the handshake but not `init`.
+    pub async fn connect(our_key: SecretKey) -> Result<(LNSocket, MockPeer), Error> {
+        Self::connect_with(our_key, default_init()).await
+    }
+
+    /// Like [`MockPeer::connect`], but the peer sends `init` as its `init`.
+    pub async fn connect_with(
+        our_key: SecretKey,
+        init: msgs::Init,
+    ) -> Result<(LNSocket, MockPeer), Error> {
+        let peer_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &peer_key);
+        let (client, server) = tokio::io::duplex(128 * 1024);
+
+        let (socket, peer) = tokio::join!(
+            LNSocket::handshake_outbound(client, our_key, node_id),
+            LNSocket::handshake_inbound(server, peer_key)
+        );
+        let (socket, mut peer) = (socket?, peer?);
+        peer.write(&init).await?;
+
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let (init_tx, their_init) = watch::channel(None);
+        tokio::spawn(run(peer, outgoing_rx, incoming_tx, init_tx));
+
+        let mock = MockPeer {
+            node_id,
+            outgoing,
+            incoming,
+            their_init,
+        };
+        Ok((socket, mock))
+    }
+
+    /// The peer's node id, which the client connected to.
+    pub fn node_id(&self) -> PublicKey {
+        self.node_id
+    }
+
+    /// Send `msg` to the client. Messages are held back until the client's `init` arrives.
+    pub fn send<M: Type + Writeable>(&self, msg: &M) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        wire::write(msg, &mut buf)?;
+        let raw = RawMessage {
+            type_id: msg.type_id(),
+            payload: buf.split_off(2),
+        };
+        self.outgoing.send(raw).map_err(|_| Error::NotConnected)
+    }
+
+    /// Send everything the peer sent in a recording, except its `init`, to the client.
+    pub fn replay(
+        &self,
+        frames: impl IntoIterator<Item = Result<RecordedFrame, Error>>,
+    ) -> Result<(), Error> {
+        for frame in frames {
+            let frame = frame?;
+            let Some(type_id) = frame.type_id() else {
+                continue;
+            };
+            if frame.direction != Direction::Inbound || type_id == msgs::Init::TYPE {
+                continue;
+            }
+            let raw = RawMessage {
+                type_id,
+                payload: frame.data[2..].to_vec(),
+            };
+            self.outgoing.send(raw).map_err(|_| Error::NotConnected)?;
+        }
+        Ok(())
+    }
+
+    /// The next message from the client, other than `init` and pings. Messages the wire
+    /// layer doesn't know come as [`Message::Custom`]. `None` once the client is gone.
+    pub async fn recv(&mut self) -> Option<Message<RawMessage>> {
+        self.incoming.recv().await
+    }
+
+    /// Wait for the client's `init`.
+    pub async fn their_init(&mut self) -> Option<msgs::Init> {
+        self.their_init
+            .wait_for(Option::is_some)
+            .await
+            .ok()
+            .and_then(|init| init.clone())
+    }
+}
+
+async fn run(
+    mut socket: LNSocket,
+    mut outgoing: mpsc::UnboundedReceiver<RawMessage>,
+    incoming: mpsc::UnboundedSender<Message<RawMessage>>,
+    their_init: watch::Sender<Option<msgs::Init>>,
+) {
+    let mut ready = false;
+    loop {
+        // write what the test queued before reading on, so replies can't overtake it
+        tokio::select! {
+            biased;
+            msg = outgoing.recv(), if ready => {
+                let Some(msg) = msg else { break };
+                if socket.write(&msg).await.is_err() {
+                    break;
+                }
+            }
+            msg = socket.read_custom(|typ, buf| read_raw(typ, buf)) => match msg {
+                Ok(Message::Init(init)) => {
+                    ready = true;
+                    let _ = their_init.send(Some(init));
+                }
+                Ok(Message::Ping(ping)) => {
+                    if let Ok(Some(pong)) = socket.pong_for(&ping)
+                        && socket.write(&pong).await.is_err()
+                    {
+                        break;
+                    }
+                }
+                Ok(msg) => {
+                    let _ = incoming.send(msg);
+                }
+                Err(_) => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_peer() -> Result<(), Error> {
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let (mut socket, mut peer) = MockPeer::connect(key).await?;
+        assert_eq!(socket.their_pubkey(), peer.node_id());
+
+        peer.send(&msgs::Ping {
+            ponglen: 2,
+            byteslen: 0,
+        })?;
+        socket.perform_init().await?;
+        assert!(peer.their_init().await.is_some());
+        assert!(matches!(socket.read().await?, Message::Ping(p) if p.ponglen == 2));
+
+        socket
+            .write(&RawMessage {
+                type_id: 0x8001,
+                payload: vec![1, 2, 3],
+            })
+            .await?;
+        match peer.recv().await {
+            Some(Message::Custom(raw)) => {
+                assert_eq!(raw.type_id, 0x8001);
+                assert_eq!(raw.payload, vec![1, 2, 3]);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+
+        drop(socket);
+        assert!(peer.recv().await.is_none());
+        Ok(())
+    }
+}
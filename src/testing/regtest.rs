@@ -0,0 +1,294 @@
+//! A throwaway regtest Core Lightning node for end-to-end tests.
+//!
+//! [`RegtestNode::start`] runs `bitcoind` and `lightningd` in regtest mode in a fresh
+//! directory, funds the node's wallet, and issues a rune, so tests can connect with
+//! [`LNSocket`](crate::LNSocket) and call it with [`CommandoClient`](crate::CommandoClient).
+//! Everything is torn down when the [`RegtestNode`] is dropped.
+//!
+//! The binaries must be on `PATH` (or named by `BITCOIND`, `BITCOIN_CLI`, `LIGHTNINGD` and
+//! `LIGHTNING_CLI`). When they aren't, `start` returns `Ok(None)` so tests can skip instead of
+//! failing on machines without them.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::{CommandoClient, LNSocket};
+//! use lnsocket::testing::regtest::RegtestNode;
+//! # use bitcoin::secp256k1::{SecretKey, rand};
+//! # async fn example() -> Result<(), lnsocket::Error> {
+//! let Some(node) = RegtestNode::start()? else {
+//!     return Ok(()); // no binaries, skip
+//! };
+//! let key = SecretKey::new(&mut rand::thread_rng());
+//! let mut socket = LNSocket::connect_and_init(key, node.node_id, &node.addr).await?;
+//! let mut commando = CommandoClient::new(&node.rune);
+//! commando.call(&mut socket, "getinfo", serde_json::json!({})).await?;
+//! # Ok(()) }
+//! ```
+
+use bitcoin::secp256k1::PublicKey;
+use serde_json::Value;
+use std::io;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const RPC_USER: &str = "lnsocket";
+const RPC_PASSWORD: &str = "lnsocket";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+static NEXT_DIR: AtomicU32 = AtomicU32::new(0);
+
+fn binary(env: &str, default: &str) -> String {
+    std::env::var(env).unwrap_or_else(|_| default.to_owned())
+}
+
+fn on_path(bin: &str) -> bool {
+    Command::new(bin)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+/// Run a command to completion and parse its stdout as JSON (or a bare string).
+fn run_json(cmd: &mut Command) -> io::Result<Value> {
+    let out = cmd.stdin(Stdio::null()).output()?;
+    if !out.status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            cmd,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let stdout = stdout.trim();
+    Ok(serde_json::from_str(stdout).unwrap_or_else(|_| Value::String(stdout.to_owned())))
+}
+
+/// Retry `f` until it succeeds or [`STARTUP_TIMEOUT`] passes.
+fn wait_for<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let start = Instant::now();
+    loop {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(err) if start.elapsed() > STARTUP_TIMEOUT => return Err(err),
+            Err(_) => sleep(Duration::from_millis(250)),
+        }
+    }
+}
+
+/// The daemons and their data, cleaned up on drop even if startup fails halfway.
+struct Daemons {
+    dir: PathBuf,
+    rpc_port: u16,
+    bitcoind: Child,
+    lightningd: Option<Child>,
+}
+
+impl Drop for Daemons {
+    fn drop(&mut self) {
+        if let Some(lightningd) = &mut self.lightningd {
+            let _ = lightningd.kill();
+            let _ = lightningd.wait();
+        }
+        let _ = self.bitcoind.kill();
+        let _ = self.bitcoind.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl Daemons {
+    fn cli(&self, method: &str, args: &[&str]) -> io::Result<Value> {
+        run_json(
+            Command::new(binary("LIGHTNING_CLI", "lightning-cli"))
+                .arg("--network=regtest")
+                .arg(format!(
+                    "--lightning-dir={}",
+                    self.dir.join("lightning").display()
+                ))
+                .arg(method)
+                .args(args),
+        )
+    }
+
+    fn bitcoin_cli(&self, args: &[&str]) -> io::Result<Value> {
+        run_json(
+            Command::new(binary("BITCOIN_CLI", "bitcoin-cli"))
+                .arg("-regtest")
+                .arg(format!("-rpcport={}", self.rpc_port))
+                .arg(format!("-rpcuser={}", RPC_USER))
+                .arg(format!("-rpcpassword={}", RPC_PASSWORD))
+                .args(args),
+        )
+    }
+
+    fn mine(&self, blocks: u32) -> io::Result<()> {
+        let address = self.bitcoin_cli(&["-rpcwallet=lnsocket", "getnewaddress"])?;
+        let address = address
+            .as_str()
+            .ok_or_else(|| io::Error::other("getnewaddress returned no address"))?;
+        self.bitcoin_cli(&["generatetoaddress", &blocks.to_string(), address])?;
+        Ok(())
+    }
+}
+
+/// A running regtest `bitcoind` + `lightningd` pair.
+pub struct RegtestNode {
+    /// The Lightning node's id.
+    pub node_id: PublicKey,
+    /// Where the node accepts peer connections, as `host:port`.
+    pub addr: String,
+    /// A rune allowing every command.
+    pub rune: String,
+    daemons: Daemons,
+}
+
+impl RegtestNode {
+    /// Start a funded node, or `Ok(None)` if the binaries aren't available.
+    ///
+    /// This blocks while the daemons start, which takes a few seconds.
+    pub fn start() -> io::Result<Option<RegtestNode>> {
+        if !on_path(&binary("BITCOIND", "bitcoind"))
+            || !on_path(&binary("LIGHTNINGD", "lightningd"))
+        {
+            return Ok(None);
+        }
+
+        let dir = std::env::temp_dir().join(format!(
+            "lnsocket-regtest-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("bitcoin"))?;
+        std::fs::create_dir_all(dir.join("lightning"))?;
+
+        let rpc_port = free_port()?;
+        let bitcoind = Command::new(binary("BITCOIND", "bitcoind"))
+            .arg("-regtest")
+            .arg(format!("-datadir={}", dir.join("bitcoin").display()))
+            .arg(format!("-rpcport={}", rpc_port))
+            .arg(format!("-rpcuser={}", RPC_USER))
+            .arg(format!("-rpcpassword={}", RPC_PASSWORD))
+            .arg("-fallbackfee=0.00001")
+            .arg("-listen=0")
+            .stdout(Stdio::null())
+            .spawn()?;
+        let mut daemons = Daemons {
+            dir,
+            rpc_port,
+            bitcoind,
+            lightningd: None,
+        };
+
+        wait_for(|| daemons.bitcoin_cli(&["getblockchaininfo"]))?;
+        daemons.bitcoin_cli(&["createwallet", "lnsocket"])?;
+        daemons.mine(101)?;
+
+        let addr = format!("127.0.0.1:{}", free_port()?);
+        let dir = &daemons.dir;
+        daemons.lightningd = Some(
+            Command::new(binary("LIGHTNINGD", "lightningd"))
+                .arg("--network=regtest")
+                .arg(format!(
+                    "--lightning-dir={}",
+                    dir.join("lightning").display()
+                ))
+                .arg(format!("--addr={}", addr))
+                .arg(format!("--bitcoin-rpcuser={}", RPC_USER))
+                .arg(format!("--bitcoin-rpcpassword={}", RPC_PASSWORD))
+                .arg(format!("--bitcoin-rpcport={}", rpc_port))
+                .arg(format!(
+                    "--bitcoin-datadir={}",
+                    dir.join("bitcoin").display()
+                ))
+                .arg(format!(
+                    "--log-file={}",
+                    dir.join("lightningd.log").display()
+                ))
+                .stdout(Stdio::null())
+                .spawn()?,
+        );
+
+        let info = wait_for(|| daemons.cli("getinfo", &[]))?;
+        let node_id = info["id"]
+            .as_str()
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| io::Error::other("getinfo has no node id"))?;
+
+        let address = daemons.cli("newaddr", &[])?;
+        let address = address["bech32"]
+            .as_str()
+            .ok_or_else(|| io::Error::other("newaddr has no address"))?;
+        daemons.bitcoin_cli(&["-rpcwallet=lnsocket", "sendtoaddress", address, "1"])?;
+        daemons.mine(6)?;
+
+        // createrune replaced commando-rune in CLN 23.08
+        let rune = daemons
+            .cli("createrune", &[])
+            .or_else(|_| daemons.cli("commando-rune", &[]))?;
+        let rune = rune["rune"]
+            .as_str()
+            .ok_or_else(|| io::Error::other("no rune issued"))?
+            .to_owned();
+
+        Ok(Some(RegtestNode {
+            node_id,
+            addr,
+            rune,
+            daemons,
+        }))
+    }
+
+    /// The directory holding both daemons' data and lightningd's log.
+    pub fn dir(&self) -> &Path {
+        &self.daemons.dir
+    }
+
+    /// Run a `lightning-cli` command against the node.
+    pub fn cli(&self, method: &str, args: &[&str]) -> io::Result<Value> {
+        self.daemons.cli(method, args)
+    }
+
+    /// Run a `bitcoin-cli` command against the node's bitcoind.
+    pub fn bitcoin_cli(&self, args: &[&str]) -> io::Result<Value> {
+        self.daemons.bitcoin_cli(args)
+    }
+
+    /// Mine `blocks` blocks to the bitcoind wallet.
+    pub fn mine(&self, blocks: u32) -> io::Result<()> {
+        self.daemons.mine(blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandoClient, Error, LNSocket};
+    use bitcoin::secp256k1::{SecretKey, rand};
+
+    #[tokio::test]
+    async fn test_regtest_commando() -> Result<(), Error> {
+        let Some(node) = RegtestNode::start()? else {
+            eprintln!("bitcoind/lightningd not found, skipping");
+            return Ok(());
+        };
+
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let mut socket = LNSocket::connect_and_init(key, node.node_id, &node.addr).await?;
+        let mut commando = CommandoClient::new(&node.rune);
+        let info = commando
+            .call(&mut socket, "getinfo", serde_json::json!({}))
+            .await?;
+        assert_eq!(info["result"]["id"], node.node_id.to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+//! How long setting up a connection took, step by step.
+//!
+//! [`LNSocket::timings`](crate::LNSocket::timings) tells whether a slow connection is slow
+//! resolving, reaching the node (or building a Tor circuit), or because the node is slow to
+//! answer the handshake or `init`. [`PeerScores`](crate::score::PeerScores) ranks peers by
+//! the total.
+
+use std::time::Duration;
+
+// `std::time::Instant` panics in browsers, web-time uses the browser's clock there and is
+// `std`'s everywhere else
+#[cfg(not(feature = "wasm"))]
+pub(crate) use std::time::Instant;
+#[cfg(feature = "wasm")]
+pub(crate) use web_time::Instant;
+
+/// Durations of the steps of setting up a connection. Steps that didn't happen, like DNS for
+/// a stream opened by the caller, are `None`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectTimings {
+    /// Resolving the host name.
+    pub dns: Option<Duration>,
+    /// Opening the TCP connection, or for Tor the SOCKS5 exchange, which includes building
+    /// the circuit.
+    pub tcp_connect: Option<Duration>,
+    /// Sending act one, or receiving it when we're the responder.
+    pub act_one: Option<Duration>,
+    /// Waiting for act two after sending act one, or sending it when we're the responder.
+    /// For the initiator this is a round trip plus the peer's work.
+    pub act_two: Option<Duration>,
+    /// Sending act three, or waiting for it when we're the responder.
+    pub act_three: Option<Duration>,
+    /// Exchanging `init`, from starting to wait for the peer's until ours is sent.
+    pub init: Option<Duration>,
+}
+
+impl ConnectTimings {
+    /// All the steps together.
+    pub fn total(&self) -> Duration {
+        [
+            self.dns,
+            self.tcp_connect,
+            self.act_one,
+            self.act_two,
+            self.act_three,
+            self.init,
+        ]
+        .into_iter()
+        .flatten()
+        .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lnsocket::tests::socket_pair;
+
+    #[tokio::test]
+    async fn test_timings() -> Result<(), crate::Error> {
+        let (a, b) = socket_pair().await?;
+        for socket in [&a, &b] {
+            let timings = socket.timings();
+            assert_eq!(timings.dns, None);
+            assert!(timings.act_one.is_some() && timings.act_two.is_some());
+            assert!(timings.act_three.is_some());
+        }
+        // only b went through perform_init
+        assert!(b.timings().init.is_some());
+        assert!(b.timings().total() >= b.timings().init.unwrap());
+        Ok(())
+    }
+}
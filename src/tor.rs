@@ -0,0 +1,424 @@
+//! Connecting through Tor.
+//!
+//! Tor is reached through its SOCKS5 proxy, normally listening on [`DEFAULT_TOR_PROXY`]. The
+//! onion address is passed to the proxy as a hostname, so it never needs resolving locally.
+//!
+//! Nodes with both a clearnet and an onion address can be reached over whichever path is
+//! faster with [`LNSocket::connect_racing`].
+//!
+//! Tor puts streams opened with different SOCKS credentials on different circuits. By default
+//! every connection is given random ones, so the exits and onion services we talk to can't
+//! tell that two connections came from the same client. See [`Isolation`].
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::tor::RaceOptions;
+//! # use bitcoin::secp256k1::{PublicKey, SecretKey};
+//! # async fn example(key: SecretKey, node: PublicKey) -> Result<(), lnsocket::Error> {
+//! let opts = RaceOptions {
+//!     // give Tor a second before trying clearnet
+//!     tor_head_start: std::time::Duration::from_secs(1),
+//!     ..Default::default()
+//! };
+//! let socket = LNSocket::connect_racing(
+//!     key,
+//!     node,
+//!     "ln.example.com:9735",
+//!     "abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuvwx.onion:9735",
+//!     &opts,
+//! )
+//! .await?;
+//! # Ok(()) }
+//! ```
+
+use crate::socket_addr::split_host_port;
+use crate::{Error, InitOptions, LNSocket};
+use bitcoin::hashes::{Hash, sha256};
+use bitcoin::secp256k1::rand::{self, RngCore};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Where the Tor daemon listens for SOCKS connections by default.
+pub const DEFAULT_TOR_PROXY: &str = "127.0.0.1:9050";
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS: u8 = 2;
+// RFC 1929
+const USER_PASS_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Which connections Tor may put on the same circuit.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Isolation {
+    /// Let Tor share circuits between connections, sending no credentials.
+    None,
+    /// Give every connection its own random SOCKS username and password, so none share a
+    /// circuit.
+    #[default]
+    PerConnection,
+    /// Connections with the same key may share circuits, ones with different keys don't. The
+    /// key is hashed into the credentials, so it can be anything, such as an account name.
+    Key(String),
+}
+
+impl Isolation {
+    /// The SOCKS username and password to send, if any.
+    pub(crate) fn credentials(&self) -> Option<(String, String)> {
+        match self {
+            Isolation::None => None,
+            Isolation::PerConnection => {
+                let mut random = [0u8; 16];
+                rand::thread_rng().fill_bytes(&mut random);
+                Some((hex::encode(random), "lnsocket".to_owned()))
+            }
+            Isolation::Key(key) => {
+                let hash = sha256::Hash::hash(key.as_bytes());
+                Some((hash.to_string(), "lnsocket".to_owned()))
+            }
+        }
+    }
+}
+
+/// Open a TCP connection to `addr` through the SOCKS5 proxy at `proxy`.
+///
+/// With `credentials`, username/password authentication is offered alongside none, which Tor
+/// prefers and uses to keep streams with different credentials on different circuits.
+/// Proxies that don't care pick no authentication.
+pub(crate) async fn socks5_connect(
+    proxy: &str,
+    addr: &str,
+    credentials: Option<(String, String)>,
+) -> Result<TcpStream, Error> {
+    let (host, port) = split_host_port(addr)?;
+    if host.len() > u8::MAX as usize {
+        return Err(Error::DnsError);
+    }
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[SOCKS_VERSION, 2, NO_AUTH, USER_PASS],
+        None => &[SOCKS_VERSION, 1, NO_AUTH],
+    };
+    stream.write_all(greeting).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    match (choice, &credentials) {
+        ([SOCKS_VERSION, NO_AUTH], _) => {}
+        ([SOCKS_VERSION, USER_PASS], Some((user, password))) => {
+            authenticate(&mut stream, user, password).await?
+        }
+        _ => return Err(Error::Socks(choice[1])),
+    }
+
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    // VER REP RSV ATYP, then the bound address, which we don't care about
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(Error::Socks(reply[1]));
+    }
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(Error::Socks(reply[3])),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(stream)
+}
+
+/// RFC 1929 username/password authentication. Fails with the status the proxy sent if it
+/// refused.
+async fn authenticate(stream: &mut TcpStream, user: &str, password: &str) -> Result<(), Error> {
+    if user.is_empty() || user.len() > 255 || password.is_empty() || password.len() > 255 {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput).into());
+    }
+    let mut request = vec![USER_PASS_VERSION, user.len() as u8];
+    request.extend_from_slice(user.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await?;
+
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).await?;
+    if status[1] != 0 {
+        return Err(Error::Socks(status[1]));
+    }
+    Ok(())
+}
+
+/// Settings for [`LNSocket::connect_racing`].
+#[derive(Clone, Debug)]
+pub struct RaceOptions {
+    /// The Tor SOCKS proxy, [`DEFAULT_TOR_PROXY`] by default.
+    pub tor_proxy: String,
+    /// How long to wait before starting the clearnet attempt. Zero (the default) races both
+    /// right away; a head start means a working onion route wins unless it's slow, which leaks
+    /// our IP to the node less often.
+    pub tor_head_start: Duration,
+    /// The `init` we send on the winning connection.
+    pub init: InitOptions,
+    /// Which circuit the onion attempt may share, [`Isolation::PerConnection`] by default.
+    pub isolation: Isolation,
+}
+
+impl Default for RaceOptions {
+    fn default() -> Self {
+        Self {
+            tor_proxy: DEFAULT_TOR_PROXY.to_owned(),
+            tor_head_start: Duration::ZERO,
+            init: InitOptions::default(),
+            isolation: Isolation::default(),
+        }
+    }
+}
+
+impl LNSocket {
+    /// Like [`LNSocket::connect`], but through the SOCKS5 proxy at `proxy` (normally Tor), on
+    /// a circuit of its own.
+    pub async fn connect_via_tor(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+        proxy: &str,
+    ) -> Result<LNSocket, Error> {
+        let isolation = Isolation::PerConnection;
+        Self::connect_via_tor_isolated(our_key, their_pubkey, addr, proxy, &isolation).await
+    }
+
+    /// Like [`LNSocket::connect_via_tor`], sharing circuits as `isolation` says.
+    pub async fn connect_via_tor_isolated(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        addr: &str,
+        proxy: &str,
+        isolation: &Isolation,
+    ) -> Result<LNSocket, Error> {
+        let start = Instant::now();
+        let stream = socks5_connect(proxy, addr, isolation.credentials()).await?;
+        let tcp_connect = start.elapsed();
+        let mut socket = Self::handshake_outbound(stream, our_key, their_pubkey).await?;
+        socket.timings.tcp_connect = Some(tcp_connect);
+        Ok(socket)
+    }
+
+    /// Connect to a node over its clearnet and onion addresses at once, and keep whichever
+    /// completes the handshake first.
+    ///
+    /// The slower attempt is dropped, closing its connection. If one attempt fails the other
+    /// is still awaited, so this only fails if both do (with the clearnet error, which is
+    /// usually the more telling one). `init` is exchanged on the winner.
+    pub async fn connect_racing(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        clearnet: &str,
+        onion: &str,
+        opts: &RaceOptions,
+    ) -> Result<LNSocket, Error> {
+        let tor = Self::connect_via_tor_isolated(
+            our_key,
+            their_pubkey,
+            onion,
+            &opts.tor_proxy,
+            &opts.isolation,
+        );
+        let clear = async {
+            tokio::time::sleep(opts.tor_head_start).await;
+            Self::connect(our_key, their_pubkey, clearnet).await
+        };
+        tokio::pin!(tor, clear);
+
+        let mut socket = tokio::select! {
+            res = &mut tor => match res {
+                Ok(socket) => socket,
+                Err(_) => clear.await?,
+            },
+            res = &mut clear => match res {
+                Ok(socket) => socket,
+                Err(err) => tor.await.map_err(|_| err)?,
+            },
+        };
+        socket.perform_init_with(&opts.init).await?;
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{Secp256k1, rand};
+    use tokio::net::TcpListener;
+
+    /// A one-shot SOCKS5 proxy that expects a request for `expected` and then plays the node.
+    /// Returns the credentials it was given, if any.
+    async fn fake_tor(
+        listener: TcpListener,
+        expected: &'static str,
+        node_key: SecretKey,
+    ) -> Option<(String, String)> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let credentials = socks_auth(&mut stream).await;
+
+        let mut head = [0u8; 5];
+        stream.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[..4], [5, 1, 0, 3]);
+        let mut host = vec![0u8; head[4] as usize + 2];
+        stream.read_exact(&mut host).await.unwrap();
+        let port = u16::from_be_bytes([host[host.len() - 2], host[host.len() - 1]]);
+        host.truncate(host.len() - 2);
+        assert_eq!(
+            format!("{}:{}", String::from_utf8(host).unwrap(), port),
+            expected
+        );
+        stream
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x23, 0x27])
+            .await
+            .unwrap();
+
+        let mut node = LNSocket::handshake_inbound(stream, node_key).await.unwrap();
+        node.write(&crate::ln::msgs::Init {
+            features: vec![],
+            global_features: vec![],
+            networks: None,
+            remote_network_address: None,
+            custom_tlvs: vec![],
+        })
+        .await
+        .unwrap();
+        node.read().await.unwrap();
+        credentials
+    }
+
+    /// The proxy's side of the greeting, picking username/password authentication when
+    /// offered.
+    async fn socks_auth(stream: &mut TcpStream) -> Option<(String, String)> {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[0], 5);
+        let mut methods = vec![0u8; head[1] as usize];
+        stream.read_exact(&mut methods).await.unwrap();
+        if !methods.contains(&USER_PASS) {
+            assert_eq!(methods, [NO_AUTH]);
+            stream.write_all(&[5, NO_AUTH]).await.unwrap();
+            return None;
+        }
+        stream.write_all(&[5, USER_PASS]).await.unwrap();
+
+        let mut version = [0u8; 1];
+        stream.read_exact(&mut version).await.unwrap();
+        assert_eq!(version, [1]);
+        let user = field(stream).await;
+        let password = field(stream).await;
+        stream.write_all(&[1, 0]).await.unwrap();
+        Some((user, password))
+    }
+
+    async fn field(stream: &mut TcpStream) -> String {
+        let mut len = [0u8; 1];
+        stream.read_exact(&mut len).await.unwrap();
+        let mut bytes = vec![0u8; len[0] as usize];
+        stream.read_exact(&mut bytes).await.unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_race_prefers_working_path() -> Result<(), Error> {
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        const ONION: &str = "example.onion:9735";
+
+        // clearnet accepts the connection but never says a word
+        let silent = TcpListener::bind("127.0.0.1:0").await?;
+        let clearnet = silent.local_addr()?.to_string();
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let opts = RaceOptions {
+            tor_proxy: proxy.local_addr()?.to_string(),
+            ..Default::default()
+        };
+        let tor = tokio::spawn(fake_tor(proxy, ONION, node_key));
+
+        let our_key = SecretKey::new(&mut rand::thread_rng());
+        let socket = LNSocket::connect_racing(our_key, node_id, &clearnet, ONION, &opts).await?;
+        assert!(socket.peer_info().is_some());
+        assert!(tor.await.unwrap().is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_isolation() -> Result<(), Error> {
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let our_key = SecretKey::new(&mut rand::thread_rng());
+
+        let mut seen = vec![];
+        let key = Isolation::Key("alice".to_owned());
+        for isolation in [
+            Isolation::PerConnection,
+            Isolation::PerConnection,
+            key.clone(),
+            key,
+        ] {
+            let proxy = TcpListener::bind("127.0.0.1:0").await?;
+            let proxy_addr = proxy.local_addr()?.to_string();
+            let tor = tokio::spawn(fake_tor(proxy, "example.onion:9735", node_key));
+            let mut socket = LNSocket::connect_via_tor_isolated(
+                our_key,
+                node_id,
+                "example.onion:9735",
+                &proxy_addr,
+                &isolation,
+            )
+            .await?;
+            socket.perform_init().await?;
+            seen.push(tor.await.unwrap().expect("credentials"));
+        }
+        // every connection on its own circuit, unless they share a key
+        assert_ne!(seen[0], seen[1]);
+        assert_ne!(seen[1], seen[2]);
+        assert_eq!(seen[2], seen[3]);
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?.to_string();
+        let tor = tokio::spawn(fake_tor(proxy, "example.onion:9735", node_key));
+        let none = Isolation::None;
+        let addr = "example.onion:9735";
+        let mut socket =
+            LNSocket::connect_via_tor_isolated(our_key, node_id, addr, &proxy_addr, &none).await?;
+        socket.perform_init().await?;
+        assert_eq!(tor.await.unwrap(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_options_socks_proxy() -> Result<(), Error> {
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let opts = crate::connect::ConnectOptions {
+            socks_proxy: Some(proxy.local_addr()?.to_string()),
+            ..Default::default()
+        };
+        // a clearnet host name, handed to the proxy unresolved
+        let fake = tokio::spawn(fake_tor(proxy, "ln.example.com:9735", node_key));
+
+        let our_key = SecretKey::new(&mut rand::thread_rng());
+        let mut socket = LNSocket::connect_with(&opts, our_key, node_id, "ln.example.com").await?;
+        socket.perform_init().await?;
+        fake.await.unwrap();
+        Ok(())
+    }
+}
@@ -0,0 +1,607 @@
+//! Byte streams tunnelled over a Lightning connection.
+//!
+//! A [`Tunnel`] multiplexes any number of [`TunnelStream`]s over a single [`LNSocket`] using
+//! one odd custom message type, so peers that don't speak it simply ignore the messages.
+//! Each stream is an `AsyncRead + AsyncWrite` pipe, which makes it possible to run arbitrary
+//! protocols (RPC, proxies, ...) between two nodes over their existing authenticated and
+//! encrypted connection.
+//!
+//! Every stream has a receive window: a sender may only have [`WINDOW`] bytes in flight
+//! before the receiver reads them and hands back credit, so a slow reader can't be flooded.
+//! The peer may have at most [`MAX_STREAMS`] streams open, and [`ACCEPT_BACKLOG`] waiting to
+//! be accepted. Streams beyond that are reset, as are streams dropped by either side.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::tunnel::Tunnel;
+//! use tokio::io::AsyncWriteExt;
+//! # async fn example(socket: LNSocket) -> Result<(), lnsocket::Error> {
+//! let (mut tunnel, driver) = Tunnel::new(socket);
+//! tokio::spawn(driver.run());
+//!
+//! let mut stream = tunnel.open();
+//! stream.write_all(b"hello over lightning").await?;
+//! stream.shutdown().await?;
+//! # Ok(()) }
+//! ```
+
+use crate::ln::msgs::DecodeError;
+use crate::ln::wire::{Message, Type};
+use crate::util::ser::{LengthLimitedRead, Readable, Writeable, Writer};
+use crate::{Error, LNSocket};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+/// The custom message type carrying tunnel frames.
+pub const TUNNEL_MESSAGE: u16 = 0x8a6d;
+
+/// How many unread bytes a stream accepts from the other side.
+pub const WINDOW: u32 = 256 * 1024;
+
+/// How many streams opened by the peer may be open at once.
+pub const MAX_STREAMS: usize = 64;
+
+/// How many streams opened by the peer may wait for [`Tunnel::accept`].
+pub const ACCEPT_BACKLOG: usize = 16;
+
+/// Largest data payload per message, leaving room for the frame header.
+const MAX_CHUNK: usize = 65000;
+
+const KIND_DATA: u8 = 0;
+const KIND_WINDOW: u8 = 1;
+const KIND_CLOSE: u8 = 2;
+const KIND_RESET: u8 = 3;
+
+/// Streams are numbered by whoever opened them. The flag says whether the *sender* of a frame
+/// opened the stream, so both sides can number their own streams without coordinating.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct StreamKey {
+    opened_by_us: bool,
+    id: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum FrameKind {
+    Data(Vec<u8>),
+    Window(u32),
+    Close,
+    /// The stream was refused or abandoned, nothing more will be read from it.
+    Reset,
+}
+
+/// A tunnel message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TunnelFrame {
+    opened_by_sender: bool,
+    id: u64,
+    kind: FrameKind,
+}
+
+impl Writeable for TunnelFrame {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        (self.opened_by_sender as u8).write(w)?;
+        self.id.write(w)?;
+        match &self.kind {
+            FrameKind::Data(data) => {
+                KIND_DATA.write(w)?;
+                w.write_all(data)
+            }
+            FrameKind::Window(credit) => {
+                KIND_WINDOW.write(w)?;
+                credit.write(w)
+            }
+            FrameKind::Close => KIND_CLOSE.write(w),
+            FrameKind::Reset => KIND_RESET.write(w),
+        }
+    }
+}
+
+impl Type for TunnelFrame {
+    fn type_id(&self) -> u16 {
+        TUNNEL_MESSAGE
+    }
+}
+
+/// Custom message reader for [`LNSocket::read_custom`] that understands tunnel frames.
+pub fn read_tunnel_frame<R: LengthLimitedRead>(
+    typ: u16,
+    r: &mut R,
+) -> Result<Option<TunnelFrame>, DecodeError> {
+    if typ != TUNNEL_MESSAGE {
+        return Ok(None);
+    }
+    let opened_by_sender = match <u8 as Readable>::read(r)? {
+        0 => false,
+        1 => true,
+        _ => return Err(DecodeError::InvalidValue),
+    };
+    let id = Readable::read(r)?;
+    let kind = match <u8 as Readable>::read(r)? {
+        KIND_DATA => {
+            let mut data = Vec::with_capacity(r.remaining_bytes() as usize);
+            r.read_to_end(&mut data)?;
+            FrameKind::Data(data)
+        }
+        KIND_WINDOW => FrameKind::Window(Readable::read(r)?),
+        KIND_CLOSE => FrameKind::Close,
+        KIND_RESET => FrameKind::Reset,
+        _ => return Err(DecodeError::InvalidValue),
+    };
+    Ok(Some(TunnelFrame {
+        opened_by_sender,
+        id,
+        kind,
+    }))
+}
+
+/// State shared between a [`TunnelStream`] and the driver.
+#[derive(Default)]
+struct Shared {
+    /// Bytes we may still send before the peer grants more.
+    send_window: u32,
+    write_waker: Option<Waker>,
+    recv_buf: VecDeque<u8>,
+    /// Bytes read by the application that we haven't returned as credit yet.
+    unacked: u32,
+    read_waker: Option<Waker>,
+    remote_closed: bool,
+    local_closed: bool,
+    /// The connection is gone, or the peer broke the protocol.
+    broken: bool,
+}
+
+impl Shared {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+enum Command {
+    Send(StreamKey, FrameKind),
+    /// Register a stream we opened so the driver can deliver its data.
+    Register(StreamKey, Arc<Mutex<Shared>>),
+}
+
+/// Opens and accepts [`TunnelStream`]s. Created by [`Tunnel::new`].
+pub struct Tunnel {
+    commands: mpsc::UnboundedSender<Command>,
+    incoming: mpsc::Receiver<TunnelStream>,
+    next_id: u64,
+}
+
+/// Owns the connection and moves frames between it and the streams.
+///
+/// Must be polled (usually spawned) for any stream to make progress. It finishes once the
+/// [`Tunnel`] and all streams are dropped, or with an error when the connection fails.
+pub struct TunnelDriver {
+    socket: LNSocket,
+    commands: mpsc::UnboundedReceiver<Command>,
+    commands_tx: mpsc::WeakUnboundedSender<Command>,
+    incoming: mpsc::Sender<TunnelStream>,
+    streams: HashMap<StreamKey, Arc<Mutex<Shared>>>,
+    /// The peer numbers its streams in order, so lower ids are ones it opened before.
+    next_remote_id: u64,
+}
+
+impl Tunnel {
+    /// Take over `socket` for tunnelling. `init` must already have been exchanged.
+    pub fn new(socket: LNSocket) -> (Tunnel, TunnelDriver) {
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let driver = TunnelDriver {
+            socket,
+            commands,
+            commands_tx: commands_tx.downgrade(),
+            incoming: incoming_tx,
+            streams: HashMap::new(),
+            next_remote_id: 0,
+        };
+        let tunnel = Tunnel {
+            commands: commands_tx,
+            incoming,
+            next_id: 0,
+        };
+        (tunnel, driver)
+    }
+
+    /// Open a new stream. The peer learns about it when the first data arrives.
+    pub fn open(&mut self) -> TunnelStream {
+        let key = StreamKey {
+            opened_by_us: true,
+            id: self.next_id,
+        };
+        self.next_id += 1;
+        let shared = Arc::new(Mutex::new(Shared {
+            send_window: WINDOW,
+            ..Default::default()
+        }));
+        let _ = self
+            .commands
+            .send(Command::Register(key, Arc::clone(&shared)));
+        TunnelStream::new(key, shared, self.commands.clone())
+    }
+
+    /// Wait for the peer to open a stream. `None` once the driver has stopped.
+    pub async fn accept(&mut self) -> Option<TunnelStream> {
+        self.incoming.recv().await
+    }
+}
+
+impl TunnelDriver {
+    /// Run until the tunnel is no longer used or the connection fails.
+    pub async fn run(mut self) -> Result<(), Error> {
+        let res = self.run_inner().await;
+        for shared in self.streams.values() {
+            let mut shared = shared.lock().unwrap();
+            shared.broken = true;
+            shared.wake();
+        }
+        res
+    }
+
+    async fn run_inner(&mut self) -> Result<(), Error> {
+        loop {
+            // reads are cancellation safe, so losing the race to a command costs nothing
+            tokio::select! {
+                command = self.commands.recv() => match command {
+                    Some(Command::Send(key, kind)) => self.send(key, kind).await?,
+                    Some(Command::Register(key, shared)) => {
+                        self.streams.insert(key, shared);
+                    }
+                    None => return Ok(()),
+                },
+                msg = self.socket.read_custom(|typ, buf| read_tunnel_frame(typ, buf)) => {
+                    match msg? {
+                        Message::Custom(frame) => self.receive(frame).await?,
+                        Message::Ping(ping) => {
+                            if let Some(pong) = self.socket.pong_for(&ping)? {
+                                self.socket.write(&pong).await?;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, key: StreamKey, kind: FrameKind) -> Result<(), Error> {
+        match kind {
+            FrameKind::Close => {
+                if let Some(shared) = self.streams.get(&key) {
+                    shared.lock().unwrap().local_closed = true;
+                    self.forget_if_done(key);
+                }
+            }
+            // the peer reset it first, or both sides closed it already
+            FrameKind::Reset if self.streams.remove(&key).is_none() => return Ok(()),
+            _ => {}
+        }
+        self.write_frame(key, kind).await
+    }
+
+    async fn write_frame(&mut self, key: StreamKey, kind: FrameKind) -> Result<(), Error> {
+        let frame = TunnelFrame {
+            opened_by_sender: key.opened_by_us,
+            id: key.id,
+            kind,
+        };
+        self.socket.write(&frame).await
+    }
+
+    async fn receive(&mut self, frame: TunnelFrame) -> Result<(), Error> {
+        let key = StreamKey {
+            opened_by_us: !frame.opened_by_sender,
+            id: frame.id,
+        };
+
+        if !self.streams.contains_key(&key) {
+            // only the opener can start a stream, only by sending data, and only once: frames
+            // still in flight for a stream that's gone must not bring it back
+            if key.opened_by_us
+                || key.id < self.next_remote_id
+                || !matches!(frame.kind, FrameKind::Data(_))
+            {
+                return Ok(());
+            }
+            self.next_remote_id = key.id + 1;
+            let Some(commands) = self.commands_tx.upgrade() else {
+                return Ok(());
+            };
+            let open = self.streams.keys().filter(|k| !k.opened_by_us).count();
+            if open >= MAX_STREAMS {
+                return self.write_frame(key, FrameKind::Reset).await;
+            }
+            let shared = Arc::new(Mutex::new(Shared {
+                send_window: WINDOW,
+                ..Default::default()
+            }));
+            self.streams.insert(key, Arc::clone(&shared));
+            if self
+                .incoming
+                .try_send(TunnelStream::new(key, shared, commands))
+                .is_err()
+            {
+                // too many waiting to be accepted: dropping the stream resets it
+                return Ok(());
+            }
+        }
+
+        let shared = &self.streams[&key];
+        let mut state = shared.lock().unwrap();
+        match frame.kind {
+            FrameKind::Data(data) => {
+                if state.recv_buf.len() + data.len() > WINDOW as usize {
+                    // the peer ignored flow control
+                    state.broken = true;
+                } else {
+                    state.recv_buf.extend(data);
+                }
+            }
+            FrameKind::Window(credit) => {
+                state.send_window = state.send_window.saturating_add(credit);
+            }
+            FrameKind::Close => state.remote_closed = true,
+            FrameKind::Reset => {
+                state.remote_closed = true;
+                state.broken = true;
+                state.wake();
+                drop(state);
+                self.streams.remove(&key);
+                return Ok(());
+            }
+        }
+        state.wake();
+        drop(state);
+        self.forget_if_done(key);
+        Ok(())
+    }
+
+    /// Stop tracking a stream once both sides have closed it.
+    fn forget_if_done(&mut self, key: StreamKey) {
+        let done = self.streams.get(&key).is_some_and(|shared| {
+            let shared = shared.lock().unwrap();
+            shared.local_closed && shared.remote_closed
+        });
+        if done {
+            self.streams.remove(&key);
+        }
+    }
+}
+
+/// One bidirectional byte stream inside a [`Tunnel`].
+pub struct TunnelStream {
+    key: StreamKey,
+    shared: Arc<Mutex<Shared>>,
+    commands: mpsc::UnboundedSender<Command>,
+    closed: bool,
+}
+
+impl TunnelStream {
+    fn new(
+        key: StreamKey,
+        shared: Arc<Mutex<Shared>>,
+        commands: mpsc::UnboundedSender<Command>,
+    ) -> Self {
+        Self {
+            key,
+            shared,
+            commands,
+            closed: false,
+        }
+    }
+
+    fn send(&self, kind: FrameKind) -> io::Result<()> {
+        self.commands
+            .send(Command::Send(self.key, kind))
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.recv_buf.is_empty() {
+            if shared.broken {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            if shared.remote_closed {
+                return Poll::Ready(Ok(()));
+            }
+            shared.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.remaining().min(shared.recv_buf.len());
+        let (front, back) = shared.recv_buf.as_slices();
+        let from_front = n.min(front.len());
+        buf.put_slice(&front[..from_front]);
+        buf.put_slice(&back[..n - from_front]);
+        shared.recv_buf.drain(..n);
+
+        // hand back credit in batches rather than per read
+        shared.unacked += n as u32;
+        if shared.unacked >= WINDOW / 2 && !shared.remote_closed {
+            let credit = std::mem::take(&mut shared.unacked);
+            drop(shared);
+            let _ = self.send(FrameKind::Window(credit));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let mut shared = self.shared.lock().unwrap();
+        if shared.broken {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if shared.send_window == 0 {
+            shared.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(shared.send_window as usize).min(MAX_CHUNK);
+        shared.send_window -= n as u32;
+        drop(shared);
+        self.send(FrameKind::Data(buf[..n].to_vec()))?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.closed {
+            self.closed = true;
+            self.send(FrameKind::Close)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TunnelStream {
+    fn drop(&mut self) {
+        // nothing will read what the peer sends anymore, so tell it to stop and forget the
+        // stream, unless both sides are done with it already
+        let done = self.closed && self.shared.lock().unwrap().remote_closed;
+        if !done {
+            let _ = self.send(FrameKind::Reset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lnsocket::tests::socket_pair;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_tunnel_roundtrip() -> Result<(), Error> {
+        let (a, b) = socket_pair().await?;
+        let (mut a, a_driver) = Tunnel::new(a);
+        let (mut b, b_driver) = Tunnel::new(b);
+        tokio::spawn(a_driver.run());
+        tokio::spawn(b_driver.run());
+
+        // several windows worth, so the writer has to wait for credit
+        let payload: Vec<u8> = (0..3 * WINDOW).map(|i| i as u8).collect();
+
+        let mut outgoing = a.open();
+        let to_send = payload.clone();
+        let writer = tokio::spawn(async move {
+            outgoing.write_all(&to_send).await?;
+            outgoing.shutdown().await?;
+            // wait for the echo
+            let mut echoed = Vec::new();
+            outgoing.read_to_end(&mut echoed).await?;
+            Ok::<_, io::Error>(echoed)
+        });
+
+        let mut incoming = b.accept().await.expect("incoming stream");
+        let mut received = Vec::new();
+        incoming.read_to_end(&mut received).await?;
+        assert_eq!(received, payload);
+        incoming.write_all(b"thanks").await?;
+        incoming.shutdown().await?;
+
+        assert_eq!(writer.await.unwrap()?, b"thanks");
+
+        // streams opened by both sides don't collide
+        let mut from_b = b.open();
+        from_b.write_all(b"hi").await?;
+        let mut at_a = a.accept().await.expect("stream from b");
+        let mut buf = [0u8; 2];
+        at_a.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hi");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_limits() -> Result<(), Error> {
+        let (a, b) = socket_pair().await?;
+        let (mut a, a_driver) = Tunnel::new(a);
+        let (mut b, b_driver) = Tunnel::new(b);
+        tokio::spawn(a_driver.run());
+        tokio::spawn(b_driver.run());
+        let mut buf = [0u8; 1];
+
+        // b isn't accepting, so one stream more than the backlog holds is refused
+        let mut streams = Vec::new();
+        for _ in 0..=ACCEPT_BACKLOG {
+            let mut stream = a.open();
+            stream.write_all(b"x").await?;
+            streams.push(stream);
+        }
+        let mut refused = streams.pop().unwrap();
+        let err = refused.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        // dropping a stream resets it at the other end
+        drop(b.accept().await.expect("backlogged stream"));
+        let err = streams[0].read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        // once MAX_STREAMS are open, even accepted ones, further streams are refused
+        let mut accepted = Vec::new();
+        for _ in ACCEPT_BACKLOG - 1..MAX_STREAMS {
+            let mut stream = a.open();
+            stream.write_all(b"x").await?;
+            streams.push(stream);
+            accepted.push(b.accept().await.expect("stream"));
+        }
+        let mut refused = a.open();
+        refused.write_all(b"x").await?;
+        let err = refused.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        // the rest still work
+        streams[1].write_all(b"y").await?;
+        let mut got = [0u8; 2];
+        accepted[0].read_exact(&mut got).await?;
+        assert_eq!(&got, b"xy");
+        Ok(())
+    }
+
+    #[test]
+    fn test_frame_encoding() {
+        let frame = TunnelFrame {
+            opened_by_sender: true,
+            id: 9,
+            kind: FrameKind::Window(1234),
+        };
+        let mut buf = Vec::new();
+        frame.write(&mut buf).unwrap();
+        let decoded = read_tunnel_frame(TUNNEL_MESSAGE, &mut io::Cursor::new(&buf[..]));
+        assert_eq!(decoded, Ok(Some(frame)));
+
+        buf[9] = 7;
+        let decoded = read_tunnel_frame(TUNNEL_MESSAGE, &mut io::Cursor::new(&buf[..]));
+        assert_eq!(decoded, Err(DecodeError::InvalidValue));
+    }
+}
@@ -13,12 +13,13 @@
 //! [`ChannelManager`]: crate::ln::channelmanager::ChannelManager
 //! [`ChannelMonitor`]: crate::chain::channelmonitor::ChannelMonitor
 
+use crate::io::{self, Cursor, Read, Write};
 use crate::prelude::*;
 use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::{PublicKey, ecdsa};
 use core::cmp;
 use core::hash::Hash;
 use core::ops::Deref;
-use std::io::{self, Cursor, Read, Write};
 //use std::io_extras::{copy, sink};
 
 //use dnssec_prover::rr::Name;
@@ -110,6 +111,23 @@ impl<'a, R: Read> FixedLengthReader<'a, R> {
             total_bytes,
         }
     }
+
+    /// Returns whether some bytes are remaining or not.
+    #[inline]
+    pub fn bytes_remain(&mut self) -> bool {
+        self.bytes_read != self.total_bytes
+    }
+
+    /// Consumes the remaining bytes.
+    #[inline]
+    pub fn eat_remaining(&mut self) -> Result<(), DecodeError> {
+        crate::io_extras::copy(self, &mut crate::io_extras::sink()).unwrap();
+        if self.bytes_read != self.total_bytes {
+            Err(DecodeError::ShortRead)
+        } else {
+            Ok(())
+        }
+    }
 }
 impl<'a, R: Read> Read for FixedLengthReader<'a, R> {
     #[inline]
@@ -259,7 +277,10 @@ impl LengthLimitedRead for &[u8] {
 
 impl LengthLimitedRead for Cursor<&[u8]> {
     fn remaining_bytes(&self) -> u64 {
+        #[cfg(feature = "std")]
         let len = self.get_ref().len() as u64;
+        #[cfg(not(feature = "std"))]
+        let len = self.inner().len() as u64;
         let pos = self.position();
         len - pos
     }
@@ -267,7 +288,10 @@ impl LengthLimitedRead for Cursor<&[u8]> {
 
 impl LengthLimitedRead for Cursor<&Vec<u8>> {
     fn remaining_bytes(&self) -> u64 {
+        #[cfg(feature = "std")]
         let len = self.get_ref().len() as u64;
+        #[cfg(not(feature = "std"))]
+        let len = self.inner().len() as u64;
         let pos = self.position();
         len - pos
     }
@@ -651,10 +675,12 @@ macro_rules! impl_array {
     };
 }
 
+impl_array!(3, u8); // for rgb colors
 impl_array!(4, u8); // for IPv4
 impl_array!(12, u8); // for OnionV2
 impl_array!(16, u8); // for IPv6
 impl_array!(32, u8); // for channel id & hmac
+impl_array!(33, u8); // for PublicKey
 impl_array!(64, u8); // for ecdsa::Signature and schnorr::Signature
 impl_array!(66, u8); // for MuSig2 nonces
 impl_array!(1300, u8); // for OnionPacket.hop_data
@@ -707,6 +733,32 @@ impl<T: Writeable> AsWriteableSlice for &[T] {
     }
 }
 
+impl Writeable for PublicKey {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.serialize().write(w)
+    }
+}
+
+impl Readable for PublicKey {
+    fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+        let buf: [u8; 33] = Readable::read(r)?;
+        PublicKey::from_slice(&buf).map_err(|_| DecodeError::InvalidValue)
+    }
+}
+
+impl Writeable for ecdsa::Signature {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.serialize_compact().write(w)
+    }
+}
+
+impl Readable for ecdsa::Signature {
+    fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+        let buf: [u8; 64] = Readable::read(r)?;
+        ecdsa::Signature::from_compact(&buf).map_err(|_| DecodeError::InvalidValue)
+    }
+}
+
 impl Writeable for ChainHash {
     fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
         w.write_all(self.as_bytes())
@@ -996,9 +1048,7 @@ mod tests {
             } else {
                 assert_eq!(
                     super::BigSize::read(&mut stream).err(),
-                    Some(crate::ln::msgs::DecodeError::Io(
-                        io::ErrorKind::UnexpectedEof
-                    ))
+                    Some(crate::ln::msgs::DecodeError::ShortRead)
                 );
             }
         }
@@ -567,8 +567,7 @@ macro_rules! _decode_tlv_stream_range {
                     },
                     Err(e) => return Err(e),
                     Ok(t) => if core::ops::RangeBounds::contains(&$range, &t.0) { t } else {
-                        drop(tracking_reader);
-
+                        // `tracking_reader` is done with, so `stream_ref` can be used again.
                         // Assumes the type id is minimally encoded, which is enforced on read.
                         use $crate::util::ser::Writeable;
                         let bytes_read = t.serialized_length();
@@ -0,0 +1,396 @@
+//! Client side of the watchtower protocol spoken by The Eye of Satoshi (the BOLT 13 draft).
+//!
+//! A user registers with a tower to buy appointment slots, then uploads one appointment per
+//! revoked commitment: the penalty transaction encrypted with the commitment txid, tagged
+//! with a locator derived from that txid. The tower can only decrypt it once the revoked
+//! commitment shows up on chain.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::watchtower::{Appointment, TowerClient, AppointmentResponse};
+//! use bitcoin::secp256k1::{SecretKey, PublicKey, rand};
+//! # async fn example(tower: PublicKey, commitment_txid: bitcoin::Txid, penalty: bitcoin::Transaction) -> Result<(), lnsocket::Error> {
+//! let user_key = SecretKey::new(&mut rand::thread_rng());
+//! let mut sock = LNSocket::connect_and_init(user_key, tower, "tower.example.com:9814").await?;
+//!
+//! let client = TowerClient::new(user_key);
+//! let subscription = client.register(&mut sock, 10_000, 4_320).await?;
+//! let appointment = Appointment::new(&commitment_txid, &penalty, 42);
+//! match client.add_appointment(&mut sock, &appointment).await? {
+//!     AppointmentResponse::Accepted(_) => {}
+//!     AppointmentResponse::Rejected(rejected) => println!("rejected: {}", rejected.reason),
+//! }
+//! # Ok(()) }
+//! ```
+
+use crate::crypto::chacha20poly1305rfc::ChaCha20Poly1305RFC;
+use crate::ln::msgs::DecodeError;
+use crate::ln::wire::{Message, Type};
+use crate::util::ser::{LengthLimitedRead, Readable, Writeable, Writer};
+use crate::{Error, LNSocket, decode_tlv_stream, encode_tlv_stream, sign};
+use bitcoin::hashes::{Hash, sha256};
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bitcoin::{Transaction, Txid};
+use std::io;
+
+pub const REGISTER: u16 = 48848;
+pub const SUBSCRIPTION_DETAILS: u16 = 48850;
+pub const ADD_UPDATE_APPOINTMENT: u16 = 48852;
+pub const APPOINTMENT_ACCEPTED: u16 = 48854;
+pub const APPOINTMENT_REJECTED: u16 = 48856;
+
+/// Identifies an appointment to the tower: the first half of the commitment txid.
+pub type Locator = [u8; 16];
+
+/// Ask the tower for (more) appointment slots.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Register {
+    pub user_id: PublicKey,
+    pub appointment_slots: u32,
+    /// In blocks.
+    pub subscription_period: u32,
+}
+
+/// The tower's answer to [`Register`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubscriptionDetails {
+    /// Largest `encrypted_blob` the tower accepts.
+    pub appointment_max_size: u16,
+    /// What the subscription costs.
+    pub amount_msat: u32,
+    /// The tower's signature over the registration receipt.
+    pub signature: Option<String>,
+}
+
+/// Upload (or update) an appointment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddUpdateAppointment {
+    pub locator: Locator,
+    pub encrypted_blob: Vec<u8>,
+    /// The user's signature over the appointment.
+    pub signature: String,
+    pub to_self_delay: Option<u64>,
+}
+
+/// The tower stored the appointment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppointmentAccepted {
+    pub locator: Locator,
+    /// The block height the tower started watching at.
+    pub start_block: u32,
+    /// The tower's signature over the appointment receipt.
+    pub receipt_signature: Option<String>,
+}
+
+/// The tower refused the appointment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppointmentRejected {
+    pub locator: Locator,
+    pub rcode: u16,
+    pub reason: String,
+}
+
+/// Messages a tower sends to its users.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TowerMessage {
+    SubscriptionDetails(SubscriptionDetails),
+    AppointmentAccepted(AppointmentAccepted),
+    AppointmentRejected(AppointmentRejected),
+}
+
+/// What became of an appointment we sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AppointmentResponse {
+    Accepted(AppointmentAccepted),
+    Rejected(AppointmentRejected),
+}
+
+impl Writeable for Register {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        w.write_all(&self.user_id.serialize())?;
+        self.appointment_slots.write(w)?;
+        self.subscription_period.write(w)
+    }
+}
+
+impl Writeable for AddUpdateAppointment {
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), io::Error> {
+        self.locator.write(w)?;
+        self.encrypted_blob.write(w)?;
+        self.signature.write(w)?;
+        encode_tlv_stream!(w, {
+            (1, self.to_self_delay, option),
+        });
+        Ok(())
+    }
+}
+
+impl Type for Register {
+    fn type_id(&self) -> u16 {
+        REGISTER
+    }
+}
+
+impl Type for AddUpdateAppointment {
+    fn type_id(&self) -> u16 {
+        ADD_UPDATE_APPOINTMENT
+    }
+}
+
+impl Type for TowerMessage {
+    fn type_id(&self) -> u16 {
+        match self {
+            TowerMessage::SubscriptionDetails(_) => SUBSCRIPTION_DETAILS,
+            TowerMessage::AppointmentAccepted(_) => APPOINTMENT_ACCEPTED,
+            TowerMessage::AppointmentRejected(_) => APPOINTMENT_REJECTED,
+        }
+    }
+}
+
+/// Custom message reader for [`LNSocket::read_custom`] that understands tower replies.
+pub fn read_tower_message<R: LengthLimitedRead>(
+    typ: u16,
+    r: &mut R,
+) -> Result<Option<TowerMessage>, DecodeError> {
+    match typ {
+        SUBSCRIPTION_DETAILS => {
+            let appointment_max_size = Readable::read(r)?;
+            let amount_msat = Readable::read(r)?;
+            let mut signature: Option<String> = None;
+            decode_tlv_stream!(r, {
+                (1, signature, option),
+            });
+            Ok(Some(TowerMessage::SubscriptionDetails(
+                SubscriptionDetails {
+                    appointment_max_size,
+                    amount_msat,
+                    signature,
+                },
+            )))
+        }
+        APPOINTMENT_ACCEPTED => {
+            let locator = Readable::read(r)?;
+            let start_block = Readable::read(r)?;
+            let mut receipt_signature: Option<String> = None;
+            decode_tlv_stream!(r, {
+                (1, receipt_signature, option),
+            });
+            Ok(Some(TowerMessage::AppointmentAccepted(
+                AppointmentAccepted {
+                    locator,
+                    start_block,
+                    receipt_signature,
+                },
+            )))
+        }
+        APPOINTMENT_REJECTED => Ok(Some(TowerMessage::AppointmentRejected(
+            AppointmentRejected {
+                locator: Readable::read(r)?,
+                rcode: Readable::read(r)?,
+                reason: Readable::read(r)?,
+            },
+        ))),
+        _ => Ok(None),
+    }
+}
+
+/// An appointment ready to be sent to a tower.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Appointment {
+    pub locator: Locator,
+    pub encrypted_blob: Vec<u8>,
+    pub to_self_delay: u32,
+}
+
+impl Appointment {
+    /// Build the appointment for a penalty transaction spending the revoked commitment
+    /// `commitment_txid`.
+    ///
+    /// The blob is `penalty_tx` encrypted with ChaCha20-Poly1305, keyed by the SHA256 of the
+    /// commitment txid and an all-zero nonce.
+    pub fn new(commitment_txid: &Txid, penalty_tx: &Transaction, to_self_delay: u32) -> Self {
+        let txid = commitment_txid.as_byte_array();
+        let mut locator = [0u8; 16];
+        locator.copy_from_slice(&txid[..16]);
+
+        let key = sha256::Hash::hash(txid);
+        let plaintext = bitcoin::consensus::serialize(penalty_tx);
+        let mut encrypted_blob = vec![0u8; plaintext.len() + 16];
+        let (ciphertext, tag) = encrypted_blob.split_at_mut(plaintext.len());
+        ChaCha20Poly1305RFC::new(key.as_byte_array(), &[0; 12], &[])
+            .encrypt(&plaintext, ciphertext, tag);
+
+        Self {
+            locator,
+            encrypted_blob,
+            to_self_delay,
+        }
+    }
+
+    /// The bytes the user signs: locator, blob and `to_self_delay`.
+    fn signing_bytes(&self) -> Vec<u8> {
+        [
+            &self.locator[..],
+            &self.encrypted_blob,
+            &self.to_self_delay.to_be_bytes(),
+        ]
+        .concat()
+    }
+}
+
+/// Registers with towers and uploads appointments over an [`LNSocket`].
+///
+/// The user id is the public key of `user_key`, which is usually also the key the connection
+/// was made with.
+pub struct TowerClient {
+    user_key: SecretKey,
+}
+
+impl TowerClient {
+    pub fn new(user_key: SecretKey) -> Self {
+        Self { user_key }
+    }
+
+    /// Our user id as the tower knows it.
+    pub fn user_id(&self) -> PublicKey {
+        self.user_key.public_key(&Secp256k1::signing_only())
+    }
+
+    /// Register (or top up) a subscription.
+    pub async fn register(
+        &self,
+        socket: &mut LNSocket,
+        appointment_slots: u32,
+        subscription_period: u32,
+    ) -> Result<SubscriptionDetails, Error> {
+        let register = Register {
+            user_id: self.user_id(),
+            appointment_slots,
+            subscription_period,
+        };
+        socket.write(&register).await?;
+
+        loop {
+            if let TowerMessage::SubscriptionDetails(details) = Self::read(socket).await? {
+                return Ok(details);
+            }
+        }
+    }
+
+    /// Sign and upload an appointment.
+    pub async fn add_appointment(
+        &self,
+        socket: &mut LNSocket,
+        appointment: &Appointment,
+    ) -> Result<AppointmentResponse, Error> {
+        let msg = AddUpdateAppointment {
+            locator: appointment.locator,
+            encrypted_blob: appointment.encrypted_blob.clone(),
+            signature: sign::sign(&appointment.signing_bytes(), &self.user_key),
+            to_self_delay: Some(appointment.to_self_delay as u64),
+        };
+        socket.write(&msg).await?;
+
+        loop {
+            match Self::read(socket).await? {
+                TowerMessage::AppointmentAccepted(accepted)
+                    if accepted.locator == appointment.locator =>
+                {
+                    return Ok(AppointmentResponse::Accepted(accepted));
+                }
+                TowerMessage::AppointmentRejected(rejected)
+                    if rejected.locator == appointment.locator =>
+                {
+                    return Ok(AppointmentResponse::Rejected(rejected));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Wait for the next tower message, answering pings on the way. Towers report bad
+    /// requests with a BOLT 1 `error`, which ends the wait with [`Error::RemoteError`].
+    async fn read(socket: &mut LNSocket) -> Result<TowerMessage, Error> {
+        loop {
+            match socket
+                .read_custom(|typ, buf| read_tower_message(typ, buf))
+                .await?
+            {
+                Message::Custom(msg) => return Ok(msg),
+                Message::Ping(ping) => {
+                    if let Some(pong) = socket.pong_for(&ping)? {
+                        socket.write(&pong).await?;
+                    }
+                }
+                Message::Error(err) => return Err(Error::RemoteError(err.data)),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ln::msgs::DecodeError;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_tower_messages() {
+        let mut buf = vec![];
+        [7u8; 16].write(&mut buf).unwrap();
+        800_000u32.write(&mut buf).unwrap();
+        // receipt_signature tlv
+        buf.extend_from_slice(&[1, 5, 0, 3]);
+        buf.extend_from_slice(b"sig");
+
+        let msg = read_tower_message(APPOINTMENT_ACCEPTED, &mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(
+            msg,
+            Some(TowerMessage::AppointmentAccepted(AppointmentAccepted {
+                locator: [7; 16],
+                start_block: 800_000,
+                receipt_signature: Some("sig".into()),
+            }))
+        );
+
+        let mut buf = vec![];
+        [7u8; 16].write(&mut buf).unwrap();
+        2u16.write(&mut buf).unwrap();
+        "too big".to_string().write(&mut buf).unwrap();
+        let msg = read_tower_message(APPOINTMENT_REJECTED, &mut Cursor::new(&buf[..])).unwrap();
+        assert!(matches!(
+            msg,
+            Some(TowerMessage::AppointmentRejected(r)) if r.rcode == 2 && r.reason == "too big"
+        ));
+
+        let res = read_tower_message(SUBSCRIPTION_DETAILS, &mut Cursor::new(&[0u8][..]));
+        assert_eq!(res, Err(DecodeError::ShortRead));
+        assert_eq!(read_tower_message(1, &mut Cursor::new(&[][..])), Ok(None));
+    }
+
+    #[test]
+    fn test_appointment_decrypts() {
+        let txid = Txid::from_byte_array([3; 32]);
+        let penalty = Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        };
+        let appointment = Appointment::new(&txid, &penalty, 42);
+        assert_eq!(appointment.locator, [3; 16]);
+
+        let key = sha256::Hash::hash(txid.as_byte_array());
+        let mut blob = appointment.encrypted_blob.clone();
+        let (ciphertext, tag) = blob.split_at_mut(appointment.encrypted_blob.len() - 16);
+        let mut plaintext = vec![0u8; ciphertext.len()];
+        assert!(
+            ChaCha20Poly1305RFC::new(key.as_byte_array(), &[0; 12], &[])
+                .variable_time_decrypt(ciphertext, &mut plaintext, tag)
+                .is_ok()
+        );
+        assert_eq!(plaintext, bitcoin::consensus::serialize(&penalty));
+    }
+}
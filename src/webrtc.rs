@@ -0,0 +1,253 @@
+//! Connecting over a WebRTC data channel.
+//!
+//! WebRTC punches through most NATs, so two endpoints without public addresses can talk
+//! directly once they have swapped session descriptions through some other channel (a chat
+//! message, a QR code, a Nostr DM). The data channel is already encrypted with DTLS, but the
+//! Noise handshake on top is what authenticates the Lightning node.
+//!
+//! Only available with the `webrtc` feature.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::webrtc::{RtcConfig, RtcOffer};
+//! # use bitcoin::secp256k1::{PublicKey, SecretKey};
+//! # async fn send_to_peer(_: &str) -> String { unimplemented!() }
+//! # async fn example(key: SecretKey, node: PublicKey) -> Result<(), lnsocket::Error> {
+//! let (offer, sdp) = RtcOffer::new(&RtcConfig::default()).await?;
+//! let answer = send_to_peer(&sdp).await;
+//! let stream = offer.connect(&answer).await?;
+//! let mut socket = LNSocket::connect_webrtc(key, node, stream).await?;
+//! socket.perform_init().await?;
+//! # Ok(()) }
+//! ```
+
+use crate::{Error, LNSocket};
+use ::webrtc::api::APIBuilder;
+use ::webrtc::api::setting_engine::SettingEngine;
+use ::webrtc::data::data_channel::PollDataChannel;
+use ::webrtc::data_channel::RTCDataChannel;
+use ::webrtc::ice_transport::ice_server::RTCIceServer;
+use ::webrtc::peer_connection::RTCPeerConnection;
+use ::webrtc::peer_connection::configuration::RTCConfiguration;
+use ::webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::oneshot;
+
+const CHANNEL_LABEL: &str = "lnsocket";
+
+/// Settings for setting up a WebRTC connection.
+#[derive(Clone, Debug)]
+pub struct RtcConfig {
+    /// STUN/TURN server URLs used to find a path between the peers. A public STUN server by
+    /// default; peers behind symmetric NATs also need a TURN server.
+    pub ice_servers: Vec<String>,
+}
+
+impl Default for RtcConfig {
+    fn default() -> Self {
+        Self {
+            ice_servers: vec!["stun:stun.l.google.com:19302".to_owned()],
+        }
+    }
+}
+
+fn rtc_error<E>(_: E) -> Error {
+    Error::Io(io::ErrorKind::ConnectionAborted)
+}
+
+async fn peer_connection(config: &RtcConfig) -> Result<Arc<RTCPeerConnection>, Error> {
+    // detached channels give us plain reads and writes instead of message callbacks
+    let mut settings = SettingEngine::default();
+    settings.detach_data_channels();
+    let api = APIBuilder::new().with_setting_engine(settings).build();
+    let pc = api
+        .new_peer_connection(RTCConfiguration {
+            ice_servers: vec![RTCIceServer {
+                urls: config.ice_servers.clone(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+        .await
+        .map_err(rtc_error)?;
+    Ok(Arc::new(pc))
+}
+
+/// Our session description once all ICE candidates are in, serialized for the other side.
+async fn local_description(pc: &RTCPeerConnection) -> Result<String, Error> {
+    // wait for candidate gathering so the peer doesn't need trickle ICE
+    let mut gathered = pc.gathering_complete_promise().await;
+    let _ = gathered.recv().await;
+    let desc = pc.local_description().await.ok_or(Error::NotConnected)?;
+    Ok(serde_json::to_string(&desc)?)
+}
+
+/// Send the channel over `tx` once it opens.
+fn on_open(channel: Arc<RTCDataChannel>, tx: oneshot::Sender<Result<WebRtcStream, Error>>) {
+    let opened = channel.clone();
+    channel.on_open(Box::new(move || {
+        Box::pin(async move {
+            let res = opened
+                .detach()
+                .await
+                .map_err(rtc_error)
+                .map(|raw| WebRtcStream {
+                    channel: PollDataChannel::new(raw),
+                    pc: None,
+                });
+            let _ = tx.send(res);
+        })
+    }));
+}
+
+async fn wait_open(
+    pc: Arc<RTCPeerConnection>,
+    rx: oneshot::Receiver<Result<WebRtcStream, Error>>,
+) -> Result<WebRtcStream, Error> {
+    let mut stream = rx.await.map_err(|_| Error::NotConnected)??;
+    stream.pc = Some(pc);
+    Ok(stream)
+}
+
+/// The offering side of a connection, waiting for the peer's answer.
+pub struct RtcOffer {
+    pc: Arc<RTCPeerConnection>,
+    opened: oneshot::Receiver<Result<WebRtcStream, Error>>,
+}
+
+impl RtcOffer {
+    /// Start a connection. Returns the offer to hand to the other side.
+    pub async fn new(config: &RtcConfig) -> Result<(RtcOffer, String), Error> {
+        let pc = peer_connection(config).await?;
+        let channel = pc
+            .create_data_channel(CHANNEL_LABEL, None)
+            .await
+            .map_err(rtc_error)?;
+        let (tx, opened) = oneshot::channel();
+        on_open(channel, tx);
+
+        let offer = pc.create_offer(None).await.map_err(rtc_error)?;
+        pc.set_local_description(offer).await.map_err(rtc_error)?;
+        let sdp = local_description(&pc).await?;
+        Ok((RtcOffer { pc, opened }, sdp))
+    }
+
+    /// Finish with the answer from the other side, once the data channel is open.
+    pub async fn connect(self, answer: &str) -> Result<WebRtcStream, Error> {
+        let answer: RTCSessionDescription = serde_json::from_str(answer)?;
+        self.pc
+            .set_remote_description(answer)
+            .await
+            .map_err(rtc_error)?;
+        wait_open(self.pc, self.opened).await
+    }
+}
+
+/// The answering side of a connection.
+pub struct RtcAnswer {
+    pc: Arc<RTCPeerConnection>,
+    opened: oneshot::Receiver<Result<WebRtcStream, Error>>,
+}
+
+impl RtcAnswer {
+    /// Accept an offer from the other side. Returns the answer to send back.
+    pub async fn new(config: &RtcConfig, offer: &str) -> Result<(RtcAnswer, String), Error> {
+        let pc = peer_connection(config).await?;
+        let (tx, opened) = oneshot::channel();
+        let mut tx = Some(tx);
+        pc.on_data_channel(Box::new(move |channel| {
+            if let Some(tx) = tx.take() {
+                on_open(channel, tx);
+            }
+            Box::pin(async {})
+        }));
+
+        let offer: RTCSessionDescription = serde_json::from_str(offer)?;
+        pc.set_remote_description(offer).await.map_err(rtc_error)?;
+        let answer = pc.create_answer(None).await.map_err(rtc_error)?;
+        pc.set_local_description(answer).await.map_err(rtc_error)?;
+        let sdp = local_description(&pc).await?;
+        Ok((RtcAnswer { pc, opened }, sdp))
+    }
+
+    /// Wait for the offering side to open the data channel.
+    pub async fn connect(self) -> Result<WebRtcStream, Error> {
+        wait_open(self.pc, self.opened).await
+    }
+}
+
+/// An open data channel, usable as a byte stream.
+pub struct WebRtcStream {
+    channel: PollDataChannel,
+    // keeps the connection alive as long as the stream is
+    pc: Option<Arc<RTCPeerConnection>>,
+}
+
+impl AsyncRead for WebRtcStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.channel).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WebRtcStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.channel).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.channel).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.channel).poll_shutdown(cx)
+    }
+}
+
+impl LNSocket {
+    /// Like [`LNSocket::connect`], but over an open WebRTC data channel.
+    pub async fn connect_webrtc(
+        our_key: SecretKey,
+        their_pubkey: PublicKey,
+        stream: WebRtcStream,
+    ) -> Result<LNSocket, Error> {
+        Self::handshake_outbound(stream, our_key, their_pubkey).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_loopback_channel() -> Result<(), Error> {
+        // host candidates are enough on one machine
+        let config = RtcConfig {
+            ice_servers: vec![],
+        };
+        let (offer, offer_sdp) = RtcOffer::new(&config).await?;
+        let (answer, answer_sdp) = RtcAnswer::new(&config, &offer_sdp).await?;
+        let (a, b) = tokio::join!(offer.connect(&answer_sdp), answer.connect());
+        let (mut a, mut b) = (a?, b?);
+
+        a.write_all(b"over the nat").await?;
+        let mut buf = [0u8; 12];
+        b.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"over the nat");
+        Ok(())
+    }
+}
@@ -0,0 +1,383 @@
+//! BOLT 8 over WebSockets.
+//!
+//! Some nodes accept WebSocket connections besides TCP, e.g. Core Lightning with
+//! `experimental-websocket-port`, or nodes behind a reverse proxy that only passes WebSockets.
+//! The Noise handshake and messages are the same, carried in binary WebSocket messages.
+//! Such nodes announce a [`SocketAddress::WebSocket`] port, which [`websocket_urls`] turns
+//! into URLs using the hosts of their other addresses.
+//!
+//! With the `experimental` feature, `LNSocket::connect` takes a `ws://` or `wss://` URL for
+//! `addr` and connects over a WebSocket instead of TCP. `wss://` needs the `tls` feature, which
+//! uses rustls and trusts the bundled Mozilla roots; `LNSocket::connect_websocket_with` takes
+//! [`TlsOptions`] for private CAs or a different SNI name, e.g. for nodes behind a
+//! TLS-terminating proxy.
+//!
+//! ### Example
+//! ```no_run
+//! use lnsocket::LNSocket;
+//! use lnsocket::websocket::websocket_urls;
+//! use bitcoin::secp256k1::{PublicKey, SecretKey, rand};
+//! # async fn example(node_id: PublicKey, book: &lnsocket::gossip::AddressBook) -> Result<(), lnsocket::Error> {
+//! let key = SecretKey::new(&mut rand::thread_rng());
+//! let addrs = book.addresses_for(&node_id).unwrap_or_default();
+//! for url in websocket_urls(addrs) {
+//!     if let Ok(mut socket) = LNSocket::connect(key, node_id, &url).await {
+//!         socket.perform_init().await?;
+//!         break;
+//!     }
+//! }
+//! # Ok(()) }
+//! ```
+
+use crate::SocketAddress;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// The `ws://` URLs a node's announced `addresses` offer: its [`SocketAddress::WebSocket`]
+/// port on each of its IP and hostname addresses. Empty if it announced no WebSocket port.
+pub fn websocket_urls(addresses: &[SocketAddress]) -> Vec<String> {
+    let ports = addresses.iter().filter_map(|addr| match addr {
+        SocketAddress::WebSocket { port } => Some(*port),
+        _ => None,
+    });
+    let mut urls = Vec::new();
+    for port in ports {
+        for addr in addresses {
+            let host = match addr {
+                SocketAddress::TcpIpV4 { addr, .. } => Ipv4Addr::from(*addr).to_string(),
+                SocketAddress::TcpIpV6 { addr, .. } => format!("[{}]", Ipv6Addr::from(*addr)),
+                SocketAddress::Hostname { hostname, .. } => hostname.to_string(),
+                _ => continue,
+            };
+            urls.push(format!("ws://{host}:{port}"));
+        }
+    }
+    urls
+}
+
+/// Whether `addr` is a WebSocket URL rather than `host[:port]`.
+#[cfg(feature = "tokio")]
+pub(crate) fn is_websocket_url(addr: &str) -> bool {
+    addr.starts_with("ws://") || addr.starts_with("wss://")
+}
+
+/// How `wss://` connections set up TLS. Only used with the `tls` feature.
+#[derive(Clone, Debug)]
+pub struct TlsOptions {
+    /// Trust the Mozilla root certificates bundled with the crate. True by default.
+    pub webpki_roots: bool,
+    /// More root certificates to trust, DER encoded, e.g. a private CA in front of a node.
+    pub extra_roots: Vec<Vec<u8>>,
+    /// The name sent with SNI and checked against the certificate, instead of the URL's
+    /// host. For proxies serving the node under a different name than the one dialed.
+    pub server_name: Option<String>,
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self {
+            webpki_roots: true,
+            extra_roots: Vec::new(),
+            server_name: None,
+        }
+    }
+}
+
+#[cfg(feature = "experimental")]
+mod stream {
+    use super::TlsOptions;
+    use crate::lnsocket::Transport;
+    use crate::{Error, LNSocket};
+    use bitcoin::secp256k1::{PublicKey, SecretKey};
+    use futures_util::{Sink, Stream};
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, ready};
+    use std::time::Instant;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::WebSocketStream;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::{self, Message as WsMessage};
+
+    fn ws_io_error(err: tungstenite::Error) -> io::Error {
+        io::Error::other(err)
+    }
+
+    /// A WebSocket carrying a byte stream in binary messages, as Core Lightning and
+    /// WebSocket-to-TCP proxies expect. Text, ping and pong messages are skipped.
+    pub struct WsStream<S = Box<dyn Transport>> {
+        ws: WebSocketStream<S>,
+        // the rest of a binary message a read didn't have room for
+        buffered: Vec<u8>,
+        pos: usize,
+    }
+
+    impl<S> std::fmt::Debug for WsStream<S> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("WsStream")
+                .field("buffered", &(self.buffered.len() - self.pos))
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl<S> WsStream<S> {
+        pub fn new(ws: WebSocketStream<S>) -> Self {
+            Self {
+                ws,
+                buffered: Vec::new(),
+                pos: 0,
+            }
+        }
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = &mut *self;
+            while this.pos == this.buffered.len() {
+                match ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                    Some(Ok(WsMessage::Binary(data))) => {
+                        this.buffered = data.into();
+                        this.pos = 0;
+                    }
+                    Some(Ok(WsMessage::Close(_))) | None => return Poll::Ready(Ok(())),
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => return Poll::Ready(Err(ws_io_error(err))),
+                }
+            }
+            let n = buf.remaining().min(this.buffered.len() - this.pos);
+            buf.put_slice(&this.buffered[this.pos..this.pos + n]);
+            this.pos += n;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let ws = Pin::new(&mut self.ws);
+            ready!(ws.poll_ready(cx)).map_err(ws_io_error)?;
+            Pin::new(&mut self.ws)
+                .start_send(WsMessage::binary(buf.to_vec()))
+                .map_err(ws_io_error)?;
+            // callers don't flush plain sockets, so push the frame out now. if that can't
+            // finish, the frame stays queued and the next read or write sends it
+            if let Poll::Ready(Err(err)) = Pin::new(&mut self.ws).poll_flush(cx) {
+                return Poll::Ready(Err(ws_io_error(err)));
+            }
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.ws).poll_flush(cx).map_err(ws_io_error)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.ws).poll_close(cx).map_err(ws_io_error)
+        }
+    }
+
+    /// Open a WebSocket to `url`, with TLS as `tls` says for `wss://`.
+    pub async fn open(url: &str, tls: &TlsOptions) -> Result<WsStream, Error> {
+        let request = url
+            .into_client_request()
+            .map_err(|_| Error::InvalidUri(url.to_owned()))?;
+        let uri = request.uri();
+        let secure = uri.scheme_str() == Some("wss");
+        let host = uri
+            .host()
+            .ok_or_else(|| Error::InvalidUri(url.to_owned()))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+
+        let tcp = TcpStream::connect((host.as_str(), port)).await?;
+        let stream: Box<dyn Transport> = if secure {
+            #[cfg(feature = "tls")]
+            {
+                Box::new(super::tls::connect(tcp, &host, tls).await?)
+            }
+            #[cfg(not(feature = "tls"))]
+            {
+                let _ = (tcp, tls);
+                return Err(Error::Tls("wss:// needs the tls feature".to_owned()));
+            }
+        } else {
+            Box::new(tcp)
+        };
+        let (ws, _) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .map_err(ws_io_error)?;
+        Ok(WsStream::new(ws))
+    }
+
+    impl LNSocket {
+        /// Like [`LNSocket::connect`], over a WebSocket to `url` (`ws://` or `wss://`).
+        /// [`LNSocket::connect`] calls this for such URLs.
+        pub async fn connect_websocket(
+            our_key: SecretKey,
+            their_pubkey: PublicKey,
+            url: &str,
+        ) -> Result<LNSocket, Error> {
+            Self::connect_websocket_with(&TlsOptions::default(), our_key, their_pubkey, url).await
+        }
+
+        /// Like [`LNSocket::connect_websocket`], with control over TLS for `wss://` URLs.
+        pub async fn connect_websocket_with(
+            tls: &TlsOptions,
+            our_key: SecretKey,
+            their_pubkey: PublicKey,
+            url: &str,
+        ) -> Result<LNSocket, Error> {
+            let start = Instant::now();
+            let stream = open(url, tls).await?;
+            let tcp_connect = start.elapsed();
+            let mut socket = Self::handshake_outbound(stream, our_key, their_pubkey).await?;
+            socket.timings.tcp_connect = Some(tcp_connect);
+            Ok(socket)
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+pub(crate) mod tls {
+    use super::TlsOptions;
+    use crate::Error;
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::{
+        Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName,
+    };
+
+    fn tls_error(err: impl std::fmt::Display) -> Error {
+        Error::Tls(err.to_string())
+    }
+
+    /// Do the TLS handshake over `tcp` with the server `host`.
+    pub(crate) async fn connect(
+        tcp: TcpStream,
+        host: &str,
+        opts: &TlsOptions,
+    ) -> Result<TlsStream<TcpStream>, Error> {
+        let mut roots = RootCertStore::empty();
+        if opts.webpki_roots {
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+        }
+        for der in &opts.extra_roots {
+            roots.add(&Certificate(der.clone())).map_err(tls_error)?;
+        }
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let name = opts.server_name.as_deref().unwrap_or(host);
+        let name = ServerName::try_from(name).map_err(tls_error)?;
+        TlsConnector::from(Arc::new(config))
+            .connect(name, tcp)
+            .await
+            .map_err(tls_error)
+    }
+}
+
+#[cfg(feature = "experimental")]
+pub use stream::{WsStream, open};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_websocket_urls() {
+        let addrs = [
+            SocketAddress::TcpIpV4 {
+                addr: [10, 0, 0, 1],
+                port: 9735,
+            },
+            SocketAddress::TcpIpV6 {
+                addr: Ipv6Addr::LOCALHOST.octets(),
+                port: 9735,
+            },
+            SocketAddress::WebSocket { port: 9736 },
+        ];
+        assert_eq!(
+            websocket_urls(&addrs),
+            ["ws://10.0.0.1:9736", "ws://[::1]:9736"]
+        );
+        assert!(websocket_urls(&addrs[..2]).is_empty());
+    }
+
+    #[cfg(feature = "experimental")]
+    #[tokio::test]
+    async fn test_connect_websocket() -> Result<(), crate::Error> {
+        use crate::LNSocket;
+        use crate::ln::wire::Message;
+        use crate::testing::default_init;
+        use bitcoin::secp256k1::{Secp256k1, SecretKey, rand};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let node = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await?;
+            let ws = tokio_tungstenite::accept_async(tcp)
+                .await
+                .map_err(ws_io_error)?;
+            let mut node = LNSocket::handshake_inbound(WsStream::new(ws), node_key).await?;
+            node.write(&default_init()).await?;
+            assert!(matches!(node.read().await?, Message::Init(_)));
+            Ok::<_, crate::Error>(())
+        });
+
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let url = format!("ws://127.0.0.1:{port}");
+        let mut socket = LNSocket::connect(key, node_id, &url).await?;
+        socket.perform_init().await?;
+        node.await.unwrap()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_tls_options() -> Result<(), crate::Error> {
+        use crate::Error;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("wss://127.0.0.1:{}", listener.local_addr()?.port());
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                drop(tcp);
+            }
+        });
+
+        let bad_root = TlsOptions {
+            extra_roots: vec![vec![1, 2, 3]],
+            ..TlsOptions::default()
+        };
+        assert!(matches!(open(&url, &bad_root).await, Err(Error::Tls(_))));
+        let bad_name = TlsOptions {
+            server_name: Some("not a name".into()),
+            ..TlsOptions::default()
+        };
+        assert!(matches!(open(&url, &bad_name).await, Err(Error::Tls(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,388 @@
+//! Reaching Lightning peers through WebSocket-to-TCP proxies.
+//!
+//! Browsers can't open TCP connections, so web clients reach `host:9735` through a proxy that
+//! relays a WebSocket to the peer's TCP port. [`ProxyPool`] keeps a list of such proxies as
+//! URL templates, tries the healthiest one first for each connection, and fails over to the
+//! next when one doesn't answer, so an app isn't tied to a single proxy going down.
+//!
+//! A template has a `{host}` and optionally a `{port}` placeholder, e.g.
+//! `wss://proxy.example.com/{host}:{port}`. Without `{port}`, the peer's port is appended to
+//! the host as `host:port`.
+//!
+//! No proxies are built in. A proxy sees which nodes its users connect to, so which ones to
+//! trust is the application's call; [`ProxyPool::candidates`] gives it the URLs in the order
+//! to try, also for apps that open the WebSocket themselves (e.g. with the browser's API).
+//!
+//! Connecting with `ProxyPool::connect` or `LNSocket::connect_ws` needs the `experimental`
+//! feature, and `wss://` proxies the `tls` feature, configured with [`ProxyOptions::tls`].
+//!
+//! ### Example
+//! ```ignore
+//! use lnsocket::LNSocket;
+//! use lnsocket::ws_proxy::ProxyPool;
+//!
+//! let pool = ProxyPool::new(&[
+//!     "wss://proxy-a.example.com/{host}:{port}",
+//!     "wss://proxy-b.example.com/connect/{host}",
+//! ])?;
+//! // a dead proxy is skipped, and put last for a while
+//! let mut socket = LNSocket::connect_ws(&pool, our_key, node_id, "ln.example.com:9735").await?;
+//! socket.perform_init().await?;
+//! ```
+
+use crate::Error;
+use crate::socket_addr::split_host_port;
+use crate::websocket::{TlsOptions, is_websocket_url};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Settings for a [`ProxyPool`].
+#[derive(Clone, Debug)]
+pub struct ProxyOptions {
+    /// How long to wait for a proxy to open the WebSocket before trying the next. 10 seconds
+    /// by default.
+    pub connect_timeout: Duration,
+    /// How long a proxy that failed is tried last. Doubles with every failure in a row, up
+    /// to 32 times. 30 seconds by default.
+    pub retry_after: Duration,
+    /// How to set up TLS with `wss://` proxies.
+    pub tls: TlsOptions,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            retry_after: Duration::from_secs(30),
+            tls: TlsOptions::default(),
+        }
+    }
+}
+
+/// A proxy URL for one peer, from [`ProxyPool::candidates`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    /// The template it was made from, for reporting back with [`ProxyPool::mark_up`] and
+    /// [`ProxyPool::mark_down`].
+    pub template: String,
+    pub url: String,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Health {
+    // failures in a row
+    failures: u32,
+    down_until: Option<Instant>,
+    latency: Option<Duration>,
+}
+
+/// A list of WebSocket-to-TCP proxies and how well each has been working. See the
+/// [module docs](self).
+///
+/// Share one pool between connections, e.g. in an `Arc`, so they all learn which proxies are
+/// down.
+#[derive(Debug)]
+pub struct ProxyPool {
+    opts: ProxyOptions,
+    templates: Vec<String>,
+    health: Mutex<Vec<Health>>,
+}
+
+impl ProxyPool {
+    /// A pool of the proxies in `templates`, in order of preference while none has failed.
+    pub fn new(templates: &[&str]) -> Result<Self, Error> {
+        Self::new_with(ProxyOptions::default(), templates)
+    }
+
+    /// Like [`ProxyPool::new`], with control over timeouts and retries.
+    pub fn new_with(opts: ProxyOptions, templates: &[&str]) -> Result<Self, Error> {
+        for template in templates {
+            check_template(template)?;
+        }
+        Ok(Self {
+            opts,
+            templates: templates.iter().map(|t| t.to_string()).collect(),
+            health: Mutex::new(vec![Health::default(); templates.len()]),
+        })
+    }
+
+    pub fn options(&self) -> &ProxyOptions {
+        &self.opts
+    }
+
+    /// The proxy URLs for reaching `addr` (`host[:port]`), in the order to try them: proxies
+    /// that are up first, fewest recent failures and then lowest latency leading, and those
+    /// marked down last, soonest to be retried first.
+    pub fn candidates(&self, addr: &str) -> Result<Vec<Candidate>, Error> {
+        let (host, port) = split_host_port(addr)?;
+        let now = Instant::now();
+        let health = self.health.lock().unwrap();
+        let mut order: Vec<usize> = (0..self.templates.len()).collect();
+        order.sort_by_key(|&i| {
+            let h = &health[i];
+            let down_until = h.down_until.filter(|until| *until > now);
+            (
+                down_until.is_some(),
+                down_until,
+                h.failures,
+                h.latency.unwrap_or(Duration::MAX),
+            )
+        });
+        Ok(order
+            .into_iter()
+            .map(|i| Candidate {
+                template: self.templates[i].clone(),
+                url: fill_template(&self.templates[i], host, port),
+            })
+            .collect())
+    }
+
+    /// Record that the proxy from `template` worked, opening the WebSocket in `latency`.
+    pub fn mark_up(&self, template: &str, latency: Duration) {
+        self.with_health(template, |h| {
+            h.failures = 0;
+            h.down_until = None;
+            h.latency = Some(latency);
+        });
+    }
+
+    /// Record that the proxy from `template` failed, so it's tried last for a while.
+    pub fn mark_down(&self, template: &str) {
+        let backoff = self.opts.retry_after;
+        self.with_health(template, |h| {
+            h.failures = h.failures.saturating_add(1);
+            let factor = 1u32 << (h.failures - 1).min(5);
+            h.down_until = Some(Instant::now() + backoff * factor);
+        });
+    }
+
+    /// Whether the proxy from `template` is currently marked down.
+    pub fn is_down(&self, template: &str) -> bool {
+        self.with_health(template, |h| {
+            h.down_until.is_some_and(|until| until > Instant::now())
+        })
+        .unwrap_or(false)
+    }
+
+    fn with_health<R>(&self, template: &str, f: impl FnOnce(&mut Health) -> R) -> Option<R> {
+        let i = self.templates.iter().position(|t| t == template)?;
+        Some(f(&mut self.health.lock().unwrap()[i]))
+    }
+}
+
+fn check_template(template: &str) -> Result<(), Error> {
+    if !is_websocket_url(template) {
+        return Err(Error::InvalidUri(format!(
+            "proxy template must be a ws:// or wss:// url: {template}"
+        )));
+    }
+    if !template.contains("{host}") {
+        return Err(Error::InvalidUri(format!(
+            "proxy template has no {{host}}: {template}"
+        )));
+    }
+    Ok(())
+}
+
+fn fill_template(template: &str, host: &str, port: u16) -> String {
+    // IPv6 literals need their brackets back in a url
+    let host = if host.contains(':') {
+        format!("[{host}]")
+    } else {
+        host.to_string()
+    };
+    if template.contains("{port}") {
+        template
+            .replace("{host}", &host)
+            .replace("{port}", &port.to_string())
+    } else {
+        template.replace("{host}", &format!("{host}:{port}"))
+    }
+}
+
+#[cfg(feature = "experimental")]
+mod connect {
+    use super::ProxyPool;
+    use crate::websocket::{self, WsStream};
+    use crate::{Error, LNSocket};
+    use bitcoin::secp256k1::{PublicKey, SecretKey};
+    use std::time::Instant;
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::timeout;
+
+    impl ProxyPool {
+        /// Open a WebSocket to `addr` through the first proxy that answers, in the order of
+        /// [`ProxyPool::candidates`], recording which worked.
+        pub async fn connect(&self, addr: &str) -> Result<WsStream, Error> {
+            let mut last_err = Error::NotConnected;
+            for candidate in self.candidates(addr)? {
+                let start = Instant::now();
+                let opened = timeout(
+                    self.opts.connect_timeout,
+                    websocket::open(&candidate.url, &self.opts.tls),
+                )
+                .await;
+                match opened {
+                    Ok(Ok(ws)) => {
+                        self.mark_up(&candidate.template, start.elapsed());
+                        return Ok(ws);
+                    }
+                    Ok(Err(err)) => {
+                        self.mark_down(&candidate.template);
+                        last_err = err;
+                    }
+                    Err(_) => {
+                        self.mark_down(&candidate.template);
+                        last_err = Error::Timeout;
+                    }
+                }
+            }
+            Err(last_err)
+        }
+
+        /// Try every proxy with a WebSocket to `probe` (`host[:port]`, any reachable node),
+        /// updating their health. Returns how many are up.
+        pub async fn check_health(&self, probe: &str) -> Result<usize, Error> {
+            let mut up = 0;
+            for candidate in self.candidates(probe)? {
+                let start = Instant::now();
+                let opened = timeout(
+                    self.opts.connect_timeout,
+                    websocket::open(&candidate.url, &self.opts.tls),
+                )
+                .await;
+                if let Ok(Ok(mut ws)) = opened {
+                    self.mark_up(&candidate.template, start.elapsed());
+                    let _ = ws.shutdown().await;
+                    up += 1;
+                } else {
+                    self.mark_down(&candidate.template);
+                }
+            }
+            Ok(up)
+        }
+    }
+
+    impl LNSocket {
+        /// Like [`LNSocket::connect`], reaching `addr` through a WebSocket-to-TCP proxy from
+        /// `pool`. See the [`ws_proxy`](crate::ws_proxy) module.
+        pub async fn connect_ws(
+            pool: &ProxyPool,
+            our_key: SecretKey,
+            their_pubkey: PublicKey,
+            addr: &str,
+        ) -> Result<LNSocket, Error> {
+            let stream = pool.connect(addr).await?;
+            LNSocket::handshake_outbound(stream, our_key, their_pubkey).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_templates() {
+        let pool = ProxyPool::new(&[
+            "wss://a.example.com/{host}:{port}",
+            "ws://b.example.com/connect/{host}",
+        ])
+        .unwrap();
+        let urls: Vec<String> = pool
+            .candidates("[::1]:9736")
+            .unwrap()
+            .into_iter()
+            .map(|c| c.url)
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "wss://a.example.com/[::1]:9736",
+                "ws://b.example.com/connect/[::1]:9736"
+            ]
+        );
+        assert_eq!(
+            pool.candidates("ln.example.com").unwrap()[1].url,
+            "ws://b.example.com/connect/ln.example.com:9735"
+        );
+
+        assert!(matches!(
+            ProxyPool::new(&["https://a.example.com/{host}"]),
+            Err(Error::InvalidUri(_))
+        ));
+        assert!(matches!(
+            ProxyPool::new(&["wss://a.example.com/"]),
+            Err(Error::InvalidUri(_))
+        ));
+    }
+
+    #[test]
+    fn test_failover_order() {
+        let (a, b, c) = ("ws://a/{host}", "ws://b/{host}", "ws://c/{host}");
+        let pool = ProxyPool::new(&[a, b, c]).unwrap();
+        let order = |pool: &ProxyPool| -> Vec<String> {
+            let candidates = pool.candidates("node").unwrap();
+            candidates.into_iter().map(|c| c.template).collect()
+        };
+        assert_eq!(order(&pool), [a, b, c]);
+
+        // a is down, b answers slower than c
+        pool.mark_down(a);
+        pool.mark_up(b, Duration::from_millis(80));
+        pool.mark_up(c, Duration::from_millis(20));
+        assert!(pool.is_down(a));
+        assert_eq!(order(&pool), [c, b, a]);
+
+        // of the ones down, the one retried soonest comes first
+        pool.mark_down(c);
+        pool.mark_down(c);
+        assert_eq!(order(&pool), [b, a, c]);
+
+        pool.mark_up(a, Duration::from_millis(10));
+        assert!(!pool.is_down(a));
+        assert_eq!(order(&pool), [a, b, c]);
+    }
+
+    #[cfg(feature = "experimental")]
+    #[tokio::test]
+    async fn test_connect_ws() -> Result<(), Error> {
+        use crate::LNSocket;
+        use crate::ln::wire::Message;
+        use crate::testing::default_init;
+        use crate::websocket::WsStream;
+        use bitcoin::secp256k1::{Secp256k1, SecretKey, rand};
+        use tokio::net::TcpListener;
+
+        // a proxy that is the node itself, speaking the handshake over the websocket
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let node_key = SecretKey::new(&mut rand::thread_rng());
+        let node_id = node_key.public_key(&Secp256k1::signing_only());
+        let node = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await?;
+            let ws = tokio_tungstenite::accept_async(tcp)
+                .await
+                .map_err(|_| Error::NotConnected)?;
+            let mut node = LNSocket::handshake_inbound(WsStream::new(ws), node_key).await?;
+            // perform_init reads first, so the node speaks first
+            node.write(&default_init()).await?;
+            assert!(matches!(node.read().await?, Message::Init(_)));
+            Ok::<_, Error>(())
+        });
+
+        // nothing listens on port 1, so the first proxy fails and the second is used
+        let dead = "ws://127.0.0.1:1/{host}";
+        let live = format!("ws://127.0.0.1:{port}/{{host}}");
+        let pool = ProxyPool::new(&[dead, &live])?;
+        let key = SecretKey::new(&mut rand::thread_rng());
+        let mut socket = LNSocket::connect_ws(&pool, key, node_id, "ln.example.com").await?;
+        socket.perform_init().await?;
+        node.await.unwrap()?;
+
+        assert!(pool.is_down(dead));
+        assert!(!pool.is_down(&live));
+        assert_eq!(pool.candidates("node")?[0].template, live);
+        Ok(())
+    }
+}